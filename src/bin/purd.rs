@@ -1,7 +1,15 @@
+use purd::headers::error::{PurdError, PurdResult};
+use purd::headers::ext4;
 use purd::headers::fs::disk;
-use purd::headers::mbr;
-use purd::headers::reader;
-use std::env;
+use purd::headers::gpt::uuids::GptArch;
+use purd::headers::luks;
+use purd::headers::md;
+use purd::headers::reader::{self, OnDisk};
+use purd::headers::source::cache::CacheConfig;
+use purd::headers::source::ewf;
+use purd::headers::source::{BlockSource, WritableFile};
+use std::sync::Arc;
+use std::{env, fs, io};
 
 /* =============================================== #
 ██████╗ ██╗   ██╗██████╗ ██████╗
@@ -16,56 +24,291 @@ Pure PURDfection. A purdfolio purdject for job apPlURDcations
 #FIXME: add more PURD taglines
 */
 
+const USAGE: &str = "usage: purd [--sector-size 512|4096] [--cache-mb N] \
+	 [--read-ahead BLOCKS] [--no-cache] [--repair-gpt [--write-to /dev/sdb]] \
+	 [--dps-arch x86|x86-64|arm|arm64] [--luks-key-file FILE] [--luks-passphrase] [--verify] /dev/sdb \n\
+	 or: purd [options] --md member1.img member2.img ... \n(will fail on a disk without an MBR or GPT, \
+	 ext4 fs only at the moment)";
+
 fn main() {
-	let file_arg = env::args().nth(1);
-	match file_arg {
-		Some(_) => {
-			println!("Let's get PURD-ey!");
+	let mut cache = CacheConfig::default();
+	let mut files = vec![];
+	let mut md_members = false;
+	let mut sector_size = None;
+	let mut repair = GptRepairArgs::default();
+	let mut dps_arch = GptArch::native();
+	let mut luks_keys = vec![];
+	let mut verify = false;
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		let mut number = |flag: &str| -> usize {
+			match args.next().and_then(|n| n.parse::<usize>().ok()) {
+				Some(n) => n,
+				None => {
+					eprintln!(
+						"purd: {} needs a number\n{}",
+						flag, USAGE
+					);
+					std::process::exit(1);
+				}
+			}
+		};
+		match arg.as_str() {
+			"--no-cache" => cache.capacity = 0,
+			"--cache-mb" => {
+				cache.capacity = number(&arg) * 1024 * 1024
+					/ cache.block_size as usize
+			}
+			"--read-ahead" => cache.read_ahead = number(&arg) as u64,
+			"--sector-size" => sector_size = Some(number(&arg) as u64),
+			"--repair-gpt" => repair.enabled = true,
+			"--write-to" => repair.write_to = args.next(),
+			"--md" => md_members = true,
+			"--verify" => verify = true,
+			"--luks-key-file" => match args.next().map(fs::read) {
+				Some(Ok(key)) => luks_keys.push(key),
+				Some(Err(e)) => {
+					eprintln!("purd: --luks-key-file: {}", e);
+					std::process::exit(1);
+				}
+				None => {
+					eprintln!("purd: --luks-key-file needs a file\n{}", USAGE);
+					std::process::exit(1);
+				}
+			},
+			// one line on stdin, without the newline
+			"--luks-passphrase" => {
+				let mut line = String::new();
+				if let Err(e) = io::stdin().read_line(&mut line) {
+					eprintln!("purd: --luks-passphrase: {}", e);
+					std::process::exit(1);
+				}
+				let line = line.strip_suffix('\n').unwrap_or(&line);
+				let line = line.strip_suffix('\r').unwrap_or(line);
+				luks_keys.push(line.as_bytes().to_vec());
+			}
+			"--dps-arch" => {
+				dps_arch = args.next().as_deref().and_then(GptArch::parse);
+				if dps_arch.is_none() {
+					eprintln!("purd: --dps-arch needs an architecture\n{}", USAGE);
+					std::process::exit(1);
+				}
+			}
+			_ => files.push(arg),
 		}
-		None => {
-			println!(
-				"usage: purd /dev/sdb \n(will fail on a disk \
-				 without an MBR or GPT, ext4 fs only at the moment)"
-			)
+	}
+	match files.last() {
+		Some(file_arg) => {
+			println!("Let's get PURD-ey!");
+			if repair.write_to.is_some() && !repair.enabled {
+				eprintln!("purd: --write-to only goes with --repair-gpt");
+				std::process::exit(1);
+			}
+			if verify {
+				match verify_ewf(file_arg) {
+					Ok(true) => {}
+					Ok(false) => std::process::exit(1),
+					Err(e) => {
+						eprintln!("purd: --verify: {}", e);
+						std::process::exit(1);
+					}
+				}
+			}
+			let result = match md_members {
+				true => purd_md(&files, cache, sector_size, &repair, dps_arch, &luks_keys),
+				false => reader::new_with_cache(file_arg, cache).and_then(|reader| {
+					match !luks_keys.is_empty() && luks::has_magic(&reader)? {
+						true => purd_luks(reader, sector_size, &repair, dps_arch, &luks_keys),
+						false => purd(reader, sector_size, &repair, dps_arch, &luks_keys),
+					}
+				}),
+			};
+			if let Err(e) = result {
+				eprintln!("purd: {}", e);
+				std::process::exit(1);
+			}
 		}
+		None => println!("{}", USAGE),
 	}
-	// start building our disk
-	let mut reader = reader::new(&file_arg.unwrap());
+}
+
+// --verify: the E01's stored MD5/SHA1 against the media, before
+// anything else gets read
+fn verify_ewf(path: &str) -> PurdResult<bool> {
+	let checks = ewf::verify_image(path)?;
+	if checks.is_empty() {
+		println!("{} has no stored hashes to verify", path);
+	}
+	for check in checks.iter() {
+		check.print();
+	}
+	Ok(checks.iter().all(|c| c.matches()))
+}
+
+// --repair-gpt on its own is a dry run, it only writes to --write-to
+#[derive(Default)]
+struct GptRepairArgs {
+	enabled: bool,
+	write_to: Option<String>,
+}
 
-	let mut d: disk::Disk = disk::Disk {
-		mbr: reader.read_header_from_offset::<mbr::Mbr>(0),
-		reader: reader,
-		pt_type: disk::PartitionTableType::Mbr,
-		partitions: vec![],
+/* --md: every file is a member of the same md array, and the array
+ * put back together is what gets read. */
+fn purd_md(
+	files: &[String],
+	cache: CacheConfig,
+	sector_size: Option<u64>,
+	repair: &GptRepairArgs,
+	dps_arch: Option<GptArch>,
+	luks_keys: &[Vec<u8>],
+) -> PurdResult<()> {
+	let mut members = vec![];
+	for file in files {
+		let reader = reader::new_with_cache(file, cache)?;
+		let superblock = match md::MdSuperblock::probe(&reader)? {
+			Some(superblock) => superblock,
+			None => {
+				return Err(PurdError::Unsupported(format!(
+					"{} without an md superblock",
+					file
+				)))
+			}
+		};
+		superblock.print(file);
+		members.push(md::MdMember {
+			name: file.clone(),
+			superblock,
+			source: reader.source.clone(),
+		});
+	}
+	let array = md::MdArray::assemble(members)?;
+	array.print();
+	purd_inner(array.source, sector_size, repair, dps_arch, luks_keys)
+}
+
+/* a whole LUKS image with keys to try, what it decrypts to gets read
+ * the same way as an md array */
+fn purd_luks(
+	reader: OnDisk,
+	sector_size: Option<u64>,
+	repair: &GptRepairArgs,
+	dps_arch: Option<GptArch>,
+	luks_keys: &[Vec<u8>],
+) -> PurdResult<()> {
+	let name = reader.source.name();
+	let header = match luks::LuksHeader::probe(&reader)? {
+		Some(header) => header,
+		None => return Err(PurdError::Unsupported(format!("{} without a LUKS header", name))),
+	};
+	header.print();
+	let device = luks::LuksDevice {
+		name,
+		header,
+		source: reader.source.clone(),
+		keyslot: None,
 	};
+	let (keyslot, source) = device.open(luks_keys)?;
+	println!("unlocked with keyslot {}", keyslot);
+	purd_inner(source, sector_size, repair, dps_arch, luks_keys)
+}
+
+// usually a filesystem straight on it, otherwise treat it like a disk
+fn purd_inner(
+	source: Arc<dyn BlockSource>,
+	sector_size: Option<u64>,
+	repair: &GptRepairArgs,
+	dps_arch: Option<GptArch>,
+	luks_keys: &[Vec<u8>],
+) -> PurdResult<()> {
+	match ext4::reader::Part::from_source(source.clone(), 0) {
+		Ok(mut ext4_reader) => ext4_reader.populate_blocks(),
+		Err(_) => purd(reader::from_source(source), sector_size, repair, dps_arch, luks_keys),
+	}
+}
+
+fn purd(
+	reader: OnDisk,
+	sector_size: Option<u64>,
+	repair: &GptRepairArgs,
+	dps_arch: Option<GptArch>,
+	luks_keys: &[Vec<u8>],
+) -> PurdResult<()> {
+	// start building our disk
+	let mut d = disk::Disk::new(reader, sector_size)?;
+	d.luks_keys = luks_keys.to_vec();
+	println!("logical sector size: {}", d.sector_size);
 	d.mbr.pretty_print();
 
 	// get that first partition to check for GPT
-	d.set_partition_table_type(); // will panic on unimplemented partition type
-	d.validate_headers();
-	d.register_partitions();
-	d.print_partitions_pretty();
+	d.set_partition_table_type()?; // errors on unimplemented partition type
+	d.validate_headers()?;
+	if repair.enabled {
+		let target = match &repair.write_to {
+			Some(path) => Some(WritableFile::open(path)?),
+			None => None,
+		};
+		let written = d.repair_gpt(target.as_ref())?;
+		/* The writes go around the reader and its cache, so when the
+		 * target is the disk being read everything from here on would
+		 * be the GPT from before the repair. Read it again fresh. */
+		if let (Some(target), false) = (&target, written.is_empty()) {
+			println!("GPT repair written to {}, run purd on it again to read the result", target.name());
+			return Ok(());
+		}
+	}
+	d.register_partitions()?;
+	d.print_partitions_pretty()?;
+	if let (disk::PartitionTableType::Gpt, Some(arch)) = (&d.pt_type, dps_arch) {
+		d.discoverable_layout(arch)?.print();
+	}
 	for part in d.partitions.clone().into_iter() {
 		match part.p_type {
 			disk::PartitionType::Ext4 => {
 				let ext4part = part.clone();
 				let mut ext4_reader =
-					d.make_ext4_block_reader(ext4part);
-				ext4_reader.populate_blocks();
+					d.make_ext4_block_reader(ext4part)?;
+				// one broken filesystem shouldn't hide the rest
+				if let Err(e) = ext4_reader.populate_blocks() {
+					println!("Error reading ext4 partition: {}", e);
+				}
 				// NOTE: haven't 100%'d EXT4,
 				// hash indexed directories are broken still.
 				// 64/32bit linear directories should work.
 				// extent tree walking works
 				// flex_bg and meta_bg not implemented yet.
 			}
-			disk::PartitionType::Unused => { /* */ }
+			disk::PartitionType::Unused
+			| disk::PartitionType::Extended
+			| disk::PartitionType::BsdSlice
+			| disk::PartitionType::SolarisSlice
+			| disk::PartitionType::LdmMetadata
+			| disk::PartitionType::LdmData
+			| disk::PartitionType::Luks
+			| disk::PartitionType::BitLocker => { /* */ }
 			_ => {
 				println!(
-					"Note: Filesystem partition type {:?} is not \
+					"Note: Filesystem partition type {} is not \
 					 implemented.",
 					part.p_type
 				);
 			}
 		}
 	}
+	for volume in d.volumes.iter() {
+		if volume.v_type != disk::PartitionType::Ext4 || volume.source.is_none() {
+			continue;
+		}
+		match d.make_ext4_volume_reader(volume) {
+			Ok(mut ext4_reader) => {
+				if let Err(e) = ext4_reader.populate_blocks() {
+					println!("Error reading ext4 volume {}: {}", volume.v_name, e);
+				}
+			}
+			Err(e) => println!("Error opening ext4 volume {}: {}", volume.v_name, e),
+		}
+	}
+	if let Some(stats) = d.reader.source.cache_stats() {
+		println!("cache: {}", stats);
+	}
+	Ok(())
 }
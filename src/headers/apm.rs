@@ -0,0 +1,259 @@
+use super::error::{PurdError, PurdResult};
+use super::reader::*;
+use crate::headers::fs::disk::PartitionType;
+use crate::prettify_output;
+use colored::*;
+use serde::Deserialize;
+
+/* Apple Partition Map, what Macs used before GPT and what hybrid
+ * ISOs still carry so old Macs can boot them. Everything is big
+ * endian. Block 0 is the driver descriptor map ("ER"), then one
+ * "PM" entry per block starting at block 1, each of which knows how
+ * many entries the whole map has.
+ * https://developer.apple.com/library/archive/documentation/mac/Devices/Devices-121.html
+ */
+
+// the driver descriptor map in block 0
+#[derive(Deserialize, Debug, Clone)]
+pub struct DriverDescriptor {
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub sig: u16, // "ER"
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub block_size: u16,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub block_count: u32,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub dev_type: u16,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub dev_id: u16,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub data: u32,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub driver_count: u16,
+}
+
+impl HasHeaderMagic for DriverDescriptor {
+    fn magic_field_offset(&self) -> u64 {
+        0
+    }
+    fn magic_field_size(&self) -> u64 {
+        2
+    }
+    fn magic_field_endianness(&self) -> Endianness {
+        Endianness::Big
+    }
+    fn magic_field_upcast(&self) -> u128 {
+        0x4552
+    }
+}
+
+// only the part of the entry anything reads, the rest is boot code
+// addresses for 68k/PPC loaders
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApmPartitionEntry {
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub sig: u16, // "PM"
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub sig_pad: u16,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub map_entries: u32, // same in every entry
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub start_block: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub block_count: u32,
+    pub name: [u8; 32],
+    pub type_name: [u8; 32],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub data_start: u32, // relative to start_block
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub data_count: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub status: u32,
+}
+
+impl HasHeaderMagic for ApmPartitionEntry {
+    fn magic_field_offset(&self) -> u64 {
+        0
+    }
+    fn magic_field_size(&self) -> u64 {
+        2
+    }
+    fn magic_field_endianness(&self) -> Endianness {
+        Endianness::Big
+    }
+    fn magic_field_upcast(&self) -> u128 {
+        0x504D
+    }
+}
+
+// NUL padded ASCII, at least in theory
+fn fixed_str(bytes: &[u8]) -> String {
+    let end =
+        bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+impl ApmPartitionEntry {
+    pub fn name(&self) -> String {
+        fixed_str(&self.name)
+    }
+    pub fn type_name(&self) -> String {
+        fixed_str(&self.type_name)
+    }
+    pub fn has_magic(&self) -> bool {
+        self.sig == self.magic_field_upcast() as u16
+    }
+    // types are compared without case, Apple's own tools do the same
+    pub fn get_disk_partition_type(&self) -> PartitionType {
+        let type_name = self.type_name().to_ascii_lowercase();
+        match type_name.as_str() {
+            "apple_free" => PartitionType::Unused,
+            "apple_partition_map" => PartitionType::ApplePartitionMap,
+            "apple_hfs" | "apple_hfsx" => PartitionType::AppleHfsPlus,
+            "apple_apfs" => PartitionType::AppleApfs,
+            "apple_unix_svr2" => PartitionType::LinuxFsTBD,
+            "dos_fat_32" | "dos_fat_16" | "dos_fat_12" => {
+                PartitionType::Fat
+            }
+            // Apple_Driver43, Apple_Driver_ATA, Apple_Driver_IOKit...
+            t if t.starts_with("apple_driver") => {
+                PartitionType::AppleDriver
+            }
+            _ => PartitionType::Unknown,
+        }
+    }
+    pub fn pretty_print(&self, block_size: u64) {
+        println!(
+            "[{}] name: {:?}\nstart block: {} ({}B blocks)\nblocks: \
+             {}\nstatus: {:#x}",
+            self.type_name().bright_blue(),
+            self.name(),
+            self.start_block,
+            block_size,
+            self.block_count,
+            self.status
+        );
+    }
+}
+
+// the kernel won't number more than this either
+pub const MAX_APM_ENTRIES: u32 = 256;
+
+#[derive(Debug, Clone)]
+pub struct Apm {
+    pub ddm: DriverDescriptor,
+    pub block_size: u64, // what every block number in here counts in
+    pub entries: Vec<ApmPartitionEntry>, // entry i is at block i + 1
+}
+
+impl Apm {
+    // anything that isn't a power of two we can read gets 512, which
+    // is what the map almost always uses anyway
+    fn block_size(ddm: &DriverDescriptor) -> u64 {
+        let block_size = ddm.block_size as u64;
+        match block_size.is_power_of_two()
+            && (512..=65536).contains(&block_size)
+        {
+            true => block_size,
+            false => 512,
+        }
+    }
+
+    // an "ER" in block 0 and a "PM" right after it
+    pub fn probe(reader: &mut OnDisk) -> PurdResult<bool> {
+        if reader.source.len() < 1024 {
+            return Ok(false);
+        }
+        let ddm =
+            reader.read_header_from_offset::<DriverDescriptor>(0)?;
+        if ddm.read_magic_field(&*reader, 0)?
+            != ddm.magic_field_upcast()
+        {
+            return Ok(false);
+        }
+        let block_size = Apm::block_size(&ddm);
+        if block_size * 2 > reader.source.len() {
+            return Ok(false);
+        }
+        let entry = reader
+            .read_header_from_offset::<ApmPartitionEntry>(
+                block_size,
+            )?;
+        Ok(entry.has_magic())
+    }
+
+    /* The first entry says how big the map is. Like the kernel, the
+     * walk stops at the first block without a "PM" rather than
+     * failing, a short map still says something. */
+    pub fn read(reader: &mut OnDisk) -> PurdResult<Apm> {
+        let ddm =
+            reader.read_header_from_offset::<DriverDescriptor>(0)?;
+        ddm.require_magic_field(&*reader, 0)?;
+        let block_size = Apm::block_size(&ddm);
+        let first = reader
+            .read_header_from_offset::<ApmPartitionEntry>(
+                block_size,
+            )?;
+        first.require_magic_field(&*reader, block_size)?;
+        let map_entries = first.map_entries.min(MAX_APM_ENTRIES);
+        let mut entries = vec![first];
+        for block in 2..=map_entries as u64 {
+            if (block + 1) * block_size > reader.source.len() {
+                break;
+            }
+            let entry = reader
+                .read_header_from_offset::<ApmPartitionEntry>(
+                    block * block_size,
+                )?;
+            if !entry.has_magic() {
+                break;
+            }
+            entries.push(entry);
+        }
+        Ok(Apm {
+            ddm,
+            block_size,
+            entries,
+        })
+    }
+
+    // how many entries the map claims, vs how many were there
+    pub fn map_entries(&self) -> u32 {
+        self.entries[0].map_entries
+    }
+    pub fn is_complete(&self) -> bool {
+        self.entries.len() as u32 == self.map_entries()
+    }
+
+    // (start, size) in bytes, checked against the disk
+    pub fn entry_extent(
+        &self,
+        entry: &ApmPartitionEntry,
+        disk_len: u64,
+    ) -> PurdResult<(u64, u64)> {
+        let start = entry.start_block as u64 * self.block_size;
+        let size = entry.block_count as u64 * self.block_size;
+        if start + size > disk_len {
+            return Err(PurdError::out_of_bounds(
+                "APM:partition",
+                start + size,
+                disk_len,
+            ));
+        }
+        Ok((start, size))
+    }
+
+    pub fn pretty_print(&self) {
+        prettify_output!(Apm, purple, bright_purple, {
+            println!(
+                "APM block size: {} blocks: {} drivers: {} map \
+                 entries: {} (found {})",
+                self.block_size,
+                self.ddm.block_count,
+                self.ddm.driver_count,
+                self.map_entries(),
+                self.entries.len()
+            );
+        });
+    }
+}
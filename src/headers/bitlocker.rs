@@ -0,0 +1,503 @@
+use super::error::{PurdError, PurdResult};
+use super::reader::*;
+use super::source::BlockSource;
+use colored::*;
+use serde::Deserialize;
+use serde_big_array::BigArray;
+use uuid::Uuid;
+
+/* BitLocker. The volume keeps an NTFS-ish boot sector with the OEM
+ * name swapped for "-FVE-FS-", which points at three copies of the
+ * FVE metadata block. Each block is a block header, a metadata
+ * header and a list of entries: key protectors (VMKs) with their
+ * nested entries, the encrypted FVEK, a description and where the
+ * real boot sector got moved to. Everything useful in there is
+ * encrypted, this only reads what isn't. Little endian throughout.
+ * Vista keeps one cluster number at 0x38 instead of three offsets.
+ * https://github.com/libyal/libbde/blob/main/documentation/
+ */
+
+pub const FVE_SIGNATURE: &[u8; 8] = b"-FVE-FS-";
+// 4967d63b-2e29-4ad8-8399-f6a339e3d001, Windows 7 and later
+const BITLOCKER_GUID: [u8; 16] = [
+    0x3b, 0xd6, 0x67, 0x49, 0x29, 0x2e, 0xd8, 0x4a, 0x83, 0x99, 0xf6,
+    0xa3, 0x39, 0xe3, 0xd0, 0x01,
+];
+const FVE_BLOCK_HEADER_SIZE: u64 = 64;
+const FVE_METADATA_HEADER_SIZE: usize = 48;
+const FVE_ENTRY_HEADER_SIZE: usize = 8;
+// the whole block is 64K on disk, metadata can't be bigger than that
+const FVE_MAX_METADATA: u32 = 0x10000;
+
+// entry types
+const FVE_ENTRY_VMK: u16 = 0x0002;
+const FVE_ENTRY_FVEK: u16 = 0x0003;
+const FVE_ENTRY_DESCRIPTION: u16 = 0x0007;
+const FVE_ENTRY_VOLUME_HEADER: u16 = 0x000f;
+
+// value types
+const FVE_VALUE_STRING: u16 = 0x0002;
+const FVE_VALUE_VMK: u16 = 0x0008;
+const FVE_VALUE_OFFSET_SIZE: u16 = 0x000f;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BitLockerBootSector {
+    pub jump: [u8; 3],
+    pub signature: [u8; 8], // "-FVE-FS-"
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    pub root_entries: u16,
+    pub total_sectors_16: u16,
+    pub media: u8,
+    pub sectors_per_fat: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+    pub total_sectors_32: u32,
+    pub unknown1: [u8; 20],
+    pub vista_metadata_lcn: u64, // 0x38
+    #[serde(with = "BigArray")]
+    pub unknown2: [u8; 96],
+    pub identifier: [u8; 16], // 0xa0, BITLOCKER_GUID from 7 on
+    pub metadata_offsets: [u64; 3], // bytes into the volume
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FveBlockHeader {
+    pub signature: [u8; 8],
+    pub size: u16,
+    pub version: u16, // 1 on Vista, 2 after
+    pub unknown1: u16,
+    pub unknown2: u16,
+    pub volume_size: u64, // how much is encrypted so far
+    pub convert_size: u32,
+    pub volume_header_sectors: u32,
+    pub metadata_offsets: [u64; 3],
+    pub volume_header_offset: u64, // the moved boot sector
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FveMetadataHeader {
+    pub metadata_size: u32, // this header and the entries
+    pub version: u32,
+    pub header_size: u32,
+    pub metadata_size_copy: u32,
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub volume_id: Uuid,
+    pub next_nonce: u32,
+    pub encryption_method: u32,
+    pub creation_time: u64, // FILETIME
+}
+
+#[derive(Debug, Clone)]
+pub struct FveEntry {
+    pub entry_type: u16,
+    pub value_type: u16,
+    pub version: u16,
+    pub data: Vec<u8>,
+}
+
+// one VMK, what it takes to get at the volume key
+#[derive(Debug, Clone)]
+pub struct KeyProtector {
+    pub id: Uuid,
+    pub modified: u64, // FILETIME
+    pub protection: u16,
+    pub entries: Vec<FveEntry>, // stretch key, encrypted key...
+}
+
+#[derive(Debug, Clone)]
+pub struct FveBlock {
+    pub offset: u64,
+    pub header: FveBlockHeader,
+    pub metadata: FveMetadataHeader,
+    pub entries: Vec<FveEntry>,
+}
+
+// how each copy of the metadata block compares to the one we used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FveCopyState {
+    Consistent,
+    Differs,
+    Unreadable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BitLockerVolume {
+    pub name: String,
+    pub boot: BitLockerBootSector,
+    pub vista: bool,
+    pub block: FveBlock,
+    pub copies: Vec<(u64, FveCopyState)>,
+    pub protectors: Vec<KeyProtector>,
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn guid_at(bytes: &[u8], at: usize) -> Uuid {
+    let guid: [u8; 16] = bytes[at..at + 16].try_into().unwrap();
+    Uuid::from_slice(&guid_byteswap(guid)).unwrap()
+}
+
+fn utf16_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|u| *u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+// 100ns ticks since 1601
+fn filetime_to_string(filetime: u64) -> String {
+    let secs = (filetime / 10_000_000).saturating_sub(11_644_473_600);
+    timestamp_to_string(secs)
+}
+
+pub fn has_signature(source: &dyn BlockSource) -> PurdResult<bool> {
+    if source.len() < 512 {
+        return Ok(false);
+    }
+    Ok(&source.read_bytes(3, 8)?[..] == FVE_SIGNATURE)
+}
+
+pub fn encryption_method_name(method: u32) -> String {
+    match method {
+        0x8000 => "AES-128-CBC with diffuser",
+        0x8001 => "AES-256-CBC with diffuser",
+        0x8002 => "AES-128-CBC",
+        0x8003 => "AES-256-CBC",
+        0x8004 => "XTS-AES-128",
+        0x8005 => "XTS-AES-256",
+        _ => return format!("unknown (0x{:04x})", method),
+    }
+    .to_string()
+}
+
+pub fn protection_name(protection: u16) -> String {
+    match protection {
+        0x0000 => "clear key",
+        0x0100 => "TPM",
+        0x0200 => "startup key",
+        0x0500 => "TPM and PIN",
+        0x0800 => "recovery password",
+        0x2000 => "password",
+        _ => return format!("unknown (0x{:04x})", protection),
+    }
+    .to_string()
+}
+
+fn value_type_name(value_type: u16) -> String {
+    match value_type {
+        0x0000 => "erased",
+        0x0001 => "key",
+        0x0002 => "string",
+        0x0003 => "stretch key",
+        0x0004 => "use key",
+        0x0005 => "AES-CCM encrypted key",
+        0x0006 => "TPM encoded key",
+        0x0007 => "validation",
+        0x0008 => "VMK",
+        0x0009 => "external key",
+        0x000a => "update",
+        0x000b => "error",
+        0x000f => "offset and size",
+        _ => return format!("value 0x{:04x}", value_type),
+    }
+    .to_string()
+}
+
+/* entries back to back, each with its size in front. A zero size
+ * ends the list early, one running off the end means it's corrupt. */
+fn parse_entries(bytes: &[u8]) -> PurdResult<Vec<FveEntry>> {
+    let mut entries = vec![];
+    let mut at = 0;
+    while at + FVE_ENTRY_HEADER_SIZE <= bytes.len() {
+        let size = u16_at(bytes, at) as usize;
+        if size == 0 {
+            break;
+        }
+        if size < FVE_ENTRY_HEADER_SIZE || at + size > bytes.len() {
+            return Err(PurdError::out_of_bounds(
+                "FVE metadata entry",
+                at as u64,
+                size as u64,
+            ));
+        }
+        entries.push(FveEntry {
+            entry_type: u16_at(bytes, at + 2),
+            value_type: u16_at(bytes, at + 4),
+            version: u16_at(bytes, at + 6),
+            data: bytes[at + FVE_ENTRY_HEADER_SIZE..at + size]
+                .to_vec(),
+        });
+        at += size;
+    }
+    Ok(entries)
+}
+
+impl KeyProtector {
+    fn parse(entry: &FveEntry) -> PurdResult<KeyProtector> {
+        if entry.data.len() < 0x1c {
+            return Err(PurdError::out_of_bounds(
+                "FVE VMK entry",
+                0,
+                entry.data.len() as u64,
+            ));
+        }
+        Ok(KeyProtector {
+            id: guid_at(&entry.data, 0),
+            modified: u64_at(&entry.data, 0x10),
+            protection: u16_at(&entry.data, 0x1a),
+            entries: parse_entries(&entry.data[0x1c..])?,
+        })
+    }
+
+    pub fn print(&self) {
+        let nested: Vec<String> = self
+            .entries
+            .iter()
+            .map(|e| match e.value_type {
+                FVE_VALUE_STRING => {
+                    format!("\"{}\"", utf16_string(&e.data))
+                }
+                v => value_type_name(v),
+            })
+            .collect();
+        println!(
+            "  key protector {}: {}, modified {}, {}",
+            self.id.to_string().bright_blue(),
+            protection_name(self.protection),
+            filetime_to_string(self.modified),
+            nested.join(", ")
+        );
+    }
+}
+
+impl FveBlock {
+    /* One copy of the metadata block, along with its raw bytes so
+     * the copies can be compared */
+    fn read(
+        source: &dyn BlockSource,
+        offset: u64,
+    ) -> PurdResult<(FveBlock, Vec<u8>)> {
+        let header = read_header_from_bytevec::<FveBlockHeader>(
+            source.read_bytes(offset, FVE_BLOCK_HEADER_SIZE)?,
+        )?;
+        if &header.signature != FVE_SIGNATURE {
+            return Err(PurdError::bad_magic(
+                "FVE metadata block",
+                offset,
+                u64::from_be_bytes(*FVE_SIGNATURE) as u128,
+                u64::from_be_bytes(header.signature) as u128,
+            ));
+        }
+        let at = offset + FVE_BLOCK_HEADER_SIZE;
+        let metadata = read_header_from_bytevec::<FveMetadataHeader>(
+            source.read_bytes(at, FVE_METADATA_HEADER_SIZE as u64)?,
+        )?;
+        let size = metadata.metadata_size;
+        if (size as usize) < FVE_METADATA_HEADER_SIZE
+            || size > FVE_MAX_METADATA
+        {
+            return Err(PurdError::out_of_bounds(
+                "FVE metadata",
+                at,
+                size as u64,
+            ));
+        }
+        let bytes = source.read_bytes(at, size as u64)?;
+        let entries =
+            parse_entries(&bytes[FVE_METADATA_HEADER_SIZE..])?;
+        let mut raw =
+            source.read_bytes(offset, FVE_BLOCK_HEADER_SIZE)?;
+        raw.extend_from_slice(&bytes);
+        Ok((
+            FveBlock {
+                offset,
+                header,
+                metadata,
+                entries,
+            },
+            raw,
+        ))
+    }
+
+    pub fn description(&self) -> Option<String> {
+        self.entries
+            .iter()
+            .find(|e| {
+                e.entry_type == FVE_ENTRY_DESCRIPTION
+                    && e.value_type == FVE_VALUE_STRING
+            })
+            .map(|e| utf16_string(&e.data))
+    }
+
+    // where the original boot sector went, (offset, size)
+    pub fn volume_header(&self) -> Option<(u64, u64)> {
+        self.entries
+            .iter()
+            .find(|e| {
+                e.entry_type == FVE_ENTRY_VOLUME_HEADER
+                    && e.value_type == FVE_VALUE_OFFSET_SIZE
+                    && e.data.len() >= 16
+            })
+            .map(|e| (u64_at(&e.data, 0), u64_at(&e.data, 8)))
+    }
+}
+
+impl BitLockerVolume {
+    /* None without the -FVE-FS- boot sector. The first metadata copy
+     * that reads is the one used, the others get checked against it
+     * byte for byte. Only every copy being unreadable is an error. */
+    pub fn probe(
+        name: &str,
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<BitLockerVolume>> {
+        if !has_signature(source)? {
+            return Ok(None);
+        }
+        let boot = read_header_from_bytevec::<BitLockerBootSector>(
+            source.read_bytes(0, 512)?,
+        )?;
+        let vista = boot.identifier != BITLOCKER_GUID;
+        let offsets = match vista {
+            // the other two are in the first block's header
+            true => {
+                let cluster = boot.bytes_per_sector as u64
+                    * boot.sectors_per_cluster as u64;
+                let first = boot
+                    .vista_metadata_lcn
+                    .checked_mul(cluster)
+                    .ok_or_else(|| {
+                        PurdError::out_of_bounds(
+                            "BitLocker:vista_metadata_lcn",
+                            boot.vista_metadata_lcn,
+                            cluster,
+                        )
+                    })?;
+                FveBlock::read(source, first)?
+                    .0
+                    .header
+                    .metadata_offsets
+            }
+            false => boot.metadata_offsets,
+        };
+        let mut copies = vec![];
+        let mut used: Option<(FveBlock, Vec<u8>)> = None;
+        for offset in offsets {
+            let state = match FveBlock::read(source, offset) {
+                Ok((block, raw)) => match &used {
+                    Some((_, first)) if *first != raw => {
+                        FveCopyState::Differs
+                    }
+                    Some(_) => FveCopyState::Consistent,
+                    None => {
+                        used = Some((block, raw));
+                        FveCopyState::Consistent
+                    }
+                },
+                Err(e) => FveCopyState::Unreadable(e.to_string()),
+            };
+            copies.push((offset, state));
+        }
+        let block = match used {
+            Some((block, _)) => block,
+            None => {
+                return Err(PurdError::Unsupported(format!(
+                    "BitLocker {} with no readable FVE metadata",
+                    name
+                )))
+            }
+        };
+        // one mangled protector shouldn't hide the rest of them
+        let mut protectors = vec![];
+        for (i, entry) in block.entries.iter().enumerate() {
+            if entry.entry_type != FVE_ENTRY_VMK
+                || entry.value_type != FVE_VALUE_VMK
+            {
+                continue;
+            }
+            match KeyProtector::parse(entry) {
+                Ok(protector) => protectors.push(protector),
+                Err(e) => println!(
+                    "{}",
+                    format!(
+                        "BitLocker: {} metadata entry {} is a \
+                         key protector that doesn't parse ({}), \
+                         skipping it",
+                        name, i, e
+                    )
+                    .yellow()
+                ),
+            }
+        }
+        Ok(Some(BitLockerVolume {
+            name: name.to_string(),
+            boot,
+            vista,
+            block,
+            copies,
+            protectors,
+        }))
+    }
+
+    pub fn consistent(&self) -> bool {
+        self.copies
+            .iter()
+            .all(|(_, state)| *state == FveCopyState::Consistent)
+    }
+
+    pub fn print(&self) {
+        let m = &self.block.metadata;
+        let h = &self.block.header;
+        println!(
+            "BitLocker{} {}, {}, created {}, {} bytes encrypted",
+            if self.vista { " (Vista)" } else { "" },
+            m.volume_id.to_string().bright_blue(),
+            encryption_method_name(m.encryption_method),
+            filetime_to_string(m.creation_time),
+            h.volume_size
+        );
+        if let Some(description) = self.block.description() {
+            println!("  description: {}", description);
+        }
+        if let Some((offset, size)) = self.block.volume_header() {
+            println!(
+                "  original boot sector moved to {}, {} bytes",
+                offset, size
+            );
+        }
+        for (offset, state) in self.copies.iter() {
+            let state = match state {
+                FveCopyState::Consistent => "consistent".green(),
+                FveCopyState::Differs => {
+                    "differs from the copy in use".yellow()
+                }
+                FveCopyState::Unreadable(e) => e.as_str().yellow(),
+            };
+            println!("  metadata copy at {}: {}", offset, state);
+        }
+        for protector in self.protectors.iter() {
+            protector.print();
+        }
+        let fvek = self
+            .block
+            .entries
+            .iter()
+            .any(|e| e.entry_type == FVE_ENTRY_FVEK);
+        println!(
+            "  encrypted volume key: {}, {} metadata entries",
+            print_bool(fvek),
+            self.block.entries.len()
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use super::error::PurdResult;
+use super::reader::*;
+use crate::headers::fs::disk::PartitionType;
+use crate::headers::mbr::{
+    slice_warning, MbrPartitionEntry, SlicePartition,
+};
+use serde::Deserialize;
+
+/* BSD disklabel, what FreeBSD/OpenBSD/NetBSD put in the second sector
+ * of their MBR slice to cut it up further. Little endian on x86,
+ * which is the only place it lives inside an MBR.
+ * https://man.freebsd.org/cgi/man.cgi?query=disklabel&sektion=5
+ */
+
+pub const BSD_DISKLABEL_MAGIC: u32 = 0x82564557;
+// sectors into the slice
+const BSD_LABEL_SECTOR: u64 = 1;
+// FreeBSD says 8, OpenBSD and NetBSD 16, 22 is all that fits in a
+// sector
+const BSD_MAX_PARTITIONS: u16 = 22;
+const BSD_RAW_PARTITION: usize = 2; // 'c', the whole slice
+const BSD_HEADER_SIZE: u64 = 148;
+const BSD_PARTITION_SIZE: u64 = 16;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BsdDisklabel {
+    pub magic: u32,
+    pub d_type: u16,
+    pub subtype: u16,
+    pub typename: [u8; 16],
+    pub packname: [u8; 16],
+    pub secsize: u32,
+    pub nsectors: u32,
+    pub ntracks: u32,
+    pub ncylinders: u32,
+    pub secpercyl: u32,
+    pub secperunit: u32,
+    pub sparespertrack: u16,
+    pub sparespercyl: u16,
+    pub acylinders: u32,
+    pub rpm: u16,
+    pub interleave: u16,
+    pub trackskew: u16,
+    pub cylskew: u16,
+    pub headswitch: u32,
+    pub trkseek: u32,
+    pub flags: u32,
+    pub drivedata: [u32; 5],
+    pub spare: [u32; 5],
+    pub magic2: u32,
+    pub checksum: u16,
+    pub npartitions: u16,
+    pub bbsize: u32,
+    pub sbsize: u32,
+}
+
+// OpenBSD keeps the high 16 bits of offset and size where FreeBSD has
+// fsize, nothing that fits in an MBR slice needs them
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct BsdPartitionEntry {
+    pub size: u32,
+    pub offset: u32,
+    pub fsize: u32,
+    pub fstype: u8,
+    pub frag: u8,
+    pub cpg: u16,
+}
+
+impl HasHeaderMagic for BsdDisklabel {
+    fn magic_field_offset(&self) -> u64 {
+        0
+    }
+    fn magic_field_size(&self) -> u64 {
+        4
+    }
+    fn magic_field_endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+    fn magic_field_upcast(&self) -> u128 {
+        BSD_DISKLABEL_MAGIC as u128
+    }
+}
+
+impl BsdPartitionEntry {
+    // FS_* from sys/disklabel.h
+    pub fn get_disk_partition_type(&self) -> PartitionType {
+        match self.fstype {
+            0 => PartitionType::Unused,
+            1 => PartitionType::FreeBsdSwap,
+            7 => PartitionType::FreeBsdUfs,
+            8 => PartitionType::Fat,
+            27 => PartitionType::FreeBsdZfs,
+            _ => PartitionType::Unknown,
+        }
+    }
+}
+
+impl BsdDisklabel {
+    pub fn has_magic(&self) -> bool {
+        self.magic == BSD_DISKLABEL_MAGIC
+            && self.magic2 == BSD_DISKLABEL_MAGIC
+    }
+    fn table_len(&self) -> u64 {
+        BSD_HEADER_SIZE
+            + self.npartitions.min(BSD_MAX_PARTITIONS) as u64
+                * BSD_PARTITION_SIZE
+    }
+}
+
+// every u16 of the label and its partitions xored, checksum included,
+// comes out to 0
+fn bsd_checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .chunks_exact(2)
+        .fold(0, |sum, w| sum ^ u16::from_le_bytes([w[0], w[1]]))
+}
+
+/* Read the disklabel in an MBR slice, None when there isn't one.
+ * Offsets in the label are absolute, except newer FreeBSD writes
+ * them relative to the slice, which it marks by starting 'c' at 0.
+ * Like the kernel, the whole-slice entries are left out and anything
+ * sticking out of the slice is skipped with a warning. */
+pub fn read_bsd_partitions(
+    reader: &mut OnDisk,
+    slice: &MbrPartitionEntry,
+    sector_size: u64,
+) -> PurdResult<Option<Vec<SlicePartition>>> {
+    let slice_start = slice.lba_of_partition_start as u64;
+    let slice_end = slice_start + slice.sectors_in_partition as u64;
+    let label_offset = (slice_start + BSD_LABEL_SECTOR) * sector_size;
+    if BSD_LABEL_SECTOR >= slice.sectors_in_partition as u64 {
+        return Ok(None);
+    }
+    let label = reader
+        .read_header_from_offset::<BsdDisklabel>(label_offset)?;
+    if !label.has_magic() {
+        return Ok(None);
+    }
+    let bytes = reader
+        .read_bytes_from_file(label_offset, label.table_len())?;
+    // the kernel doesn't check it either, a bad one is worth a mention
+    // but the partitions are usually still right
+    let label_sum = label.checksum;
+    if bsd_checksum(&bytes) != 0 {
+        slice_warning(format!(
+            "BSD disklabel at LBA {} has a bad checksum ({:#x})",
+            slice_start + BSD_LABEL_SECTOR,
+            label_sum
+        ));
+    }
+    let entries: Vec<BsdPartitionEntry> = bytes
+        [BSD_HEADER_SIZE as usize..]
+        .chunks_exact(BSD_PARTITION_SIZE as usize)
+        .map(read_header_from_bytes::<BsdPartitionEntry>)
+        .collect::<PurdResult<_>>()?;
+    let relative = entries
+        .get(BSD_RAW_PARTITION)
+        .is_some_and(|raw| raw.offset == 0 && slice_start != 0);
+
+    let mut found = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        let p_type = entry.get_disk_partition_type();
+        if entry.size == 0 || p_type == PartitionType::Unused {
+            continue;
+        }
+        let start_lba = match relative {
+            true => slice_start + entry.offset as u64,
+            false => entry.offset as u64,
+        };
+        let sectors = entry.size as u64;
+        if start_lba == slice_start
+            && sectors == slice_end - slice_start
+        {
+            continue;
+        }
+        let label = ((b'a' + i as u8) as char).to_string();
+        if start_lba < slice_start || start_lba + sectors > slice_end
+        {
+            slice_warning(format!(
+                "BSD partition {} ({}..{}) is outside its slice \
+                 ({}..{}), skipping it",
+                label,
+                start_lba,
+                start_lba + sectors,
+                slice_start,
+                slice_end
+            ));
+            continue;
+        }
+        found.push(SlicePartition {
+            label,
+            start_lba,
+            sectors,
+            p_type,
+        });
+    }
+    Ok(Some(found))
+}
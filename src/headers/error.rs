@@ -0,0 +1,139 @@
+use std::fmt;
+use std::io;
+
+/* one error type for the whole parsing stack so a corrupt disk
+ * comes back as a value instead of taking the process down */
+#[derive(Debug)]
+pub enum PurdError {
+    Io(io::Error),
+    BadMagic {
+        name: String,
+        offset: u64,
+        expected: u128,
+        found: u128,
+    },
+    ChecksumMismatch {
+        name: String,
+        expected: u64,
+        found: u64,
+    },
+    OutOfBounds {
+        name: String,
+        offset: u64,
+        size: u64,
+    },
+    Unsupported(String),
+    WrongKey(String), // nothing it was given unlocks it
+}
+
+pub type PurdResult<T> = Result<T, PurdError>;
+
+impl PurdError {
+    pub fn bad_magic(
+        name: &str,
+        offset: u64,
+        expected: u128,
+        found: u128,
+    ) -> PurdError {
+        PurdError::BadMagic {
+            name: name.to_string(),
+            offset,
+            expected,
+            found,
+        }
+    }
+    pub fn checksum_mismatch(
+        name: &str,
+        expected: u64,
+        found: u64,
+    ) -> PurdError {
+        PurdError::ChecksumMismatch {
+            name: name.to_string(),
+            expected,
+            found,
+        }
+    }
+    pub fn out_of_bounds(
+        name: &str,
+        offset: u64,
+        size: u64,
+    ) -> PurdError {
+        PurdError::OutOfBounds {
+            name: name.to_string(),
+            offset,
+            size,
+        }
+    }
+    pub fn unsupported(what: &str) -> PurdError {
+        PurdError::Unsupported(what.to_string())
+    }
+}
+
+impl fmt::Display for PurdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PurdError::Io(e) => write!(f, "I/O error: {}", e),
+            PurdError::BadMagic {
+                name,
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "bad magic for {} at 0x{:X}: expected 0x{:X} found \
+                 0x{:X}",
+                name, offset, expected, found
+            ),
+            PurdError::ChecksumMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "checksum mismatch for {}: expected 0x{:X} found \
+                 0x{:X}",
+                name, expected, found
+            ),
+            PurdError::OutOfBounds { name, offset, size } => write!(
+                f,
+                "out of bounds read in {}: 0x{:X} bytes at 0x{:X}",
+                name, size, offset
+            ),
+            PurdError::Unsupported(what) => {
+                write!(f, "not supported: {}", what)
+            }
+            PurdError::WrongKey(what) => {
+                write!(f, "no key given opens {}", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PurdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PurdError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PurdError {
+    fn from(e: io::Error) -> PurdError {
+        PurdError::Io(e)
+    }
+}
+
+// fixed size structs only fail to deserialize when the buffer is
+// short, anything else bincode reports is bad data.
+impl From<bincode::Error> for PurdError {
+    fn from(e: bincode::Error) -> PurdError {
+        match *e {
+            bincode::ErrorKind::Io(io_err) => PurdError::Io(io_err),
+            other => PurdError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                other.to_string(),
+            )),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::headers::disx86::disassemble;
+use crate::headers::error::{PurdError, PurdResult};
 use byteorder::{ByteOrder, LittleEndian};
 use serde::Deserialize;
 use serde_big_array::BigArray;
@@ -20,25 +21,40 @@ pub struct ExtendedBootSector {
 }
 
 impl ExtendedBootSector {
-    pub fn disassemble_boot_code(&self, boot_code_size: u64, rip: u64) {
-        disassemble(
-            &self.raw_sector_bytes,
-            32,
-            rip,
-            boot_code_size.try_into().unwrap(),
-        );
+    pub fn disassemble_boot_code(&self, boot_code_size: u64, rip: u64) -> PurdResult<()> {
+        let usize_size = self.checked_boot_code_size(boot_code_size, 0)?;
+        disassemble(&self.raw_sector_bytes, 32, rip, usize_size);
+        Ok(())
     }
-    pub fn get_boot_sector_signature(&self, boot_code_size: u64) -> u32 {
-        let usize_size = boot_code_size.try_into().unwrap();
-        LittleEndian::read_u32(&self.raw_sector_bytes[usize_size..usize_size + 4])
+    pub fn get_boot_sector_signature(&self, boot_code_size: u64) -> PurdResult<u32> {
+        let usize_size = self.checked_boot_code_size(boot_code_size, 4)?;
+        Ok(LittleEndian::read_u32(
+            &self.raw_sector_bytes[usize_size..usize_size + 4],
+        ))
+    }
+    // the boot code size comes from the main boot sector, don't trust it
+    fn checked_boot_code_size(&self, boot_code_size: u64, trailing: u64) -> PurdResult<usize> {
+        if boot_code_size + trailing > self.raw_sector_bytes.len() as u64 {
+            return Err(PurdError::out_of_bounds(
+                "exFAT:ExtendedBootSector",
+                boot_code_size,
+                trailing,
+            ));
+        }
+        Ok(boot_code_size as usize)
     }
     pub fn get_boot_code_size(&self, bytes_per_sector_shift: u8) -> u64 {
-        (1 << bytes_per_sector_shift) - 4
+        (1u64 << bytes_per_sector_shift) - 4
     }
 
-    pub fn section_is_valid(&self, bytes_per_sector_shift: u8) -> bool {
+    pub fn section_is_valid(&self, bytes_per_sector_shift: u8) -> PurdResult<bool> {
+        if !(9..=12).contains(&bytes_per_sector_shift) {
+            return Err(PurdError::unsupported(
+                "exFAT bytes per sector shift outside of 9..=12",
+            ));
+        }
         let signature =
-            self.get_boot_sector_signature(self.get_boot_code_size(bytes_per_sector_shift));
-        signature == 0xAA550000
+            self.get_boot_sector_signature(self.get_boot_code_size(bytes_per_sector_shift))?;
+        Ok(signature == 0xAA550000)
     }
 }
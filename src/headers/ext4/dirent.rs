@@ -1,3 +1,4 @@
+use crate::headers::error::{PurdError, PurdResult};
 use colored::*;
 
 // __(le|u)([0-9]+)\s+([a-z_]+)(.*)
@@ -12,14 +13,39 @@ pub struct DirEnt {
     pub filename: String,
     pub csum: Option<u32>,
 }
-pub fn peek_record_len(bytes: &[u8]) -> (u32, u16) {
+pub fn peek_record_len(bytes: &[u8]) -> PurdResult<(u32, u16)> {
+    if bytes.len() < 8 {
+        return Err(PurdError::out_of_bounds(
+            "ext4:DirEnt",
+            0,
+            bytes.len() as u64,
+        ));
+    }
     let inode_arr: [u8; 4] =
         [bytes[0], bytes[1], bytes[2], bytes[3]];
     let inode_u = u32::from_le_bytes(inode_arr);
     let dir: [u8; 2] = [bytes[4], bytes[5]];
-    (inode_u, u16::from_le_bytes(dir))
+    Ok((inode_u, u16::from_le_bytes(dir)))
 }
-pub fn get_dir_ent(bytes: &[u8]) -> DirEnt {
+pub fn get_dir_ent(bytes: &[u8]) -> PurdResult<DirEnt> {
+    // the name (or the checksum of a tail entry) has to fit in
+    // the record we were handed
+    let needed = if bytes.len() > 7
+        && bytes[7] == file_type::FAKE_TAIL_ENTRY_CHECKSUM
+    {
+        12
+    } else if bytes.len() > 6 {
+        8 + bytes[6] as usize
+    } else {
+        8
+    };
+    if bytes.len() < needed {
+        return Err(PurdError::out_of_bounds(
+            "ext4:DirEnt",
+            needed as u64,
+            bytes.len() as u64,
+        ));
+    }
     let dir: [u8; 2] = [bytes[4], bytes[5]];
     let rec_len_u = u16::from_le_bytes(dir);
     let inode_arr: [u8; 4] =
@@ -34,19 +60,19 @@ pub fn get_dir_ent(bytes: &[u8]) -> DirEnt {
             [bytes[8], bytes[9], bytes[10], bytes[11]];
         csum = Some(u32::from_le_bytes(csumbytes));
     } else {
-        filename = String::from_utf8(
-            bytes[8..8 + bytes[6] as usize].to_vec(),
+        filename = String::from_utf8_lossy(
+            &bytes[8..8 + bytes[6] as usize],
         )
-        .unwrap();
+        .to_string();
     }
-    DirEnt {
+    Ok(DirEnt {
         inode: inode_u,
         rec_len: rec_len_u,
         namelen: bytes[6],
         filetype: bytes[7],
         filename: filename,
         csum: csum,
-    }
+    })
 }
 
 impl DirEnt {
@@ -100,11 +126,9 @@ impl DirEnt {
                 );
             }
             x => {
-                panic!(
-                    "Error, unknown filetype for Dirent: \
-                     {:X}",
-                    x
-                );
+                return format!("UNKNOWN(filetype {:X})", x)
+                    .purple()
+                    .to_string();
             }
         }
 
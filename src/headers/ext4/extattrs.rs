@@ -1,3 +1,4 @@
+use crate::headers::error::{PurdError, PurdResult};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -25,13 +26,20 @@ pub struct ExtendedAttrEntry {
 
 pub const EXTATTR_ENTRY_SIZE_WO_NAME: u64 = 16;
 
-pub fn get_extended_attr_entry(bytes: &[u8]) -> ExtendedAttrEntry {
+pub fn get_extended_attr_entry(bytes: &[u8]) -> PurdResult<ExtendedAttrEntry> {
+    if bytes.len() < 16 || bytes.len() < 16 + bytes[0] as usize {
+        return Err(PurdError::out_of_bounds(
+            "ext4:ExtendedAttrEntry",
+            0,
+            bytes.len() as u64,
+        ));
+    }
     let value_offs_ = u16::from_le_bytes([bytes[2], bytes[3]]);
     let value_inum_ = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
     let value_size_ = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
     let hash_ = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
-    let filename_ = String::from_utf8(bytes[16..(16 + bytes[0] as usize)].to_vec()).unwrap();
-    ExtendedAttrEntry {
+    let filename_ = String::from_utf8_lossy(&bytes[16..(16 + bytes[0] as usize)]).to_string();
+    Ok(ExtendedAttrEntry {
         name_len: bytes[0],
         name_index: bytes[1],
         value_offs: value_offs_,
@@ -39,7 +47,7 @@ pub fn get_extended_attr_entry(bytes: &[u8]) -> ExtendedAttrEntry {
         value_size: value_size_,
         hash: hash_,
         name: filename_,
-    }
+    })
 }
 
 impl ExtendedAttrEntry {
@@ -1,3 +1,4 @@
+use crate::headers::error::{PurdError, PurdResult};
 use crate::headers::reader;
 use crate::headers::reader::OnDisk;
 use serde::Deserialize;
@@ -125,9 +126,13 @@ impl ExtentLeaf {
         block_0: u64,
         block_size: u64,
         content_size: u64,
-    ) -> Vec<u8> {
+    ) -> PurdResult<Vec<u8>> {
         // let block = self.ee_block;
-        assert_eq!(self.leaf_initialized(), true);
+        if !self.leaf_initialized() {
+            return Err(PurdError::unsupported(
+                "reading content from an uninitialized extent",
+            ));
+        }
         let bytes_in_bloc = self.bytes_covered(block_size);
         // println!(
         //     "File block: 0x{:X} bytesin_bloc: 0x{:X}
@@ -148,17 +153,28 @@ impl ExtentLeaf {
 }
 
 impl ExtentTree {
-    pub fn init(block: &[u8]) -> ExtentTree {
+    pub fn init(block: &[u8]) -> PurdResult<ExtentTree> {
         let header =
-            reader::read_header_from_bytes::<ExtentHeader>(&block);
+            reader::read_header_from_bytes::<ExtentHeader>(block)?;
         let sz_hdr = std::mem::size_of::<ExtentHeader>();
         let entries = header.eh_entries as usize;
         println!("Extent header has {} entries", entries);
         let max_entries = header.eh_max;
         let magic = header.eh_magic;
-        assert_eq!(0xF30A, magic);
-        if entries >= max_entries as usize {
-            panic!("oops, entries larger than max entries");
+        if !header.check_magic() {
+            return Err(PurdError::bad_magic(
+                "ext4:ExtentHeader",
+                0,
+                0xF30A,
+                magic as u128,
+            ));
+        }
+        if entries > max_entries as usize {
+            return Err(PurdError::out_of_bounds(
+                "ext4:ExtentHeader",
+                entries as u64,
+                max_entries as u64,
+            ));
         }
         println!("Header: {:X?}", header);
         let mut leaf_op: Option<Vec<ExtentLeaf>> = None;
@@ -171,18 +187,20 @@ impl ExtentTree {
             // leaf town
             let mut leafs: Vec<ExtentLeaf> = vec![];
             for i in 0..entries {
-                let leaf = reader::read_header_from_bytes::<ExtentLeaf>(
-                    &block[sz_hdr + i * leaf_size..],
-                );
+                let leaf =
+                    reader::read_header_from_bytes::<ExtentLeaf>(
+                        extent_slice(block, sz_hdr + i * leaf_size)?,
+                    )?;
                 if leaf.all_zero() {
                     //println!("empty leaf");
                 }
                 leafs.push(leaf);
             }
             leaf_op = Some(leafs);
-            tail = reader::read_header_from_bytes(
-                &block[sz_hdr + entries * leaf_size as usize..],
-            );
+            tail = read_extent_tail(
+                block,
+                sz_hdr + entries * leaf_size,
+            )?;
         } else {
             //node city
             let mut branches: Vec<ExtentNode> = vec![];
@@ -190,15 +208,16 @@ impl ExtentTree {
             for i in 0..entries {
                 let branch =
                     reader::read_header_from_bytes::<ExtentNode>(
-                        &block[sz_hdr + i * node_size..],
-                    );
+                        extent_slice(block, sz_hdr + i * node_size)?,
+                    )?;
 
                 branches.push(branch);
             }
             branch_op = Some(branches);
-            tail = reader::read_header_from_bytes(
-                &block[sz_hdr + entries * node_size as usize..],
-            );
+            tail = read_extent_tail(
+                block,
+                sz_hdr + entries * node_size,
+            )?;
         }
 
         let newtree = ExtentTree {
@@ -208,7 +227,7 @@ impl ExtentTree {
             tail: tail,
             subtrees: vec![],
         };
-        return newtree;
+        Ok(newtree)
     }
 
     pub fn ascend(
@@ -216,37 +235,57 @@ impl ExtentTree {
         reader: &mut OnDisk,
         block_0: u64,
         block_size: u64,
-    ) {
+    ) -> PurdResult<()> {
         //println!("ascending: node depth: {}", depth);
         if self.hdr.eh_depth != 0 {
-            if matches!(self.branch, None) {
-                panic!(
-                    "Extent error: depth was not 0 but there were \
-                     no branches"
-                );
-            }
-            for node in self.branch.as_ref().unwrap() {
+            let branches = match self.branch.as_ref() {
+                Some(branches) => branches,
+                None => {
+                    return Err(PurdError::unsupported(
+                        "Extent error: depth was not 0 but there \
+                         were no branches",
+                    ));
+                }
+            };
+            for node in branches {
                 // get the address of the next block
                 let addr = node.get_block();
                 let offset = reader::get_offset_from_block_number(
                     block_0, addr, block_size,
                 );
                 // init the block
-                let bytes = reader.read_bytes_from_file(offset, 12); //read a block
+                let bytes =
+                    reader.read_bytes_from_file(offset, 12)?; //read a block
                 let header = reader::read_header_from_bytevec::<
                     ExtentHeader,
-                >(bytes);
-                assert_eq!(header.check_magic(), true);
+                >(bytes)?;
+                if !header.check_magic() {
+                    let magic = header.eh_magic;
+                    return Err(PurdError::bad_magic(
+                        "ext4:ExtentHeader",
+                        offset,
+                        0xF30A,
+                        magic as u128,
+                    ));
+                }
+                // a child can't be as deep as its parent, that would
+                // make the tree loop forever
+                if header.eh_depth >= self.hdr.eh_depth {
+                    return Err(PurdError::unsupported(
+                        "extent node deeper than its parent",
+                    ));
+                }
                 let next_block_size =
                     12 + (header.eh_entries as u64 * 12) + 4;
                 let next_node_block = reader
-                    .read_bytes_from_file(offset, next_block_size);
+                    .read_bytes_from_file(offset, next_block_size)?;
                 // add it to the list
-                let mut tree = ExtentTree::init(&next_node_block);
-                tree.ascend(reader, block_0, block_size);
+                let mut tree = ExtentTree::init(&next_node_block)?;
+                tree.ascend(reader, block_0, block_size)?;
                 self.subtrees.push(tree);
             }
         }
+        Ok(())
     }
 
     pub fn dx_get_file_block(
@@ -288,7 +327,7 @@ impl ExtentTree {
         block_0: u64,
         block_size: u64,
         f_size: usize,
-    ) -> Vec<u8> {
+    ) -> PurdResult<Vec<u8>> {
         //TODO: this is dumb, loading a huge file right
         // into memory will broke thin
         let mut content: Vec<u8> = vec![];
@@ -300,17 +339,19 @@ impl ExtentTree {
                 Some(leafs) => {
                     //println!("leaves: {}", leafs.len());
                     for mut leaf in leafs.clone() {
-                        bytes_left = (f_size - content.len())
-                            .try_into()
-                            .unwrap();
+                        bytes_left = f_size
+                            .saturating_sub(content.len())
+                            as u64;
                         let mut bytes = leaf.get_file_content(
                             reader, block_0, block_size, bytes_left,
-                        );
+                        )?;
                         content.append(&mut bytes);
                     }
                 }
                 None => {
-                    panic!("tree length was 0 but contined no leaves")
+                    return Err(PurdError::unsupported(
+                        "tree length was 0 but contined no leaves",
+                    ));
                 }
             }
             // we've populated already so subtrees should
@@ -320,17 +361,40 @@ impl ExtentTree {
         for mut tree in self.subtrees.clone() {
             // this doesn't take into account the file block
             // order yet
-            bytes_left = (f_size - content.len()).try_into().unwrap();
+            bytes_left = f_size.saturating_sub(content.len()) as u64;
             let mut tree_bytes = tree.walk(
                 reader,
                 block_0,
                 block_size,
                 bytes_left as usize,
-            );
+            )?;
             content.append(&mut tree_bytes);
         }
         //println!("Found content length: {:X}",
         // content.len());
-        content
+        Ok(content)
+    }
+}
+
+fn extent_slice(block: &[u8], offset: usize) -> PurdResult<&[u8]> {
+    match block.get(offset..) {
+        Some(slice) => Ok(slice),
+        None => Err(PurdError::out_of_bounds(
+            "ext4:ExtentTree",
+            offset as u64,
+            block.len() as u64,
+        )),
+    }
+}
+
+// the inode's i_block copy of the root has no room for a tail, so
+// a missing one just reads as zero.
+fn read_extent_tail(
+    block: &[u8],
+    offset: usize,
+) -> PurdResult<ExtentTail> {
+    if offset + std::mem::size_of::<ExtentTail>() > block.len() {
+        return Ok(ExtentTail { eb_checksum: 0 });
     }
+    reader::read_header_from_bytes(&block[offset..])
 }
@@ -1,3 +1,4 @@
+use crate::headers::error::{PurdError, PurdResult};
 use crate::headers::ext4::extent::*;
 use crate::headers::reader::*;
 use colored::*;
@@ -235,11 +236,9 @@ impl Inode {
                 return "UNKNOWN".purple().to_string();
             }
             x => {
-                panic!(
-                    "Error, unknown entry in filemode for inode: \
-                     mode was {}",
-                    x
-                );
+                return format!("UNKNOWN(mode {:X})", x)
+                    .purple()
+                    .to_string();
             }
         }
 
@@ -261,10 +260,15 @@ impl Inode {
             attr_bitflags::EXT4_EA_INODE,
         )
     }
-    pub fn get_extent(&self) -> ExtentTree {
-        assert_eq!(self.inode_uses_extents(), true);
+    pub fn get_extent(&self) -> PurdResult<ExtentTree> {
+        if !self.inode_uses_extents() {
+            return Err(PurdError::unsupported(
+                "extent tree for an inode without EXT4_EXTENTS",
+            ));
+        }
         // needs to have ascend called after init
-        ExtentTree::init(self.block[..].try_into().unwrap())
+        let block = self.block;
+        ExtentTree::init(&block[..])
     }
 
     pub fn get_ext_attrs_addr(&self) -> u64 {
@@ -1,3 +1,4 @@
+use crate::headers::error::PurdResult;
 use crate::headers::ext4::block_group::*;
 use crate::headers::ext4::reader::Bg;
 use crate::headers::ext4::reader::Ino;
@@ -86,10 +87,10 @@ impl Bg {
         reader: &mut OnDisk,
         s: &Superblock,
         start: u64,
-    ) {
+    ) -> PurdResult<()> {
         if self.inodes_uninit() {
             println!("Skipping uninitialized inode table...");
-            return;
+            return Ok(());
         }
         let block_table = self.get_inode_table_block();
         let bs = s.block_size_bytes();
@@ -111,7 +112,7 @@ impl Bg {
             let inode = reader
                 .read_header_from_offset::<ext4::inode::Inode>(
                     current_offset,
-                );
+                )?;
 
             let mut ino = Ino {
                 start: current_offset,
@@ -132,12 +133,12 @@ impl Bg {
             );
             inode.print_fields();
             ino.set_inode_checksum_seed(s);
-            ino.populate_ext_attrs(reader, s, start);
-            ino.populate_extents(reader, s, start);
+            ino.populate_ext_attrs(reader, s, start)?;
+            ino.populate_extents(reader, s, start)?;
             // doesn't differentiate between file content
             // and dirents yet
             if ino.id != s.journal_inum {
-                let cnt = ino.get_file_content(reader, s, start);
+                let cnt = ino.get_file_content(reader, s, start)?;
                 if cnt.len() > 0 && cnt.len() < 0x1000 {
                     if cnt.len() > 0x100 {
                         //println!("{:X?}", &cnt[..100]);
@@ -149,11 +150,12 @@ impl Bg {
                         format!("{}", cont.green())
                     );
                 }
-                ino.get_directory_entries(reader, s, start);
+                ino.get_directory_entries(reader, s, start)?;
             }
-            ino.validate_checksum(reader, s);
+            ino.validate_checksum(reader, s)?;
 
             self.ino.push(ino);
         }
+        Ok(())
     }
 }
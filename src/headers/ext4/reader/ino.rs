@@ -88,6 +88,7 @@ if inode.inode_uses_extents() {
 */
 use super::hashdir;
 use crate::headers::constants;
+use crate::headers::error::{PurdError, PurdResult};
 use crate::headers::ext4;
 use crate::headers::ext4::dirent;
 use crate::headers::ext4::extent;
@@ -107,7 +108,7 @@ impl Ino {
         reader: &mut OnDisk,
         s: &Superblock,
         block0: u64,
-    ) {
+    ) -> PurdResult<()> {
         if self.inode.get_ext_attrs_addr() != 0 {
             let extoffset = get_offset_from_block_number(
                 block0,
@@ -115,8 +116,8 @@ impl Ino {
                 s.block_size_bytes(),
             );
             type HdrType = ext4::extattrs::ExtendedAttrBlock;
-            let extadd =
-                reader.read_header_from_offset::<HdrType>(extoffset);
+            let extadd = reader
+                .read_header_from_offset::<HdrType>(extoffset)?;
             //println!("EXTATTR: {:#X?}", extadd);
             //println!("size of header: 0x{:x?}",
             // size_of::<HdrType>());
@@ -130,12 +131,12 @@ impl Ino {
                 let extblockbytes = reader.read_bytes_from_file(
                     extoffset + entry_offset + size_of_hdr,
                     0xff + ext4::extattrs::EXTATTR_ENTRY_SIZE_WO_NAME,
-                );
+                )?;
                 //println!("{:X?}", extblockbytes);
                 let extblock =
                     ext4::extattrs::get_extended_attr_entry(
                         &extblockbytes,
-                    );
+                    )?;
                 if !extblock.is_empty() {
                     //println!("{:#X?}", extblock);
                     entry_offset +=
@@ -148,6 +149,7 @@ impl Ino {
             }
             self.attr = Some(exat);
         }
+        Ok(())
     }
 
     pub fn populate_extents(
@@ -155,16 +157,17 @@ impl Ino {
         reader: &mut OnDisk,
         s: &Superblock,
         block0: u64,
-    ) {
+    ) -> PurdResult<()> {
         let inode = self.inode;
         //inode.print_fields();
 
         if !inode.inode_uses_extents() {
-            return;
+            return Ok(());
         }
-        let mut extent = inode.get_extent();
-        extent.ascend(reader, block0, s.block_size_bytes());
+        let mut extent = inode.get_extent()?;
+        extent.ascend(reader, block0, s.block_size_bytes())?;
         self.extent = Some(extent);
+        Ok(())
     }
 
     pub fn set_inode_checksum_seed(&mut self, s: &Superblock) {
@@ -185,13 +188,13 @@ impl Ino {
         &mut self,
         reader: &mut OnDisk,
         s: &Superblock,
-    ) -> bool {
+    ) -> PurdResult<bool> {
         if !s.metadata_csum() {
             println!(
                 "METADATA_CSUM not set, skipping inode csum \
                  validation"
             );
-            return true;
+            return Ok(true);
         }
         if self.seed == 0 {
             self.set_inode_checksum_seed(s);
@@ -201,10 +204,10 @@ impl Ino {
         inode.checksum_hi = 0;
         inode.checksum_lo = 0;
         let inode_size = s.inode_size;
-        let inode_des = bincode::serialize::<Inode>(&inode).unwrap();
+        let inode_des = bincode::serialize::<Inode>(&inode)?;
 
         let mut inode_bytes = reader
-            .read_bytes_from_file(self.start, s.inode_size as u64);
+            .read_bytes_from_file(self.start, s.inode_size as u64)?;
         for i in 0..2 {
             inode_bytes[constants::EXT4_INODE_CHECKSUM_LO_OFFSET
                 as usize
@@ -217,7 +220,14 @@ impl Ino {
                     + i] = 0;
             }
         }
-        assert_eq!(inode_des[..s.inode_size as usize], inode_bytes);
+        let compared = inode_des.len().min(inode_bytes.len());
+        if inode_des[..compared] != inode_bytes[..compared] {
+            println!(
+                "{} inode {:X} changed on disk while reading it",
+                "WARNING:".yellow(),
+                self.id
+            );
+        }
         let byte_content = &inode_bytes[..s.inode_size as usize];
         csum = summer::crc32c(csum, byte_content.to_vec());
         let mut in_inode = self.inode.checksum();
@@ -236,7 +246,7 @@ impl Ino {
             csum,
             print_bool(in_inode == csum)
         );
-        in_inode == csum
+        Ok(in_inode == csum)
     }
 
     pub fn get_file_content(
@@ -244,11 +254,13 @@ impl Ino {
         reader: &mut OnDisk,
         s: &Superblock,
         block0: u64,
-    ) -> Vec<u8> {
-        if !self.inode.inode_uses_extents() {
-            return vec![];
-        }
-        let mut tree = self.extent.clone().unwrap();
+    ) -> PurdResult<Vec<u8>> {
+        let mut tree = match &self.extent {
+            Some(tree) if self.inode.inode_uses_extents() => {
+                tree.clone()
+            }
+            _ => return Ok(vec![]),
+        };
         tree.walk(
             reader,
             block0,
@@ -277,9 +289,9 @@ impl Ino {
         reader: &mut OnDisk,
         s: &Superblock,
         block0: u64,
-    ) {
+    ) -> PurdResult<()> {
         if !self.inode.directory() {
-            return;
+            return Ok(());
         }
         let inode = self.inode.clone();
         let mut extents: extent::ExtentTree;
@@ -289,7 +301,7 @@ impl Ino {
                 extents = tree.clone();
             }
             None => {
-                return;
+                return Ok(());
             }
         }
         let bs = s.block_size_bytes();
@@ -298,23 +310,23 @@ impl Ino {
             block0,
             bs,
             self.inode.get_file_size() as usize,
-        );
+        )?;
         let mut slice = &data[..];
         println!("Data from extent was length: {}", data.len());
 
         if s.uses_indexed_dirs()
             && self.inode.uses_hash_tree_directories()
         {
-            let root = read_header_from_bytes::<hashdir::Root>(&data);
+            let root =
+                read_header_from_bytes::<hashdir::Root>(&data)?;
             if !root.not_inode_0() {
-                return;
+                return Ok(());
             }
             println!("{:x?}", root);
             if !root.validate(bs as u16) {
                 println!("{}", "Root did not validate".red());
-                std::thread::sleep_ms(1000);
                 self.dirs = None;
-                return;
+                return Ok(());
             }
 
             println!("{:?}", root.hash_version());
@@ -326,9 +338,17 @@ impl Ino {
                 // work since we're just reading to
                 // validate and don't care about
                 // perf I'll come back to actually bsearch later.
+                let entry_start = entry_offset + entry_size * i;
+                if entry_start >= data.len() {
+                    return Err(PurdError::out_of_bounds(
+                        "ext4:hashdir::Entry",
+                        entry_start as u64,
+                        data.len() as u64,
+                    ));
+                }
                 let entry = read_header_from_bytes::<hashdir::Entry>(
-                    &data[entry_offset + entry_size * i..],
-                );
+                    &data[entry_start..],
+                )?;
                 println!("{:x?}", entry);
                 if entry.empty() {
                     continue;
@@ -363,11 +383,11 @@ impl Ino {
                     }
                 }
                 let dir_data =
-                    leaf.get_file_content(reader, block0, bs, 1024);
+                    leaf.get_file_content(reader, block0, bs, 1024)?;
                 let (_ino, len) =
-                    dirent::peek_record_len(&dir_data[..]);
-                let dirent =
-                    dirent::get_dir_ent(&dir_data[..len as usize]);
+                    dirent::peek_record_len(&dir_data[..])?;
+                let len = (len as usize).min(dir_data.len());
+                let dirent = dirent::get_dir_ent(&dir_data[..len])?;
                 println!("{:x?}", dirent);
                 let (major, _minor) = hash::dirhash::create_dirhash(
                     s.hash_seed,
@@ -380,12 +400,12 @@ impl Ino {
                     major,
                     print_bool(hash == major)
                 );
-                if !(hash == major) {
-                    println!(
-                        "Error, hash indexed dir's hash did not \
-                         match."
-                    );
-                    std::process::exit(-1);
+                if hash != major {
+                    return Err(PurdError::checksum_mismatch(
+                        "ext4:hashdir",
+                        hash as u64,
+                        major as u64,
+                    ));
                 }
                 //entry.validate();
                 // ah yes, reading the btree as an array to validate
@@ -398,20 +418,29 @@ impl Ino {
                 // can be an entire extent tree of blocks of them?
                 // either way need to peek header to read len first
                 let len_left = data.len() - slice.len();
-                let (ino, rec_len) = dirent::peek_record_len(slice);
+                let (ino, rec_len) = dirent::peek_record_len(slice)?;
                 if ino == 0 {
                     println!(
                         "found last entry at offset: {}",
                         len_left
                     );
                 }
+                // a zero length record would spin here forever
+                if rec_len < 8 || rec_len as usize > slice.len() {
+                    return Err(PurdError::out_of_bounds(
+                        "ext4:DirEnt",
+                        len_left as u64,
+                        rec_len as u64,
+                    ));
+                }
                 let cur_slice = &slice[..rec_len as usize];
-                let dirent = dirent::get_dir_ent(&cur_slice);
+                let dirent = dirent::get_dir_ent(cur_slice)?;
                 println!("dirent: {:x?}", dirent);
                 println!("file_type: {}", dirent.filetype_to_str());
                 let last = dirent.is_last_dirent();
-                if dirent.is_checksum_entry() {
-                    let csum = dirent.csum.unwrap();
+                if let (true, Some(csum)) =
+                    (dirent.is_checksum_entry(), dirent.csum)
+                {
                     println!(
                         "DATA_LEN: {} LEN LEFT: {}",
                         data.len(),
@@ -433,6 +462,7 @@ impl Ino {
             }
             self.dirs = Some(dirs);
         }
+        Ok(())
     }
 
     /*
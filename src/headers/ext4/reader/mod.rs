@@ -4,6 +4,7 @@ use super::extent::*;
 use super::inode::*;
 use super::superblock::Superblock;
 use super::*;
+use crate::headers::error::PurdResult;
 use crate::headers::reader::OnDisk;
 use colored::*;
 
@@ -50,9 +51,9 @@ pub struct PartReader {
 }
 
 impl Part {
-    pub fn populate_blocks(&mut self) {
-        self.populate_block_groups();
-        self.validate_block_groups();
-        self.populate_inodes();
+    pub fn populate_blocks(&mut self) -> PurdResult<()> {
+        self.populate_block_groups()?;
+        self.validate_block_groups()?;
+        self.populate_inodes()
     }
 }
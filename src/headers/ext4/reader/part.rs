@@ -1,7 +1,10 @@
 use super::superblock::*;
 use super::*;
+use crate::headers::error::{PurdError, PurdResult};
 use crate::headers::reader::*;
-use crate::headers::summer;
+use crate::headers::source::BlockSource;
+use crate::headers::{constants, summer};
+use std::sync::Arc;
 impl Part {
     pub fn init(reader: OnDisk, sb: Superblock, start: u64) -> Part {
         Part {
@@ -11,7 +14,23 @@ impl Part {
             bg: vec![],
         }
     }
-    pub fn populate_block_groups(&mut self) {
+    // for callers that only have a source and know where the
+    // filesystem starts in it
+    pub fn from_source(
+        source: Arc<dyn BlockSource>,
+        start: u64,
+    ) -> PurdResult<Part> {
+        let mut reader = from_source(source);
+        let sb = reader.read_header_from_offset::<Superblock>(
+            start + constants::EXT4_SUPERBLOCK_0_OFFSET,
+        )?;
+        sb.require_magic_field(
+            &reader,
+            start + constants::EXT4_SUPERBLOCK_0_OFFSET,
+        )?;
+        Ok(Part::init(reader, sb, start))
+    }
+    pub fn populate_block_groups(&mut self) -> PurdResult<()> {
         let bgdt_offset =
             self.s.get_group_descriptor_table_offset(self.start);
         for i in 0..self.s.number_of_groups() {
@@ -21,22 +40,22 @@ impl Part {
                         + std::mem::size_of::<BlockGroupDescriptor64>(
                         );
                 if self.s.desc_size < combined_size as u16 {
-                    panic!(
+                    return Err(PurdError::Unsupported(format!(
                         "size for 64bit group descriptor didn't \
                          validate, should be at least {}",
                         combined_size
-                    );
+                    )));
                 }
                 let bg_offset =
                     bgdt_offset + combined_size as u64 * i;
                 let bg32 = self
                     .reader
-                    .read_header_from_offset::<BlockGroupDescriptor32>(bg_offset);
+                    .read_header_from_offset::<BlockGroupDescriptor32>(bg_offset)?;
                 let bg64 = self
                     .reader
                     .read_header_from_offset::<BlockGroupDescriptor64>(
                         bg_offset + std::mem::size_of::<BlockGroupDescriptor32>() as u64,
-                    );
+                    )?;
                 //println!("{:#x?} {:#x?}", bg32, bg64);
                 let bgboi = Bg::init(
                     i as u32,
@@ -53,7 +72,7 @@ impl Part {
                         * i;
                 let bg = self
                     .reader
-                    .read_header_from_offset::<BlockGroupDescriptor32>(bg_offset);
+                    .read_header_from_offset::<BlockGroupDescriptor32>(bg_offset)?;
                 let bgboi =
                     Bg::init(i as u32, bg_offset, Some(bg), None);
                 //bgboi.print();
@@ -68,9 +87,10 @@ impl Part {
             )
             .blue(),
         );
+        Ok(())
     }
 
-    pub fn populate_inodes(&mut self) {
+    pub fn populate_inodes(&mut self) -> PurdResult<()> {
         if self.s.backup_bgs != [0, 0] {
             println!("Note: Backup BGS at {:x?}", self.s.backup_bgs);
         }
@@ -79,15 +99,16 @@ impl Part {
                 &mut self.reader,
                 &self.s,
                 self.start,
-            );
+            )?;
             let count = self.bg[i].ino.len();
             if count != 0 {
                 println!("Found {} inodes in bg:{} ", count, i + 1);
             }
         }
+        Ok(())
     }
 
-    pub fn validate_block_groups(&mut self) {
+    pub fn validate_block_groups(&mut self) -> PurdResult<()> {
         self.s.debug_print_some_stuf();
         for bgid in 0..self.bg.len() {
             let mut bytes: Vec<u8> = vec![];
@@ -106,13 +127,13 @@ impl Part {
                         bytes.push(byte);
                     }
                 }
-                let bg_bytes =
-                    <u32>::to_le_bytes(bgid.try_into().unwrap());
+                let bg_bytes = <u32>::to_le_bytes(bgid as u32);
                 bytes.append(&mut bg_bytes.to_vec());
-                let bg_purt = self.bg.get(bgid).unwrap();
+                let bg_purt = &self.bg[bgid];
                 let bg_start = bg_purt.start;
-                let bg_ondisk =
-                    self.reader.read_bytes_from_file(bg_start, 0x1e);
+                let bg_ondisk = self
+                    .reader
+                    .read_bytes_from_file(bg_start, 0x1e)?;
                 bytes.append(&mut bg_ondisk.clone());
                 bytes.push(0);
                 bytes.push(0); //fake checksum field
@@ -121,10 +142,13 @@ impl Part {
                         &mut self.reader.read_bytes_from_file(
                             bg_start + 0x20,
                             (self.s.desc_size - 0x20) as u64,
-                        ),
+                        )?,
                     );
                 }
-                let bgd_actual = bg_purt.b32.unwrap();
+                let bgd_actual = match bg_purt.b32 {
+                    Some(b32) => b32,
+                    None => continue,
+                };
 
                 let crcsum = summer::crc32c(csum_seed, bytes);
                 if bgd_actual.checksum as u32 != (crcsum & 0xffff) {
@@ -144,33 +168,46 @@ impl Part {
                 let bytesdisk = self.reader.read_bytes_from_file(
                     self.start + 1024 + 0x68,
                     16,
-                );
-                assert_eq!(bytesdisk, self.s.uuid);
+                )?;
+                if bytesdisk != self.s.uuid {
+                    println!(
+                        "{}",
+                        "WARNING: superblock uuid changed on disk, \
+                         skipping bg checksums"
+                            .yellow()
+                    );
+                    return Ok(());
+                }
 
                 bytes.append(&mut self.s.uuid.to_vec());
                 for byte in <u32>::to_le_bytes(bgid as u32) {
                     bytes.push(byte);
                 }
 
-                let bg_purt = self.bg.get(bgid).unwrap();
+                let bg_purt = &self.bg[bgid];
+                let bg32 = match bg_purt.b32 {
+                    Some(b32) => b32,
+                    None => continue,
+                };
 
                 let bg_start = bg_purt.start;
-                let bitecopy =
-                    self.reader.read_bytes_from_file(bg_start, 0x1e);
+                let bitecopy = self
+                    .reader
+                    .read_bytes_from_file(bg_start, 0x1e)?;
                 /* not sure whether BE requires using the
                  * in-memory fields yet. */
                 unsafe {
                     let bites = std::mem::transmute::<
                         BlockGroupDescriptor32,
                         [u8; 0x20],
-                    >(
-                        bg_purt.b32.as_ref().unwrap().clone(),
-                    );
-                    // assert if they're not equal for now
-                    assert_eq!(
-                        bitecopy,
-                        bites[..bites.len() - 2].to_vec()
-                    );
+                    >(bg32);
+                    if bitecopy != bites[..bites.len() - 2] {
+                        println!(
+                            "{} bg {:x} differs from the copy on disk",
+                            "WARNING:".yellow(),
+                            bgid
+                        );
+                    }
                     bytes.append(
                         &mut bites[..bites.len() - 2].to_vec(),
                     )
@@ -180,11 +217,10 @@ impl Part {
                         &mut self.reader.read_bytes_from_file(
                             bg_start + 0x20,
                             (self.s.desc_size - 0x20) as u64,
-                        ),
+                        )?,
                     );
                 }
 
-                let bg32 = bg_purt.b32.as_ref().unwrap();
                 let crcsum = summer::crc16(!0, bytes.clone());
                 let bgcrc = bg32.checksum;
                 if bgcrc != crcsum {
@@ -203,5 +239,6 @@ impl Part {
                 }
             }
         }
+        Ok(())
     }
 }
@@ -355,24 +355,19 @@ impl Superblock {
     }
 
     pub fn volume_name(&self) -> String {
-        std::string::String::from_utf8(self.volume_name.to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.volume_name).to_string()
     }
     pub fn mount_opts(&self) -> String {
-        std::string::String::from_utf8(self.mount_opts.to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.mount_opts).to_string()
     }
     pub fn last_mounted(&self) -> String {
-        std::string::String::from_utf8(self.last_mounted.to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.last_mounted).to_string()
     }
     pub fn first_error_func(&self) -> String {
-        std::string::String::from_utf8(self.first_error_func.to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.first_error_func).to_string()
     }
     pub fn last_error_func(&self) -> String {
-        std::string::String::from_utf8(self.last_error_func.to_vec())
-            .unwrap()
+        String::from_utf8_lossy(&self.last_error_func).to_string()
     }
 
     pub fn uses_64bit(&self) -> bool {
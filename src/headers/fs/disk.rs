@@ -1,7 +1,12 @@
 use super::DiskPart;
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::gpt::uuids::GptArch;
 use crate::headers::reader::*;
+use crate::headers::source::{BlockSource, SubSource};
 use crate::headers::*;
 use colored::*;
+use std::fmt;
+use std::sync::Arc;
 
 /* I don't care that nobody uses disks anymore I'm calling it this
  * to justify the name of the exe */
@@ -10,20 +15,32 @@ pub struct Disk {
     pub pt_type: PartitionTableType,
     pub partitions: Vec<Partition>,
     pub mbr: mbr::Mbr,
+    pub apm: Option<apm::Apm>,
+    pub logical_partitions: Vec<mbr::LogicalPartition>,
+    pub ldm: Option<ldm::LdmDatabase>,
+    pub lvm: Vec<lvm::LvmVolumeGroup>,
+    pub md: Vec<md::MdMember>,
+    pub luks: Vec<luks::LuksDevice>,
+    pub luks_keys: Vec<Vec<u8>>, // passphrases and keyfiles to try
+    pub bitlocker: Vec<bitlocker::BitLockerVolume>,
+    pub volumes: Vec<LogicalVolume>,
+    gpt_check: Option<gpt::backup::GptCheck>, // from the first get_gpt
     pub reader: OnDisk,
+    pub sector_size: u64, // logical, what LBAs are counted in
 }
 
 #[derive(Debug)]
 pub enum PartitionTableType {
     Mbr,
     Gpt,
+    Apm,
 }
 #[derive(Debug)]
 pub struct PartitionTable {
     pt_type: PartitionTableType,
     pt_offset: u64,
 }
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PartitionType {
     Xfs,
     Ext4,
@@ -32,148 +49,1101 @@ pub enum PartitionType {
     EfiSystem,
     BiosBoot,
     LinuxFsTBD,
+    Fat,
+    Ntfs,
+    LinuxSwap,
+    LinuxLvm,
+    LinuxRaid,
+    Extended, // MBR container for the logical partitions
+    Luks,
+    BitLocker,
+    LinuxHome,
+    LinuxRoot(GptArch),
+    MicrosoftBasicData,
+    MicrosoftReserved,
+    WindowsRecovery,
+    AppleApfs,
+    AppleHfsPlus,
+    AppleDriver,
+    ApplePartitionMap, // APM lists itself as a partition
+    BsdSlice,          // MBR partition with a disklabel in it
+    SolarisSlice,      // MBR partition with a VTOC in it
+    SolarisData,
+    SolarisSwap,
+    LdmMetadata, // the dynamic disk database
+    LdmData,     // where the dynamic volumes are
+    FreeBsdBoot,
+    FreeBsdData,
+    FreeBsdSwap,
+    FreeBsdUfs,
+    FreeBsdZfs,
+    ChromeOsKernel,
+    ChromeOsRoot,
     Unknown,
 }
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PartitionType::Xfs => "XFS",
+            PartitionType::Ext4 => "ext4",
+            PartitionType::Exfat => "exFAT",
+            PartitionType::Unused => "unused",
+            PartitionType::EfiSystem => "EFI system",
+            PartitionType::BiosBoot => "BIOS boot",
+            PartitionType::LinuxFsTBD => "Linux filesystem",
+            PartitionType::Fat => "FAT",
+            PartitionType::Ntfs => "NTFS",
+            PartitionType::LinuxSwap => "Linux swap",
+            PartitionType::LinuxLvm => "Linux LVM",
+            PartitionType::LinuxRaid => "Linux RAID",
+            PartitionType::Extended => "extended",
+            PartitionType::Luks => "LUKS",
+            PartitionType::BitLocker => "BitLocker",
+            PartitionType::LinuxHome => "Linux /home",
+            PartitionType::LinuxRoot(arch) => {
+                return write!(f, "Linux root ({})", arch)
+            }
+            PartitionType::MicrosoftBasicData => {
+                "Microsoft basic data"
+            }
+            PartitionType::MicrosoftReserved => "Microsoft reserved",
+            PartitionType::WindowsRecovery => "Windows recovery",
+            PartitionType::AppleApfs => "Apple APFS",
+            PartitionType::AppleHfsPlus => "Apple HFS+",
+            PartitionType::AppleDriver => "Apple driver",
+            PartitionType::ApplePartitionMap => "Apple partition map",
+            PartitionType::BsdSlice => "BSD slice",
+            PartitionType::SolarisSlice => "Solaris slice",
+            PartitionType::SolarisData => "Solaris",
+            PartitionType::SolarisSwap => "Solaris swap",
+            PartitionType::LdmMetadata => "LDM metadata",
+            PartitionType::LdmData => "LDM data",
+            PartitionType::FreeBsdBoot => "FreeBSD boot",
+            PartitionType::FreeBsdData => "FreeBSD data",
+            PartitionType::FreeBsdSwap => "FreeBSD swap",
+            PartitionType::FreeBsdUfs => "FreeBSD UFS",
+            PartitionType::FreeBsdZfs => "FreeBSD ZFS",
+            PartitionType::ChromeOsKernel => "ChromeOS kernel",
+            PartitionType::ChromeOsRoot => "ChromeOS root",
+            PartitionType::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
 #[derive(Debug, Clone)]
 pub struct Partition {
     pub p_type: PartitionType,
     pub p_offset: u64,
     p_size: u64,
     p_name: String,
+    pub p_sector_size: u64,
+    pub p_number: u32, // what linux would call it, sda<p_number>
+    pub p_label: Option<String>, // disklabel/VTOC name for children
+    pub p_children: Vec<Partition>, // BSD/Solaris slices cut up again
 }
 
 impl Partition {
     pub fn check_linux_fs_type(
         &self,
         reader: &mut OnDisk,
-    ) -> PartitionType {
+    ) -> PurdResult<PartitionType> {
         // really wish I could loop on types, this might be macro-able
         // though once I need to
 
         let sb = reader
             .read_header_from_offset::<ext4::superblock::Superblock>(
                 self.p_offset + constants::EXT4_SUPERBLOCK_0_OFFSET,
-            );
+            )?;
         if sb.check_magic_field(
             reader,
             self.p_offset + constants::EXT4_SUPERBLOCK_0_OFFSET,
-        ) {
-            return PartitionType::Ext4;
+        )? {
+            return Ok(PartitionType::Ext4);
         }
-        return PartitionType::LinuxFsTBD;
+        Ok(PartitionType::LinuxFsTBD)
         //let xfs = read::read_header_from_offset::<xfs::ondiskhdr::
         // XfsOndiskHeader> when implemented
     }
-    pub fn get_partition_bitness(&self, reader: &mut OnDisk) -> u16 {
+    // LUKS hides whatever filesystem is inside from the checks below
+    pub fn check_luks(
+        &self,
+        reader: &mut OnDisk,
+    ) -> PurdResult<bool> {
+        if self.p_size < luks::LUKS_SECTOR {
+            return Ok(false);
+        }
+        let magic = reader.read_bytes_from_file(self.p_offset, 6)?;
+        Ok(&magic[..] == luks::LUKS_MAGIC)
+    }
+    // MBR type 0x07 covers both, the OEM name in the boot sector
+    // doesn't. BitLocker swaps it for its own.
+    pub fn check_ntfs_or_exfat(
+        &self,
+        reader: &mut OnDisk,
+    ) -> PurdResult<PartitionType> {
+        let oem =
+            reader.read_bytes_from_file(self.p_offset + 3, 8)?;
+        match &oem[..] {
+            b"EXFAT   " => Ok(PartitionType::Exfat),
+            b"NTFS    " => Ok(PartitionType::Ntfs),
+            b"-FVE-FS-" => Ok(PartitionType::BitLocker),
+            _ => Ok(PartitionType::Unknown),
+        }
+    }
+    // the boot sector says FAT32 at 0x52, FAT12/16 at 0x36
+    pub fn check_fat(
+        &self,
+        reader: &mut OnDisk,
+    ) -> PurdResult<PartitionType> {
+        let sector =
+            reader.read_bytes_from_file(self.p_offset, 0x5a)?;
+        if &sector[0x52..0x5a] == b"FAT32   "
+            || &sector[0x36..0x3a] == b"FAT1"
+        {
+            return Ok(PartitionType::Fat);
+        }
+        Ok(PartitionType::Unknown)
+    }
+    /* what the table says vs what's actually in there. Anything we
+     * can't pin down keeps the type the table gave it. */
+    pub fn probe_fs_type(
+        &self,
+        reader: &mut OnDisk,
+    ) -> PurdResult<PartitionType> {
+        match self.p_type {
+            PartitionType::LinuxFsTBD
+            | PartitionType::LinuxRoot(_)
+            | PartitionType::LinuxHome => {
+                if self.check_luks(reader)? {
+                    return Ok(PartitionType::Luks);
+                }
+                match self.check_linux_fs_type(reader)? {
+                    PartitionType::Ext4 => Ok(PartitionType::Ext4),
+                    _ => Ok(self.p_type),
+                }
+            }
+            PartitionType::Ntfs => self.check_ntfs_or_exfat(reader),
+            PartitionType::MicrosoftBasicData => {
+                match self.check_ntfs_or_exfat(reader)? {
+                    PartitionType::Unknown => {
+                        match self.check_fat(reader)? {
+                            PartitionType::Unknown => Ok(self.p_type),
+                            fat => Ok(fat),
+                        }
+                    }
+                    found => Ok(found),
+                }
+            }
+            _ => Ok(self.p_type),
+        }
+    }
+    // one line each, indented under whatever printed the parent
+    pub fn print_children(&self) {
+        for child in self.p_children.iter() {
+            println!(
+                "  {} ({}) {}: LBA {}, {} sectors",
+                child.p_name.bright_blue(),
+                child.p_label.as_deref().unwrap_or("?"),
+                child.p_type,
+                child.p_offset / child.p_sector_size,
+                child.p_size / child.p_sector_size
+            );
+        }
+    }
+    pub fn get_partition_bitness(
+        &self,
+        reader: &mut OnDisk,
+    ) -> PurdResult<u16> {
         match self.p_type {
             PartitionType::Ext4 => {
                 let sb = reader.read_header_from_offset::<ext4::superblock::Superblock>(
                     self.p_offset + constants::EXT4_SUPERBLOCK_0_OFFSET,
-                );
-                return sb.bitness();
-            }
-            _ => {
-                panic!("not implemented")
+                )?;
+                Ok(sb.bitness())
             }
+            _ => Err(PurdError::Unsupported(format!(
+                "bitness for partition type {:?}",
+                self.p_type
+            ))),
         }
     }
 }
 
+/* a volume put back together from pieces of partitions, like an LDM
+ * dynamic volume. It doesn't sit at one offset on the disk so it gets
+ * a source of its own, None when some piece isn't on this disk or
+ * it's a layout we can't put back together. */
+#[derive(Debug, Clone)]
+pub struct LogicalVolume {
+    pub v_name: String,
+    pub v_layout: String,
+    pub v_size: u64,
+    pub v_type: PartitionType,
+    pub source: Option<Arc<dyn BlockSource>>,
+}
+
+impl LogicalVolume {
+    pub fn print(&self) {
+        let readable = match self.source {
+            Some(_) => "".normal(),
+            None => ", can't be read from this disk".yellow(),
+        };
+        println!(
+            "volume {}: {}, {}, {} bytes{}",
+            self.v_name.bright_blue(),
+            self.v_layout,
+            self.v_type,
+            self.v_size,
+            readable
+        );
+    }
+}
+
+// a sector size someone asked for has to at least be plausible
+fn check_sector_size(sector_size: u64) -> PurdResult<u64> {
+    if !sector_size.is_power_of_two()
+        || !(512..=65536).contains(&sector_size)
+    {
+        return Err(PurdError::Unsupported(format!(
+            "logical sector size {}",
+            sector_size
+        )));
+    }
+    Ok(sector_size)
+}
+
+// sda + 5 -> sda5, but loop0 + 5 -> loop0p5 like the kernel does it
+pub fn partition_name(disk: &str, number: u32) -> String {
+    let disk = std::path::Path::new(disk)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| disk.to_string());
+    match disk.ends_with(|c: char| c.is_ascii_digit()) {
+        true => format!("{}p{}", disk, number),
+        false => format!("{}{}", disk, number),
+    }
+}
+
+/* figure out what an LBA is on this disk: whatever the user said,
+ * then wherever the GPT header turned up, then whatever the image
+ * or block device claims (512 when it has no opinion) */
+pub fn detect_sector_size(
+    reader: &OnDisk,
+    requested: Option<u64>,
+) -> PurdResult<u64> {
+    if let Some(sector_size) = requested {
+        return check_sector_size(sector_size);
+    }
+    if let Some(sector_size) = gpt::probe_sector_size(reader)? {
+        return Ok(sector_size);
+    }
+    check_sector_size(reader.source.sector_size())
+}
+
 impl Disk {
-    pub fn set_partition_table_type(&mut self) {
-        let gpt_part = self.mbr.get_partition(0);
+    pub fn new(
+        mut reader: OnDisk,
+        sector_size: Option<u64>,
+    ) -> PurdResult<Disk> {
+        let sector_size = detect_sector_size(&reader, sector_size)?;
+        Ok(Disk {
+            mbr: reader.read_header_from_offset::<mbr::Mbr>(0)?,
+            apm: None,
+            logical_partitions: vec![],
+            ldm: None,
+            lvm: vec![],
+            md: vec![],
+            luks: vec![],
+            luks_keys: vec![],
+            bitlocker: vec![],
+            volumes: vec![],
+            gpt_check: None,
+            reader,
+            pt_type: PartitionTableType::Mbr,
+            partitions: vec![],
+            sector_size,
+        })
+    }
+
+    pub fn set_partition_table_type(&mut self) -> PurdResult<()> {
+        // hybrid MBRs don't always keep the 0xEE entry in slot 0
+        let protective = self.mbr.partitions.iter().any(|p| {
+            matches!(p.get_partition_type(), mbr::PartitionId::Gpt)
+        });
 
-        match gpt_part.get_partition_type() {
-            mbr::PartitionId::Gpt => {
+        // hybrid ISOs have an MBR too, but the APM is the one that
+        // lists everything
+        match protective {
+            true => {
                 self.pt_type = PartitionTableType::Gpt;
             }
+            _ if apm::Apm::probe(&mut self.reader)? => {
+                self.pt_type = PartitionTableType::Apm;
+                self.apm = Some(apm::Apm::read(&mut self.reader)?);
+            }
+            _ if self
+                .mbr
+                .partitions
+                .iter()
+                .any(|p| !p.is_unused()) =>
+            {
+                self.pt_type = PartitionTableType::Mbr;
+            }
             _ => {
                 println!("MBR has no partitions listed.");
-                let _gpt_part = self.get_gpt(); // will panic on unrecognized part
                 self.pt_type = PartitionTableType::Gpt;
+                let _gpt_part = self.get_gpt()?; // errors on unrecognized part
             }
         }
+        Ok(())
     }
 
-    pub fn get_gpt(&mut self) -> gpt::Gpt {
-        assert_eq!(
-            matches!(self.pt_type, PartitionTableType::Gpt),
-            true
-        );
-        let gpt = self.reader.read_header_from_offset::<gpt::Gpt>(
-            constants::SMOL_BLOCKS,
-        );
-        if !gpt.check_magic_field(
-            &mut self.reader,
-            constants::SMOL_BLOCKS,
-        ) {
-            panic!("This was not a GPT partition!");
+    // checking both halves reads both tables, once per disk is enough
+    fn gpt_check(&mut self) -> PurdResult<&gpt::backup::GptCheck> {
+        if self.gpt_check.is_none() {
+            self.gpt_check = Some(gpt::backup::GptCheck::check(
+                &mut self.reader,
+                self.sector_size,
+            )?);
         }
+        Ok(self.gpt_check.as_ref().unwrap())
+    }
+
+    pub fn get_gpt(&mut self) -> PurdResult<gpt::Gpt> {
+        if !matches!(self.pt_type, PartitionTableType::Gpt) {
+            return Err(PurdError::Unsupported(format!(
+                "reading a GPT from a {:?} disk",
+                self.pt_type
+            )));
+        }
+        // a clobbered primary falls back to the backup at the end
+        if let Some(gpt) = self.gpt_check()?.best() {
+            return Ok(gpt.clone());
+        }
+        let gpt = self
+            .reader
+            .read_header_from_offset::<gpt::Gpt>(self.sector_size)?;
+        gpt.require_magic_field(&self.reader, self.sector_size)?;
         //gpt.print_partition_table(&self.file_arg);
-        gpt
+        Ok(gpt)
     }
-    pub fn validate_headers(&mut self) -> bool {
+    pub fn validate_headers(&mut self) -> PurdResult<bool> {
         match self.pt_type {
             PartitionTableType::Gpt => {
-                let gpt = self.get_gpt();
-                let comparison =
-                    summer::struct_validate_checksum32::<gpt::Gpt>(
-                        &mut self.reader,
-                        &gpt,
-                        "GPT:header",
-                        constants::SMOL_BLOCKS,
+                self.get_gpt()?; // no GPT at all is still an error
+                let check = self.gpt_check()?;
+                summer::print_valid_checksum(
+                    "GPT:primary",
+                    check.primary_valid,
+                );
+                check.print_problems();
+                let fallback =
+                    !check.primary_valid && check.backup_valid;
+                let clean = check.is_clean();
+                self.analyze_mbr()?.print();
+                if fallback {
+                    println!(
+                        "{}",
+                        "primary GPT is corrupt, using the backup"
+                            .yellow()
                     );
-                return comparison
-                    && gpt
-                        .validate_table_checksums(&mut self.reader);
+                }
+                Ok(clean)
             }
             PartitionTableType::Mbr => {
-                // self.mbr fe
-                true
+                self.mbr.check_magic_field(&self.reader, 0)
+            }
+            PartitionTableType::Apm => {
+                let apm = self.get_apm()?;
+                if !apm.is_complete() {
+                    println!(
+                        "{}",
+                        format!(
+                            "APM: map says {} entries, found {}",
+                            apm.map_entries(),
+                            apm.entries.len()
+                        )
+                        .yellow()
+                    );
+                }
+                Ok(apm.is_complete())
             }
         }
     }
 
-    pub fn register_partitions(&mut self) {
+    pub fn get_apm(&self) -> PurdResult<&apm::Apm> {
+        match &self.apm {
+            Some(apm) => Ok(apm),
+            None => Err(PurdError::Unsupported(format!(
+                "reading an APM from a {:?} disk",
+                self.pt_type
+            ))),
+        }
+    }
+
+    // protective or hybrid, and whether it agrees with the GPT
+    pub fn analyze_mbr(
+        &mut self,
+    ) -> PurdResult<gpt::protective::MbrAnalysis> {
+        let gpt = self.get_gpt()?;
+        let entries = gpt.create_partition_table(
+            &mut self.reader,
+            self.sector_size,
+        )?;
+        Ok(gpt::protective::MbrAnalysis::analyze(
+            &self.mbr,
+            &entries,
+            self.reader.source.len() / self.sector_size,
+        ))
+    }
+
+    // what systemd-gpt-auto-generator would mount from this disk
+    pub fn discoverable_layout(
+        &mut self,
+        arch: GptArch,
+    ) -> PurdResult<gpt::discoverable::DpsLayout> {
+        let gpt = self.get_gpt()?;
+        let entries = gpt.create_partition_table(
+            &mut self.reader,
+            self.sector_size,
+        )?;
+        Ok(gpt::discoverable::DpsLayout::from_entries(&entries, arch))
+    }
+
+    // dry run unless there's somewhere it's allowed to write
+    pub fn repair_gpt(
+        &mut self,
+        target: Option<&source::WritableFile>,
+    ) -> PurdResult<gpt::repair::GptRepair> {
+        let repair = gpt::Gpt::repair(
+            &mut self.reader,
+            self.sector_size,
+            target,
+        )?;
+        self.gpt_check = None; // whatever it said may not be true now
+        Ok(repair)
+    }
+
+    pub fn register_partitions(&mut self) -> PurdResult<()> {
+        self.register_table_partitions()?;
+        self.volumes.clear();
+        self.register_ldm_volumes()?;
+        self.register_md_volumes()?;
+        self.register_lvm_volumes()?;
+        self.register_luks_devices()?;
+        self.register_bitlocker_volumes()
+    }
+
+    fn register_table_partitions(&mut self) -> PurdResult<()> {
         match self.pt_type {
             PartitionTableType::Gpt => {
-                let gpt = self.get_gpt();
-                for partition in
-                    gpt.create_partition_table(&mut self.reader)
+                let gpt = self.get_gpt()?;
+                for (i, partition) in gpt
+                    .create_partition_table(
+                        &mut self.reader,
+                        self.sector_size,
+                    )?
+                    .into_iter()
+                    .enumerate()
                 {
+                    if partition.last_lba < partition.first_lba {
+                        return Err(PurdError::out_of_bounds(
+                            "GPT:partition",
+                            partition.first_lba,
+                            partition.last_lba,
+                        ));
+                    }
                     let mut part = Partition {
                         p_type: partition.get_partition_type(),
                         p_offset: partition.first_lba
-                            * constants::SMOL_BLOCKS,
+                            * self.sector_size,
                         p_size: (partition.last_lba
                             - partition.first_lba)
-                            * constants::SMOL_BLOCKS,
+                            * self.sector_size,
                         p_name: partition.name(),
+                        p_sector_size: self.sector_size,
+                        p_number: i as u32 + 1,
+                        p_label: None,
+                        p_children: vec![],
                     };
-                    if matches!(
-                        part.p_type,
-                        PartitionType::LinuxFsTBD
-                    ) {
-                        part.p_type =
-                            part.check_linux_fs_type(&mut self.reader)
+                    part.p_type =
+                        part.probe_fs_type(&mut self.reader)?;
+                    self.partitions.push(part);
+                }
+                Ok(())
+            }
+            PartitionTableType::Mbr => {
+                // empty slots stay in so indices match the table,
+                // logicals go after the four primaries. One that
+                // runs off the disk is left out, not the whole table.
+                let disk_sectors =
+                    self.reader.source.len() / self.sector_size;
+                let mut entries = vec![];
+                for (i, entry) in
+                    self.mbr.partitions.iter().enumerate()
+                {
+                    let start = entry.lba_of_partition_start as u64;
+                    let sectors = entry.sectors_in_partition as u64;
+                    if !entry.is_unused()
+                        && start + sectors > disk_sectors
+                    {
+                        println!(
+                            "{}",
+                            format!(
+                                "MBR: partition {} ({}..{}) runs past \
+                                 the end of the disk ({}), skipping it",
+                                i + 1,
+                                start,
+                                start + sectors,
+                                disk_sectors
+                            )
+                            .yellow()
+                        );
+                        continue;
                     }
+                    entries.push((i as u32 + 1, start, *entry));
+                }
+                self.logical_partitions.clear();
+                let extended: Vec<mbr::MbrPartitionEntry> = entries
+                    .iter()
+                    .map(|(_, _, entry)| *entry)
+                    .filter(|entry| entry.is_extended())
+                    .collect();
+                for entry in extended {
+                    let first =
+                        self.logical_partitions.len() as u32 + 5;
+                    self.logical_partitions.extend(
+                        mbr::read_logical_partitions(
+                            &mut self.reader,
+                            &entry,
+                            self.sector_size,
+                            first,
+                        )?,
+                    );
+                }
+                for logical in self.logical_partitions.iter() {
+                    entries.push((
+                        logical.number,
+                        logical.start_lba,
+                        logical.entry,
+                    ));
+                }
+                let disk_name = self.reader.source.name();
+                for (number, start, entry) in entries {
+                    let mut part = Partition {
+                        p_type: entry.get_disk_partition_type(),
+                        p_offset: start * self.sector_size,
+                        p_size: entry.sectors_in_partition as u64
+                            * self.sector_size,
+                        p_name: partition_name(&disk_name, number),
+                        p_sector_size: self.sector_size,
+                        p_number: number,
+                        p_label: None,
+                        p_children: vec![],
+                    };
+                    part.p_type =
+                        part.probe_fs_type(&mut self.reader)?;
                     self.partitions.push(part);
                 }
+                self.register_slice_partitions()
             }
-            _ => {
-                panic!(
-                    "Partition table type not implemented: {:?}",
-                    self.pt_type
+            PartitionTableType::Apm => {
+                // numbered by map block like the kernel, so the map
+                // itself is usually sda1. Like a short map, an entry
+                // off the end of the disk only costs that entry.
+                let apm = self.get_apm()?.clone();
+                let disk_len = self.reader.source.len();
+                let disk_name = self.reader.source.name();
+                for (i, entry) in apm.entries.iter().enumerate() {
+                    let (start, size) =
+                        match apm.entry_extent(entry, disk_len) {
+                            Ok(extent) => extent,
+                            Err(e) => {
+                                println!(
+                                    "{}",
+                                    format!(
+                                    "APM: entry {}: {}, skipping it",
+                                    i + 1,
+                                    e
+                                )
+                                    .yellow()
+                                );
+                                continue;
+                            }
+                        };
+                    let mut part = Partition {
+                        p_type: entry.get_disk_partition_type(),
+                        p_offset: start,
+                        p_size: size,
+                        p_name: partition_name(
+                            &disk_name,
+                            i as u32 + 1,
+                        ),
+                        p_sector_size: self.sector_size,
+                        p_number: i as u32 + 1,
+                        p_label: None,
+                        p_children: vec![],
+                    };
+                    part.p_type =
+                        part.probe_fs_type(&mut self.reader)?;
+                    self.partitions.push(part);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /* Dynamic disks: an MBR one has a 0x42 partition and PRIVHEAD at
+     * LBA 6, a GPT one keeps it in the last LBA of the LDM metadata
+     * partition. A database we can't make sense of gets reported,
+     * the partitions around it are still fine. */
+    fn register_ldm_volumes(&mut self) -> PurdResult<()> {
+        let privhead_lba = match self.pt_type {
+            PartitionTableType::Mbr
+                if self
+                    .partitions
+                    .iter()
+                    .any(|p| p.p_type == PartitionType::LdmData) =>
+            {
+                Some(ldm::LDM_PRIVHEAD_MBR_LBA)
+            }
+            PartitionTableType::Gpt => self
+                .get_gpt()?
+                .create_partition_table(
+                    &mut self.reader,
+                    self.sector_size,
+                )?
+                .iter()
+                .find(|e| {
+                    e.get_partition_type()
+                        == PartitionType::LdmMetadata
+                })
+                .map(|e| e.last_lba),
+            _ => None,
+        };
+        let privhead_lba = match privhead_lba {
+            Some(lba) => lba,
+            None => return Ok(()),
+        };
+        let disk = self.reader.source.clone();
+        let sector_size = self.sector_size;
+        let read = ldm::LdmDatabase::read(
+            &mut self.reader,
+            privhead_lba,
+            sector_size,
+        )
+        .and_then(|db| {
+            let sources = db
+                .volumes()
+                .into_iter()
+                .map(|volume| {
+                    let source = db.volume_source(
+                        &volume,
+                        disk.clone(),
+                        sector_size,
+                    )?;
+                    Ok((volume, source))
+                })
+                .collect::<PurdResult<Vec<_>>>()?;
+            Ok((db, sources))
+        });
+        let (db, sources) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                println!(
+                    "{}",
+                    format!("LDM: no usable database: {}", e)
+                        .yellow()
                 );
+                return Ok(());
+            }
+        };
+        for (volume, source) in sources {
+            let v_type = self.probe_volume_type(
+                &volume.name,
+                mbr::partition_type_from_id(volume.partition_type),
+                &source,
+            )?;
+            self.volumes.push(LogicalVolume {
+                v_name: volume.name.clone(),
+                v_layout: volume.layout.to_string(),
+                v_size: volume.sectors.saturating_mul(sector_size),
+                v_type,
+                source,
+            });
+        }
+        self.ldm = Some(db);
+        Ok(())
+    }
+
+    // same probing as a partition, at the start of the volume
+    fn probe_volume_type(
+        &self,
+        name: &str,
+        v_type: PartitionType,
+        source: &Option<Arc<dyn BlockSource>>,
+    ) -> PurdResult<PartitionType> {
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(v_type),
+        };
+        let part = Partition {
+            p_type: v_type,
+            p_offset: 0,
+            p_size: source.len(),
+            p_name: name.to_string(),
+            p_sector_size: self.sector_size,
+            p_number: 0,
+            p_label: None,
+            p_children: vec![],
+        };
+        part.probe_fs_type(&mut reader::from_source(source.clone()))
+    }
+
+    /* MD RAID members in Linux RAID partitions. Arrays with every
+     * member they need on this disk (a RAID1 only needs one) become
+     * volumes, the rest get listed without a source. */
+    fn register_md_volumes(&mut self) -> PurdResult<()> {
+        self.md.clear();
+        for part in self.partitions.iter() {
+            if part.p_type != PartitionType::LinuxRaid {
+                continue;
+            }
+            let source: Arc<dyn BlockSource> =
+                Arc::new(SubSource::new(
+                    self.reader.source.clone(),
+                    part.p_offset,
+                    part.p_size,
+                )?);
+            match md::MdSuperblock::probe(&*source) {
+                Ok(Some(superblock)) => self.md.push(md::MdMember {
+                    name: part.p_name.clone(),
+                    superblock,
+                    source,
+                }),
+                Ok(None) => {}
+                Err(e) => println!(
+                    "{}",
+                    format!("md: {}: {}", part.p_name, e).yellow()
+                ),
+            }
+        }
+        let mut arrays: Vec<Vec<md::MdMember>> = vec![];
+        for member in self.md.iter() {
+            match arrays.iter_mut().find(|a| {
+                a[0].superblock.uuid == member.superblock.uuid
+            }) {
+                Some(array) => array.push(member.clone()),
+                None => arrays.push(vec![member.clone()]),
+            }
+        }
+        for members in arrays {
+            let sb = members[0].superblock.clone();
+            let name = match sb.name.is_empty() {
+                true => format!("md {}", sb.uuid_string()),
+                false => format!("md {}", sb.name),
+            };
+            let source =
+                md::MdArray::assemble(members).ok().map(|a| a.source);
+            let v_type = self.probe_volume_type(
+                &name,
+                PartitionType::LinuxFsTBD,
+                &source,
+            )?;
+            self.volumes.push(LogicalVolume {
+                v_name: name,
+                v_layout: md::level_name(sb.level),
+                v_size: sb.array_size(),
+                v_type,
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    /* LUKS headers on partitions and volumes, a header we can't read
+     * is reported and skipped. With keys to try, what they unlock
+     * becomes a volume and gets looked at for LVM in turn. */
+    fn register_luks_devices(&mut self) -> PurdResult<()> {
+        self.luks.clear();
+        let mut candidates: Vec<(String, Arc<dyn BlockSource>)> =
+            vec![];
+        for part in self.partitions.iter() {
+            if part.p_type == PartitionType::Luks {
+                candidates.push((
+                    part.p_name.clone(),
+                    Arc::new(SubSource::new(
+                        self.reader.source.clone(),
+                        part.p_offset,
+                        part.p_size,
+                    )?),
+                ));
+            }
+        }
+        for volume in self.volumes.iter() {
+            if let (Some(source), PartitionType::Luks) =
+                (&volume.source, volume.v_type)
+            {
+                candidates
+                    .push((volume.v_name.clone(), source.clone()));
+            }
+        }
+        for (name, source) in candidates {
+            match luks::LuksHeader::probe(&*source) {
+                Ok(Some(header)) => {
+                    self.luks.push(luks::LuksDevice {
+                        name,
+                        header,
+                        source,
+                        keyslot: None,
+                    })
+                }
+                Ok(None) => {}
+                Err(e) => println!(
+                    "{}",
+                    format!("LUKS: {}: {}", name, e).yellow()
+                ),
+            }
+        }
+        if self.luks_keys.is_empty() {
+            return Ok(());
+        }
+        let mut unlocked: Vec<(String, Arc<dyn BlockSource>)> =
+            vec![];
+        for i in 0..self.luks.len() {
+            let (keyslot, source) = match self.luks[i]
+                .open(&self.luks_keys)
+            {
+                Ok(opened) => opened,
+                Err(e) => {
+                    println!("{}", format!("LUKS: {}", e).yellow());
+                    continue;
+                }
+            };
+            self.luks[i].keyslot = Some(keyslot);
+            let name = source.name();
+            let source = Some(source);
+            let v_type = self.probe_volume_type(
+                &name,
+                PartitionType::LinuxFsTBD,
+                &source,
+            )?;
+            if let (Some(source), PartitionType::LinuxFsTBD) =
+                (&source, v_type)
+            {
+                unlocked.push((name.clone(), source.clone()));
+            }
+            self.volumes.push(LogicalVolume {
+                v_name: name,
+                v_layout: self.luks[i].header.cipher(),
+                v_size: source.as_ref().map_or(0, |s| s.len()),
+                v_type,
+                source,
+            });
+        }
+        self.add_lvm_volumes(unlocked)
+    }
+
+    /* BitLocker metadata on partitions and volumes (LDM ones too).
+     * Nothing gets decrypted, a volume whose metadata can't be read
+     * at all is reported and skipped. */
+    fn register_bitlocker_volumes(&mut self) -> PurdResult<()> {
+        self.bitlocker.clear();
+        let mut candidates: Vec<(String, Arc<dyn BlockSource>)> =
+            vec![];
+        for part in self.partitions.iter() {
+            if part.p_type == PartitionType::BitLocker {
+                candidates.push((
+                    part.p_name.clone(),
+                    Arc::new(SubSource::new(
+                        self.reader.source.clone(),
+                        part.p_offset,
+                        part.p_size,
+                    )?),
+                ));
+            }
+        }
+        for volume in self.volumes.iter() {
+            if let (Some(source), PartitionType::BitLocker) =
+                (&volume.source, volume.v_type)
+            {
+                candidates
+                    .push((volume.v_name.clone(), source.clone()));
+            }
+        }
+        for (name, source) in candidates {
+            match bitlocker::BitLockerVolume::probe(&name, &*source) {
+                Ok(Some(volume)) => self.bitlocker.push(volume),
+                Ok(None) => {}
+                Err(e) => println!(
+                    "{}",
+                    format!("BitLocker: {}: {}", name, e).yellow()
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    // PVs in LVM partitions, or on an md array put together above
+    fn register_lvm_volumes(&mut self) -> PurdResult<()> {
+        self.lvm.clear();
+        let mut candidates: Vec<(String, Arc<dyn BlockSource>)> =
+            vec![];
+        for part in self.partitions.iter() {
+            if part.p_type == PartitionType::LinuxLvm {
+                candidates.push((
+                    part.p_name.clone(),
+                    Arc::new(SubSource::new(
+                        self.reader.source.clone(),
+                        part.p_offset,
+                        part.p_size,
+                    )?),
+                ));
+            }
+        }
+        for volume in self.volumes.iter() {
+            if let (Some(source), PartitionType::LinuxFsTBD) =
+                (&volume.source, volume.v_type)
+            {
+                candidates
+                    .push((volume.v_name.clone(), source.clone()));
             }
         }
+        self.add_lvm_volumes(candidates)
+    }
+
+    /* LVM physical volumes among the candidates. Every PV carries the
+     * group's metadata, the newest copy (highest seqno) wins. LVs
+     * spread over PVs on other disks show up without a source. */
+    fn add_lvm_volumes(
+        &mut self,
+        candidates: Vec<(String, Arc<dyn BlockSource>)>,
+    ) -> PurdResult<()> {
+        let mut pv_sources: Vec<(String, Arc<dyn BlockSource>)> =
+            vec![];
+        let mut groups: Vec<lvm::LvmVolumeGroup> = vec![];
+        for (name, source) in candidates {
+            let found = lvm::LvmPhysicalVolume::probe(&*source)
+                .and_then(|pv| match pv {
+                    Some(pv) => Ok(Some((
+                        pv.read_volume_group(&*source)?,
+                        pv,
+                    ))),
+                    None => Ok(None),
+                });
+            let (vg, pv) = match found {
+                Ok(Some(found)) => found,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!(
+                        "{}",
+                        format!("LVM: {}: {}", name, e).yellow()
+                    );
+                    continue;
+                }
+            };
+            pv_sources.push((pv.uuid, source));
+            let vg = match vg {
+                Some(vg) => vg,
+                None => continue,
+            };
+            match groups.iter_mut().find(|g| g.id == vg.id) {
+                Some(known) if known.seqno < vg.seqno => *known = vg,
+                Some(_) => {}
+                None => groups.push(vg),
+            }
+        }
+        for vg in groups.iter() {
+            for lv in vg.lvs.iter().filter(|lv| lv.visible) {
+                let name = format!("{}/{}", vg.name, lv.name);
+                let found = vg.lv_bytes(lv).and_then(|size| {
+                    Ok((size, vg.lv_source(lv, &pv_sources)?))
+                });
+                let (v_size, source) = match found {
+                    Ok(found) => found,
+                    Err(e) => {
+                        println!(
+                            "{}",
+                            format!("LVM: {}: {}", name, e).yellow()
+                        );
+                        continue;
+                    }
+                };
+                let v_type = self.probe_volume_type(
+                    &name,
+                    PartitionType::LinuxFsTBD,
+                    &source,
+                )?;
+                self.volumes.push(LogicalVolume {
+                    v_name: name,
+                    v_layout: lv.layout(),
+                    v_size,
+                    v_type,
+                    source,
+                });
+            }
+        }
+        self.lvm.extend(groups);
+        Ok(())
+    }
+
+    /* BSD disklabels and Solaris VTOCs inside the primaries. They
+     * hang off the partition they're in, numbered after the logicals
+     * the way the kernel hands them out. */
+    fn register_slice_partitions(&mut self) -> PurdResult<()> {
+        let disk_name = self.reader.source.name();
+        let mut number = 5 + self.logical_partitions.len() as u32;
+        for (i, entry) in self.mbr.partitions.into_iter().enumerate()
+        {
+            // left out when it didn't fit on the disk
+            let parent = match self
+                .partitions
+                .iter()
+                .position(|p| p.p_number == i as u32 + 1)
+            {
+                Some(parent) if !entry.is_unused() => parent,
+                _ => continue,
+            };
+            let slices = match mbr::read_slice_partitions(
+                &mut self.reader,
+                &entry,
+                self.sector_size,
+            )? {
+                Some(slices) => slices,
+                None => continue,
+            };
+            let mut children = vec![];
+            for slice in slices {
+                let mut part = Partition {
+                    p_type: slice.p_type,
+                    p_offset: slice.start_lba * self.sector_size,
+                    p_size: slice.sectors * self.sector_size,
+                    p_name: partition_name(&disk_name, number),
+                    p_sector_size: self.sector_size,
+                    p_number: number,
+                    p_label: Some(slice.label),
+                    p_children: vec![],
+                };
+                part.p_type = part.probe_fs_type(&mut self.reader)?;
+                children.push(part);
+                number += 1;
+            }
+            let parent = &mut self.partitions[parent];
+            // a VTOC in an 0x82 means it was never swap
+            if parent.p_type == PartitionType::LinuxSwap {
+                parent.p_type = PartitionType::SolarisSlice;
+            }
+            parent.p_children = children;
+        }
+        Ok(())
     }
 
     pub fn print_partitions_shitty(&self) {
         for part in self.partitions.clone().into_iter() {
             if !matches!(part.p_type, PartitionType::Unused) {
                 println!(
-                    "PartitionName:{}\nPartitionType:{:?}\\
+                    "PartitionName:{}\nPartitionType:{}\\
                      nPartitionStart:0x{:X}\nPartitionSize:0x{:X}\\
                      n---------------------------",
                     part.p_name.yellow(),
@@ -185,43 +1155,158 @@ impl Disk {
         }
     }
 
-    pub fn print_partitions_pretty(&mut self) {
+    pub fn print_partitions_pretty(&mut self) -> PurdResult<()> {
         match &self.pt_type {
             PartitionTableType::Gpt => {
-                self.get_gpt()
-                    .print_partition_table(&mut self.reader);
+                self.get_gpt()?.print_partition_table(
+                    &mut self.reader,
+                    self.sector_size,
+                )?;
             }
             PartitionTableType::Mbr => {
-                self.mbr.pretty_print();
+                let disk_name = self.reader.source.name();
+                for (i, entry) in
+                    self.mbr.partitions.iter().enumerate()
+                {
+                    if entry.is_unused() {
+                        continue;
+                    }
+                    let number = i as u32 + 1;
+                    // slots that ran off the disk never got registered
+                    let part = self
+                        .partitions
+                        .iter()
+                        .find(|p| p.p_number == number);
+                    let name = partition_name(&disk_name, number);
+                    match part {
+                        Some(_) => println!("{}", name.purple()),
+                        None => println!(
+                            "{} {}",
+                            name.purple(),
+                            "(past the end of the disk, skipped)"
+                                .yellow()
+                        ),
+                    }
+                    entry.pretty_print();
+                    if let Some(part) = part {
+                        part.print_children();
+                    }
+                }
+                for logical in self.logical_partitions.iter() {
+                    println!(
+                        "{}",
+                        format!(
+                            "{} (logical, EBR at LBA {}, starts at \
+                             LBA {})",
+                            partition_name(
+                                &disk_name,
+                                logical.number
+                            ),
+                            logical.ebr_lba,
+                            logical.start_lba
+                        )
+                        .purple()
+                    );
+                    logical.entry.pretty_print();
+                }
+            }
+            PartitionTableType::Apm => {
+                let apm = self.get_apm()?;
+                let disk_name = self.reader.source.name();
+                apm.pretty_print();
+                for (i, entry) in apm.entries.iter().enumerate() {
+                    println!(
+                        "{}",
+                        partition_name(&disk_name, i as u32 + 1)
+                            .purple()
+                    );
+                    entry.pretty_print(apm.block_size);
+                }
+            }
+        }
+        if let Some(db) = &self.ldm {
+            db.print();
+        }
+        for member in self.md.iter() {
+            member.superblock.print(&member.name);
+        }
+        for vg in self.lvm.iter() {
+            vg.print();
+        }
+        for volume in self.volumes.iter() {
+            volume.print();
+        }
+        for device in self.luks.iter() {
+            println!("{}", device.name.purple());
+            device.header.print();
+            if let Some(keyslot) = &device.keyslot {
+                println!("  unlocked with keyslot {}", keyslot);
             }
         }
+        for volume in self.bitlocker.iter() {
+            println!("{}", volume.name.purple());
+            volume.print();
+        }
+        Ok(())
     }
 
-    pub fn get_partition(&self, ptid: usize) -> Partition {
-        return self.partitions.get(ptid).unwrap().clone();
+    pub fn get_partition(
+        &self,
+        ptid: usize,
+    ) -> PurdResult<Partition> {
+        match self.partitions.get(ptid) {
+            Some(part) => Ok(part.clone()),
+            None => Err(PurdError::out_of_bounds(
+                "Disk:partitions",
+                ptid as u64,
+                self.partitions.len() as u64,
+            )),
+        }
     }
 
     pub fn make_ext4_block_reader(
         &mut self,
         p: Partition,
-    ) -> ext4::reader::Part {
-        assert!(matches!(p.p_type, PartitionType::Ext4));
+    ) -> PurdResult<ext4::reader::Part> {
+        if !matches!(p.p_type, PartitionType::Ext4) {
+            return Err(PurdError::Unsupported(format!(
+                "ext4 reader for partition type {:?}",
+                p.p_type
+            )));
+        }
         let sb = self
             .reader
             .read_header_from_offset::<ext4::superblock::Superblock>(
                 p.p_offset + constants::EXT4_SUPERBLOCK_0_OFFSET,
-            );
-        ext4::reader::Part::init(
-            reader::new(&self.reader.file.clone()),
+            )?;
+        // share the source rather than re-opening it by name, it
+        // might not be a file at all
+        Ok(ext4::reader::Part::init(
+            reader::from_source(self.reader.source.clone()),
             sb,
             p.p_offset,
-        )
+        ))
+    }
+    // an LVM or LDM volume, read through its own source
+    pub fn make_ext4_volume_reader(
+        &self,
+        volume: &LogicalVolume,
+    ) -> PurdResult<ext4::reader::Part> {
+        match (&volume.source, volume.v_type) {
+            (Some(source), PartitionType::Ext4) => {
+                ext4::reader::Part::from_source(source.clone(), 0)
+            }
+            _ => Err(PurdError::Unsupported(format!(
+                "ext4 reader for volume {} ({})",
+                volume.v_name, volume.v_type
+            ))),
+        }
     }
     pub fn make_ext4_block_reader_by_index(
         &mut self,
         ptid: usize,
-    ) -> ext4::reader::Part {
-        let part = self.get_partition(ptid);
+    ) -> PurdResult<ext4::reader::Part> {
+        let part = self.get_partition(ptid)?;
         self.make_ext4_block_reader(part)
     }
 }
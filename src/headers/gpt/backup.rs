@@ -0,0 +1,257 @@
+use super::Gpt;
+use crate::headers::error::PurdResult;
+use crate::headers::reader::*;
+use crate::headers::source::BlockSource;
+use crate::headers::summer::*;
+use colored::*;
+use std::fmt;
+
+/* The backup header lives in the last LBA with its copy of the
+ * partition entries right before it. Nobody looks at it until the
+ * primary gets clobbered, which is exactly when it matters, so check
+ * that both halves are intact and still agree with each other. */
+
+// anything bigger than this is a corrupt header, not a real table
+pub const GPT_MAX_TABLE_BYTES: u64 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GptProblem {
+    MissingPrimary,
+    MissingBackup { lba: u64 },
+    HeaderChecksum { backup: bool },
+    TableChecksum { backup: bool },
+    TableTooBig { backup: bool, bytes: u64 },
+    EntrySize { backup: bool, size: u32 },
+    PrimaryAltLba { found: u64, backup_lba: u64 },
+    BackupSelfLba { found: u64, backup_lba: u64 },
+    BackupAltLba { found: u64, primary_lba: u64 },
+    FieldMismatch { field: &'static str, primary: u64, backup: u64 },
+    EntryMismatch { index: u32 },
+}
+
+fn which(backup: bool) -> &'static str {
+    match backup {
+        true => "backup",
+        false => "primary",
+    }
+}
+
+impl fmt::Display for GptProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GptProblem::MissingPrimary => write!(f, "no primary GPT header at LBA 1"),
+            GptProblem::MissingBackup { lba } => write!(f, "no backup GPT header at LBA {}", lba),
+            GptProblem::HeaderChecksum { backup } => write!(f, "{} header checksum is wrong", which(*backup)),
+            GptProblem::TableChecksum { backup } => write!(f, "{} partition entry checksum is wrong", which(*backup)),
+            GptProblem::TableTooBig { backup, bytes } => {
+                write!(f, "{} partition entries claim to be {} bytes", which(*backup), bytes)
+            }
+            GptProblem::EntrySize { backup, size } => {
+                write!(f, "{} partition entries claim to be {} bytes each", which(*backup), size)
+            }
+            GptProblem::PrimaryAltLba { found, backup_lba } => {
+                write!(f, "primary alt_lba is {} but the backup is at {}", found, backup_lba)
+            }
+            GptProblem::BackupSelfLba { found, backup_lba } => {
+                write!(f, "backup self_lba is {} but it was read from {}", found, backup_lba)
+            }
+            GptProblem::BackupAltLba { found, primary_lba } => {
+                write!(f, "backup alt_lba is {} but the primary is at {}", found, primary_lba)
+            }
+            GptProblem::FieldMismatch { field, primary, backup } => {
+                write!(f, "{} differs: primary {:#x} backup {:#x}", field, primary, backup)
+            }
+            GptProblem::EntryMismatch { index } => {
+                write!(f, "partition entry {} differs between primary and backup", index)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GptCheck {
+    pub primary: Option<Gpt>, // only headers that say "EFI PART"
+    pub backup: Option<Gpt>,
+    pub backup_lba: u64,
+    pub primary_valid: bool, // header and entry checksums both good
+    pub backup_valid: bool,
+    pub problems: Vec<GptProblem>,
+}
+
+fn read_gpt_at(reader: &mut OnDisk, lba: u64, sector_size: u64) -> PurdResult<Option<Gpt>> {
+    // alt_lba comes straight off the disk, junk there just means no backup
+    let end = match lba.checked_add(1).and_then(|end| end.checked_mul(sector_size)) {
+        Some(end) if end <= reader.len() => end,
+        _ => return Ok(None),
+    };
+    let gpt = reader.read_header_from_offset::<Gpt>(end - sector_size)?;
+    match &gpt.signature {
+        b"EFI PART" => Ok(Some(gpt)),
+        _ => Ok(None),
+    }
+}
+
+// checks one header and its entries, the problems go in the report
+fn validate_one(
+    reader: &mut OnDisk,
+    gpt: &Gpt,
+    lba: u64,
+    sector_size: u64,
+    backup: bool,
+    problems: &mut Vec<GptProblem>,
+) -> PurdResult<bool> {
+    let sum = crc32_structure_from_disk::<Gpt>(reader, gpt, lba * sector_size)?;
+    if !gpt.validate_checksum(sum) {
+        // nothing else in a corrupt header can be trusted to point anywhere
+        problems.push(GptProblem::HeaderChecksum { backup });
+        return Ok(false);
+    }
+    // entries smaller than one or off the 8 byte grid overlap each other
+    if !gpt.entry_size_ok() {
+        problems.push(GptProblem::EntrySize { backup, size: gpt.gpe_table_entry_size });
+        return Ok(false);
+    }
+    let bytes = gpt.table_len();
+    let table_offset = gpt.table_offset(sector_size)?;
+    let table_end = table_offset.saturating_add(bytes);
+    if bytes > GPT_MAX_TABLE_BYTES || table_end > reader.len() {
+        problems.push(GptProblem::TableTooBig { backup, bytes });
+        return Ok(false);
+    }
+    let table_sum = crc32_bytes_from_disk(reader, gpt.crc_parameters(), table_offset, bytes)?;
+    if table_sum != gpt.gpe_table_crc32 {
+        problems.push(GptProblem::TableChecksum { backup });
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+impl GptCheck {
+    /* The backup is wherever the spec says, the last LBA. If there's
+     * nothing there but the primary points somewhere else (image got
+     * resized) that's tried too. */
+    pub fn check(reader: &mut OnDisk, sector_size: u64) -> PurdResult<GptCheck> {
+        let mut problems = vec![];
+        let last_lba = (reader.len() / sector_size).saturating_sub(1);
+
+        let primary = read_gpt_at(reader, 1, sector_size)?;
+        let primary_valid = match &primary {
+            Some(gpt) => validate_one(reader, gpt, 1, sector_size, false, &mut problems)?,
+            None => {
+                problems.push(GptProblem::MissingPrimary);
+                false
+            }
+        };
+
+        let mut backup_lba = last_lba;
+        let mut backup = read_gpt_at(reader, last_lba, sector_size)?;
+        if let (None, Some(gpt)) = (&backup, &primary) {
+            if gpt.alt_lba != last_lba && gpt.alt_lba > 1 {
+                backup = read_gpt_at(reader, gpt.alt_lba, sector_size)?;
+                if backup.is_some() {
+                    backup_lba = gpt.alt_lba;
+                }
+            }
+        }
+        let backup_valid = match &backup {
+            Some(gpt) => validate_one(reader, gpt, backup_lba, sector_size, true, &mut problems)?,
+            None => {
+                problems.push(GptProblem::MissingBackup { lba: last_lba });
+                false
+            }
+        };
+
+        if let Some(gpt) = &primary {
+            if gpt.alt_lba != backup_lba {
+                problems.push(GptProblem::PrimaryAltLba { found: gpt.alt_lba, backup_lba });
+            }
+        }
+        if let Some(gpt) = &backup {
+            if gpt.self_lba != backup_lba {
+                problems.push(GptProblem::BackupSelfLba { found: gpt.self_lba, backup_lba });
+            }
+            if gpt.alt_lba != 1 {
+                problems.push(GptProblem::BackupAltLba { found: gpt.alt_lba, primary_lba: 1 });
+            }
+        }
+        // only worth comparing when neither one is junk
+        if let (Some(p), Some(b), true, true) = (&primary, &backup, primary_valid, backup_valid) {
+            compare_headers(p, b, &mut problems);
+            compare_entries(reader, p, b, sector_size, &mut problems)?;
+        }
+
+        Ok(GptCheck {
+            primary,
+            backup,
+            backup_lba,
+            primary_valid,
+            backup_valid,
+            problems,
+        })
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    // the header to trust: the primary when it's intact, otherwise the backup
+    pub fn best(&self) -> Option<&Gpt> {
+        match (self.primary_valid, self.backup_valid) {
+            (true, _) => self.primary.as_ref(),
+            (false, true) => self.backup.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn print_problems(&self) {
+        print_valid_checksum("GPT:backup", self.backup_valid);
+        for problem in self.problems.iter() {
+            println!("{}", format!("GPT: {}", problem).yellow());
+        }
+    }
+}
+
+fn compare_headers(primary: &Gpt, backup: &Gpt, problems: &mut Vec<GptProblem>) {
+    let fields: [(&'static str, u64, u64); 5] = [
+        ("first_usable_block", primary.first_usable_block, backup.first_usable_block),
+        ("last_usable_block", primary.last_usable_block, backup.last_usable_block),
+        ("gpe_table_entries", primary.gpe_table_entries as u64, backup.gpe_table_entries as u64),
+        ("gpe_table_entry_size", primary.gpe_table_entry_size as u64, backup.gpe_table_entry_size as u64),
+        ("gpe_table_crc32", primary.gpe_table_crc32 as u64, backup.gpe_table_crc32 as u64),
+    ];
+    for (field, p, b) in fields {
+        if p != b {
+            problems.push(GptProblem::FieldMismatch { field, primary: p, backup: b });
+        }
+    }
+    if primary.guid != backup.guid {
+        problems.push(GptProblem::FieldMismatch {
+            field: "guid",
+            primary: u64::from_le_bytes(primary.guid[..8].try_into().unwrap()),
+            backup: u64::from_le_bytes(backup.guid[..8].try_into().unwrap()),
+        });
+    }
+}
+
+fn compare_entries(
+    reader: &mut OnDisk,
+    primary: &Gpt,
+    backup: &Gpt,
+    sector_size: u64,
+    problems: &mut Vec<GptProblem>,
+) -> PurdResult<()> {
+    if primary.gpe_table_entry_size != backup.gpe_table_entry_size {
+        return Ok(()); // already reported, entries can't line up
+    }
+    let p = reader.read_bytes(primary.table_offset(sector_size)?, primary.table_len())?;
+    let b = reader.read_bytes(backup.table_offset(sector_size)?, backup.table_len())?;
+    let size = primary.gpe_table_entry_size.max(1) as usize;
+    let count = primary.gpe_table_entries.max(backup.gpe_table_entries);
+    for index in 0..count {
+        let at = index as usize * size;
+        if p.get(at..at + size) != b.get(at..at + size) {
+            problems.push(GptProblem::EntryMismatch { index });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use super::partitions::PartitionEntry;
+use super::uuids::GptArch;
+use colored::*;
+use compiled_uuid::uuid;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/*
+Discoverable Partitions Specification, the GPT types and flags that let
+systemd-gpt-auto-generator mount a disk with no fstab at all.
+https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+
+Root and /usr have a type per architecture and only the one matching the
+machine counts, each with a dm-verity hash partition and a verity
+signature partition next to it.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DpsType {
+    Root(GptArch),
+    RootVerity(GptArch),
+    RootVeritySig(GptArch),
+    Usr(GptArch),
+    UsrVerity(GptArch),
+    UsrVeritySig(GptArch),
+    Esp,
+    Xbootldr,
+    Swap,
+    Home,
+    Srv,
+    Var,
+    Tmp,
+    UserHome,
+    Generic,
+}
+
+lazy_static! {
+    pub static ref DPS_TYPE_MAP: HashMap<Uuid, DpsType> = HashMap::from([
+        (uuid!("44479540-F297-41B2-9AF7-D131D5F0458A"), DpsType::Root(GptArch::X86)),
+        (uuid!("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"), DpsType::Root(GptArch::X86_64)),
+        (uuid!("69DAD710-2CE4-4E3C-B16C-21A1D49ABED3"), DpsType::Root(GptArch::Arm)),
+        (uuid!("B921B045-1DF0-41C3-AF44-4C6F280D3FAE"), DpsType::Root(GptArch::Aarch64)),
+        (uuid!("D13C5D3B-B5D1-422A-B29F-9454FDC89D76"), DpsType::RootVerity(GptArch::X86)),
+        (uuid!("2C7357ED-EBD2-46D9-AEC1-23D437EC2BF5"), DpsType::RootVerity(GptArch::X86_64)),
+        (uuid!("7386CDF2-203C-47A9-A498-F2ECCE45A2D6"), DpsType::RootVerity(GptArch::Arm)),
+        (uuid!("DF3300CE-D69F-4C92-978C-9BFB0F38D820"), DpsType::RootVerity(GptArch::Aarch64)),
+        (uuid!("5996FC05-109C-48DE-808B-23FA0830B676"), DpsType::RootVeritySig(GptArch::X86)),
+        (uuid!("41092B05-9FC8-4523-994F-2DEF0408B176"), DpsType::RootVeritySig(GptArch::X86_64)),
+        (uuid!("42B0455F-EB11-491D-98D3-56145BA9D037"), DpsType::RootVeritySig(GptArch::Arm)),
+        (uuid!("6DB69DE6-29F4-4758-A7A5-962190F00CE3"), DpsType::RootVeritySig(GptArch::Aarch64)),
+        (uuid!("75250D76-8CC6-458E-BD66-BD47CC81A812"), DpsType::Usr(GptArch::X86)),
+        (uuid!("8484680C-9521-48C6-9C11-B0720656F69E"), DpsType::Usr(GptArch::X86_64)),
+        (uuid!("7D0359A3-02B3-4F0A-865C-654403E70625"), DpsType::Usr(GptArch::Arm)),
+        (uuid!("B0E01050-EE5F-4390-949A-9101B17104E9"), DpsType::Usr(GptArch::Aarch64)),
+        (uuid!("8F461B0D-14EE-4E81-9AA9-049B6FB97ABD"), DpsType::UsrVerity(GptArch::X86)),
+        (uuid!("77FF5F63-E7B6-4633-ACF4-1565B864C0E6"), DpsType::UsrVerity(GptArch::X86_64)),
+        (uuid!("C215D751-7BCD-4649-BE90-6627490A4C05"), DpsType::UsrVerity(GptArch::Arm)),
+        (uuid!("6E11A4E7-FBCA-4DED-B9E9-E1A512BB664E"), DpsType::UsrVerity(GptArch::Aarch64)),
+        (uuid!("974A71C0-DE41-43C3-BE5D-5C5CCD1AD2C0"), DpsType::UsrVeritySig(GptArch::X86)),
+        (uuid!("E7BB33FB-06CF-4E81-8273-E543B413E2E2"), DpsType::UsrVeritySig(GptArch::X86_64)),
+        (uuid!("D7FF812F-37D1-4902-A810-D76BA57B975A"), DpsType::UsrVeritySig(GptArch::Arm)),
+        (uuid!("C23CE4FF-44BD-4B00-B2D4-B41B3419E02A"), DpsType::UsrVeritySig(GptArch::Aarch64)),
+        (uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"), DpsType::Esp),
+        (uuid!("BC13C2FF-59E6-4262-A352-B275FD6F7172"), DpsType::Xbootldr),
+        (uuid!("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"), DpsType::Swap),
+        (uuid!("933AC7E1-2EB4-4F13-B844-0E14E2AEF915"), DpsType::Home),
+        (uuid!("3B8F8425-20E0-4F3B-907F-1A25A76F98E8"), DpsType::Srv),
+        (uuid!("4D21B016-B534-45C2-A9FB-5C16E091FD2D"), DpsType::Var),
+        (uuid!("7EC6F557-3BC5-4ACA-B293-16EF5DF639D1"), DpsType::Tmp),
+        (uuid!("773F91EF-66D4-49B5-BD83-D683BF40AD16"), DpsType::UserHome),
+        (uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4"), DpsType::Generic),
+    ]);
+}
+
+pub fn dps_type(type_guid: &Uuid) -> Option<DpsType> {
+    DPS_TYPE_MAP.get(type_guid).copied()
+}
+
+// what ends up in the generated mount unit, partitions by number
+// counting from 1 like everywhere else
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DpsMount {
+    pub target: &'static str, // "swap" for swap
+    pub number: u32,
+    pub read_only: bool,
+    pub grow_fs: bool,
+    pub verity: Option<u32>,
+    pub verity_sig: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DpsSkip {
+    NoAuto,
+    OtherArch,
+    Duplicate { used: u32 }, // the first one of a type wins
+    NotMounted,              // verity without a partner, generic data, homed
+    NeedsMachineId,          // /var and /var/tmp, see below
+}
+
+#[derive(Debug)]
+pub struct DpsLayout {
+    pub arch: GptArch,
+    pub mounts: Vec<DpsMount>,
+    pub skipped: Vec<(u32, DpsType, DpsSkip)>,
+}
+
+impl DpsLayout {
+    pub fn from_entries(entries: &[PartitionEntry], arch: GptArch) -> DpsLayout {
+        let found: Vec<(u32, DpsType, &PartitionEntry)> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.is_in_use())
+            .filter_map(|(i, e)| dps_type(&e.type_guid).map(|t| (i as u32 + 1, t, e)))
+            .collect();
+        // the first usable partition of each type, no-auto ones don't count
+        let first = |kind: DpsType| -> Option<u32> {
+            found
+                .iter()
+                .find(|(_, t, e)| *t == kind && !e.attributes().dps_no_auto())
+                .map(|(number, _, _)| *number)
+        };
+        let has_xbootldr = first(DpsType::Xbootldr).is_some();
+
+        let mut mounts: Vec<DpsMount> = vec![];
+        let mut skipped = vec![];
+        for (number, kind, entry) in found.iter() {
+            let flags = entry.attributes();
+            let (target, verity, verity_sig) = match kind {
+                DpsType::Root(a) if *a == arch => {
+                    ("/", first(DpsType::RootVerity(arch)), first(DpsType::RootVeritySig(arch)))
+                }
+                DpsType::Usr(a) if *a == arch => {
+                    ("/usr", first(DpsType::UsrVerity(arch)), first(DpsType::UsrVeritySig(arch)))
+                }
+                DpsType::Root(_) | DpsType::Usr(_) => {
+                    skipped.push((*number, *kind, DpsSkip::OtherArch));
+                    continue;
+                }
+                // the ESP only gets /boot when there's no XBOOTLDR for it
+                DpsType::Esp if has_xbootldr => ("/efi", None, None),
+                DpsType::Esp => ("/boot", None, None),
+                DpsType::Xbootldr => ("/boot", None, None),
+                DpsType::Swap => ("swap", None, None),
+                DpsType::Home => ("/home", None, None),
+                DpsType::Srv => ("/srv", None, None),
+                // only if its UUID is derived from the machine id, which
+                // an offline disk can't tell us
+                DpsType::Var | DpsType::Tmp => {
+                    skipped.push((*number, *kind, DpsSkip::NeedsMachineId));
+                    continue;
+                }
+                // verity halves get picked up by their data partition
+                _ => {
+                    skipped.push((*number, *kind, DpsSkip::NotMounted));
+                    continue;
+                }
+            };
+            if flags.dps_no_auto() {
+                skipped.push((*number, *kind, DpsSkip::NoAuto));
+                continue;
+            }
+            if let Some(used) = mounts.iter().find(|m| m.target == target) {
+                skipped.push((*number, *kind, DpsSkip::Duplicate { used: used.number }));
+                continue;
+            }
+            mounts.push(DpsMount {
+                target,
+                number: *number,
+                read_only: flags.dps_read_only() && *kind != DpsType::Swap,
+                grow_fs: flags.dps_grow_fs() && *kind != DpsType::Swap,
+                verity,
+                verity_sig,
+            });
+        }
+        skipped.retain(|(number, _, skip)| {
+            *skip != DpsSkip::NotMounted
+                || !mounts.iter().any(|m| m.verity == Some(*number) || m.verity_sig == Some(*number))
+        });
+        DpsLayout { arch, mounts, skipped }
+    }
+
+    pub fn print(&self) {
+        println!("{}", format!("systemd-gpt-auto-generator layout ({}):", self.arch).purple());
+        if self.mounts.is_empty() {
+            println!("nothing discoverable");
+        }
+        for mount in self.mounts.iter() {
+            let mut options = vec![];
+            if mount.read_only {
+                options.push("ro".to_string());
+            }
+            if mount.grow_fs {
+                options.push("x-systemd.growfs".to_string());
+            }
+            if let Some(verity) = mount.verity {
+                options.push(format!("verity={}", verity));
+            }
+            if let Some(sig) = mount.verity_sig {
+                options.push(format!("verity-sig={}", sig));
+            }
+            println!(
+                "{:<10} <- partition {} {}",
+                mount.target.bright_blue(),
+                mount.number,
+                options.join(",")
+            );
+        }
+        for (number, kind, skip) in self.skipped.iter() {
+            println!("{}", format!("skipped partition {} ({:?}): {:?}", number, kind, skip).yellow());
+        }
+    }
+}
@@ -1,17 +1,25 @@
 use partitions::PartitionEntry;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
+pub mod backup;
+pub mod discoverable;
 pub mod partitions;
+pub mod protective;
+pub mod repair;
 pub mod uuids;
 use super::summer::*;
-use crate::headers::constants::SMOL_BLOCKS;
+use crate::headers::error::{PurdError, PurdResult};
 use crate::headers::reader::*;
+use crate::headers::source::BlockSource;
 use crate::prettify_output;
 use colored::*;
 use crc::Algorithm;
 use std::ops::Range;
 
-#[derive(Deserialize, Debug)]
+// the smallest entry there is, every field of a PartitionEntry
+pub const GPT_ENTRY_SIZE: u32 = 128;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Gpt {
     pub signature: [u8; 8], //	Signature, can be identified by 8 bytes magic "EFI PART" (45h 46h 49h 20h 50h 41h 52h 54h)
     pub revision: [u8; 4],  //	GPT Revision
@@ -68,23 +76,22 @@ impl Summable32 for Gpt {
 }
 
 impl Gpt {
-    pub fn create_partition_table(&self, reader: &mut OnDisk) -> Vec<PartitionEntry> {
+    pub fn create_partition_table(&self, reader: &mut OnDisk, sector_size: u64) -> PurdResult<Vec<PartitionEntry>> {
         let mut partition_table: Vec<PartitionEntry> = vec![];
-        for i in 0..self.gpe_table_entries as u64 {
-            let entry = reader.read_header_from_offset::<PartitionEntry>(
-                self.gpe_table_start * SMOL_BLOCKS + i * self.gpe_table_entry_size as u64,
-            );
+        for i in 0..self.entry_count()? {
+            let entry = reader
+                .read_header_from_offset::<PartitionEntry>(self.entry_offset(i, sector_size)?)?;
             partition_table.push(entry);
         }
-        partition_table
+        Ok(partition_table)
     }
-    pub fn print_partition_table(&self, reader: &mut OnDisk) {
+    pub fn print_partition_table(&self, reader: &mut OnDisk, sector_size: u64) -> PurdResult<()> {
         let mut unused_counter = 0;
+        let count = self.entry_count()?;
         prettify_output!(PartitionEntry, purple, bright_purple, {
-            for i in 0..self.gpe_table_entries as u64 {
-                let entry = reader.read_header_from_offset::<PartitionEntry>(
-                    self.gpe_table_start * SMOL_BLOCKS + i * self.gpe_table_entry_size as u64,
-                );
+            for i in 0..count {
+                let entry = reader
+                .read_header_from_offset::<PartitionEntry>(self.entry_offset(i, sector_size)?)?;
                 if entry.is_in_use() {
                     println!(
                         "Name: {} Type: {}",
@@ -102,27 +109,62 @@ impl Gpt {
             "{}",
             format!("skipped {} unused partition entries", unused_counter).blue()
         );
+        Ok(())
     }
 
-    fn table_offset(&self) -> u64 {
-        self.gpe_table_start * SMOL_BLOCKS
+    // LBAs in the header are in logical sectors, 4Kn disks put the
+    // table at LBA 2 * 4096 not 2 * 512
+    pub fn table_offset(&self, sector_size: u64) -> PurdResult<u64> {
+        self.gpe_table_start
+            .checked_mul(sector_size)
+            .ok_or_else(|| PurdError::out_of_bounds("GPT:TABLE", self.gpe_table_start, sector_size))
+    }
+    fn entry_offset(&self, index: u64, sector_size: u64) -> PurdResult<u64> {
+        self.table_offset(sector_size)?
+            .checked_add(index * self.gpe_table_entry_size as u64)
+            .ok_or_else(|| PurdError::out_of_bounds("GPT:TABLE", self.gpe_table_start, index))
     }
-    pub fn get_parition(&self, reader: &mut OnDisk, index: u32) -> PartitionEntry {
-        reader.read_header_from_offset::<PartitionEntry>(
-            self.table_offset() + (index * self.gpe_table_entry_size) as u64,
-        )
+    // 128 * 2^n per the spec, older tools used any multiple of 8 big
+    // enough to hold a whole entry
+    pub fn entry_size_ok(&self) -> bool {
+        self.gpe_table_entry_size >= GPT_ENTRY_SIZE && self.gpe_table_entry_size.is_multiple_of(8)
+    }
+    // a junk header can claim 2^32 entries of 0 bytes each, only walk
+    // a table that could really be on disk
+    fn entry_count(&self) -> PurdResult<u64> {
+        if !self.entry_size_ok() || self.table_len() > backup::GPT_MAX_TABLE_BYTES {
+            return Err(PurdError::out_of_bounds(
+                "GPT:TABLE",
+                self.gpe_table_entries as u64,
+                self.gpe_table_entry_size as u64,
+            ));
+        }
+        Ok(self.gpe_table_entries as u64)
+    }
+    pub fn table_len(&self) -> u64 {
+        self.gpe_table_entries as u64 * self.gpe_table_entry_size as u64
+    }
+    pub fn get_parition(&self, reader: &mut OnDisk, index: u32, sector_size: u64) -> PurdResult<PartitionEntry> {
+        if index >= self.gpe_table_entries {
+            return Err(PurdError::out_of_bounds(
+                "GPT:TABLE",
+                index as u64,
+                self.gpe_table_entries as u64,
+            ));
+        }
+        reader.read_header_from_offset::<PartitionEntry>(self.entry_offset(index as u64, sector_size)?)
     }
 
-    pub fn validate_table_checksums(&self, reader: &mut OnDisk) -> bool {
+    pub fn validate_table_checksums(&self, reader: &mut OnDisk, sector_size: u64) -> PurdResult<bool> {
         let table_crc = crate::headers::summer::crc32_bytes_from_disk(
             reader,
             self.crc_parameters(),
-            self.table_offset(),
-            self.gpe_table_entries as u64 * self.gpe_table_entry_size as u64,
-        );
+            self.table_offset(sector_size)?,
+            self.table_len(),
+        )?;
         let comparison = table_crc == self.gpe_table_crc32;
         print_valid_checksum("GPT:TABLE", comparison);
-        comparison
+        Ok(comparison)
     }
 }
 
@@ -141,3 +183,18 @@ impl HasHeaderMagic for Gpt {
         0x4546492050415254
     }
 }
+
+// the protective MBR doesn't say how big a sector is but the header is
+// always at LBA 1, so wherever "EFI PART" shows up gives it away
+pub const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+pub fn probe_sector_size(reader: &dyn BlockSource) -> PurdResult<Option<u64>> {
+    for sector_size in GPT_SECTOR_SIZES {
+        if sector_size + 8 > reader.len() {
+            continue;
+        }
+        if reader.read_bytes(sector_size, 8)? == b"EFI PART" {
+            return Ok(Some(sector_size));
+        }
+    }
+    Ok(None)
+}
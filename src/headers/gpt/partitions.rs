@@ -61,6 +61,17 @@ impl Attributes {
     pub fn chrome_priority(&self) -> u8 {
         return (self.container & (0b1111 << 48) >> 48) as u8;
     }
+    // discoverable partitions spec flags, what systemd-gpt-auto-generator goes by
+    // https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+    pub fn dps_grow_fs(&self) -> bool {
+        bitfield_fetch(self.container, 1 << 59)
+    }
+    pub fn dps_read_only(&self) -> bool {
+        bitfield_fetch(self.container, 1 << 60)
+    }
+    pub fn dps_no_auto(&self) -> bool {
+        bitfield_fetch(self.container, 1 << 63)
+    }
 }
 
 impl fmt::Debug for Attributes {
@@ -103,7 +114,7 @@ impl<'de> Deserialize<'de> for Attributes {
         D: Deserializer<'de>,
     {
         let visitor = AttributesVisitor {};
-        let bitfield = deserializer.deserialize_u64(visitor).unwrap();
+        let bitfield = deserializer.deserialize_u64(visitor)?;
         let mut reserved_flags = [false; 47];
         for i in 0..47 {
             reserved_flags[i] = bitfield_fetch(bitfield, 0b1000 << i);
@@ -152,7 +163,8 @@ impl<'de> de::Visitor<'de> for AttributesVisitor {
 
 impl PartitionEntry {
     pub fn name(&self) -> String {
-        std::string::String::from_utf16(&self._name).unwrap()
+        // a lone surrogate in a name shouldn't take the whole disk down
+        std::string::String::from_utf16_lossy(&self._name)
     }
     pub fn type_to_str(&self) -> &str {
         match uuids::GUID_TYPE_MAP.get(&self.type_guid) {
@@ -160,6 +172,9 @@ impl PartitionEntry {
             Some(v) => v,
         }
     }
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
     pub fn is_in_use(&self) -> bool {
         self.type_guid != uuid!("00000000-0000-0000-0000-000000000000")
     }
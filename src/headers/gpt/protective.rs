@@ -0,0 +1,164 @@
+use super::partitions::PartitionEntry;
+use crate::headers::fs::disk::PartitionType;
+use crate::headers::mbr::{Mbr, MbrPartitionEntry, PartitionId};
+use colored::*;
+use std::fmt;
+
+/* What the MBR in front of a GPT is up to. The spec wants a single
+ * 0xEE entry from LBA 1 to the end of the disk (or 0xFFFFFFFF when
+ * the disk is too big to say) and nothing else. A hybrid MBR keeps
+ * the 0xEE entry but also lists some of the GPT partitions so old
+ * BIOS code can boot them (Boot Camp did this). Firmware reads
+ * whichever table it likes, so any hybrid entry that doesn't line up
+ * with the GPT is worth shouting about. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbrKind {
+    Protective,
+    Hybrid,
+    NotProtective, // no 0xEE entry at all
+}
+
+// slots are 0-3 like mbr.partitions, GPT indices are table indices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MbrFinding {
+    ProtectiveStart { slot: usize, start: u32 },
+    ProtectiveSize { slot: usize, sectors: u32, expected: u32 },
+    ExtraProtective { slot: usize },
+    OverlapsProtective { slot: usize, protective: usize },
+    Mirrors { slot: usize, gpt_index: u32 }, // fine, that's a hybrid
+    TypeMismatch { slot: usize, gpt_index: u32, mbr: PartitionType, gpt: PartitionType },
+    Overlap { slot: usize, gpt_index: u32 },
+    NotInGpt { slot: usize },
+}
+
+impl MbrFinding {
+    pub fn is_problem(&self) -> bool {
+        !matches!(self, MbrFinding::Mirrors { .. })
+    }
+}
+
+impl fmt::Display for MbrFinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MbrFinding::ProtectiveStart { slot, start } => {
+                write!(f, "MBR entry {} (0xEE) starts at LBA {}, should be 1", slot, start)
+            }
+            MbrFinding::ProtectiveSize { slot, sectors, expected } => {
+                write!(f, "MBR entry {} (0xEE) covers {} sectors, should be {}", slot, sectors, expected)
+            }
+            MbrFinding::ExtraProtective { slot } => write!(f, "MBR entry {} is a second 0xEE entry", slot),
+            MbrFinding::OverlapsProtective { slot, protective } => {
+                write!(f, "MBR entry {} overlaps the 0xEE entry {}", slot, protective)
+            }
+            MbrFinding::Mirrors { slot, gpt_index } => {
+                write!(f, "MBR entry {} mirrors GPT partition {}", slot, gpt_index)
+            }
+            MbrFinding::TypeMismatch { slot, gpt_index, mbr, gpt } => write!(
+                f,
+                "MBR entry {} says {:?} but GPT partition {} says {:?}",
+                slot, mbr, gpt_index, gpt
+            ),
+            MbrFinding::Overlap { slot, gpt_index } => {
+                write!(f, "MBR entry {} partly overlaps GPT partition {}", slot, gpt_index)
+            }
+            MbrFinding::NotInGpt { slot } => write!(f, "MBR entry {} isn't a GPT partition", slot),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MbrAnalysis {
+    pub kind: MbrKind,
+    pub findings: Vec<MbrFinding>,
+}
+
+// inclusive LBA range, the way GPT entries store it
+fn mbr_range(entry: &MbrPartitionEntry) -> (u64, u64) {
+    let start = entry.lba_of_partition_start as u64;
+    (start, start + entry.sectors_in_partition as u64 - 1)
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 <= b.1 && b.0 <= a.1
+}
+
+impl MbrAnalysis {
+    pub fn analyze(mbr: &Mbr, gpt_entries: &[PartitionEntry], disk_sectors: u64) -> MbrAnalysis {
+        let mut findings = vec![];
+        let used: Vec<(usize, &MbrPartitionEntry)> =
+            mbr.partitions.iter().enumerate().filter(|(_, p)| !p.is_unused()).collect();
+        let protective: Vec<usize> = used
+            .iter()
+            .filter(|(_, p)| matches!(p.get_partition_type(), PartitionId::Gpt))
+            .map(|(slot, _)| *slot)
+            .collect();
+        let kind = match (protective.len(), used.len()) {
+            (0, _) => MbrKind::NotProtective,
+            (p, u) if p == u => MbrKind::Protective,
+            _ => MbrKind::Hybrid,
+        };
+        for slot in protective.iter().skip(1) {
+            findings.push(MbrFinding::ExtraProtective { slot: *slot });
+        }
+        if let Some(slot) = protective.first() {
+            let entry = &mbr.partitions[*slot];
+            if entry.lba_of_partition_start != 1 {
+                findings.push(MbrFinding::ProtectiveStart { slot: *slot, start: entry.lba_of_partition_start });
+            }
+            // a hybrid's 0xEE entry stops short to make room, that's the point
+            let expected = disk_sectors.saturating_sub(1).min(u32::MAX as u64) as u32;
+            if kind == MbrKind::Protective && entry.sectors_in_partition != expected {
+                findings.push(MbrFinding::ProtectiveSize { slot: *slot, sectors: entry.sectors_in_partition, expected });
+            }
+        }
+
+        for (slot, entry) in used.iter().filter(|(slot, _)| !protective.contains(slot)) {
+            let range = mbr_range(entry);
+            for p in protective.iter() {
+                if overlaps(range, mbr_range(&mbr.partitions[*p])) {
+                    findings.push(MbrFinding::OverlapsProtective { slot: *slot, protective: *p });
+                }
+            }
+            // extended partitions are containers, they won't match anything
+            if kind != MbrKind::Hybrid || entry.is_extended() {
+                continue;
+            }
+            let mut found = false;
+            for (index, gpt) in gpt_entries.iter().enumerate().filter(|(_, e)| e.is_in_use()) {
+                let gpt_index = index as u32;
+                if !overlaps(range, (gpt.first_lba, gpt.last_lba)) {
+                    continue;
+                }
+                found = true;
+                if range != (gpt.first_lba, gpt.last_lba) {
+                    findings.push(MbrFinding::Overlap { slot: *slot, gpt_index });
+                    continue;
+                }
+                findings.push(MbrFinding::Mirrors { slot: *slot, gpt_index });
+                let (mbr_type, gpt_type) = (entry.get_disk_partition_type(), gpt.get_partition_type());
+                if mbr_type != gpt_type && mbr_type != PartitionType::Unknown && gpt_type != PartitionType::Unknown {
+                    findings.push(MbrFinding::TypeMismatch { slot: *slot, gpt_index, mbr: mbr_type, gpt: gpt_type });
+                }
+            }
+            if !found {
+                findings.push(MbrFinding::NotInGpt { slot: *slot });
+            }
+        }
+        MbrAnalysis { kind, findings }
+    }
+
+    pub fn has_problems(&self) -> bool {
+        self.kind == MbrKind::NotProtective || self.findings.iter().any(|f| f.is_problem())
+    }
+
+    pub fn print(&self) {
+        println!("{}", format!("MBR in front of the GPT: {:?}", self.kind).purple());
+        for finding in self.findings.iter() {
+            match finding.is_problem() {
+                true => println!("{}", format!("MBR: {}", finding).yellow()),
+                false => println!("MBR: {}", finding),
+            }
+        }
+    }
+}
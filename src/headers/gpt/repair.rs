@@ -0,0 +1,200 @@
+use super::backup::GptCheck;
+use super::Gpt;
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use crate::headers::source::{BlockSource, WritableFile};
+use crate::headers::summer::*;
+use colored::*;
+
+/* Rebuild one half of a GPT from the other, what gdisk does with
+ * "load backup" / "rebuild backup". Everything is planned up front
+ * as a list of writes so the same plan can be shown as a diff or put
+ * on disk, and nothing gets written without a WritableFile. */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairDirection {
+    PrimaryFromBackup,
+    BackupFromPrimary,
+}
+
+#[derive(Debug, Clone)]
+pub struct GptWrite {
+    pub what: &'static str,
+    pub offset: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct GptRepair {
+    pub direction: RepairDirection,
+    pub writes: Vec<GptWrite>,
+}
+
+// (start, end) of each run of bytes that differ
+fn changed_runs(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for i in (0..new.len()).filter(|i| old.get(*i) != Some(&new[*i])) {
+        match runs.last_mut() {
+            Some((_, end)) if *end == i => *end = i + 1,
+            _ => runs.push((i, i + 1)),
+        }
+    }
+    runs
+}
+
+impl GptWrite {
+    fn plan(reader: &mut OnDisk, what: &'static str, offset: u64, new: Vec<u8>) -> PurdResult<GptWrite> {
+        let old = reader.read_bytes(offset, new.len() as u64)?;
+        Ok(GptWrite { what, offset, old, new })
+    }
+    pub fn is_noop(&self) -> bool {
+        self.old == self.new
+    }
+    pub fn print_diff(&self) {
+        let runs = changed_runs(&self.old, &self.new);
+        println!(
+            "{}",
+            format!("{} at 0x{:X}: {} bytes differ", self.what, self.offset, runs.iter().map(|(s, e)| e - s).sum::<usize>())
+                .purple()
+        );
+        for (start, end) in runs {
+            // 16 a line like a hexdump
+            for line in (start..end).step_by(16) {
+                let line_end = (line + 16).min(end);
+                println!(
+                    "  0x{:08X}: {} -> {}",
+                    self.offset + line as u64,
+                    format!("{:02x?}", &self.old[line..line_end]).red(),
+                    format!("{:02x?}", &self.new[line..line_end]).green()
+                );
+            }
+        }
+    }
+}
+
+impl GptRepair {
+    pub fn is_empty(&self) -> bool {
+        self.writes.iter().all(|w| w.is_noop())
+    }
+    pub fn print_diff(&self) {
+        println!("{}", format!("GPT repair ({:?}), dry run:", self.direction).purple());
+        if self.is_empty() {
+            println!("nothing to change");
+        }
+        for write in self.writes.iter().filter(|w| !w.is_noop()) {
+            write.print_diff();
+        }
+    }
+    /* The plan was made from whatever the reader saw, which could be
+     * an image format where offsets mean something else entirely. So
+     * the target has to hold exactly those old bytes before anything
+     * is written. Tables go down before the headers that vouch for
+     * them. */
+    pub fn apply(&self, target: &WritableFile) -> PurdResult<()> {
+        for write in self.writes.iter() {
+            if target.read_bytes(write.offset, write.old.len() as u64)? != write.old {
+                return Err(PurdError::Unsupported(format!(
+                    "writing the GPT repair to {}, it doesn't match the disk that was read at 0x{:X}",
+                    target.name(),
+                    write.offset
+                )));
+            }
+        }
+        for write in self.writes.iter().filter(|w| !w.is_noop()) {
+            target.write_at(write.offset, &write.new)?;
+            println!("{}", format!("wrote {} at 0x{:X}", write.what, write.offset).green());
+        }
+        target.sync()
+    }
+}
+
+impl Gpt {
+    // same header moved somewhere else, crc32s redone for the new spot
+    pub fn relocated(&self, self_lba: u64, alt_lba: u64, table_lba: u64, table: &[u8]) -> PurdResult<Gpt> {
+        let mut gpt = self.clone();
+        gpt.self_lba = self_lba;
+        gpt.alt_lba = alt_lba;
+        gpt.gpe_table_start = table_lba;
+        gpt.gpe_table_crc32 = crc::Crc::<u32>::new(self.crc_parameters()).checksum(table);
+        gpt.crc32 = 0;
+        gpt.crc32 = crc32_structure_from_bytes(&gpt, &gpt.to_bytes()?)?;
+        Ok(gpt)
+    }
+    pub fn to_bytes(&self) -> PurdResult<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /* Plan rebuilding the broken half from the good one. The rebuilt
+     * half goes where the spec wants it: primary at LBA 1 with its
+     * entries at LBA 2, backup in the last LBA with its entries right
+     * before it. */
+    pub fn plan_repair(
+        reader: &mut OnDisk,
+        sector_size: u64,
+        direction: RepairDirection,
+    ) -> PurdResult<GptRepair> {
+        let check = GptCheck::check(reader, sector_size)?;
+        let last_lba = (reader.len() / sector_size).saturating_sub(1);
+        let (good, good_valid) = match direction {
+            RepairDirection::PrimaryFromBackup => (&check.backup, check.backup_valid),
+            RepairDirection::BackupFromPrimary => (&check.primary, check.primary_valid),
+        };
+        let good = match (good, good_valid) {
+            (Some(good), true) => good,
+            _ => {
+                return Err(PurdError::Unsupported(format!(
+                    "GPT repair {:?}, the header to copy from is corrupt",
+                    direction
+                )))
+            }
+        };
+        let table = reader.read_bytes(good.table_offset(sector_size)?, good.table_len())?;
+        let table_sectors = good.table_len().div_ceil(sector_size);
+        let mut writes = vec![];
+        match direction {
+            RepairDirection::PrimaryFromBackup => {
+                if 2 + table_sectors > good.first_usable_block {
+                    return Err(PurdError::out_of_bounds("GPT:primary table", 2 + table_sectors, good.first_usable_block));
+                }
+                let primary = good.relocated(1, check.backup_lba, 2, &table)?;
+                writes.push(GptWrite::plan(reader, "primary partition entries", 2 * sector_size, table)?);
+                writes.push(GptWrite::plan(reader, "primary header", sector_size, primary.to_bytes()?)?);
+            }
+            RepairDirection::BackupFromPrimary => {
+                let table_lba = last_lba.saturating_sub(table_sectors);
+                if table_lba <= good.last_usable_block {
+                    return Err(PurdError::out_of_bounds("GPT:backup table", good.last_usable_block, table_lba));
+                }
+                let backup = good.relocated(last_lba, 1, table_lba, &table)?;
+                writes.push(GptWrite::plan(reader, "backup partition entries", table_lba * sector_size, table.clone())?);
+                writes.push(GptWrite::plan(reader, "backup header", last_lba * sector_size, backup.to_bytes()?)?);
+                // the primary has to point at where the backup went
+                if good.alt_lba != last_lba {
+                    let primary = good.relocated(1, last_lba, good.gpe_table_start, &table)?;
+                    writes.push(GptWrite::plan(reader, "primary header", sector_size, primary.to_bytes()?)?);
+                }
+            }
+        }
+        Ok(GptRepair { direction, writes })
+    }
+
+    /* Repair whichever half is broken. Without a target this is a dry
+     * run that prints what would change. */
+    pub fn repair(reader: &mut OnDisk, sector_size: u64, target: Option<&WritableFile>) -> PurdResult<GptRepair> {
+        let check = GptCheck::check(reader, sector_size)?;
+        let direction = match (check.primary_valid, check.backup_valid) {
+            (false, true) => RepairDirection::PrimaryFromBackup,
+            (true, _) => RepairDirection::BackupFromPrimary,
+            (false, false) => {
+                return Err(PurdError::Unsupported("GPT repair with both headers corrupt".to_string()))
+            }
+        };
+        let repair = Gpt::plan_repair(reader, sector_size, direction)?;
+        match target {
+            Some(target) => repair.apply(target)?,
+            None => repair.print_diff(),
+        }
+        Ok(repair)
+    }
+}
@@ -2,8 +2,48 @@ use crate::headers::fs::disk::PartitionType;
 use compiled_uuid::uuid;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::fmt;
 use uuid::Uuid;
 
+// the architectures that get their own root partition type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GptArch {
+    X86,
+    X86_64,
+    Arm,
+    Aarch64,
+}
+
+impl GptArch {
+    // whatever purd itself was built for, the machine reading the disk
+    pub fn native() -> Option<GptArch> {
+        match std::env::consts::ARCH {
+            "x86" => Some(GptArch::X86),
+            "x86_64" => Some(GptArch::X86_64),
+            "arm" => Some(GptArch::Arm),
+            "aarch64" => Some(GptArch::Aarch64),
+            _ => None,
+        }
+    }
+    pub fn parse(name: &str) -> Option<GptArch> {
+        [GptArch::X86, GptArch::X86_64, GptArch::Arm, GptArch::Aarch64]
+            .into_iter()
+            .find(|arch| arch.to_string() == name)
+    }
+}
+
+impl fmt::Display for GptArch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GptArch::X86 => "x86",
+            GptArch::X86_64 => "x86-64",
+            GptArch::Arm => "arm",
+            GptArch::Aarch64 => "arm64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 /* would write this differently if I did it again */
 lazy_static! {
     pub static ref GUID_TYPE_ENUM_MAP: HashMap<Uuid, PartitionType> =
@@ -23,7 +63,31 @@ lazy_static! {
             (
                 uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
                 PartitionType::LinuxFsTBD,
-            )
+            ),
+            (uuid!("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"), PartitionType::LinuxSwap),
+            (uuid!("E6D6D379-F507-44C2-A23C-238F2A3DF928"), PartitionType::LinuxLvm),
+            (uuid!("A19D880F-05FC-4D3B-A006-743F0F84911E"), PartitionType::LinuxRaid),
+            (uuid!("CA7D7CCB-63ED-4C53-861C-1742536059CC"), PartitionType::Luks),
+            (uuid!("933AC7E1-2EB4-4F13-B844-0E14E2AEF915"), PartitionType::LinuxHome),
+            // root per architecture, from the discoverable partitions spec
+            (uuid!("44479540-F297-41B2-9AF7-D131D5F0458A"), PartitionType::LinuxRoot(GptArch::X86)),
+            (uuid!("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"), PartitionType::LinuxRoot(GptArch::X86_64)),
+            (uuid!("69DAD710-2CE4-4E3C-B16C-21A1D49ABED3"), PartitionType::LinuxRoot(GptArch::Arm)),
+            (uuid!("B921B045-1DF0-41C3-AF44-4C6F280D3FAE"), PartitionType::LinuxRoot(GptArch::Aarch64)),
+            (uuid!("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"), PartitionType::MicrosoftBasicData),
+            (uuid!("E3C9E316-0B5C-4DB8-817D-F92DF00215AE"), PartitionType::MicrosoftReserved),
+            (uuid!("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC"), PartitionType::WindowsRecovery),
+            (uuid!("5808C8AA-7E8F-42E0-85D2-E1E90434CFB3"), PartitionType::LdmMetadata),
+            (uuid!("AF9B60A0-1431-4F62-BC68-3311714A69AD"), PartitionType::LdmData),
+            (uuid!("7C3457EF-0000-11AA-AA11-00306543ECAC"), PartitionType::AppleApfs),
+            (uuid!("48465300-0000-11AA-AA11-00306543ECAC"), PartitionType::AppleHfsPlus),
+            (uuid!("83BD6B9D-7F41-11DC-BE0B-001560B84F0F"), PartitionType::FreeBsdBoot),
+            (uuid!("516E7CB4-6ECF-11D6-8FF8-00022D09712B"), PartitionType::FreeBsdData),
+            (uuid!("516E7CB5-6ECF-11D6-8FF8-00022D09712B"), PartitionType::FreeBsdSwap),
+            (uuid!("516E7CB6-6ECF-11D6-8FF8-00022D09712B"), PartitionType::FreeBsdUfs),
+            (uuid!("516E7CBA-6ECF-11D6-8FF8-00022D09712B"), PartitionType::FreeBsdZfs),
+            (uuid!("FE3A2A5D-4F32-41A7-B725-ACCC3285A309"), PartitionType::ChromeOsKernel),
+            (uuid!("3CB8E202-3B7E-47DD-8A3C-7FF2A13CFCEC"), PartitionType::ChromeOsRoot),
         ]);
 }
 lazy_static! {
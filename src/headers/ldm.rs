@@ -0,0 +1,772 @@
+use super::error::{PurdError, PurdResult};
+use super::reader::*;
+use super::source::{BlockSource, ConcatSource, SubSource};
+use colored::*;
+use serde::Deserialize;
+use serde_big_array::BigArray;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/* Windows LDM, "dynamic disks". The partition table only says where
+ * the data area is, what's in it is described by a 1MB database
+ * (usually at the end of the disk) that every disk in the group has
+ * a copy of. PRIVHEAD says where the database is, TOCBLOCK where the
+ * VMDB is inside it, and the VMDB is a pile of fixed size VBLKs
+ * holding one record each (or part of one) for every volume,
+ * component, partition, disk and disk group. All big endian.
+ * https://github.com/mdbooth/libldm/blob/master/src/ldm.c and
+ * linux/block/partitions/ldm.c
+ */
+
+// where PRIVHEAD is on an MBR disk, on GPT it's the last LBA of the
+// LDM metadata partition
+pub const LDM_PRIVHEAD_MBR_LBA: u64 = 6;
+// LBAs into the database, the other two are backups
+const LDM_TOCBLOCK_LBAS: [u64; 4] = [1, 2, 2045, 2046];
+const VBLK_HEAD_SIZE: usize = 16;
+// a 1MB database can't hold more than this many even at 128B each
+const LDM_MAX_VBLKS: u32 = 8192;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrivHead {
+    pub magic: [u8; 8], // "PRIVHEAD"
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub sequence: u32,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub ver_major: u16,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub ver_minor: u16,
+    pub timestamp: u64,
+    pub unknown_number: u64,
+    pub unknown_size1: u64,
+    pub unknown_size2: u64,
+    #[serde(with = "BigArray")]
+    pub disk_guid: [u8; 64], // ASCII, NUL padded
+    #[serde(with = "BigArray")]
+    pub host_guid: [u8; 64],
+    #[serde(with = "BigArray")]
+    pub disk_group_guid: [u8; 64],
+    pub disk_group_name: [u8; 32],
+    pub unknown1: u16,
+    pub padding1: [u8; 9],
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub logical_disk_start: u64, // the data area, in LBAs
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub logical_disk_size: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub config_start: u64, // the database
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub config_size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TocBitmap {
+    pub name: [u8; 8], // "config" or "log"
+    pub flags1: u16,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub start: u64, // LBAs into the database
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub size: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub flags2: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TocBlock {
+    pub magic: [u8; 8], // "TOCBLOCK"
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub seq1: u32,
+    pub padding1: [u8; 4],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub seq2: u32,
+    pub padding2: [u8; 16],
+    pub bitmaps: [TocBitmap; 2],
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Vmdb {
+    pub magic: [u8; 4], // "VMDB"
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub vblk_last: u32, // sequence number of the last VBLK
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub vblk_size: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub vblk_first_offset: u32, // bytes from the VMDB
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub update_status: u16,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub ver_major: u16,
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub ver_minor: u16,
+}
+
+fn fixed_str(bytes: &[u8]) -> String {
+    let end =
+        bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+impl PrivHead {
+    pub fn disk_guid(&self) -> Option<Uuid> {
+        Uuid::parse_str(&fixed_str(&self.disk_guid)).ok()
+    }
+    pub fn disk_group_name(&self) -> String {
+        fixed_str(&self.disk_group_name)
+    }
+}
+
+impl TocBlock {
+    // where the VMDB starts, in LBAs into the database
+    pub fn config_start(&self) -> Option<u64> {
+        self.bitmaps
+            .iter()
+            .find(|b| fixed_str(&b.name) == "config")
+            .map(|b| b.start)
+    }
+}
+
+/* Records are full of variable length fields: a length byte then
+ * that many bytes, big endian numbers or strings. This walks them in
+ * order and errors instead of running off the end. */
+struct VblkCursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> VblkCursor<'a> {
+    fn take(&mut self, len: usize) -> PurdResult<&'a [u8]> {
+        match self.bytes.get(self.at..self.at + len) {
+            Some(slice) => {
+                self.at += len;
+                Ok(slice)
+            }
+            None => Err(PurdError::out_of_bounds(
+                "LDM:VBLK",
+                (self.at + len) as u64,
+                self.bytes.len() as u64,
+            )),
+        }
+    }
+    fn skip(&mut self, len: usize) -> PurdResult<()> {
+        self.take(len).map(|_| ())
+    }
+    fn u8(&mut self) -> PurdResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u64(&mut self) -> PurdResult<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn guid(&mut self) -> PurdResult<Uuid> {
+        let at = self.at as u64;
+        Uuid::from_slice(self.take(16)?)
+            .map_err(|_| PurdError::out_of_bounds("LDM:VBLK", at, 16))
+    }
+    fn var_bytes(&mut self) -> PurdResult<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+    fn var_num(&mut self) -> PurdResult<u64> {
+        let bytes = self.var_bytes()?;
+        if bytes.len() > 8 {
+            return Err(PurdError::Unsupported(format!(
+                "LDM number {} bytes long",
+                bytes.len()
+            )));
+        }
+        Ok(bytes.iter().fold(0, |n, b| (n << 8) | *b as u64))
+    }
+    fn var_str(&mut self) -> PurdResult<String> {
+        Ok(String::from_utf8_lossy(self.var_bytes()?).to_string())
+    }
+}
+
+// (entry, everything after the VBLK header)
+type Fragment<'a> = (u16, &'a [u8]);
+
+// VBLK record types, the low nibble is the kind and the high one the
+// revision
+const VBLK_COMPONENT: u8 = 0x32;
+const VBLK_PARTITION: u8 = 0x33;
+const VBLK_DISK3: u8 = 0x34;
+const VBLK_DISK4: u8 = 0x44;
+const VBLK_VOLUME: u8 = 0x51;
+
+const VBLK_FLAG_COMP_STRIPE: u8 = 0x10;
+const VBLK_FLAG_PART_INDEX: u8 = 0x08;
+
+// component types
+const COMP_STRIPE: u8 = 1;
+const COMP_BASIC: u8 = 2; // simple or spanned
+const COMP_RAID5: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub struct LdmDiskRecord {
+    pub id: u64,
+    pub name: String,
+    pub guid: Option<Uuid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdmVolumeRecord {
+    pub id: u64,
+    pub name: String,
+    pub volume_type: String, // "gen" or "raid5"
+    pub size: u64,           // sectors
+    pub partition_type: u8,  // the MBR type it would have had
+    pub guid: Uuid,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdmComponentRecord {
+    pub id: u64,
+    pub name: String,
+    pub comp_type: u8,
+    pub parent_id: u64, // volume
+    pub stripe_size: u64,
+    pub columns: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdmPartitionRecord {
+    pub id: u64,
+    pub name: String,
+    pub start: u64, // LBAs into the data area of its disk
+    pub volume_offset: u64,
+    pub size: u64,
+    pub parent_id: u64, // component
+    pub disk_id: u64,
+    pub index: u64, // column in a stripe
+}
+
+#[derive(Debug, Clone)]
+pub enum LdmRecord {
+    Disk(LdmDiskRecord),
+    Volume(LdmVolumeRecord),
+    Component(LdmComponentRecord),
+    Partition(LdmPartitionRecord),
+    Other(u8), // disk groups and anything newer
+}
+
+/* One record, starting at its record header (just past the VBLK
+ * header, or the reassembled fragments). */
+pub fn parse_record(record: &[u8]) -> PurdResult<LdmRecord> {
+    let mut c = VblkCursor {
+        bytes: record,
+        at: 0,
+    };
+    c.skip(2)?; // status
+    let flags = c.u8()?;
+    let rec_type = c.u8()?;
+    c.skip(4)?; // size
+    if !matches!(
+        rec_type,
+        VBLK_COMPONENT
+            | VBLK_PARTITION
+            | VBLK_DISK3
+            | VBLK_DISK4
+            | VBLK_VOLUME
+    ) {
+        return Ok(LdmRecord::Other(rec_type));
+    }
+    let id = c.var_num()?;
+    let name = c.var_str()?;
+    let record = match rec_type {
+        VBLK_DISK3 => LdmRecord::Disk(LdmDiskRecord {
+            id,
+            name,
+            guid: Uuid::parse_str(&c.var_str()?).ok(),
+        }),
+        VBLK_DISK4 => LdmRecord::Disk(LdmDiskRecord {
+            id,
+            name,
+            guid: Some(c.guid()?),
+        }),
+        VBLK_VOLUME => {
+            let volume_type = c.var_str()?;
+            c.var_bytes()?; // "disable drive letter", always 8 zeros
+            c.skip(21)?; // state, type, flags
+            c.var_num()?; // children
+            c.skip(16)?; // log commit id and something else
+            let size = c.var_num()?;
+            c.skip(4)?;
+            let partition_type = c.u8()?;
+            let guid = c.guid()?;
+            LdmRecord::Volume(LdmVolumeRecord {
+                id,
+                name,
+                volume_type,
+                size,
+                partition_type,
+                guid,
+            })
+        }
+        VBLK_COMPONENT => {
+            c.var_str()?; // state, "ACTIVE"
+            let comp_type = c.u8()?;
+            c.skip(4)?;
+            c.var_num()?; // children
+            c.skip(16)?;
+            let parent_id = c.var_num()?;
+            c.skip(1)?;
+            let (stripe_size, columns) =
+                match flags & VBLK_FLAG_COMP_STRIPE != 0 {
+                    true => (c.var_num()?, c.var_num()?),
+                    false => (0, 0),
+                };
+            LdmRecord::Component(LdmComponentRecord {
+                id,
+                name,
+                comp_type,
+                parent_id,
+                stripe_size,
+                columns,
+            })
+        }
+        _ => {
+            c.skip(12)?; // zeros and the log commit id
+            let start = c.u64()?;
+            let volume_offset = c.u64()?;
+            let size = c.var_num()?;
+            let parent_id = c.var_num()?;
+            let disk_id = c.var_num()?;
+            let index = match flags & VBLK_FLAG_PART_INDEX != 0 {
+                true => c.var_num()?,
+                false => 0,
+            };
+            LdmRecord::Partition(LdmPartitionRecord {
+                id,
+                name,
+                start,
+                volume_offset,
+                size,
+                parent_id,
+                disk_id,
+                index,
+            })
+        }
+    };
+    Ok(record)
+}
+
+fn ldm_warning(msg: String) {
+    println!("{}", format!("LDM: {}", msg).yellow());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LdmLayout {
+    Simple,
+    Spanned,
+    Striped { stripe_size: u64, columns: u64 },
+    Mirrored,
+    Raid5,
+}
+
+impl fmt::Display for LdmLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LdmLayout::Simple => write!(f, "simple"),
+            LdmLayout::Spanned => write!(f, "spanned"),
+            LdmLayout::Striped {
+                stripe_size,
+                columns,
+            } => write!(
+                f,
+                "striped ({} columns, {} sector stripes)",
+                columns, stripe_size
+            ),
+            LdmLayout::Mirrored => write!(f, "mirrored"),
+            LdmLayout::Raid5 => write!(f, "RAID5"),
+        }
+    }
+}
+
+// one piece of a volume, on whichever disk it's on
+#[derive(Debug, Clone)]
+pub struct LdmExtent {
+    pub name: String,
+    pub disk_id: u64,
+    pub start: u64, // LBAs into that disk's data area
+    pub volume_offset: u64,
+    pub sectors: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdmVolume {
+    pub name: String,
+    pub layout: LdmLayout,
+    pub sectors: u64,
+    pub partition_type: u8,
+    pub guid: Uuid,
+    // the copy we'd read from, for mirrors the first one that's all
+    // on this disk
+    pub extents: Vec<LdmExtent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdmDatabase {
+    pub privhead: PrivHead,
+    pub toc: TocBlock,
+    pub vmdb: Vmdb,
+    pub disks: Vec<LdmDiskRecord>,
+    pub volumes: Vec<LdmVolumeRecord>,
+    pub components: Vec<LdmComponentRecord>,
+    pub partitions: Vec<LdmPartitionRecord>,
+}
+
+fn require_magic(
+    found: &[u8],
+    magic: &[u8],
+    offset: u64,
+) -> PurdResult<()> {
+    if found != magic {
+        return Err(PurdError::bad_magic(
+            &String::from_utf8_lossy(magic),
+            offset,
+            magic.iter().fold(0, |n, b| (n << 8) | *b as u128),
+            found.iter().fold(0, |n, b| (n << 8) | *b as u128),
+        ));
+    }
+    Ok(())
+}
+
+/* Every LBA and length in PRIVHEAD and the TOCBLOCK is whatever was
+ * on disk, one that doesn't fit in a byte offset is a corrupt
+ * database rather than a panic. */
+fn ldm_offset(
+    lba: u64,
+    plus: u64,
+    sector_size: u64,
+) -> PurdResult<u64> {
+    lba.checked_add(plus)
+        .and_then(|lba| lba.checked_mul(sector_size))
+        .ok_or_else(|| PurdError::out_of_bounds("LDM", lba, plus))
+}
+
+impl LdmDatabase {
+    /* PRIVHEAD at privhead_lba, then the database it points to. A
+     * VBLK without its magic is skipped rather than losing the
+     * whole database over it. */
+    pub fn read(
+        reader: &mut OnDisk,
+        privhead_lba: u64,
+        sector_size: u64,
+    ) -> PurdResult<LdmDatabase> {
+        let offset = ldm_offset(privhead_lba, 0, sector_size)?;
+        let privhead =
+            reader.read_header_from_offset::<PrivHead>(offset)?;
+        require_magic(&privhead.magic, b"PRIVHEAD", offset)?;
+        if (privhead.ver_major, privhead.ver_minor) != (2, 11)
+            && (privhead.ver_major, privhead.ver_minor) != (2, 12)
+        {
+            ldm_warning(format!(
+                "PRIVHEAD version {}.{}, expected 2.11 or 2.12",
+                privhead.ver_major, privhead.ver_minor
+            ));
+        }
+        let db_start = privhead.config_start;
+        let db_len =
+            ldm_offset(privhead.config_size, 0, sector_size)?;
+
+        let mut toc = None;
+        for lba in LDM_TOCBLOCK_LBAS {
+            if lba >= privhead.config_size {
+                continue;
+            }
+            let block = reader.read_header_from_offset::<TocBlock>(
+                ldm_offset(db_start, lba, sector_size)?,
+            )?;
+            if &block.magic == b"TOCBLOCK"
+                && block.config_start().is_some()
+            {
+                toc = Some(block);
+                break;
+            }
+            ldm_warning(format!(
+                "no TOCBLOCK at LBA {}",
+                db_start + lba
+            ));
+        }
+        let (toc, config_start) = toc
+            .and_then(|toc| toc.config_start().map(|at| (toc, at)))
+            .ok_or_else(|| {
+                PurdError::Unsupported(format!(
+                    "LDM database at LBA {} without a TOCBLOCK",
+                    db_start
+                ))
+            })?;
+
+        let vmdb_offset =
+            ldm_offset(db_start, config_start, sector_size)?;
+        let vmdb =
+            reader.read_header_from_offset::<Vmdb>(vmdb_offset)?;
+        require_magic(&vmdb.magic, b"VMDB", vmdb_offset)?;
+        if (vmdb.ver_major, vmdb.ver_minor) != (4, 10) {
+            ldm_warning(format!(
+                "VMDB version {}.{}, expected 4.10",
+                vmdb.ver_major, vmdb.ver_minor
+            ));
+        }
+        let vblk_size = vmdb.vblk_size as u64;
+        if vblk_size <= VBLK_HEAD_SIZE as u64 {
+            return Err(PurdError::Unsupported(format!(
+                "LDM VBLKs {} bytes long",
+                vblk_size
+            )));
+        }
+        // VBLKs run from vblk_first_offset up to the last sequence
+        // number, and can't leave the database
+        let first = vmdb.vblk_first_offset as u64;
+        let last =
+            vmdb.vblk_last.min(LDM_MAX_VBLKS) as u64 * vblk_size;
+        let end = last
+            .min(db_len.saturating_sub(config_start * sector_size));
+        let bytes = match end > first {
+            true => reader.read_bytes_from_file(
+                ldm_offset(vmdb_offset, first, 1)?,
+                end - first,
+            )?,
+            false => vec![],
+        };
+
+        let mut records = vec![];
+        // record id -> (entries total, fragments by entry)
+        let mut fragments: BTreeMap<u32, (u16, Vec<Fragment>)> =
+            BTreeMap::new();
+        for (i, vblk) in
+            bytes.chunks_exact(vblk_size as usize).enumerate()
+        {
+            if &vblk[..4] != b"VBLK" {
+                ldm_warning(format!(
+                    "VBLK {} has no magic, skipping it",
+                    i
+                ));
+                continue;
+            }
+            let record_id =
+                u32::from_be_bytes(vblk[8..12].try_into().unwrap());
+            let entry =
+                u16::from_be_bytes(vblk[12..14].try_into().unwrap());
+            let total =
+                u16::from_be_bytes(vblk[14..16].try_into().unwrap());
+            match total {
+                0 => continue, // free
+                1 => records
+                    .push(parse_record(&vblk[VBLK_HEAD_SIZE..])),
+                _ => {
+                    let frags = fragments
+                        .entry(record_id)
+                        .or_insert((total, vec![]));
+                    frags.1.push((entry, &vblk[VBLK_HEAD_SIZE..]));
+                }
+            }
+        }
+        // records too big for one VBLK continue in the next ones with
+        // the same record id
+        for (id, (total, mut frags)) in fragments {
+            frags.sort_by_key(|(entry, _)| *entry);
+            if frags.len() != total as usize {
+                ldm_warning(format!(
+                    "record {} has {} of its {} fragments, skipping it",
+                    id,
+                    frags.len(),
+                    total
+                ));
+                continue;
+            }
+            records.push(parse_record(
+                &frags
+                    .iter()
+                    .map(|(_, f)| *f)
+                    .collect::<Vec<_>>()
+                    .concat(),
+            ));
+        }
+
+        let mut db = LdmDatabase {
+            privhead,
+            toc,
+            vmdb,
+            disks: vec![],
+            volumes: vec![],
+            components: vec![],
+            partitions: vec![],
+        };
+        for record in records {
+            match record {
+                Ok(LdmRecord::Disk(d)) => db.disks.push(d),
+                Ok(LdmRecord::Volume(v)) => db.volumes.push(v),
+                Ok(LdmRecord::Component(c)) => db.components.push(c),
+                Ok(LdmRecord::Partition(p)) => db.partitions.push(p),
+                Ok(LdmRecord::Other(_)) => {}
+                Err(e) => ldm_warning(format!("bad record: {}", e)),
+            }
+        }
+        Ok(db)
+    }
+
+    // the disk record for the disk this database was read from
+    pub fn this_disk(&self) -> Option<&LdmDiskRecord> {
+        let guid = self.privhead.disk_guid()?;
+        self.disks.iter().find(|d| d.guid == Some(guid))
+    }
+
+    fn component_extents(
+        &self,
+        comp: &LdmComponentRecord,
+    ) -> Vec<LdmExtent> {
+        let mut parts: Vec<&LdmPartitionRecord> = self
+            .partitions
+            .iter()
+            .filter(|p| p.parent_id == comp.id)
+            .collect();
+        match comp.comp_type {
+            COMP_STRIPE | COMP_RAID5 => {
+                parts.sort_by_key(|p| p.index)
+            }
+            _ => parts.sort_by_key(|p| p.volume_offset),
+        }
+        parts
+            .iter()
+            .map(|p| LdmExtent {
+                name: p.name.clone(),
+                disk_id: p.disk_id,
+                start: p.start,
+                volume_offset: p.volume_offset,
+                sectors: p.size,
+            })
+            .collect()
+    }
+
+    /* Volumes put back together from their components and partitions.
+     * A mirror has one component per copy, the one to read from is
+     * the first that's entirely on this disk. */
+    pub fn volumes(&self) -> Vec<LdmVolume> {
+        let this_disk = self.this_disk().map(|d| d.id);
+        let mut volumes = vec![];
+        for vol in self.volumes.iter() {
+            let comps: Vec<&LdmComponentRecord> = self
+                .components
+                .iter()
+                .filter(|c| c.parent_id == vol.id)
+                .collect();
+            let copies: Vec<Vec<LdmExtent>> = comps
+                .iter()
+                .map(|c| self.component_extents(c))
+                .collect();
+            let pick = copies
+                .iter()
+                .position(|extents| {
+                    extents
+                        .iter()
+                        .all(|e| Some(e.disk_id) == this_disk)
+                })
+                .unwrap_or(0);
+            let layout = match comps.get(pick) {
+                _ if comps.len() > 1 => LdmLayout::Mirrored,
+                Some(c) if c.comp_type == COMP_STRIPE => {
+                    LdmLayout::Striped {
+                        stripe_size: c.stripe_size,
+                        columns: c.columns,
+                    }
+                }
+                Some(c) if c.comp_type == COMP_RAID5 => {
+                    LdmLayout::Raid5
+                }
+                Some(c)
+                    if c.comp_type == COMP_BASIC
+                        && copies[pick].len() > 1 =>
+                {
+                    LdmLayout::Spanned
+                }
+                _ => LdmLayout::Simple,
+            };
+            volumes.push(LdmVolume {
+                name: vol.name.clone(),
+                layout,
+                sectors: vol.size,
+                partition_type: vol.partition_type,
+                guid: vol.guid,
+                extents: copies
+                    .into_iter()
+                    .nth(pick)
+                    .unwrap_or_default(),
+            });
+        }
+        volumes
+    }
+
+    /* Something to read the volume through, when we can: every
+     * extent has to be on this disk and laid end to end (simple,
+     * spanned, or one copy of a mirror). Stripes and RAID5 aren't
+     * put back together. */
+    pub fn volume_source(
+        &self,
+        volume: &LdmVolume,
+        disk: Arc<dyn BlockSource>,
+        sector_size: u64,
+    ) -> PurdResult<Option<Arc<dyn BlockSource>>> {
+        let this_disk = self.this_disk().map(|d| d.id);
+        if matches!(
+            volume.layout,
+            LdmLayout::Striped { .. } | LdmLayout::Raid5
+        ) || volume.extents.is_empty()
+            || volume
+                .extents
+                .iter()
+                .any(|e| Some(e.disk_id) != this_disk)
+        {
+            return Ok(None);
+        }
+        let data_start = self.privhead.logical_disk_start;
+        let mut pieces: Vec<Arc<dyn BlockSource>> = vec![];
+        let mut expected = 0;
+        for extent in volume.extents.iter() {
+            if extent.volume_offset != expected {
+                ldm_warning(format!(
+                    "volume {} has a gap at sector {}",
+                    volume.name, expected
+                ));
+                return Ok(None);
+            }
+            pieces.push(Arc::new(SubSource::new(
+                disk.clone(),
+                ldm_offset(data_start, extent.start, sector_size)?,
+                ldm_offset(extent.sectors, 0, sector_size)?,
+            )?));
+            expected = ldm_offset(expected, extent.sectors, 1)?;
+        }
+        Ok(Some(Arc::new(ConcatSource::new(
+            &format!("{}:{}", disk.name(), volume.name),
+            pieces,
+        ))))
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{}",
+            format!(
+                "LDM disk group {:?}, data at LBA {} ({} sectors), database at LBA {}",
+                self.privhead.disk_group_name(),
+                self.privhead.logical_disk_start,
+                self.privhead.logical_disk_size,
+                self.privhead.config_start
+            )
+            .purple()
+        );
+        let this_disk = self.this_disk().map(|d| d.id);
+        for disk in self.disks.iter() {
+            let mark = match Some(disk.id) == this_disk {
+                true => " (this one)",
+                false => "",
+            };
+            println!(
+                "disk {} {}{}",
+                disk.name.bright_blue(),
+                disk.guid.map(|g| g.to_string()).unwrap_or_default(),
+                mark
+            );
+        }
+    }
+}
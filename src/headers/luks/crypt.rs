@@ -0,0 +1,204 @@
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::source::{cache, check_bounds, BlockSource};
+use aes::cipher::{
+    generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit,
+};
+use aes::{Aes128, Aes256};
+use std::fmt;
+use std::sync::Arc;
+
+const IV_SECTOR: u64 = 512;
+
+/* aes-xts-plain64, what nearly every LUKS volume uses. Sectors are
+ * always whole AES blocks so there's no ciphertext stealing. The
+ * tweak is the sector number, little endian, encrypted with the
+ * second half of the key, then doubled in GF(2^128) for each block.
+ */
+
+enum Aes {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> PurdResult<Aes> {
+        match key.len() {
+            16 => Ok(Aes::Aes128(Box::new(Aes128::new(
+                GenericArray::from_slice(key),
+            )))),
+            32 => Ok(Aes::Aes256(Box::new(Aes256::new(
+                GenericArray::from_slice(key),
+            )))),
+            n => Err(PurdError::Unsupported(format!(
+                "{} bit AES keys",
+                n * 8
+            ))),
+        }
+    }
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.encrypt_block(block),
+            Aes::Aes256(aes) => aes.encrypt_block(block),
+        }
+    }
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(aes) => aes.decrypt_block(block),
+            Aes::Aes256(aes) => aes.decrypt_block(block),
+        }
+    }
+}
+
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+// keys stay out of debug output
+impl fmt::Debug for Xts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Xts")
+    }
+}
+
+impl Xts {
+    // two AES keys back to back, 256 or 512 bits all told
+    pub fn new(key: &[u8]) -> PurdResult<Xts> {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Ok(Xts {
+            data: Aes::new(data)?,
+            tweak: Aes::new(tweak)?,
+        })
+    }
+
+    /* What a cipher spec like "aes-xts-plain64" (LUKS2) or "aes" and
+     * "xts-plain64" (LUKS1, joined) needs, the only one there is so
+     * far. */
+    pub fn for_cipher(spec: &str, key: &[u8]) -> PurdResult<Xts> {
+        match spec {
+            "aes-xts-plain64" => Xts::new(key),
+            _ => Err(PurdError::Unsupported(format!(
+                "LUKS cipher {}",
+                spec
+            ))),
+        }
+    }
+
+    pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt(&mut t);
+        let mut tweak = u128::from_le_bytes(t);
+        for block in buf.chunks_exact_mut(16) {
+            let t = tweak.to_le_bytes();
+            block.iter_mut().zip(t.iter()).for_each(|(b, t)| *b ^= t);
+            self.data.decrypt(block);
+            block.iter_mut().zip(t.iter()).for_each(|(b, t)| *b ^= t);
+            let carry = tweak >> 127;
+            tweak = (tweak << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+/* The decrypted view of a LUKS data segment. Reads get widened to
+ * whole sectors and cut back down. cryptsetup activates LUKS2 with
+ * dm-crypt's iv_large_sectors, so IVs count sector_size sectors: a
+ * 4K sector steps the IV by 1. iv_offset is still in 512 byte
+ * sectors and has to be a whole number of the big ones. */
+#[derive(Debug)]
+pub struct CryptSource {
+    name: String,
+    parent: Arc<dyn BlockSource>,
+    start: u64,
+    len: u64,
+    sector_size: u64,
+    first_iv: u64, // iv_offset in sector_size units
+    cipher: Xts,
+}
+
+impl CryptSource {
+    pub fn new(
+        name: &str,
+        parent: Arc<dyn BlockSource>,
+        start: u64,
+        len: u64,
+        sector_size: u64,
+        iv_offset: u64,
+        cipher: Xts,
+    ) -> PurdResult<CryptSource> {
+        check_bounds(parent.as_ref(), start, len)?;
+        if sector_size == 0 || !sector_size.is_multiple_of(IV_SECTOR)
+        {
+            return Err(PurdError::Unsupported(format!(
+                "{} byte encryption sectors",
+                sector_size
+            )));
+        }
+        let step = sector_size / IV_SECTOR;
+        if !iv_offset.is_multiple_of(step) {
+            return Err(PurdError::Unsupported(format!(
+                "IV offset {} with {} byte sectors",
+                iv_offset, sector_size
+            )));
+        }
+        // iv_tweak comes from the JSON, the last IV has to fit too
+        let first_iv = iv_offset / step;
+        if first_iv.checked_add(len / sector_size).is_none() {
+            return Err(PurdError::out_of_bounds(
+                &format!("{}:iv", name),
+                iv_offset,
+                len,
+            ));
+        }
+        Ok(CryptSource {
+            name: name.to_string(),
+            parent,
+            start,
+            // a partial sector at the end can't be decrypted
+            len: len - len % sector_size,
+            sector_size,
+            first_iv,
+            cipher,
+        })
+    }
+}
+
+impl BlockSource for CryptSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let ss = self.sector_size;
+        let first = offset / ss;
+        let end = (offset + buf.len() as u64).div_ceil(ss);
+        let mut raw = self.parent.read_bytes(
+            self.start + first * ss,
+            (end - first) * ss,
+        )?;
+        for (i, sector) in
+            raw.chunks_exact_mut(ss as usize).enumerate()
+        {
+            // checked against len when it was made
+            let iv = self.first_iv + first + i as u64;
+            self.cipher.decrypt_sector(iv, sector);
+        }
+        let skip = (offset - first * ss) as usize;
+        buf.copy_from_slice(&raw[skip..skip + buf.len()]);
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+    fn cache_stats(&self) -> Option<cache::CacheStats> {
+        self.parent.cache_stats()
+    }
+}
@@ -0,0 +1,606 @@
+use super::error::{PurdError, PurdResult};
+use super::reader::*;
+use super::source::BlockSource;
+use colored::*;
+use serde::{Deserialize, Deserializer};
+use serde_big_array::BigArray;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub mod crypt;
+mod unlock;
+
+/* LUKS, the dm-crypt on-disk format. LUKS1 is one big endian header
+ * in front of the keyslot areas. LUKS2 has two copies of a small
+ * binary header, each followed by JSON describing the segments (the
+ * encrypted data), keyslots, digests and tokens, and checksummed
+ * together. Unlocking a keyslot with a passphrase or keyfile is in
+ * unlock.rs, decrypting the data in crypt.rs.
+ * https://gitlab.com/cryptsetup/cryptsetup/-/wikis/Specification
+ * https://gitlab.com/cryptsetup/LUKS2-docs
+ */
+
+pub const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+pub const LUKS2_SECONDARY_MAGIC: &[u8; 6] = b"SKUL\xba\xbe";
+pub const LUKS_SECTOR: u64 = 512;
+const LUKS1_KEYSLOTS: usize = 8;
+const LUKS1_KEY_ENABLED: u32 = 0x00ac71f3;
+const LUKS2_BINARY_SIZE: u64 = 4096;
+const LUKS2_CSUM_OFFSET: usize = 448;
+const LUKS2_CSUM_SIZE: usize = 64;
+// every size the binary + JSON header can be, which is also where
+// the secondary copy can start
+const LUKS2_HDR_SIZES: [u64; 9] = [
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000,
+    0x200000, 0x400000,
+];
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Luks1Keyslot {
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub active: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub iterations: u32,
+    pub salt: [u8; 32],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub key_material_offset: u32, // sectors
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub stripes: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks1Header {
+    pub magic: [u8; 6],
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub version: u16,
+    pub cipher_name: [u8; 32],
+    pub cipher_mode: [u8; 32],
+    pub hash_spec: [u8; 32],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub payload_offset: u32, // sectors
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub key_bytes: u32,
+    pub mk_digest: [u8; 20],
+    pub mk_digest_salt: [u8; 32],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub mk_digest_iter: u32,
+    #[serde(with = "BigArray")]
+    pub uuid: [u8; 40],
+    pub keyslots: [Luks1Keyslot; LUKS1_KEYSLOTS],
+}
+
+// the part of the 4K LUKS2 binary header before the padding
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2BinaryHeader {
+    pub magic: [u8; 6],
+    #[serde(deserialize_with = "be_u16_deserialize")]
+    pub version: u16,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub hdr_size: u64, // binary header and JSON, in bytes
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub seqid: u64, // bumped on every update, the higher copy wins
+    #[serde(with = "BigArray")]
+    pub label: [u8; 48],
+    pub csum_alg: [u8; 32],
+    #[serde(with = "BigArray")]
+    pub salt: [u8; 64],
+    #[serde(with = "BigArray")]
+    pub uuid: [u8; 40],
+    #[serde(with = "BigArray")]
+    pub subsystem: [u8; 48],
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub hdr_offset: u64, // where this copy is
+    #[serde(with = "BigArray")]
+    pub padding: [u8; 184],
+    #[serde(with = "BigArray")]
+    pub csum: [u8; 64],
+}
+
+fn c_str(bytes: &[u8]) -> String {
+    let end =
+        bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+// the JSON keeps 64 bit numbers as strings so they survive javascript
+fn str_u64<'de, D>(d: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+// a segment's size can also be "dynamic", to the end of the device
+fn str_size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match String::deserialize(d)?.as_str() {
+        "dynamic" => Ok(None),
+        s => s.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Segment {
+    #[serde(rename = "type")]
+    pub segment_type: String,
+    #[serde(deserialize_with = "str_u64")]
+    pub offset: u64, // bytes
+    #[serde(deserialize_with = "str_size")]
+    pub size: Option<u64>,
+    #[serde(default, deserialize_with = "str_u64")]
+    pub iv_tweak: u64,
+    #[serde(default)]
+    pub encryption: String,
+    #[serde(default)]
+    pub sector_size: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Luks2Kdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: String,
+    },
+    Argon2i {
+        time: u32,
+        memory: u32, // KB
+        cpus: u32,
+        salt: String,
+    },
+    Argon2id {
+        time: u32,
+        memory: u32,
+        cpus: u32,
+        salt: String,
+    },
+    // reencryption keyslots don't have one
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Af {
+    #[serde(rename = "type")]
+    pub af_type: String,
+    #[serde(default)]
+    pub stripes: u32,
+    #[serde(default)]
+    pub hash: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Area {
+    #[serde(rename = "type")]
+    pub area_type: String,
+    #[serde(deserialize_with = "str_u64")]
+    pub offset: u64,
+    #[serde(deserialize_with = "str_u64")]
+    pub size: u64,
+    #[serde(default)]
+    pub encryption: String,
+    #[serde(default)]
+    pub key_size: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Keyslot {
+    #[serde(rename = "type")]
+    pub keyslot_type: String,
+    pub key_size: u32,
+    pub area: Luks2Area,
+    pub kdf: Luks2Kdf,
+    pub af: Option<Luks2Af>,
+    pub priority: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Digest {
+    #[serde(rename = "type")]
+    pub digest_type: String,
+    pub keyslots: Vec<String>,
+    pub segments: Vec<String>,
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default)]
+    pub iterations: u32,
+    pub salt: String,
+    pub digest: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Token {
+    #[serde(rename = "type")]
+    pub token_type: String,
+    #[serde(default)]
+    pub keyslots: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Config {
+    #[serde(deserialize_with = "str_u64")]
+    pub json_size: u64,
+    #[serde(deserialize_with = "str_u64")]
+    pub keyslots_size: u64,
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Luks2Metadata {
+    pub keyslots: BTreeMap<String, Luks2Keyslot>,
+    pub tokens: BTreeMap<String, Luks2Token>,
+    pub segments: BTreeMap<String, Luks2Segment>,
+    pub digests: BTreeMap<String, Luks2Digest>,
+    pub config: Luks2Config,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Header {
+    pub binary: Luks2BinaryHeader,
+    pub metadata: Luks2Metadata,
+    // (offset, seqid, checksum ok) of each copy we found
+    pub copies: Vec<(u64, u64, bool)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LuksHeader {
+    Luks1(Box<Luks1Header>),
+    Luks2(Box<Luks2Header>),
+}
+
+fn luks_warning(msg: String) {
+    println!("{}", msg.yellow());
+}
+
+// the JSON sorts keys as strings, "10" would come before "2"
+fn by_number<T>(map: &BTreeMap<String, T>) -> Vec<(&String, &T)> {
+    let mut sorted: Vec<_> = map.iter().collect();
+    sorted.sort_by_key(|(k, _)| k.parse::<u64>().unwrap_or(u64::MAX));
+    sorted
+}
+
+pub fn has_magic(source: &dyn BlockSource) -> PurdResult<bool> {
+    if source.len() < LUKS_SECTOR {
+        return Ok(false);
+    }
+    Ok(&source.read_bytes(0, 6)?[..] == LUKS_MAGIC)
+}
+
+// the hashes LUKS uses for checksums, AF and digests, over parts
+fn hash(alg: &str, parts: &[&[u8]]) -> Option<Vec<u8>> {
+    fn run<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+        let mut d = D::new();
+        parts.iter().for_each(|p| d.update(p));
+        d.finalize().to_vec()
+    }
+    match alg {
+        "sha1" => Some(run::<Sha1>(parts)),
+        "sha256" => Some(run::<Sha256>(parts)),
+        "sha512" => Some(run::<Sha512>(parts)),
+        _ => None,
+    }
+}
+
+/* The LUKS2 checksum is over the whole copy, JSON included, with the
+ * checksum field zeroed. Only the first digest-length bytes of the
+ * field are used. */
+fn luks2_checksum(alg: &str, bytes: &[u8]) -> PurdResult<Vec<u8>> {
+    let mut zeroed = bytes.to_vec();
+    zeroed[LUKS2_CSUM_OFFSET..LUKS2_CSUM_OFFSET + LUKS2_CSUM_SIZE]
+        .fill(0);
+    hash(alg, &[&zeroed]).ok_or_else(|| {
+        PurdError::Unsupported(format!(
+            "LUKS2 checksum algorithm {}",
+            alg
+        ))
+    })
+}
+
+// one LUKS2 header copy, checked, None without the right magic
+fn read_luks2_copy(
+    source: &dyn BlockSource,
+    offset: u64,
+    magic: &[u8; 6],
+) -> PurdResult<Option<(Luks2BinaryHeader, Vec<u8>, bool)>> {
+    if offset + LUKS2_BINARY_SIZE > source.len() {
+        return Ok(None);
+    }
+    let binary = read_header_from_bytevec::<Luks2BinaryHeader>(
+        source.read_bytes(offset, LUKS2_BINARY_SIZE)?,
+    )?;
+    if &binary.magic != magic || binary.version != 2 {
+        return Ok(None);
+    }
+    if !LUKS2_HDR_SIZES.contains(&binary.hdr_size)
+        || offset + binary.hdr_size > source.len()
+    {
+        return Err(PurdError::Unsupported(format!(
+            "LUKS2 header at {} with size {}",
+            offset, binary.hdr_size
+        )));
+    }
+    let bytes = source.read_bytes(offset, binary.hdr_size)?;
+    let csum = luks2_checksum(&c_str(&binary.csum_alg), &bytes)?;
+    let ok = binary.csum[..csum.len()] == csum[..];
+    Ok(Some((binary, bytes, ok)))
+}
+
+impl Luks2Header {
+    /* Read both copies and go with the newest one whose checksum
+     * holds up, like cryptsetup. The secondary is hdr_size in, or
+     * wherever we find it if the primary is too broken to say. */
+    pub fn read(source: &dyn BlockSource) -> PurdResult<Luks2Header> {
+        let primary = read_luks2_copy(source, 0, LUKS_MAGIC)?;
+        let secondary_at = match &primary {
+            Some((binary, _, _)) => vec![binary.hdr_size],
+            None => LUKS2_HDR_SIZES.to_vec(),
+        };
+        let mut secondary = None;
+        for offset in secondary_at {
+            secondary = read_luks2_copy(
+                source,
+                offset,
+                LUKS2_SECONDARY_MAGIC,
+            )?
+            .map(|copy| (offset, copy));
+            if secondary.is_some() {
+                break;
+            }
+        }
+        let mut copies = vec![];
+        let mut best: Option<(Luks2BinaryHeader, Vec<u8>)> = None;
+        for (offset, (binary, bytes, ok)) in
+            primary.map(|copy| (0, copy)).into_iter().chain(secondary)
+        {
+            copies.push((offset, binary.seqid, ok));
+            if !ok {
+                luks_warning(format!(
+                    "LUKS2 header copy at {} has a bad checksum",
+                    offset
+                ));
+                continue;
+            }
+            if best
+                .as_ref()
+                .is_none_or(|(b, _)| b.seqid < binary.seqid)
+            {
+                best = Some((binary, bytes));
+            }
+        }
+        let (binary, bytes) = match best {
+            Some(best) => best,
+            None => {
+                return Err(PurdError::Unsupported(
+                    "LUKS2 header without a good copy".to_string(),
+                ))
+            }
+        };
+        let json = &bytes[LUKS2_BINARY_SIZE as usize..];
+        let end =
+            json.iter().position(|b| *b == 0).unwrap_or(json.len());
+        let metadata =
+            serde_json::from_slice::<Luks2Metadata>(&json[..end])
+                .map_err(|e| {
+                    PurdError::Unsupported(format!(
+                        "LUKS2 metadata: {}",
+                        e
+                    ))
+                })?;
+        Ok(Luks2Header {
+            binary,
+            metadata,
+            copies,
+        })
+    }
+
+    pub fn print(&self) {
+        let b = &self.binary;
+        println!(
+            "LUKS2 {}, label \"{}\", seqid {}, {} byte header, {} checksum",
+            c_str(&b.uuid).bright_blue(),
+            c_str(&b.label),
+            b.seqid,
+            b.hdr_size,
+            c_str(&b.csum_alg)
+        );
+        for (offset, seqid, ok) in self.copies.iter() {
+            println!(
+                "  header copy at {}: seqid {}, checksum {}",
+                offset,
+                seqid,
+                print_bool(*ok)
+            );
+        }
+        let m = &self.metadata;
+        for (id, seg) in by_number(&m.segments) {
+            let size = match seg.size {
+                Some(size) => format!("{} bytes", size),
+                None => "to the end".to_string(),
+            };
+            println!(
+                "  segment {}: {} {}, from {}, {}, {} byte sectors",
+                id,
+                seg.segment_type,
+                seg.encryption,
+                seg.offset,
+                size,
+                seg.sector_size
+            );
+        }
+        for (id, slot) in by_number(&m.keyslots) {
+            let kdf = match &slot.kdf {
+                Luks2Kdf::Pbkdf2 {
+                    hash, iterations, ..
+                } => format!(
+                    "pbkdf2-{}, {} iterations",
+                    hash, iterations
+                ),
+                Luks2Kdf::Argon2i {
+                    time, memory, cpus, ..
+                } => format!(
+                    "argon2i, time {}, {} KB, {} threads",
+                    time, memory, cpus
+                ),
+                Luks2Kdf::Argon2id {
+                    time, memory, cpus, ..
+                } => format!(
+                    "argon2id, time {}, {} KB, {} threads",
+                    time, memory, cpus
+                ),
+                Luks2Kdf::Other => "no kdf".to_string(),
+            };
+            let af = match &slot.af {
+                Some(af) => format!(
+                    ", af {} {} stripes {}",
+                    af.af_type, af.stripes, af.hash
+                ),
+                None => "".to_string(),
+            };
+            println!(
+                "  keyslot {}: {}, {} bit key, {}{}, area {} {}+{} {}",
+                id,
+                slot.keyslot_type,
+                slot.key_size as u64 * 8,
+                kdf,
+                af,
+                slot.area.area_type,
+                slot.area.offset,
+                slot.area.size,
+                slot.area.encryption
+            );
+        }
+        for (id, digest) in by_number(&m.digests) {
+            println!(
+                "  digest {}: {}-{}, {} iterations, keyslots [{}], segments [{}]",
+                id,
+                digest.digest_type,
+                digest.hash,
+                digest.iterations,
+                digest.keyslots.join(", "),
+                digest.segments.join(", ")
+            );
+        }
+        for (id, token) in by_number(&m.tokens) {
+            println!(
+                "  token {}: {}, keyslots [{}]",
+                id,
+                token.token_type,
+                token.keyslots.join(", ")
+            );
+        }
+    }
+}
+
+impl Luks1Header {
+    pub fn print(&self) {
+        println!(
+            "LUKS1 {}, {}-{}, {} bit key, hash {}, payload at sector {}",
+            c_str(&self.uuid).bright_blue(),
+            c_str(&self.cipher_name),
+            c_str(&self.cipher_mode),
+            self.key_bytes as u64 * 8,
+            c_str(&self.hash_spec),
+            self.payload_offset
+        );
+        println!(
+            "  master key digest: {} iterations",
+            self.mk_digest_iter
+        );
+        for (i, slot) in self.keyslots.iter().enumerate() {
+            if slot.active != LUKS1_KEY_ENABLED {
+                continue;
+            }
+            println!(
+                "  keyslot {}: pbkdf2-{}, {} iterations, {} stripes at sector {}",
+                i,
+                c_str(&self.hash_spec),
+                slot.iterations,
+                slot.stripes,
+                slot.key_material_offset
+            );
+        }
+    }
+    pub fn cipher(&self) -> String {
+        format!(
+            "{}-{}",
+            c_str(&self.cipher_name),
+            c_str(&self.cipher_mode)
+        )
+    }
+    pub fn keyslot_enabled(&self, slot: usize) -> bool {
+        self.keyslots
+            .get(slot)
+            .is_some_and(|s| s.active == LUKS1_KEY_ENABLED)
+    }
+}
+
+impl LuksHeader {
+    // None when there's no LUKS magic at the start
+    pub fn probe(
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<LuksHeader>> {
+        if !has_magic(source)? {
+            return Ok(None);
+        }
+        let version = u16::from_be_bytes(
+            source.read_bytes(6, 2)?[..].try_into().unwrap(),
+        );
+        match version {
+            1 => Ok(Some(LuksHeader::Luks1(Box::new(
+                read_header_from_bytevec::<Luks1Header>(
+                    source.read_bytes(0, 592)?,
+                )?,
+            )))),
+            2 => Ok(Some(LuksHeader::Luks2(Box::new(
+                Luks2Header::read(source)?,
+            )))),
+            v => Err(PurdError::Unsupported(format!(
+                "LUKS version {}",
+                v
+            ))),
+        }
+    }
+
+    pub fn uuid(&self) -> String {
+        match self {
+            LuksHeader::Luks1(h) => c_str(&h.uuid),
+            LuksHeader::Luks2(h) => c_str(&h.binary.uuid),
+        }
+    }
+
+    // of the data, in cryptsetup's spelling
+    pub fn cipher(&self) -> String {
+        match self {
+            LuksHeader::Luks1(h) => h.cipher(),
+            LuksHeader::Luks2(h) => h
+                .metadata
+                .segments
+                .values()
+                .next()
+                .map_or(String::new(), |s| s.encryption.clone()),
+        }
+    }
+
+    pub fn print(&self) {
+        match self {
+            LuksHeader::Luks1(h) => h.print(),
+            LuksHeader::Luks2(h) => h.print(),
+        }
+    }
+}
+
+// an encrypted partition or volume and what its header says
+#[derive(Debug, Clone)]
+pub struct LuksDevice {
+    pub name: String,
+    pub header: LuksHeader,
+    pub source: Arc<dyn BlockSource>,
+    pub keyslot: Option<String>, // the one a key opened
+}
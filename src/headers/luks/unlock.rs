@@ -0,0 +1,418 @@
+use super::crypt::{CryptSource, Xts};
+use super::*;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::Hmac;
+
+/* Getting the master key out of a keyslot: run the passphrase through
+ * the slot's KDF, decrypt the key material with what comes out,
+ * AF-merge the stripes back into one key and check it against the
+ * digest. A wrong passphrase just fails the digest check. Keyslot
+ * areas are always 512 byte sectors with IVs from 0. */
+
+// cryptsetup won't make or open anything past these, a header asking
+// for more is junk and argon2 would try to allocate it all
+const ARGON2_MAX_MEMORY_KB: u32 = 4 * 1024 * 1024;
+const ARGON2_MAX_CPUS: u32 = 4;
+const ARGON2_MAX_TIME: u32 = 10_000;
+
+fn b64(s: &str) -> PurdResult<Vec<u8>> {
+    STANDARD.decode(s).map_err(|e| {
+        PurdError::Unsupported(format!("LUKS2 base64: {}", e))
+    })
+}
+
+fn pbkdf2_hash(
+    alg: &str,
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    len: usize,
+) -> PurdResult<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    let done = match alg {
+        "sha1" => pbkdf2::pbkdf2::<Hmac<Sha1>>(
+            password, salt, iterations, &mut out,
+        ),
+        "sha256" => pbkdf2::pbkdf2::<Hmac<Sha256>>(
+            password, salt, iterations, &mut out,
+        ),
+        "sha512" => pbkdf2::pbkdf2::<Hmac<Sha512>>(
+            password, salt, iterations, &mut out,
+        ),
+        _ => {
+            return Err(PurdError::Unsupported(format!(
+                "pbkdf2 with {}",
+                alg
+            )))
+        }
+    };
+    done.map_err(|_| {
+        PurdError::Unsupported(format!("{} byte pbkdf2 output", len))
+    })?;
+    Ok(out)
+}
+
+fn kdf(
+    kdf: &Luks2Kdf,
+    password: &[u8],
+    len: usize,
+) -> PurdResult<Vec<u8>> {
+    let (alg, time, memory, cpus, salt) = match kdf {
+        Luks2Kdf::Pbkdf2 {
+            hash,
+            iterations,
+            salt,
+        } => {
+            return pbkdf2_hash(
+                hash,
+                password,
+                &b64(salt)?,
+                *iterations,
+                len,
+            )
+        }
+        Luks2Kdf::Argon2i {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => (Algorithm::Argon2i, time, memory, cpus, salt),
+        Luks2Kdf::Argon2id {
+            time,
+            memory,
+            cpus,
+            salt,
+        } => (Algorithm::Argon2id, time, memory, cpus, salt),
+        Luks2Kdf::Other => {
+            return Err(PurdError::Unsupported(
+                "LUKS2 keyslot without a KDF".to_string(),
+            ))
+        }
+    };
+    if *memory > ARGON2_MAX_MEMORY_KB
+        || *cpus > ARGON2_MAX_CPUS
+        || *time > ARGON2_MAX_TIME
+    {
+        return Err(PurdError::Unsupported(format!(
+            "argon2 with {} KiB, {} threads and {} passes",
+            memory, cpus, time
+        )));
+    }
+    let argon_err = |e: argon2::Error| {
+        PurdError::Unsupported(format!("argon2: {}", e))
+    };
+    let params = Params::new(*memory, *time, *cpus, Some(len))
+        .map_err(argon_err)?;
+    let mut out = vec![0u8; len];
+    Argon2::new(alg, Version::V0x13, params)
+        .hash_password_into(password, &b64(salt)?, &mut out)
+        .map_err(argon_err)?;
+    Ok(out)
+}
+
+/* AF diffusion, each digest-sized piece gets hashed with its index in
+ * front, the last piece cut short */
+fn diffuse(block: &[u8], alg: &str) -> PurdResult<Vec<u8>> {
+    let unsupported =
+        || PurdError::Unsupported(format!("LUKS AF hash {}", alg));
+    let size = hash(alg, &[]).ok_or_else(unsupported)?.len();
+    let mut out = Vec::with_capacity(block.len());
+    for (i, piece) in block.chunks(size).enumerate() {
+        let h = hash(alg, &[&(i as u32).to_be_bytes(), piece])
+            .ok_or_else(unsupported)?;
+        out.extend_from_slice(&h[..piece.len()]);
+    }
+    Ok(out)
+}
+
+// undo the anti-forensic split: xor and diffuse all but the last stripe
+fn af_merge(
+    material: &[u8],
+    key_size: usize,
+    stripes: usize,
+    alg: &str,
+) -> PurdResult<Vec<u8>> {
+    if stripes == 0 || material.len() < key_size * stripes {
+        return Err(PurdError::Unsupported(format!(
+            "LUKS AF with {} stripes",
+            stripes
+        )));
+    }
+    let mut key = vec![0u8; key_size];
+    for stripe in material.chunks_exact(key_size).take(stripes - 1) {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        key = diffuse(&key, alg)?;
+    }
+    let last =
+        &material[(stripes - 1) * key_size..stripes * key_size];
+    key.iter_mut().zip(last).for_each(|(k, s)| *k ^= s);
+    Ok(key)
+}
+
+// read and decrypt a keyslot's key material, then merge it
+fn keyslot_key(
+    source: &dyn BlockSource,
+    offset: u64,
+    cipher: &str,
+    derived: &[u8],
+    key_size: usize,
+    stripes: usize,
+    af_hash: &str,
+) -> PurdResult<Vec<u8>> {
+    let size = (key_size * stripes).div_ceil(LUKS_SECTOR as usize)
+        * LUKS_SECTOR as usize;
+    let mut material = source.read_bytes(offset, size as u64)?;
+    let xts = Xts::for_cipher(cipher, derived)?;
+    for (i, sector) in
+        material.chunks_exact_mut(LUKS_SECTOR as usize).enumerate()
+    {
+        xts.decrypt_sector(i as u64, sector);
+    }
+    af_merge(&material, key_size, stripes, af_hash)
+}
+
+impl Luks2Header {
+    // the master key digest a keyslot is checked against
+    fn keyslot_digest(&self, id: &str) -> Option<&Luks2Digest> {
+        self.metadata
+            .digests
+            .values()
+            .find(|d| d.keyslots.iter().any(|k| k == id))
+    }
+
+    fn check_digest(
+        digest: &Luks2Digest,
+        key: &[u8],
+    ) -> PurdResult<bool> {
+        if digest.digest_type != "pbkdf2" {
+            return Err(PurdError::Unsupported(format!(
+                "LUKS2 digest {}",
+                digest.digest_type
+            )));
+        }
+        let expected = b64(&digest.digest)?;
+        let found = pbkdf2_hash(
+            &digest.hash,
+            key,
+            &b64(&digest.salt)?,
+            digest.iterations,
+            expected.len(),
+        )?;
+        Ok(found == expected)
+    }
+
+    fn try_keyslot(
+        &self,
+        source: &dyn BlockSource,
+        id: &str,
+        slot: &Luks2Keyslot,
+        password: &[u8],
+    ) -> PurdResult<Option<Vec<u8>>> {
+        let af = match (&slot.af, slot.keyslot_type.as_str()) {
+            (Some(af), "luks2") if af.af_type == "luks1" => af,
+            _ => {
+                return Err(PurdError::Unsupported(format!(
+                    "LUKS2 keyslot type {}",
+                    slot.keyslot_type
+                )))
+            }
+        };
+        let digest = match self.keyslot_digest(id) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        let derived =
+            kdf(&slot.kdf, password, slot.area.key_size as usize)?;
+        let key = keyslot_key(
+            source,
+            slot.area.offset,
+            &slot.area.encryption,
+            &derived,
+            slot.key_size as usize,
+            af.stripes as usize,
+            &af.hash,
+        )?;
+        match Self::check_digest(digest, &key)? {
+            true => Ok(Some(key)),
+            false => Ok(None),
+        }
+    }
+
+    /* priority 2 keyslots get tried first and priority 0 ones not at
+     * all, the way cryptsetup does it */
+    fn unlock(
+        &self,
+        source: &dyn BlockSource,
+        password: &[u8],
+    ) -> Option<(String, Vec<u8>)> {
+        let mut slots = by_number(&self.metadata.keyslots);
+        slots.retain(|(_, s)| s.priority != Some(0));
+        slots.sort_by_key(|(_, s)| s.priority != Some(2));
+        for (id, slot) in slots {
+            match self.try_keyslot(source, id, slot, password) {
+                Ok(Some(key)) => return Some((id.clone(), key)),
+                Ok(None) => {}
+                Err(e) => luks_warning(format!(
+                    "LUKS2 keyslot {}: {}",
+                    id, e
+                )),
+            }
+        }
+        None
+    }
+
+    // the segment the keyslot's digest covers
+    fn open_segment(
+        &self,
+        name: &str,
+        source: Arc<dyn BlockSource>,
+        id: &str,
+        key: &[u8],
+    ) -> PurdResult<CryptSource> {
+        let segment = self
+            .keyslot_digest(id)
+            .and_then(|d| d.segments.first())
+            .and_then(|s| self.metadata.segments.get(s))
+            .ok_or_else(|| {
+                PurdError::Unsupported(format!(
+                    "LUKS2 keyslot {} without a segment",
+                    id
+                ))
+            })?;
+        if segment.segment_type != "crypt" {
+            return Err(PurdError::Unsupported(format!(
+                "LUKS2 segment type {}",
+                segment.segment_type
+            )));
+        }
+        let len = match segment.size {
+            Some(size) => size,
+            None => source.len().saturating_sub(segment.offset),
+        };
+        CryptSource::new(
+            name,
+            source,
+            segment.offset,
+            len,
+            segment.sector_size as u64,
+            segment.iv_tweak,
+            Xts::for_cipher(&segment.encryption, key)?,
+        )
+    }
+}
+
+impl Luks1Header {
+    fn try_keyslot(
+        &self,
+        source: &dyn BlockSource,
+        slot: &Luks1Keyslot,
+        password: &[u8],
+    ) -> PurdResult<Option<Vec<u8>>> {
+        let hash_spec = c_str(&self.hash_spec);
+        let key_size = self.key_bytes as usize;
+        let derived = pbkdf2_hash(
+            &hash_spec,
+            password,
+            &slot.salt,
+            slot.iterations,
+            key_size,
+        )?;
+        let key = keyslot_key(
+            source,
+            slot.key_material_offset as u64 * LUKS_SECTOR,
+            &self.cipher(),
+            &derived,
+            key_size,
+            slot.stripes as usize,
+            &hash_spec,
+        )?;
+        let digest = pbkdf2_hash(
+            &hash_spec,
+            &key,
+            &self.mk_digest_salt,
+            self.mk_digest_iter,
+            self.mk_digest.len(),
+        )?;
+        match digest == self.mk_digest {
+            true => Ok(Some(key)),
+            false => Ok(None),
+        }
+    }
+
+    fn unlock(
+        &self,
+        source: &dyn BlockSource,
+        password: &[u8],
+    ) -> Option<(String, Vec<u8>)> {
+        for (i, slot) in self.keyslots.iter().enumerate() {
+            if !self.keyslot_enabled(i) {
+                continue;
+            }
+            match self.try_keyslot(source, slot, password) {
+                Ok(Some(key)) => return Some((i.to_string(), key)),
+                Ok(None) => {}
+                Err(e) => luks_warning(format!(
+                    "LUKS1 keyslot {}: {}",
+                    i, e
+                )),
+            }
+        }
+        None
+    }
+
+    // the payload runs to the end of the device, IVs from its start
+    fn open_segment(
+        &self,
+        name: &str,
+        source: Arc<dyn BlockSource>,
+        key: &[u8],
+    ) -> PurdResult<CryptSource> {
+        let start = self.payload_offset as u64 * LUKS_SECTOR;
+        let len = source.len().saturating_sub(start);
+        CryptSource::new(
+            name,
+            source,
+            start,
+            len,
+            LUKS_SECTOR,
+            0,
+            Xts::for_cipher(&self.cipher(), key)?,
+        )
+    }
+}
+
+impl LuksDevice {
+    /* Try every key on every keyslot. Gives back the keyslot that
+     * opened and the decrypted data, WrongKey when nothing did. */
+    pub fn open(
+        &self,
+        keys: &[Vec<u8>],
+    ) -> PurdResult<(String, Arc<dyn BlockSource>)> {
+        let name = format!("luks {}", self.name);
+        for key in keys {
+            let source = self.source.clone();
+            let opened = match &self.header {
+                LuksHeader::Luks1(h) => {
+                    h.unlock(&*source, key).map(|(id, mk)| {
+                        (
+                            id.clone(),
+                            h.open_segment(&name, source, &mk),
+                        )
+                    })
+                }
+                LuksHeader::Luks2(h) => {
+                    h.unlock(&*source, key).map(|(id, mk)| {
+                        let data =
+                            h.open_segment(&name, source, &id, &mk);
+                        (id, data)
+                    })
+                }
+            };
+            if let Some((id, data)) = opened {
+                return Ok((id, Arc::new(data?)));
+            }
+        }
+        Err(PurdError::WrongKey(self.name.clone()))
+    }
+}
@@ -0,0 +1,795 @@
+use super::error::{PurdError, PurdResult};
+use super::reader::*;
+use super::source::{
+    BlockSource, ConcatSource, StripedSource, SubSource,
+};
+use colored::*;
+use crc::{Algorithm, Crc};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/* LVM2. A physical volume has a label in one of its first four
+ * sectors pointing at the PV header, which lists the data area (where
+ * extents live) and the metadata areas. A metadata area is a small
+ * header plus a ring buffer holding the volume group's config as
+ * text, every PV in the group carries a copy. Everything is little
+ * endian and counted in 512 byte sectors whatever the disk uses.
+ * https://github.com/lvmteam/lvm2/blob/main/lib/format_text/layout.h
+ * and lib/label/label.h
+ */
+
+pub const LVM_LABEL_ID: &[u8; 8] = b"LABELONE";
+pub const LVM_LABEL_TYPE: &[u8; 8] = b"LVM2 001";
+pub const LVM_MDA_MAGIC: &[u8; 16] = b" LVM2 x[5A%r0N*>";
+pub const LVM_SECTOR: u64 = 512;
+const LVM_LABEL_SCAN_SECTORS: u64 = 4;
+const LVM_MDA_HEADER_SIZE: u64 = 512;
+// the label checksum covers everything after its own field
+const LVM_LABEL_CRC_START: usize = 20;
+const LVM_PV_UUID_LEN: usize = 32;
+const RAW_LOCN_IGNORED: u32 = 1;
+// nobody nests sections this deep, a corrupt area might
+const LVM_MAX_DEPTH: usize = 32;
+
+// lib/misc/crc.c, CRC32 started at 0xf597a6cf with no final xor
+const LVM_CRC: Algorithm<u32> = Algorithm::<u32> {
+    poly: 0x04c11db7,
+    init: 0xf597a6cf_u32.reverse_bits(),
+    refin: true,
+    refout: true,
+    xorout: 0,
+    check: 0,
+    residue: 0,
+};
+
+pub fn lvm_crc(bytes: &[u8]) -> u32 {
+    Crc::<u32>::new(&LVM_CRC).checksum(bytes)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LabelHeader {
+    pub id: [u8; 8], // "LABELONE"
+    pub sector: u64, // where this label is, in sectors
+    pub crc: u32,
+    pub offset: u32, // of the PV header, from the label
+    pub label_type: [u8; 8], // "LVM2 001"
+}
+
+// offset and size in bytes from the start of the PV, a zero offset
+// ends the list
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct DiskLocn {
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MdaHeader {
+    pub checksum: u32,
+    pub magic: [u8; 16],
+    pub version: u32,
+    pub start: u64, // where this header is on the PV
+    pub size: u64,  // of the whole area, header included
+}
+
+// where the current metadata text is, from the start of the area
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RawLocn {
+    pub offset: u64,
+    pub size: u64,
+    pub checksum: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmPhysicalVolume {
+    pub label_sector: u64,
+    pub uuid: String, // 32 characters, no dashes
+    pub device_size: u64,
+    pub data_areas: Vec<DiskLocn>,
+    pub metadata_areas: Vec<DiskLocn>,
+}
+
+fn lvm_error(what: String) -> PurdError {
+    PurdError::Unsupported(format!("LVM: {}", what))
+}
+
+// the metadata writes uuids with dashes, the PV header without
+fn bare_uuid(uuid: &str) -> String {
+    uuid.chars().filter(|c| *c != '-').collect()
+}
+
+fn read_locn_list(
+    bytes: &[u8],
+    at: &mut usize,
+) -> PurdResult<Vec<DiskLocn>> {
+    let mut found = vec![];
+    loop {
+        let end = *at + 16;
+        if end > bytes.len() {
+            return Err(lvm_error(
+                "PV header runs off its sector".to_string(),
+            ));
+        }
+        let locn =
+            read_header_from_bytes::<DiskLocn>(&bytes[*at..end])?;
+        *at = end;
+        if locn.offset == 0 {
+            return Ok(found);
+        }
+        found.push(locn);
+    }
+}
+
+impl LvmPhysicalVolume {
+    /* Look for the label in the first four sectors, None when there
+     * isn't one. A label with a bad checksum is an error, LVM won't
+     * use it either. */
+    pub fn probe(
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<LvmPhysicalVolume>> {
+        for sector in 0..LVM_LABEL_SCAN_SECTORS {
+            let offset = sector * LVM_SECTOR;
+            if offset + LVM_SECTOR > source.len() {
+                break;
+            }
+            let bytes = source.read_bytes(offset, LVM_SECTOR)?;
+            let label =
+                read_header_from_bytes::<LabelHeader>(&bytes)?;
+            if &label.id != LVM_LABEL_ID {
+                continue;
+            }
+            let crc = lvm_crc(&bytes[LVM_LABEL_CRC_START..]);
+            if crc != label.crc {
+                return Err(PurdError::checksum_mismatch(
+                    "LVM label",
+                    label.crc as u64,
+                    crc as u64,
+                ));
+            }
+            if &label.label_type != LVM_LABEL_TYPE {
+                return Err(lvm_error(format!(
+                    "label type {}",
+                    String::from_utf8_lossy(&label.label_type)
+                )));
+            }
+            return Self::from_label(&bytes, &label, sector)
+                .map(Some);
+        }
+        Ok(None)
+    }
+
+    fn from_label(
+        bytes: &[u8],
+        label: &LabelHeader,
+        sector: u64,
+    ) -> PurdResult<LvmPhysicalVolume> {
+        let mut at = label.offset as usize;
+        if at + LVM_PV_UUID_LEN + 8 > bytes.len() {
+            return Err(lvm_error(format!(
+                "PV header at {} is outside the label sector",
+                at
+            )));
+        }
+        let uuid =
+            String::from_utf8_lossy(&bytes[at..at + LVM_PV_UUID_LEN])
+                .to_string();
+        at += LVM_PV_UUID_LEN;
+        let device_size =
+            u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        at += 8;
+        let data_areas = read_locn_list(bytes, &mut at)?;
+        let metadata_areas = read_locn_list(bytes, &mut at)?;
+        Ok(LvmPhysicalVolume {
+            label_sector: sector,
+            uuid,
+            device_size,
+            data_areas,
+            metadata_areas,
+        })
+    }
+
+    /* The metadata text out of the first area that has any, None for
+     * a PV that doesn't keep a copy (pvcreate --metadatacopies 0). */
+    pub fn read_metadata(
+        &self,
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<String>> {
+        for area in self.metadata_areas.iter() {
+            let bytes = source
+                .read_bytes(area.offset, LVM_MDA_HEADER_SIZE)?;
+            let header = read_header_from_bytes::<MdaHeader>(&bytes)?;
+            if &header.magic != LVM_MDA_MAGIC {
+                return Err(lvm_error(format!(
+                    "no metadata area header at {}",
+                    area.offset
+                )));
+            }
+            let crc = lvm_crc(&bytes[4..]);
+            if crc != header.checksum {
+                return Err(PurdError::checksum_mismatch(
+                    "LVM metadata area header",
+                    header.checksum as u64,
+                    crc as u64,
+                ));
+            }
+            // the first raw_locn is right after the header fields
+            let locn =
+                read_header_from_bytes::<RawLocn>(&bytes[40..])?;
+            if locn.size == 0 || locn.flags & RAW_LOCN_IGNORED != 0 {
+                continue;
+            }
+            let text = read_ring(source, area, &header, &locn)?;
+            let crc = lvm_crc(&text);
+            if crc != locn.checksum {
+                return Err(PurdError::checksum_mismatch(
+                    "LVM metadata",
+                    locn.checksum as u64,
+                    crc as u64,
+                ));
+            }
+            let end = text
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(text.len());
+            return Ok(Some(
+                String::from_utf8_lossy(&text[..end]).to_string(),
+            ));
+        }
+        Ok(None)
+    }
+
+    // label and metadata together, the group is None when this PV
+    // doesn't have a copy of it
+    pub fn read_volume_group(
+        &self,
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<LvmVolumeGroup>> {
+        match self.read_metadata(source)? {
+            Some(text) => {
+                let config = parse_metadata(&text)?;
+                LvmVolumeGroup::from_config(&config).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/* The text can wrap, what doesn't fit before the end of the area
+ * carries on right after the header. */
+fn read_ring(
+    source: &dyn BlockSource,
+    area: &DiskLocn,
+    header: &MdaHeader,
+    locn: &RawLocn,
+) -> PurdResult<Vec<u8>> {
+    let ring = header.size.saturating_sub(LVM_MDA_HEADER_SIZE);
+    if locn.offset < LVM_MDA_HEADER_SIZE
+        || locn.offset >= header.size
+        || locn.size > ring
+    {
+        return Err(lvm_error(format!(
+            "metadata at {}+{} doesn't fit its {} byte area",
+            locn.offset, locn.size, header.size
+        )));
+    }
+    let first = locn.size.min(header.size - locn.offset);
+    let start = checked(
+        area.offset.checked_add(locn.offset),
+        "metadata offset",
+    )?;
+    let mut text = source.read_bytes(start, first)?;
+    if first < locn.size {
+        let wrapped = checked(
+            area.offset.checked_add(LVM_MDA_HEADER_SIZE),
+            "metadata area offset",
+        )?;
+        text.extend(source.read_bytes(wrapped, locn.size - first)?);
+    }
+    Ok(text)
+}
+
+/* The metadata is LVM's config format: `name = value` and
+ * `name { ... }`, values being integers, "strings" or [lists] of
+ * either, # to the end of the line is a comment. */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LvmValue {
+    Int(i64),
+    Str(String),
+    List(Vec<LvmValue>),
+    Section(LvmSection),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LvmSection {
+    pub entries: Vec<(String, LvmValue)>,
+}
+
+fn missing(key: &str) -> PurdError {
+    lvm_error(format!("metadata without a usable {}", key))
+}
+
+// sizes in the metadata are only as sane as whoever wrote it
+fn checked(value: Option<u64>, what: &str) -> PurdResult<u64> {
+    value.ok_or_else(|| {
+        lvm_error(format!("{} doesn't fit in 64 bits", what))
+    })
+}
+
+impl LvmSection {
+    pub fn get(&self, key: &str) -> Option<&LvmValue> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+    pub fn int(&self, key: &str) -> PurdResult<u64> {
+        match self.get(key) {
+            Some(LvmValue::Int(i)) if *i >= 0 => Ok(*i as u64),
+            _ => Err(missing(key)),
+        }
+    }
+    pub fn str(&self, key: &str) -> PurdResult<&str> {
+        match self.get(key) {
+            Some(LvmValue::Str(s)) => Ok(s),
+            _ => Err(missing(key)),
+        }
+    }
+    pub fn list(&self, key: &str) -> Option<&[LvmValue]> {
+        match self.get(key) {
+            Some(LvmValue::List(l)) => Some(l),
+            _ => None,
+        }
+    }
+    pub fn section(&self, key: &str) -> Option<&LvmSection> {
+        match self.get(key) {
+            Some(LvmValue::Section(s)) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn sections(
+        &self,
+    ) -> impl Iterator<Item = (&str, &LvmSection)> {
+        self.entries.iter().filter_map(|(k, v)| match v {
+            LvmValue::Section(s) => Some((k.as_str(), s)),
+            _ => None,
+        })
+    }
+}
+
+struct TextCursor<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TextCursor<'a> {
+    fn error(&self, what: &str) -> PurdError {
+        lvm_error(format!("metadata {} at byte {}", what, self.pos))
+    }
+    fn peek(&mut self) -> Option<u8> {
+        loop {
+            while self
+                .text
+                .get(self.pos)
+                .is_some_and(|c| c.is_ascii_whitespace())
+            {
+                self.pos += 1;
+            }
+            if self.text.get(self.pos) != Some(&b'#') {
+                return self.text.get(self.pos).copied();
+            }
+            while self.text.get(self.pos).is_some_and(|c| *c != b'\n')
+            {
+                self.pos += 1;
+            }
+        }
+    }
+    fn name(&mut self) -> PurdResult<String> {
+        self.peek();
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(|c| {
+            c.is_ascii_alphanumeric() || b"_.+-".contains(c)
+        }) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a name"));
+        }
+        Ok(String::from_utf8_lossy(&self.text[start..self.pos])
+            .to_string())
+    }
+    fn string(&mut self) -> PurdResult<String> {
+        let mut out = vec![];
+        self.pos += 1; // opening quote
+        loop {
+            match self.text.get(self.pos) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.text.get(self.pos) {
+                        Some(c) => out.push(*c),
+                        None => {
+                            return Err(
+                                self.error("unterminated string")
+                            )
+                        }
+                    }
+                }
+                Some(c) => out.push(*c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(String::from_utf8_lossy(&out).to_string())
+    }
+    fn int(&mut self) -> PurdResult<i64> {
+        let start = self.pos;
+        if self.text.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit())
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| self.error("bad number"))
+    }
+    // lists only hold plain values, LVM doesn't nest them
+    fn value(&mut self, in_list: bool) -> PurdResult<LvmValue> {
+        match self.peek() {
+            Some(b'"') => Ok(LvmValue::Str(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => {
+                Ok(LvmValue::Int(self.int()?))
+            }
+            Some(b'[') if !in_list => {
+                self.pos += 1;
+                let mut items = vec![];
+                loop {
+                    match self.peek() {
+                        Some(b']') => break,
+                        Some(b',') if !items.is_empty() => {
+                            self.pos += 1
+                        }
+                        _ => items.push(self.value(true)?),
+                    }
+                }
+                self.pos += 1;
+                Ok(LvmValue::List(items))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+    fn section(&mut self, depth: usize) -> PurdResult<LvmSection> {
+        if depth > LVM_MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        let mut section = LvmSection::default();
+        loop {
+            match self.peek() {
+                None if depth == 0 => break,
+                None => return Err(self.error("missing }")),
+                Some(b'}') if depth > 0 => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => {}
+            }
+            let name = self.name()?;
+            let value = match self.peek() {
+                Some(b'{') => {
+                    self.pos += 1;
+                    LvmValue::Section(self.section(depth + 1)?)
+                }
+                Some(b'=') => {
+                    self.pos += 1;
+                    self.value(false)?
+                }
+                _ => return Err(self.error("expected = or {")),
+            };
+            section.entries.push((name, value));
+        }
+        Ok(section)
+    }
+}
+
+pub fn parse_metadata(text: &str) -> PurdResult<LvmSection> {
+    TextCursor {
+        text: text.as_bytes(),
+        pos: 0,
+    }
+    .section(0)
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmPv {
+    pub name: String, // "pv0", what the segments call it
+    pub id: String,
+    pub pe_start: u64, // sectors
+    pub pe_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmStripe {
+    pub pv: String,
+    pub extent: u64, // first extent on that PV
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmSegment {
+    pub start_extent: u64,
+    pub extent_count: u64,
+    pub seg_type: String,
+    pub stripe_size: u64, // sectors, only with more than one stripe
+    pub stripes: Vec<LvmStripe>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmLv {
+    pub name: String,
+    pub id: String,
+    pub visible: bool,
+    pub segments: Vec<LvmSegment>,
+}
+
+impl LvmLv {
+    pub fn extents(&self) -> u64 {
+        self.segments
+            .iter()
+            .fold(0, |n, s| n.saturating_add(s.extent_count))
+    }
+    // striped covers linear too, it's just one stripe
+    pub fn layout(&self) -> String {
+        match self.segments.iter().find(|s| s.seg_type != "striped") {
+            Some(other) => other.seg_type.clone(),
+            None if self
+                .segments
+                .iter()
+                .any(|s| s.stripes.len() > 1) =>
+            {
+                "striped".to_string()
+            }
+            None => "linear".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmVolumeGroup {
+    pub name: String,
+    pub id: String,
+    pub seqno: u64,
+    pub extent_size: u64, // sectors
+    pub pvs: Vec<LvmPv>,
+    pub lvs: Vec<LvmLv>,
+}
+
+fn read_segment(seg: &LvmSection) -> PurdResult<LvmSegment> {
+    let seg_type = seg.str("type")?.to_string();
+    let mut stripes = vec![];
+    // ["pv0", 0, "pv1", 0], only striped has them like this
+    if seg_type == "striped" {
+        let list =
+            seg.list("stripes").ok_or_else(|| missing("stripes"))?;
+        for pair in list.chunks(2) {
+            match pair {
+                [LvmValue::Str(pv), LvmValue::Int(extent)]
+                    if *extent >= 0 =>
+                {
+                    stripes.push(LvmStripe {
+                        pv: pv.clone(),
+                        extent: *extent as u64,
+                    })
+                }
+                _ => return Err(missing("stripes")),
+            }
+        }
+        if stripes.is_empty()
+            || seg.int("stripe_count")? != stripes.len() as u64
+        {
+            return Err(missing("stripe_count"));
+        }
+    }
+    Ok(LvmSegment {
+        start_extent: seg.int("start_extent")?,
+        extent_count: seg.int("extent_count")?,
+        stripe_size: match stripes.len() {
+            0 | 1 => 0,
+            _ => seg.int("stripe_size")?,
+        },
+        seg_type,
+        stripes,
+    })
+}
+
+impl LvmVolumeGroup {
+    /* The config has one section for the group, named after it, and
+     * a few top level values about who wrote it. */
+    pub fn from_config(
+        config: &LvmSection,
+    ) -> PurdResult<LvmVolumeGroup> {
+        let (name, vg) = config
+            .sections()
+            .next()
+            .ok_or_else(|| missing("volume group"))?;
+        let mut pvs = vec![];
+        if let Some(section) = vg.section("physical_volumes") {
+            for (pv_name, pv) in section.sections() {
+                pvs.push(LvmPv {
+                    name: pv_name.to_string(),
+                    id: pv.str("id")?.to_string(),
+                    pe_start: pv.int("pe_start")?,
+                    pe_count: pv.int("pe_count")?,
+                });
+            }
+        }
+        let mut lvs = vec![];
+        if let Some(section) = vg.section("logical_volumes") {
+            for (lv_name, lv) in section.sections() {
+                let visible =
+                    lv.list("status").is_some_and(|flags| {
+                        flags.contains(&LvmValue::Str(
+                            "VISIBLE".into(),
+                        ))
+                    });
+                let mut segments = lv
+                    .sections()
+                    .filter(|(k, _)| k.starts_with("segment"))
+                    .map(|(_, seg)| read_segment(seg))
+                    .collect::<PurdResult<Vec<_>>>()?;
+                segments.sort_by_key(|s| s.start_extent);
+                lvs.push(LvmLv {
+                    name: lv_name.to_string(),
+                    id: lv.str("id")?.to_string(),
+                    visible,
+                    segments,
+                });
+            }
+        }
+        Ok(LvmVolumeGroup {
+            name: name.to_string(),
+            id: vg.str("id")?.to_string(),
+            seqno: vg.int("seqno")?,
+            extent_size: vg.int("extent_size")?,
+            pvs,
+            lvs,
+        })
+    }
+
+    pub fn extent_bytes(&self) -> PurdResult<u64> {
+        checked(
+            self.extent_size.checked_mul(LVM_SECTOR),
+            "extent_size",
+        )
+    }
+
+    pub fn lv_bytes(&self, lv: &LvmLv) -> PurdResult<u64> {
+        checked(
+            lv.extents().checked_mul(self.extent_bytes()?),
+            &lv.name,
+        )
+    }
+
+    /* Put an LV back together out of the PVs we have, given as (PV
+     * header uuid, source). None when part of it is on a PV we don't
+     * have or it isn't plain linear/striped (thin, raid, snapshots,
+     * ...). Segments have to follow each other from extent 0. */
+    pub fn lv_source(
+        &self,
+        lv: &LvmLv,
+        pv_sources: &[(String, Arc<dyn BlockSource>)],
+    ) -> PurdResult<Option<Arc<dyn BlockSource>>> {
+        let extent_bytes = self.extent_bytes()?;
+        let name = format!("{}/{}", self.name, lv.name);
+        let mut pieces: Vec<Arc<dyn BlockSource>> = vec![];
+        let mut next_extent = 0;
+        for seg in lv.segments.iter() {
+            if seg.seg_type != "striped"
+                || seg.start_extent != next_extent
+                || seg.extent_count % seg.stripes.len() as u64 != 0
+            {
+                return Ok(None);
+            }
+            next_extent = checked(
+                next_extent.checked_add(seg.extent_count),
+                "extent_count",
+            )?;
+            let per_stripe =
+                seg.extent_count / seg.stripes.len() as u64;
+            let mut areas: Vec<Arc<dyn BlockSource>> = vec![];
+            for stripe in seg.stripes.iter() {
+                let pv = match self
+                    .pvs
+                    .iter()
+                    .find(|p| p.name == stripe.pv)
+                {
+                    Some(pv) => pv,
+                    None => return Err(missing(&stripe.pv)),
+                };
+                let source = match pv_sources
+                    .iter()
+                    .find(|(uuid, _)| *uuid == bare_uuid(&pv.id))
+                {
+                    Some((_, source)) => source,
+                    None => return Ok(None),
+                };
+                if stripe
+                    .extent
+                    .checked_add(per_stripe)
+                    .is_none_or(|end| end > pv.pe_count)
+                {
+                    return Err(lvm_error(format!(
+                        "{} runs past the end of {}",
+                        name, pv.name
+                    )));
+                }
+                let offset = pv
+                    .pe_start
+                    .checked_mul(LVM_SECTOR)
+                    .zip(stripe.extent.checked_mul(extent_bytes))
+                    .and_then(|(start, at)| start.checked_add(at));
+                areas.push(Arc::new(SubSource::new(
+                    source.clone(),
+                    checked(offset, "pe_start")?,
+                    checked(
+                        per_stripe.checked_mul(extent_bytes),
+                        "extent_count",
+                    )?,
+                )?));
+            }
+            match areas.len() {
+                1 => pieces.push(areas.pop().unwrap()),
+                _ => pieces.push(Arc::new(StripedSource::new(
+                    &name,
+                    areas,
+                    checked(
+                        seg.stripe_size.checked_mul(LVM_SECTOR),
+                        "stripe_size",
+                    )?,
+                )?)),
+            }
+        }
+        match pieces.len() {
+            0 => Ok(None),
+            1 => Ok(pieces.pop()),
+            _ => Ok(Some(Arc::new(ConcatSource::new(&name, pieces)))),
+        }
+    }
+
+    pub fn print(&self) {
+        let extents = match self.extent_bytes() {
+            Ok(bytes) => format!("{} byte extents", bytes),
+            Err(_) => format!("{} sector extents", self.extent_size),
+        };
+        println!(
+            "LVM volume group {} ({}), seqno {}, {}",
+            self.name.bright_blue(),
+            self.id,
+            self.seqno,
+            extents
+        );
+        for pv in self.pvs.iter() {
+            println!(
+                "  {}: {}, {} extents from sector {}",
+                pv.name, pv.id, pv.pe_count, pv.pe_start
+            );
+        }
+        for lv in self.lvs.iter() {
+            let hidden = match lv.visible {
+                true => "".normal(),
+                false => ", hidden".dimmed(),
+            };
+            println!(
+                "  {}: {}, {} extents in {} segments{}",
+                lv.name,
+                lv.layout(),
+                lv.extents(),
+                lv.segments.len(),
+                hidden
+            );
+        }
+    }
+}
@@ -1,11 +1,15 @@
+use super::error::{PurdError, PurdResult};
 use super::reader::*;
+use super::{bsd, vtoc};
 use crate::headers::disx86::disassemble;
+use crate::headers::fs::disk::PartitionType;
 use crate::prettify_output;
 use colored::*;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use serde::Deserialize;
 use serde_big_array::BigArray;
+use std::collections::HashSet;
 use std::fmt;
 use std::str;
 
@@ -23,8 +27,18 @@ impl Mbr {
     pub fn disassemble_bootstrap_sector(&self) {
         disassemble(&self.bootstrap, 16, 0, self.bootstrap.len());
     }
-    pub fn get_partition(&self, index: u64) -> MbrPartitionEntry {
-        return self.partitions[index as usize];
+    pub fn get_partition(
+        &self,
+        index: u64,
+    ) -> PurdResult<MbrPartitionEntry> {
+        match self.partitions.get(index as usize) {
+            Some(entry) => Ok(*entry),
+            None => Err(PurdError::out_of_bounds(
+                "MBR:partitions",
+                index,
+                self.partitions.len() as u64,
+            )),
+        }
     }
     pub fn pretty_print(&self) {
         prettify_output!(Mbr, purple, bright_purple, {
@@ -126,6 +140,215 @@ impl MbrPartitionEntry {
             }
         }
     }
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self.get_partition_type(),
+            PartitionId::Ebr
+                | PartitionId::EbrLba
+                | PartitionId::LinuxExtended
+        )
+    }
+    pub fn is_unused(&self) -> bool {
+        self.partition_type == PartitionId::Empty as u8
+            || self.sectors_in_partition == 0
+    }
+    // best guess from the type byte, the filesystem still gets probed
+    pub fn get_disk_partition_type(&self) -> PartitionType {
+        if self.is_unused() {
+            return PartitionType::Unused;
+        }
+        partition_type_from_id(self.partition_type)
+    }
+}
+
+// LDM volumes remember the type byte they'd have had too
+pub fn partition_type_from_id(id: u8) -> PartitionType {
+    match PartitionId::from_u8(id).unwrap_or(PartitionId::Unknown) {
+        PartitionId::Fat12
+        | PartitionId::Fat16
+        | PartitionId::Fat16b
+        | PartitionId::Fat16Lba
+        | PartitionId::Fat32Chs
+        | PartitionId::Fat32Lba
+        | PartitionId::Fat12Fat16Logical => PartitionType::Fat,
+        // 0x07 is shared, check_ntfs_or_exfat sorts it out
+        PartitionId::NtfsAlsoExfat => PartitionType::Ntfs,
+        PartitionId::LinuxFs => PartitionType::LinuxFsTBD,
+        PartitionId::LinuxSwap => PartitionType::LinuxSwap,
+        PartitionId::LinuxLvm => PartitionType::LinuxLvm,
+        PartitionId::LinuxRaidSuperblock
+        | PartitionId::LinuxExt3Pache => PartitionType::LinuxRaid,
+        PartitionId::WindowsGptSafeMbr => PartitionType::EfiSystem,
+        PartitionId::Ebr
+        | PartitionId::EbrLba
+        | PartitionId::LinuxExtended => PartitionType::Extended,
+        PartitionId::FreeBsd
+        | PartitionId::OpenBsd
+        | PartitionId::NetBsd => PartitionType::BsdSlice,
+        PartitionId::Solaris => PartitionType::SolarisSlice,
+        PartitionId::WindowsLdm => PartitionType::LdmData,
+        _ => PartitionType::Unknown,
+    }
+}
+
+// the kernel stops handing out numbers somewhere around here too
+pub const MAX_LOGICAL_PARTITIONS: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LogicalPartition {
+    pub number: u32,  // same as linux, logicals start at 5
+    pub ebr_lba: u64, // the EBR that described it
+    pub start_lba: u64, // absolute, the entry's is EBR relative
+    pub entry: MbrPartitionEntry,
+}
+
+impl LogicalPartition {
+    pub fn end_lba(&self) -> u64 {
+        self.start_lba + self.entry.sectors_in_partition as u64
+    }
+}
+
+fn ebr_warning(msg: String) {
+    println!("{}", format!("EBR chain: {}", msg).yellow());
+}
+
+/* Follow the EBR chain of an extended partition. Every EBR holds a
+ * logical partition relative to itself and a link to the next EBR
+ * relative to the start of the extended partition. The chain is
+ * whatever someone wrote to the disk, so loops and links leaving the
+ * extended partition end the walk, and logicals that stick out or
+ * land on top of each other are skipped. Either way we keep what we
+ * found and say so rather than failing the whole disk. */
+pub fn read_logical_partitions(
+    reader: &mut OnDisk,
+    extended: &MbrPartitionEntry,
+    sector_size: u64,
+    first_number: u32,
+) -> PurdResult<Vec<LogicalPartition>> {
+    let ext_start = extended.lba_of_partition_start as u64;
+    let ext_end = ext_start + extended.sectors_in_partition as u64;
+    let mut logicals: Vec<LogicalPartition> = vec![];
+    let mut visited = HashSet::new();
+    let mut ebr_lba = ext_start;
+    let mut number = first_number;
+    loop {
+        if !visited.insert(ebr_lba) {
+            ebr_warning(format!("loops back to LBA {}", ebr_lba));
+            break;
+        }
+        if logicals.len() >= MAX_LOGICAL_PARTITIONS
+            || visited.len() > MAX_LOGICAL_PARTITIONS
+        {
+            ebr_warning(format!(
+                "giving up after {} EBRs",
+                visited.len() - 1
+            ));
+            break;
+        }
+        let ebr = reader
+            .read_header_from_offset::<Mbr>(ebr_lba * sector_size)?;
+        if ebr.boot_sector_sig != 0xaa55 {
+            ebr_warning(format!(
+                "no boot signature in the EBR at LBA {}",
+                ebr_lba
+            ));
+            break;
+        }
+        let mut next = None;
+        for entry in ebr.partitions.iter() {
+            if entry.is_unused() {
+                continue;
+            }
+            // only the first link counts
+            if entry.is_extended() {
+                next = next.or(Some(
+                    ext_start + entry.lba_of_partition_start as u64,
+                ));
+                continue;
+            }
+            let logical = LogicalPartition {
+                number,
+                ebr_lba,
+                start_lba: ebr_lba
+                    + entry.lba_of_partition_start as u64,
+                entry: *entry,
+            };
+            if logical.start_lba <= ebr_lba
+                || logical.end_lba() > ext_end
+            {
+                ebr_warning(format!(
+                    "logical partition at LBA {} is outside the \
+                     extended partition ({}..{}), skipping it",
+                    logical.start_lba, ext_start, ext_end
+                ));
+                continue;
+            }
+            if let Some(other) = logicals.iter().find(|l| {
+                logical.start_lba < l.end_lba()
+                    && l.start_lba < logical.end_lba()
+            }) {
+                ebr_warning(format!(
+                    "logical partition at LBA {} overlaps partition \
+                     {}, skipping it",
+                    logical.start_lba, other.number
+                ));
+                continue;
+            }
+            logicals.push(logical);
+            number += 1;
+        }
+        match next {
+            Some(lba) if (ext_start..ext_end).contains(&lba) => {
+                ebr_lba = lba;
+            }
+            Some(lba) => {
+                ebr_warning(format!(
+                    "next EBR at LBA {} is outside the extended \
+                     partition ({}..{})",
+                    lba, ext_start, ext_end
+                ));
+                break;
+            }
+            None => break,
+        }
+    }
+    Ok(logicals)
+}
+
+// a partition inside an MBR partition, from a BSD disklabel or a
+// Solaris VTOC
+#[derive(Debug, Clone)]
+pub struct SlicePartition {
+    pub label: String, // what the label calls it, "a" or "s0"
+    pub start_lba: u64, // absolute
+    pub sectors: u64,
+    pub p_type: PartitionType,
+}
+
+pub fn slice_warning(msg: String) {
+    println!("{}", format!("slice: {}", msg).yellow());
+}
+
+/* BSD and Solaris cut their MBR partition up again with their own
+ * table. None when the type byte doesn't say so or there's no table
+ * in there after all. 0x82 is Solaris as well as Linux swap, the
+ * VTOC's sanity value decides. */
+pub fn read_slice_partitions(
+    reader: &mut OnDisk,
+    entry: &MbrPartitionEntry,
+    sector_size: u64,
+) -> PurdResult<Option<Vec<SlicePartition>>> {
+    match entry.get_partition_type() {
+        PartitionId::FreeBsd
+        | PartitionId::OpenBsd
+        | PartitionId::NetBsd => {
+            bsd::read_bsd_partitions(reader, entry, sector_size)
+        }
+        PartitionId::Solaris | PartitionId::LinuxSwap => {
+            vtoc::read_vtoc_partitions(reader, entry, sector_size)
+        }
+        _ => Ok(None),
+    }
 }
 
 // this one sucks it doesn't have fun colors
@@ -176,16 +399,22 @@ pub enum PartitionId {
     Fat12Fat16Logical = 0x11,
     SomeOemPartition = 0x12,
     WindowsServiceFs = 0x27,
+    WindowsLdm = 0x42,
     LynxRtos = 0x50,
     YocFs = 0x59,
     LinuxSwap = 0x82,
     LinuxFs = 0x83,
     Hibernation = 0x84,
+    LinuxExtended = 0x85,
     LinuxRaidSuperblock = 0x86,
     LinuxLvm = 0x8e,
+    FreeBsd = 0xa5,
+    OpenBsd = 0xa6,
     AppleUfs = 0xa8,
+    NetBsd = 0xa9,
     AppleBoot = 0xAB,
     AppleRaid = 0xAc,
+    Solaris = 0xBF,
     Gpt = 0xEE, /* if this is the type we just ignore all the
                  * other purd in the MBR. */
     WindowsGptSafeMbr = 0xEF,
@@ -0,0 +1,535 @@
+use super::error::{PurdError, PurdResult};
+use super::source::{BlockSource, StripedSource, SubSource};
+use colored::*;
+use std::fmt;
+use std::sync::Arc;
+
+/* Linux MD RAID superblocks. v0.90 sits in the last 64K aligned 64K
+ * of the member and counts everything in u32 words (native endian,
+ * which is little for anything we'll see). v1.x is little endian and
+ * lives at the end (1.0), the start (1.1) or 4K in (1.2), with the
+ * data somewhere after it. Both checksums are the 32 bit words
+ * summed into a u64 and folded, with the checksum itself as 0.
+ * https://github.com/torvalds/linux/blob/master/include/uapi/linux/raid/md_p.h
+ */
+
+pub const MD_MAGIC: u32 = 0xa92b4efc;
+const MD_SECTOR: u64 = 512;
+const MD_V090_RESERVED: u64 = 64 * 1024;
+const MD_V090_SIZE: u64 = 4096;
+const MD_V1_HEADER_SIZE: usize = 256;
+// v1 has a u16 role per device after the header, up to a 4K sector
+const MD_V1_MAX_DEVS: u32 = (4096 - 256) / 2;
+// v0.90 only has room to describe this many
+const MD_SB_DISKS: u32 = 27;
+
+// v0.90 word offsets
+const V090_LEVEL: usize = 7;
+const V090_SIZE: usize = 8; // KB per member
+const V090_RAID_DISKS: usize = 10;
+const V090_UUID0: usize = 5;
+const V090_UUID1: usize = 13;
+const V090_CSUM: usize = 38;
+const V090_EVENTS: usize = 39;
+const V090_LAYOUT: usize = 64;
+const V090_CHUNK: usize = 65; // bytes
+const V090_THIS_DISK: usize = 992;
+const V090_DISK_FAULTY: u32 = 1 << 0;
+const V090_DISK_ACTIVE: u32 = 1 << 1;
+const V090_DISK_SYNC: u32 = 1 << 2;
+const V090_IN_SYNC: u32 = V090_DISK_ACTIVE | V090_DISK_SYNC;
+
+// v1 roles that aren't slots
+const MD_ROLE_SPARE: u16 = 0xffff;
+const MD_ROLE_FAULTY: u16 = 0xfffe;
+const MD_ROLE_JOURNAL: u16 = 0xfffd;
+// a member that's still being rebuilt, only good up to recovery_offset
+const MD_FEATURE_RECOVERY_OFFSET: u32 = 2;
+
+pub const MD_LEVEL_LINEAR: i32 = -1;
+pub const MD_LEVEL_RAID0: i32 = 0;
+pub const MD_LEVEL_RAID1: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdVersion {
+    V090,
+    V10,
+    V11,
+    V12,
+}
+
+impl fmt::Display for MdVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MdVersion::V090 => "0.90",
+            MdVersion::V10 => "1.0",
+            MdVersion::V11 => "1.1",
+            MdVersion::V12 => "1.2",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdRole {
+    Active(u32), // slot in the array
+    Spare,
+    Faulty,
+    Journal,
+}
+
+impl fmt::Display for MdRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MdRole::Active(slot) => write!(f, "active {}", slot),
+            MdRole::Spare => write!(f, "spare"),
+            MdRole::Faulty => write!(f, "faulty"),
+            MdRole::Journal => write!(f, "journal"),
+        }
+    }
+}
+
+pub fn level_name(level: i32) -> String {
+    match level {
+        MD_LEVEL_LINEAR => "linear".to_string(),
+        -4 => "multipath".to_string(),
+        -5 => "faulty".to_string(),
+        l => format!("raid{}", l),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MdSuperblock {
+    pub version: MdVersion,
+    pub offset: u64, // of the superblock on the member
+    pub uuid: [u8; 16],
+    pub name: String, // v1 only
+    pub level: i32,
+    pub layout: u32,
+    pub chunk_size: u64, // bytes
+    pub raid_disks: u32,
+    pub events: u64,
+    pub role: MdRole,
+    pub recovering: bool,
+    pub data_offset: u64, // bytes into the member
+    pub data_size: u64,   // bytes of it in use
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+// md's checksum, skipping the word at csum_at that holds it
+pub fn md_checksum(bytes: &[u8], csum_at: usize) -> u32 {
+    let mut sum: u64 = 0;
+    let mut words = bytes.chunks_exact(4);
+    for (i, word) in words.by_ref().enumerate() {
+        if i * 4 != csum_at {
+            sum +=
+                u32::from_le_bytes(word.try_into().unwrap()) as u64;
+        }
+    }
+    // v1 can end on half a word
+    if let [lo, hi] = words.remainder() {
+        sum += u16::from_le_bytes([*lo, *hi]) as u64;
+    }
+    ((sum & 0xffffffff) + (sum >> 32)) as u32
+}
+
+fn check_sum(
+    version: MdVersion,
+    bytes: &[u8],
+    csum_at: usize,
+) -> PurdResult<()> {
+    let found = md_checksum(bytes, csum_at);
+    let expected = le32(bytes, csum_at);
+    if found != expected {
+        return Err(PurdError::checksum_mismatch(
+            &format!("md {} superblock", version),
+            expected as u64,
+            found as u64,
+        ));
+    }
+    Ok(())
+}
+
+impl MdSuperblock {
+    pub fn uuid_string(&self) -> String {
+        self.uuid
+            .chunks(4)
+            .map(|w| w.iter().map(|b| format!("{:02x}", b)).collect())
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+
+    /* Try every place a superblock can be, None when there isn't
+     * one. A superblock with a bad checksum is an error, the kernel
+     * won't touch those either. */
+    pub fn probe(
+        source: &dyn BlockSource,
+    ) -> PurdResult<Option<MdSuperblock>> {
+        let len = source.len();
+        let sectors = len / MD_SECTOR;
+        let mut v1_at =
+            vec![(MdVersion::V11, 0), (MdVersion::V12, 8)];
+        if sectors >= 16 {
+            v1_at.push((MdVersion::V10, (sectors - 16) & !7));
+        }
+        for (version, sector) in v1_at {
+            let offset = sector * MD_SECTOR;
+            if offset + 4096 > len {
+                continue;
+            }
+            let bytes = source.read_bytes(offset, 4096)?;
+            if le32(&bytes, 0) == MD_MAGIC && le32(&bytes, 4) == 1 {
+                return Self::read_v1(&bytes, version, offset, len)
+                    .map(Some);
+            }
+        }
+        if len >= MD_V090_RESERVED {
+            let offset =
+                (len & !(MD_V090_RESERVED - 1)) - MD_V090_RESERVED;
+            let bytes = source.read_bytes(offset, MD_V090_SIZE)?;
+            if le32(&bytes, 0) == MD_MAGIC && le32(&bytes, 4) == 0 {
+                return Self::read_v090(&bytes, offset).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_v090(
+        bytes: &[u8],
+        offset: u64,
+    ) -> PurdResult<MdSuperblock> {
+        let word = |i: usize| le32(bytes, i * 4);
+        check_sum(MdVersion::V090, bytes, V090_CSUM * 4)?;
+        let mut uuid = [0u8; 16];
+        for (i, w) in
+            [V090_UUID0, V090_UUID1, V090_UUID1 + 1, V090_UUID1 + 2]
+                .iter()
+                .enumerate()
+        {
+            uuid[i * 4..i * 4 + 4]
+                .copy_from_slice(&word(*w).to_be_bytes());
+        }
+        let raid_disks = word(V090_RAID_DISKS);
+        check_raid_disks(raid_disks, MD_SB_DISKS)?;
+        // mdp_disk_t: number, major, minor, raid_disk, state
+        let slot = word(V090_THIS_DISK + 3);
+        let state = word(V090_THIS_DISK + 4);
+        let role = if state & V090_DISK_FAULTY != 0 {
+            MdRole::Faulty
+        } else if state & V090_IN_SYNC == V090_IN_SYNC
+            && slot < raid_disks
+        {
+            MdRole::Active(slot)
+        } else {
+            MdRole::Spare
+        };
+        Ok(MdSuperblock {
+            version: MdVersion::V090,
+            offset,
+            uuid,
+            name: String::new(),
+            level: word(V090_LEVEL) as i32,
+            layout: word(V090_LAYOUT),
+            chunk_size: word(V090_CHUNK) as u64,
+            raid_disks,
+            events: (word(V090_EVENTS + 1) as u64) << 32
+                | word(V090_EVENTS) as u64,
+            role,
+            recovering: false,
+            data_offset: 0,
+            data_size: word(V090_SIZE) as u64 * 1024,
+        })
+    }
+
+    fn read_v1(
+        bytes: &[u8],
+        version: MdVersion,
+        offset: u64,
+        len: u64,
+    ) -> PurdResult<MdSuperblock> {
+        let max_dev = le32(bytes, 220);
+        if max_dev > MD_V1_MAX_DEVS {
+            return Err(PurdError::Unsupported(format!(
+                "md superblock with {} devices",
+                max_dev
+            )));
+        }
+        check_sum(
+            version,
+            &bytes[..MD_V1_HEADER_SIZE + max_dev as usize * 2],
+            216,
+        )?;
+        let dev_number = le32(bytes, 160);
+        let role = match dev_number < max_dev {
+            true => {
+                let at = MD_V1_HEADER_SIZE + dev_number as usize * 2;
+                u16::from_le_bytes([bytes[at], bytes[at + 1]])
+            }
+            false => MD_ROLE_SPARE,
+        };
+        let role = match role {
+            MD_ROLE_SPARE => MdRole::Spare,
+            MD_ROLE_FAULTY => MdRole::Faulty,
+            MD_ROLE_JOURNAL => MdRole::Journal,
+            slot => MdRole::Active(slot as u32),
+        };
+        let name = &bytes[32..64];
+        let name_end =
+            name.iter().position(|b| *b == 0).unwrap_or(32);
+        let raid_disks = le32(bytes, 92);
+        check_raid_disks(raid_disks, MD_V1_MAX_DEVS)?;
+        let data_offset = le64(bytes, 128);
+        // raid0 leaves size at 0, it uses all of data_size
+        let data_size = match le64(bytes, 80) {
+            0 => le64(bytes, 136),
+            size => size,
+        };
+        let data_end = data_offset
+            .checked_add(data_size)
+            .and_then(|end| end.checked_mul(MD_SECTOR));
+        match data_end {
+            Some(end) if end <= len => {}
+            _ => {
+                return Err(PurdError::out_of_bounds(
+                    "md data area",
+                    data_offset
+                        .saturating_add(data_size)
+                        .saturating_mul(MD_SECTOR),
+                    len,
+                ))
+            }
+        }
+        let data_offset = data_offset * MD_SECTOR;
+        let data_size = data_size * MD_SECTOR;
+        Ok(MdSuperblock {
+            version,
+            offset,
+            uuid: bytes[16..32].try_into().unwrap(),
+            name: String::from_utf8_lossy(&name[..name_end])
+                .to_string(),
+            level: le32(bytes, 72) as i32,
+            layout: le32(bytes, 76),
+            chunk_size: le32(bytes, 88) as u64 * MD_SECTOR,
+            raid_disks,
+            events: le64(bytes, 200),
+            role,
+            recovering: le32(bytes, 8) & MD_FEATURE_RECOVERY_OFFSET
+                != 0,
+            data_offset,
+            data_size,
+        })
+    }
+
+    // what the whole array holds, going by this member
+    pub fn array_size(&self) -> u64 {
+        let disks = self.raid_disks as u64;
+        let size = |disks: u64| self.data_size.saturating_mul(disks);
+        match self.level {
+            MD_LEVEL_RAID1 => self.data_size,
+            4 | 5 => size(disks.saturating_sub(1)),
+            6 => size(disks.saturating_sub(2)),
+            10 => size(disks) / 2, // the usual 2 copies
+            _ => size(disks),
+        }
+    }
+
+    pub fn print(&self, member: &str) {
+        let name = match self.name.is_empty() {
+            true => "".to_string(),
+            false => format!(" \"{}\"", self.name),
+        };
+        let recovering = match self.recovering {
+            true => ", recovering".yellow(),
+            false => "".normal(),
+        };
+        println!(
+            "md {} member {}: array {}{}, {} of {} disks, {}{}, events {}",
+            self.version,
+            member.bright_blue(),
+            self.uuid_string(),
+            name,
+            level_name(self.level),
+            self.raid_disks,
+            self.role,
+            recovering,
+            self.events
+        );
+    }
+}
+
+// a member device and what its superblock says
+#[derive(Debug, Clone)]
+pub struct MdMember {
+    pub name: String,
+    pub superblock: MdSuperblock,
+    pub source: Arc<dyn BlockSource>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MdArray {
+    pub uuid: String,
+    pub name: String,
+    pub level: i32,
+    pub raid_disks: u32,
+    pub events: u64,
+    pub members: Vec<MdMember>,
+    // members whose events are behind, left out of the array
+    pub stale: Vec<String>,
+    pub degraded: bool,
+    pub source: Arc<dyn BlockSource>,
+}
+
+// the slots get allocated from this, so garbage can't be believed
+fn check_raid_disks(raid_disks: u32, max: u32) -> PurdResult<()> {
+    if raid_disks > max {
+        return Err(PurdError::Unsupported(format!(
+            "md superblock with {} raid disks",
+            raid_disks
+        )));
+    }
+    Ok(())
+}
+
+fn md_warning(msg: String) {
+    println!("{}", msg.yellow());
+}
+
+impl MdArray {
+    /* Put an array back together out of its members. They all have
+     * to be the same array, and ones with fewer events than the
+     * newest missed writes so they're left out, like mdadm does
+     * without --force. RAID1 needs one good member, RAID0 all of
+     * them. */
+    pub fn assemble(members: Vec<MdMember>) -> PurdResult<MdArray> {
+        let first = match members.first() {
+            Some(m) => m.superblock.clone(),
+            None => {
+                return Err(PurdError::unsupported(
+                    "md array with no members",
+                ))
+            }
+        };
+        if let Some(other) =
+            members.iter().find(|m| m.superblock.uuid != first.uuid)
+        {
+            return Err(PurdError::Unsupported(format!(
+                "md members from different arrays ({} is {}, not {})",
+                other.name,
+                other.superblock.uuid_string(),
+                first.uuid_string()
+            )));
+        }
+        let events = members
+            .iter()
+            .map(|m| m.superblock.events)
+            .max()
+            .unwrap();
+        let (current, behind): (Vec<MdMember>, Vec<MdMember>) =
+            members
+                .into_iter()
+                .partition(|m| m.superblock.events == events);
+        let stale: Vec<String> =
+            behind.into_iter().map(|m| m.name).collect();
+        for name in stale.iter() {
+            md_warning(format!(
+                "md: {} is behind the rest of the array, leaving it out",
+                name
+            ));
+        }
+        // one source per slot, from the members that are in sync
+        let mut slots: Vec<Option<Arc<dyn BlockSource>>> =
+            vec![None; first.raid_disks as usize];
+        for member in current.iter() {
+            let sb = &member.superblock;
+            if let (MdRole::Active(slot), false) =
+                (sb.role, sb.recovering)
+            {
+                if let Some(s) = slots.get_mut(slot as usize) {
+                    *s = Some(Arc::new(SubSource::new(
+                        member.source.clone(),
+                        sb.data_offset,
+                        sb.data_size,
+                    )?));
+                }
+            }
+        }
+        let degraded = slots.iter().any(|s| s.is_none());
+        let name = match first.name.is_empty() {
+            true => format!("md {}", first.uuid_string()),
+            false => format!("md {}", first.name),
+        };
+        let source: Arc<dyn BlockSource> = match first.level {
+            MD_LEVEL_RAID1 => {
+                match slots.into_iter().flatten().next() {
+                    Some(source) => source,
+                    None => {
+                        return Err(PurdError::Unsupported(format!(
+                            "{} without any in sync member",
+                            name
+                        )))
+                    }
+                }
+            }
+            MD_LEVEL_RAID0 => {
+                if let Some(missing) =
+                    slots.iter().position(|s| s.is_none())
+                {
+                    return Err(PurdError::Unsupported(format!(
+                        "{} without member {}, raid0 needs them all",
+                        name, missing
+                    )));
+                }
+                Arc::new(StripedSource::new(
+                    &name,
+                    slots.into_iter().flatten().collect(),
+                    first.chunk_size,
+                )?)
+            }
+            level => {
+                return Err(PurdError::Unsupported(format!(
+                    "assembling md {}",
+                    level_name(level)
+                )))
+            }
+        };
+        Ok(MdArray {
+            uuid: first.uuid_string(),
+            name: first.name.clone(),
+            level: first.level,
+            raid_disks: first.raid_disks,
+            events,
+            members: current,
+            stale,
+            degraded,
+            source,
+        })
+    }
+
+    pub fn print(&self) {
+        let state = match self.degraded {
+            true => ", degraded".yellow(),
+            false => "".normal(),
+        };
+        println!(
+            "md array {} {}: {}, {} of {} members{}, events {}, {} bytes",
+            self.uuid.bright_blue(),
+            self.name,
+            level_name(self.level),
+            self.members.len(),
+            self.raid_disks,
+            state,
+            self.events,
+            self.source.len()
+        );
+        for member in self.members.iter() {
+            println!("  {}: {}", member.name, member.superblock.role);
+        }
+    }
+}
@@ -1,10 +1,20 @@
+pub mod apm;
+pub mod bitlocker;
+pub mod bsd;
 pub mod disx86;
+pub mod error;
 pub mod exfat;
 pub mod ext4;
 pub mod gpt;
+pub mod ldm;
+pub mod luks;
+pub mod lvm;
 pub mod mbr;
+pub mod md;
 pub mod printer;
 pub mod reader;
+pub mod source;
+pub mod vtoc;
 pub mod xfs;
 extern crate serde_big_array;
 mod constants;
@@ -1,29 +1,31 @@
+use super::error::{PurdError, PurdResult};
+use super::source::cache::{CacheConfig, CachedSource};
+use super::source::{self, BlockSource};
 use bincode::deserialize;
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use num_traits::PrimInt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::ErrorKind;
 use std::mem::size_of;
+use std::sync::Arc;
 use uuid::*;
 extern crate chrono;
 extern crate colored;
 use chrono::prelude::*;
 use colored::*;
-use std::io::BufReader;
 
 pub enum Endianness {
     Big,
     Little,
 }
 
-/* container for the bufreader */
+/* container for whatever the disk is being read out of */
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OnDisk {
     pub file: String,
-    pub reader: BufReader<File>,
+    pub source: Arc<dyn BlockSource>,
 }
 
 /* convert lba/fileblock/etc to bytes from an offset */
@@ -35,10 +37,23 @@ pub fn get_offset_from_block_number(
     block_0 + index * block_size
 }
 
-pub fn new(file_arg: &str) -> OnDisk {
+pub fn new(file_arg: &str) -> PurdResult<OnDisk> {
+    new_with_cache(file_arg, CacheConfig::default())
+}
+
+// capacity 0 reads straight through to the image
+pub fn new_with_cache(
+    file_arg: &str,
+    config: CacheConfig,
+) -> PurdResult<OnDisk> {
+    let source = source::open(file_arg)?;
+    Ok(from_source(Arc::new(CachedSource::new(source, config))))
+}
+
+pub fn from_source(source: Arc<dyn BlockSource>) -> OnDisk {
     OnDisk {
-        file: file_arg.to_string(),
-        reader: BufReader::new(File::open(file_arg).unwrap()),
+        file: source.name(),
+        source,
     }
 }
 
@@ -47,16 +62,8 @@ impl OnDisk {
         &mut self,
         offset: u64,
         size: u64,
-    ) -> Vec<u8> {
-        let res =
-            self.reader.seek(SeekFrom::Start(offset as u64)).unwrap();
-        if res != offset {
-            panic!("Failed to seek to offset\n");
-        }
-        let mut file_data: Vec<u8> =
-            vec![0; size.try_into().unwrap()];
-        self.reader.read_exact(&mut file_data[..]).unwrap();
-        file_data
+    ) -> PurdResult<Vec<u8>> {
+        self.source.read_bytes(offset, size)
     }
 
     pub fn read_header_from_offset<
@@ -64,28 +71,54 @@ impl OnDisk {
     >(
         &mut self,
         offset: u64,
-    ) -> Header {
-        let header: Header = {
-            let size = size_of::<Header>() as u64;
-            let file_data = self.read_bytes_from_file(offset, size);
-            // read the bytes into the struct
-            read_header_from_bytevec::<Header>(file_data)
-        };
-        header
+    ) -> PurdResult<Header> {
+        let size = size_of::<Header>() as u64;
+        let file_data = self.read_bytes_from_file(offset, size)?;
+        // read the bytes into the struct
+        read_header_from_bytevec::<Header>(file_data)
+    }
+}
+
+impl BlockSource for OnDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        self.source.read_at(offset, buf)
+    }
+    fn len(&self) -> u64 {
+        self.source.len()
+    }
+    fn name(&self) -> String {
+        self.source.name()
+    }
+    fn sector_size(&self) -> u64 {
+        self.source.sector_size()
     }
 }
 
 pub fn read_header_from_bytevec<Header: Sized + DeserializeOwned>(
     bytes: Vec<u8>,
-) -> Header {
+) -> PurdResult<Header> {
     // read the bytes into the struct
-    deserialize::<Header>(&bytes[..]).unwrap()
+    read_header_from_bytes::<Header>(&bytes[..])
 }
 
 pub fn read_header_from_bytes<Header: Sized + DeserializeOwned>(
     bytes: &[u8],
-) -> Header {
-    deserialize::<Header>(&bytes[..]).unwrap()
+) -> PurdResult<Header> {
+    match deserialize::<Header>(bytes) {
+        Ok(header) => Ok(header),
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(ref io_err)
+                if io_err.kind() == ErrorKind::UnexpectedEof =>
+            {
+                Err(PurdError::out_of_bounds(
+                    std::any::type_name::<Header>(),
+                    0,
+                    bytes.len() as u64,
+                ))
+            }
+            _ => Err(PurdError::from(e)),
+        },
+    }
 }
 
 pub fn le_u128_deserialize<'de, D>(d: D) -> Result<u128, D::Error>
@@ -124,6 +157,15 @@ where
     Ok(data)
 }
 
+pub fn be_u16_deserialize<'de, D>(d: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut data = <u16>::deserialize(d)?;
+    data = u16::from_be(data);
+    Ok(data)
+}
+
 pub fn le_u32_deserialize<'de, D>(d: D) -> Result<u32, D::Error>
 where
     D: Deserializer<'de>,
@@ -223,17 +265,15 @@ pub trait HasHeaderMagic {
     fn magic_field_endianness(&self) -> Endianness;
     fn magic_field_upcast(&self) -> u128;
 
-    // this should check the magic value based on the partition start
-    // for FS main headers or from the header start for headers
-    fn check_magic_field(
+    fn read_magic_field(
         &self,
-        ondisk: &mut OnDisk,
+        ondisk: &dyn BlockSource,
         offset: u64,
-    ) -> bool {
-        let magic_bytes = ondisk.read_bytes_from_file(
+    ) -> PurdResult<u128> {
+        let magic_bytes = ondisk.read_bytes(
             offset + self.magic_field_offset(),
             self.magic_field_size(),
-        );
+        )?;
 
         let found_magic: u128;
         macro_rules! upcast {
@@ -254,7 +294,9 @@ pub trait HasHeaderMagic {
                         found_magic = upcast!($endian, read_u16);
                     }
                     _default => {
-                        panic!("Invalid size for magic field value");
+                        return Err(PurdError::unsupported(
+                            "magic field size other than 2, 4 or 8",
+                        ));
                     }
                 }
             };
@@ -267,11 +309,41 @@ pub trait HasHeaderMagic {
                 match_types!(LittleEndian)
             }
         }
+        Ok(found_magic)
+    }
+
+    // this should check the magic value based on the partition start
+    // for FS main headers or from the header start for headers
+    fn check_magic_field(
+        &self,
+        ondisk: &dyn BlockSource,
+        offset: u64,
+    ) -> PurdResult<bool> {
+        let found_magic = self.read_magic_field(ondisk, offset)?;
         println!(
             "found:{:X} == expected:{:X} ?",
             found_magic,
             self.magic_field_upcast()
         );
-        found_magic == self.magic_field_upcast()
+        Ok(found_magic == self.magic_field_upcast())
+    }
+
+    // same as check_magic_field but a mismatch is an error, for
+    // callers that can't continue without the header.
+    fn require_magic_field(
+        &self,
+        ondisk: &dyn BlockSource,
+        offset: u64,
+    ) -> PurdResult<()> {
+        let found_magic = self.read_magic_field(ondisk, offset)?;
+        if found_magic != self.magic_field_upcast() {
+            return Err(PurdError::bad_magic(
+                std::any::type_name::<Self>(),
+                offset,
+                self.magic_field_upcast(),
+                found_magic,
+            ));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,222 @@
+use super::{check_bounds, BlockSource};
+use crate::headers::error::PurdResult;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/* LRU cache of aligned blocks in front of another source. The
+ * parsers do lots of tiny reads (magic checks, 12 byte extent
+ * headers, one inode at a time) and over slow storage every one of
+ * those was a trip to the disk. A miss right where the previous fill
+ * ended looks sequential and pulls in a few blocks extra. */
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    pub block_size: u64,
+    pub capacity: usize, // in blocks, 0 turns the cache off
+    pub read_ahead: u64, // extra blocks fetched on sequential misses
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            block_size: 4096,
+            capacity: 8192, // 32MB
+            read_ahead: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub read_ahead: u64, // blocks fetched before anyone asked
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.hits + self.misses;
+        let rate = match total {
+            0 => 0.0,
+            _ => self.hits as f64 * 100.0 / total as f64,
+        };
+        write!(
+            f,
+            "{} hits, {} misses ({:.1}% hit rate), {} read ahead, {} \
+             evicted",
+            self.hits, self.misses, rate, self.read_ahead, self.evictions
+        )
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    blocks: HashMap<u64, (Vec<u8>, u64)>, // block -> (data, last use)
+    lru: BTreeMap<u64, u64>,              // last use -> block
+    tick: u64,
+    next_sequential: Option<u64>, // block after the last fill
+}
+
+pub struct CachedSource {
+    inner: Arc<dyn BlockSource>,
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    read_ahead: AtomicU64,
+    evictions: AtomicU64,
+}
+
+// the cached blocks themselves are just noise in debug output
+impl fmt::Debug for CachedSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CachedSource {{ inner: {:?} config: {:?} }}",
+            self.inner, self.config
+        )
+    }
+}
+
+impl CachedSource {
+    pub fn new(
+        inner: Arc<dyn BlockSource>,
+        config: CacheConfig,
+    ) -> CachedSource {
+        CachedSource {
+            inner,
+            config: CacheConfig {
+                block_size: config.block_size.max(1),
+                ..config
+            },
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            read_ahead: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            read_ahead: self.read_ahead.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner.len().div_ceil(self.config.block_size)
+    }
+
+    fn touch(state: &mut CacheState, block: u64) {
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some((_, used)) = state.blocks.get_mut(&block) {
+            state.lru.remove(used);
+            *used = tick;
+            state.lru.insert(tick, block);
+        }
+    }
+
+    fn insert(
+        &self,
+        state: &mut CacheState,
+        block: u64,
+        data: Vec<u8>,
+    ) {
+        while state.blocks.len() >= self.config.capacity {
+            let oldest = match state.lru.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            state.blocks.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        state.tick += 1;
+        state.lru.insert(state.tick, block);
+        state.blocks.insert(block, (data, state.tick));
+    }
+
+    // one read from the inner source for the missed block and, when
+    // it picks up where the last fill stopped, the read ahead window
+    // after it
+    fn fill(
+        &self,
+        state: &mut CacheState,
+        block: u64,
+    ) -> PurdResult<()> {
+        let sequential = state.next_sequential == Some(block);
+        let mut count = 1;
+        if sequential {
+            count += self.config.read_ahead;
+        }
+        // don't read past the end or over blocks we already have
+        count = count.min(self.block_count() - block);
+        count = (1..count)
+            .find(|n| state.blocks.contains_key(&(block + n)))
+            .unwrap_or(count)
+            .min(self.config.capacity as u64);
+
+        let bs = self.config.block_size;
+        let start = block * bs;
+        let end = ((block + count) * bs).min(self.inner.len());
+        let mut data = vec![0u8; (end - start) as usize];
+        self.inner.read_at(start, &mut data)?;
+        for (i, chunk) in data.chunks(bs as usize).enumerate() {
+            self.insert(state, block + i as u64, chunk.to_vec());
+        }
+        state.next_sequential = Some(block + count);
+        self.read_ahead.fetch_add(count - 1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl BlockSource for CachedSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let bs = self.config.block_size;
+        // big reads would just flush everything else out
+        if self.config.capacity == 0
+            || buf.len() as u64 > bs * self.config.capacity as u64 / 4
+        {
+            return self.inner.read_at(offset, buf);
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block = pos / bs;
+            let in_block = (pos % bs) as usize;
+            if state.blocks.contains_key(&block) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                CachedSource::touch(&mut state, block);
+            } else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                self.fill(&mut state, block)?;
+            }
+            let data = &state.blocks[&block].0;
+            let chunk = (data.len() - in_block).min(buf.len() - done);
+            buf[done..done + chunk]
+                .copy_from_slice(&data[in_block..in_block + chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+    fn sector_size(&self) -> u64 {
+        self.inner.sector_size()
+    }
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.stats())
+    }
+}
@@ -0,0 +1,119 @@
+use super::FileSource;
+use crate::headers::error::{PurdError, PurdResult};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/* compressed images can't be seeked into, so they get inflated
+ * once into an unlinked scratch file and read back out of that.
+ * Slower to open but every read after is a plain pread. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+static SCRATCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Option<Compression> {
+        if magic.starts_with(&GZIP_MAGIC) {
+            return Some(Compression::Gzip);
+        }
+        if magic.starts_with(&XZ_MAGIC) {
+            return Some(Compression::Xz);
+        }
+        if magic.starts_with(&ZSTD_MAGIC) {
+            return Some(Compression::Zstd);
+        }
+        None
+    }
+
+    fn decompress<W: Write>(
+        &self,
+        input: File,
+        output: &mut W,
+    ) -> PurdResult<()> {
+        let mut input = BufReader::new(input);
+        match self {
+            Compression::Gzip => {
+                // concatenated members are legal and pigz makes them
+                let mut decoder =
+                    flate2::bufread::MultiGzDecoder::new(input);
+                io::copy(&mut decoder, output)?;
+            }
+            Compression::Xz => {
+                if let Err(e) =
+                    lzma_rs::xz_decompress(&mut input, output)
+                {
+                    return Err(decode_error("xz", e));
+                }
+            }
+            Compression::Zstd => {
+                let mut decoder =
+                    match ruzstd::StreamingDecoder::new(&mut input) {
+                        Ok(decoder) => decoder,
+                        Err(e) => {
+                            return Err(decode_error("zstd", e))
+                        }
+                    };
+                io::copy(&mut decoder, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_error<E: std::fmt::Display>(
+    format: &str,
+    e: E,
+) -> PurdError {
+    PurdError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} stream: {}", format, e),
+    ))
+}
+
+pub fn sniff(path: &str) -> PurdResult<Option<Compression>> {
+    let mut magic = vec![];
+    File::open(path)?.take(8).read_to_end(&mut magic)?;
+    Ok(Compression::detect(&magic))
+}
+
+// the scratch file is removed as soon as it's open, the fd keeps the
+// data alive until the source is dropped.
+fn scratch_file() -> PurdResult<File> {
+    let path = std::env::temp_dir().join(format!(
+        "purd-{}-{}.img",
+        std::process::id(),
+        SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+pub fn open(
+    path: &str,
+    compression: Compression,
+) -> PurdResult<FileSource> {
+    let scratch = scratch_file()?;
+    {
+        let mut writer = BufWriter::new(&scratch);
+        compression.decompress(File::open(path)?, &mut writer)?;
+        writer.flush()?;
+    }
+    FileSource::from_file(
+        &format!("{} ({:?})", path, compression),
+        scratch,
+    )
+}
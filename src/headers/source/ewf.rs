@@ -0,0 +1,577 @@
+use super::{check_bounds, BlockSource, FileSource};
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use crate::headers::summer::{adler32, print_valid_checksum};
+use byteorder::{ByteOrder, LittleEndian};
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use serde_big_array::BigArray;
+use sha1::Sha1;
+use std::io::Read;
+use std::sync::Arc;
+
+/* Expert Witness / EnCase E01 (EWF-E01). Each segment file has a
+ * small file header and then a chain of sections, each starting
+ * with a descriptor that says where the next one is. Media is
+ * stored in chunks listed by table sections, zlib compressed or
+ * raw with an adler32 tacked on. table2 is a backup of table.
+https://github.com/libyal/libewf/blob/main/documentation/Expert%20Witness%20Compression%20Format%20(EWF).asciidoc
+*/
+
+pub const EWF_SIGNATURE: [u8; 8] =
+    [b'E', b'V', b'F', 0x09, 0x0d, 0x0a, 0xff, 0x00];
+const EWF_FILE_HEADER_SIZE: u64 = 13;
+const EWF_SECTION_SIZE: u64 = 76;
+const EWF_TABLE_HEADER_SIZE: u64 = 24;
+const EWF_CHUNK_COMPRESSED: u32 = 1 << 31;
+// E01..E99 then EAA..ZZZ, more than that isn't a real acquisition
+const EWF_MAX_SEGMENTS: u32 = 99 + 26 * 26 * 22;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EwfFileHeader {
+    pub signature: [u8; 8],
+    pub fields_start: u8,
+    pub segment_number: u16,
+    pub fields_end: u16,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EwfSectionDescriptor {
+    pub section_type: [u8; 16],
+    pub next_offset: u64, // from the start of the segment file
+    pub size: u64,        // including this descriptor
+    #[serde(with = "BigArray")]
+    pub padding: [u8; 40],
+    pub checksum: u32, // adler32 of everything above
+}
+
+impl EwfSectionDescriptor {
+    pub fn type_name(&self) -> String {
+        String::from_utf8_lossy(&self.section_type)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+}
+
+// only the start of the volume section matters for reading
+#[derive(Deserialize, Debug, Clone)]
+pub struct EwfVolume {
+    pub media_type: u8,
+    pub unknown1: [u8; 3],
+    pub chunk_count: u32,
+    pub sectors_per_chunk: u32,
+    pub bytes_per_sector: u32,
+    pub sector_count: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EwfTableHeader {
+    pub entry_count: u32,
+    pub padding1: u32,
+    pub base_offset: u64,
+    pub padding2: u32,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EwfChunk {
+    pub segment: usize,
+    pub offset: u64,
+    pub size: u64,
+    pub compressed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct HashCheck {
+    pub name: &'static str,
+    pub stored: Vec<u8>,
+    pub computed: Vec<u8>,
+}
+
+impl HashCheck {
+    pub fn matches(&self) -> bool {
+        self.stored == self.computed
+    }
+    pub fn print(&self) {
+        let hex = |bytes: &[u8]| -> String {
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        };
+        print_valid_checksum(
+            &format!("EWF:{}", self.name),
+            self.matches(),
+        );
+        println!("  stored   {}", hex(&self.stored));
+        println!("  computed {}", hex(&self.computed));
+    }
+}
+
+/* Check an E01 against the hashes stored when it was acquired. It
+ * reads the whole image so it only happens when asked for. */
+pub fn verify_image(path: &str) -> PurdResult<Vec<HashCheck>> {
+    let file: Arc<dyn BlockSource> =
+        Arc::new(FileSource::open(path)?);
+    if !is_ewf(file.as_ref())? {
+        return Err(PurdError::Unsupported(format!(
+            "verifying {}, it isn't an EWF image",
+            path
+        )));
+    }
+    EwfSource::open(path, file)?.verify()
+}
+
+pub fn is_ewf(file: &dyn BlockSource) -> PurdResult<bool> {
+    if file.len() < EWF_FILE_HEADER_SIZE {
+        return Ok(false);
+    }
+    Ok(file.read_bytes(0, 8)? == EWF_SIGNATURE)
+}
+
+// case.E01 -> case.E02 ... case.E99 -> case.EAA ... case.EZZ -> FAA
+pub fn segment_path(first: &str, number: u32) -> Option<String> {
+    if number == 0 || number > EWF_MAX_SEGMENTS || first.len() < 3 {
+        return None;
+    }
+    let (stem, ext) = first.split_at(first.len() - 3);
+    let letter = ext.chars().next()?;
+    let ext = if number <= 99 {
+        format!("{}{:02}", letter, number)
+    } else {
+        let n = number - 100;
+        let (base, a) = if letter.is_ascii_lowercase() {
+            (b'a', b'a')
+        } else {
+            (b'A', b'A')
+        };
+        let first = (letter as u8 - base) as u32 + n / (26 * 26);
+        format!(
+            "{}{}{}",
+            (base + first as u8) as char,
+            (a + ((n / 26) % 26) as u8) as char,
+            (a + (n % 26) as u8) as char
+        )
+    };
+    Some(format!("{}{}", stem, ext))
+}
+
+#[derive(Debug)]
+pub struct EwfSource {
+    name: String,
+    segments: Vec<Arc<dyn BlockSource>>,
+    pub volume: EwfVolume,
+    pub header: Option<String>,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+    chunks: Vec<EwfChunk>,
+}
+
+fn section_data(
+    file: &dyn BlockSource,
+    offset: u64,
+    section: &EwfSectionDescriptor,
+) -> PurdResult<Vec<u8>> {
+    let size = section
+        .size
+        .checked_sub(EWF_SECTION_SIZE)
+        .ok_or_else(|| {
+            PurdError::out_of_bounds(
+                "EWF:section",
+                offset,
+                section.size,
+            )
+        })?;
+    file.read_bytes(offset + EWF_SECTION_SIZE, size)
+}
+
+fn inflate(data: &[u8], limit: u64) -> PurdResult<Vec<u8>> {
+    let mut out = vec![];
+    flate2::read::ZlibDecoder::new(data)
+        .take(limit)
+        .read_to_end(&mut out)?;
+    Ok(out)
+}
+
+// chunk offsets in a table, sizes come from the gap to the next one
+// and the last one runs up to the end of its sectors section
+fn read_table(
+    file: &dyn BlockSource,
+    segment: usize,
+    offset: u64,
+    section: &EwfSectionDescriptor,
+    data_end: u64,
+) -> PurdResult<Vec<EwfChunk>> {
+    let data = section_data(file, offset, section)?;
+    let header = read_header_from_bytes::<EwfTableHeader>(&data)?;
+    let sum = adler32(1, &data[..20]);
+    if sum != header.checksum {
+        return Err(PurdError::checksum_mismatch(
+            "EWF:table",
+            header.checksum as u64,
+            sum as u64,
+        ));
+    }
+    let start = EWF_TABLE_HEADER_SIZE as usize;
+    let end = start + header.entry_count as usize * 4;
+    let entries = data.get(start..end).ok_or_else(|| {
+        PurdError::out_of_bounds(
+            "EWF:table",
+            offset,
+            header.entry_count as u64 * 4,
+        )
+    })?;
+    if let Some(stored) = data.get(end..end + 4) {
+        let sum = adler32(1, entries);
+        if sum != LittleEndian::read_u32(stored) {
+            return Err(PurdError::checksum_mismatch(
+                "EWF:table entries",
+                LittleEndian::read_u32(stored) as u64,
+                sum as u64,
+            ));
+        }
+    }
+    let offsets: Vec<(u64, bool)> = entries
+        .chunks_exact(4)
+        .map(LittleEndian::read_u32)
+        .map(|e| {
+            let relative = (e & !EWF_CHUNK_COMPRESSED) as u64;
+            (
+                header.base_offset + relative,
+                e & EWF_CHUNK_COMPRESSED != 0,
+            )
+        })
+        .collect();
+    let mut chunks = vec![];
+    for (i, (chunk_offset, compressed)) in offsets.iter().enumerate()
+    {
+        let end = match offsets.get(i + 1) {
+            Some((next, _)) => *next,
+            None => data_end,
+        };
+        let size =
+            end.checked_sub(*chunk_offset).ok_or_else(|| {
+                PurdError::out_of_bounds(
+                    "EWF:chunk",
+                    *chunk_offset,
+                    0,
+                )
+            })?;
+        chunks.push(EwfChunk {
+            segment,
+            offset: *chunk_offset,
+            size,
+            compressed: *compressed,
+        });
+    }
+    Ok(chunks)
+}
+
+impl EwfSource {
+    pub fn open(
+        path: &str,
+        file: Arc<dyn BlockSource>,
+    ) -> PurdResult<EwfSource> {
+        let mut ewf = EwfSource {
+            name: path.to_string(),
+            segments: vec![],
+            volume: EwfVolume {
+                media_type: 0,
+                unknown1: [0; 3],
+                chunk_count: 0,
+                sectors_per_chunk: 0,
+                bytes_per_sector: 0,
+                sector_count: 0,
+            },
+            header: None,
+            md5: None,
+            sha1: None,
+            chunks: vec![],
+        };
+        let mut have_volume = false;
+        let mut file = file;
+        let mut number = 1;
+        loop {
+            let file_header = read_header_from_bytes::<EwfFileHeader>(
+                &file.read_bytes(0, EWF_FILE_HEADER_SIZE)?,
+            )?;
+            if file_header.signature != EWF_SIGNATURE {
+                return Err(PurdError::bad_magic(
+                    "EWF:file header",
+                    0,
+                    LittleEndian::read_u64(&EWF_SIGNATURE) as u128,
+                    LittleEndian::read_u64(&file_header.signature)
+                        as u128,
+                ));
+            }
+            if file_header.segment_number as u32 != number {
+                return Err(PurdError::Unsupported(format!(
+                    "EWF segment {} is number {}, expected {} (open \
+                     the first segment)",
+                    file.name(),
+                    file_header.segment_number,
+                    number
+                )));
+            }
+            let segment = ewf.segments.len();
+            ewf.segments.push(file.clone());
+            let more = ewf.walk_segment(
+                file.as_ref(),
+                segment,
+                &mut have_volume,
+            )?;
+            if !more {
+                break;
+            }
+            number += 1;
+            let next =
+                segment_path(path, number).ok_or_else(|| {
+                    PurdError::Unsupported(format!(
+                        "EWF segment {} of {}",
+                        number, path
+                    ))
+                })?;
+            file = Arc::new(FileSource::open(&next)?);
+        }
+
+        if !have_volume {
+            return Err(PurdError::unsupported(
+                "EWF without a volume",
+            ));
+        }
+        let volume = &ewf.volume;
+        if volume.bytes_per_sector == 0
+            || volume.sectors_per_chunk == 0
+        {
+            return Err(PurdError::Unsupported(format!(
+                "EWF {} sectors per chunk of {} bytes",
+                volume.sectors_per_chunk, volume.bytes_per_sector
+            )));
+        }
+        if volume
+            .sector_count
+            .checked_mul(volume.bytes_per_sector as u64)
+            .is_none()
+        {
+            return Err(PurdError::Unsupported(format!(
+                "EWF {} sectors of {} bytes",
+                volume.sector_count, volume.bytes_per_sector
+            )));
+        }
+        let needed = ewf.len().div_ceil(ewf.chunk_size());
+        if (ewf.chunks.len() as u64) < needed {
+            return Err(PurdError::out_of_bounds(
+                "EWF:chunk table",
+                ewf.chunks.len() as u64,
+                needed,
+            ));
+        }
+        Ok(ewf)
+    }
+
+    // returns whether there's another segment after this one
+    fn walk_segment(
+        &mut self,
+        file: &dyn BlockSource,
+        segment: usize,
+        have_volume: &mut bool,
+    ) -> PurdResult<bool> {
+        let mut offset = EWF_FILE_HEADER_SIZE;
+        let mut data_end = 0;
+        let mut table_failed = None;
+        loop {
+            let raw = file.read_bytes(offset, EWF_SECTION_SIZE)?;
+            let section =
+                read_header_from_bytes::<EwfSectionDescriptor>(&raw)?;
+            let sum = adler32(1, &raw[..72]);
+            if sum != section.checksum {
+                return Err(PurdError::checksum_mismatch(
+                    "EWF:section",
+                    section.checksum as u64,
+                    sum as u64,
+                ));
+            }
+            let name = section.type_name();
+            match name.as_str() {
+                "header" if self.header.is_none() => {
+                    let data = section_data(file, offset, &section)?;
+                    let text = inflate(&data, 1 << 20)?;
+                    self.header = Some(
+                        String::from_utf8_lossy(&text).to_string(),
+                    );
+                }
+                "volume" | "disk" => {
+                    let data = section_data(file, offset, &section)?;
+                    self.volume =
+                        read_header_from_bytes::<EwfVolume>(&data)?;
+                    *have_volume = true;
+                }
+                "sectors" => data_end = offset + section.size,
+                "table" => {
+                    // old EnCase has no sectors section, the chunks
+                    // run right up to the table
+                    let end =
+                        if data_end == 0 { offset } else { data_end };
+                    match read_table(
+                        file, segment, offset, &section, end,
+                    ) {
+                        Ok(chunks) => self.chunks.extend(chunks),
+                        Err(e) => table_failed = Some((e, end)),
+                    }
+                }
+                "table2" => {
+                    if let Some((e, end)) = table_failed.take() {
+                        match read_table(
+                            file, segment, offset, &section, end,
+                        ) {
+                            Ok(chunks) => self.chunks.extend(chunks),
+                            Err(_) => return Err(e),
+                        }
+                    }
+                }
+                "hash" => {
+                    let data = section_data(file, offset, &section)?;
+                    self.md5 =
+                        data.get(..16).map(|m| m.try_into().unwrap());
+                }
+                "digest" => {
+                    let data = section_data(file, offset, &section)?;
+                    self.md5 =
+                        data.get(..16).map(|m| m.try_into().unwrap());
+                    self.sha1 = data
+                        .get(16..36)
+                        .map(|s| s.try_into().unwrap());
+                }
+                "next" => return Ok(true),
+                "done" => return Ok(false),
+                _ => (),
+            }
+            // a bad table is only ok if its backup comes right after
+            if name != "table" {
+                if let Some((e, _)) = table_failed.take() {
+                    return Err(e);
+                }
+            }
+            if section.next_offset <= offset {
+                return Err(PurdError::Unsupported(format!(
+                    "EWF section chain loops at 0x{:X} in {}",
+                    offset,
+                    file.name()
+                )));
+            }
+            offset = section.next_offset;
+        }
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.volume.sectors_per_chunk as u64
+            * self.volume.bytes_per_sector as u64
+    }
+
+    // the tables can list more chunks than the media has, those
+    // aren't part of the image
+    pub fn read_chunk(&self, index: u64) -> PurdResult<Vec<u8>> {
+        let start = index
+            .checked_mul(self.chunk_size())
+            .filter(|start| *start < self.len());
+        let (start, chunk) =
+            match (start, self.chunks.get(index as usize)) {
+                (Some(start), Some(chunk)) => (start, chunk),
+                _ => {
+                    return Err(PurdError::out_of_bounds(
+                        "EWF:chunk",
+                        index,
+                        1,
+                    ))
+                }
+            };
+        let file = &self.segments[chunk.segment];
+        let expected = self.chunk_size().min(self.len() - start);
+        let stored = file.read_bytes(chunk.offset, chunk.size)?;
+        if chunk.compressed {
+            let data = inflate(&stored, self.chunk_size())?;
+            if (data.len() as u64) < expected {
+                return Err(PurdError::out_of_bounds(
+                    "EWF:compressed chunk",
+                    chunk.offset,
+                    expected,
+                ));
+            }
+            return Ok(data);
+        }
+        if chunk.size < expected + 4 {
+            return Err(PurdError::out_of_bounds(
+                "EWF:chunk",
+                chunk.offset,
+                expected + 4,
+            ));
+        }
+        let (data, sum) = stored.split_at(expected as usize);
+        let computed = adler32(1, data);
+        if computed != LittleEndian::read_u32(sum) {
+            return Err(PurdError::checksum_mismatch(
+                "EWF:chunk",
+                LittleEndian::read_u32(sum) as u64,
+                computed as u64,
+            ));
+        }
+        Ok(data.to_vec())
+    }
+
+    /* reads the whole image back and hashes it, only as fast as the
+     * chunks can be inflated so it's not done on open. */
+    pub fn verify(&self) -> PurdResult<Vec<HashCheck>> {
+        let mut md5 = Md5::new();
+        let mut sha1 = Sha1::new();
+        let chunks = self.len().div_ceil(self.chunk_size());
+        for index in 0..chunks {
+            let chunk = self.read_chunk(index)?;
+            let end = self
+                .chunk_size()
+                .min(self.len() - index * self.chunk_size());
+            md5.update(&chunk[..end as usize]);
+            sha1.update(&chunk[..end as usize]);
+        }
+        let mut checks = vec![];
+        if let Some(stored) = self.md5 {
+            checks.push(HashCheck {
+                name: "MD5",
+                stored: stored.to_vec(),
+                computed: md5.finalize().to_vec(),
+            });
+        }
+        if let Some(stored) = self.sha1 {
+            checks.push(HashCheck {
+                name: "SHA1",
+                stored: stored.to_vec(),
+                computed: sha1.finalize().to_vec(),
+            });
+        }
+        Ok(checks)
+    }
+}
+
+impl BlockSource for EwfSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let chunk_size = self.chunk_size();
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_chunk = (pos % chunk_size) as usize;
+            let chunk = (chunk_size as usize - in_chunk)
+                .min(buf.len() - done);
+            let data = self.read_chunk(pos / chunk_size)?;
+            buf[done..done + chunk]
+                .copy_from_slice(&data[in_chunk..in_chunk + chunk]);
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.volume.sector_count * self.volume.bytes_per_sector as u64
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        self.volume.bytes_per_sector as u64
+    }
+}
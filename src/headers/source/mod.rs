@@ -0,0 +1,474 @@
+use crate::headers::constants::SMOL_BLOCKS;
+use crate::headers::error::{PurdError, PurdResult};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::Path;
+use std::sync::Arc;
+pub mod cache;
+pub mod compressed;
+pub mod ewf;
+pub mod qcow2;
+pub mod split;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+/* anything purd can read a disk out of. Reads take &self so one
+ * source can be shared between the disk and every partition reader
+ * hanging off of it. */
+pub trait BlockSource: fmt::Debug + Send + Sync {
+    // fill all of buf starting at offset, short reads are errors
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()>;
+    fn len(&self) -> u64;
+    fn name(&self) -> String;
+    fn sector_size(&self) -> u64 {
+        SMOL_BLOCKS
+    }
+
+    // only sources with a cache in them have anything to say
+    fn cache_stats(&self) -> Option<cache::CacheStats> {
+        None
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn read_bytes(
+        &self,
+        offset: u64,
+        size: u64,
+    ) -> PurdResult<Vec<u8>> {
+        check_bounds(self, offset, size)?;
+        let mut bytes = vec![0; size as usize];
+        self.read_at(offset, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/* pick a backend by looking at what's in the file */
+pub fn open(path: &str) -> PurdResult<Arc<dyn BlockSource>> {
+    open_image(path, 0)
+}
+
+// depth counts how far down a backing file chain we are
+pub(crate) fn open_image(
+    path: &str,
+    depth: u32,
+) -> PurdResult<Arc<dyn BlockSource>> {
+    if depth > qcow2::MAX_BACKING_DEPTH {
+        return Err(PurdError::Unsupported(format!(
+            "backing file chain deeper than {} at {}",
+            qcow2::MAX_BACKING_DEPTH,
+            path
+        )));
+    }
+    // split segments are glued together and compressed files inflated
+    // first, whatever's inside gets sniffed like any other file
+    let is_split = split::first_segment(path).is_some();
+    let file: Arc<dyn BlockSource> = if is_split {
+        Arc::new(split::SplitSource::open(path)?)
+    } else if let Some(compression) = compressed::sniff(path)? {
+        Arc::new(compressed::open(path, compression)?)
+    } else {
+        Arc::new(FileSource::open(path)?)
+    };
+    if qcow2::is_qcow2(file.as_ref())? {
+        return Ok(Arc::new(qcow2::Qcow2Source::open(
+            path, file, depth,
+        )?));
+    }
+    if ewf::is_ewf(file.as_ref())? {
+        return Ok(Arc::new(ewf::EwfSource::open(path, file)?));
+    }
+    if vmdk::is_vmdk(file.as_ref())? {
+        return Ok(Arc::new(vmdk::VmdkSource::open(
+            path, file, depth,
+        )?));
+    }
+    if vhdx::is_vhdx(file.as_ref())? {
+        return Ok(Arc::new(vhdx::VhdxSource::open(path, file)?));
+    }
+    if vhd::is_vhd(file.as_ref())? {
+        return Ok(Arc::new(vhd::VhdSource::open(path, file)?));
+    }
+    Ok(file)
+}
+
+// relative paths inside an image (backing files, extents) are
+// relative to the image itself, not wherever purd was run from
+pub(crate) fn relative_to(image: &str, name: &str) -> String {
+    match Path::new(image).parent() {
+        Some(dir) if !Path::new(name).is_absolute() => {
+            dir.join(name).to_string_lossy().to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+pub fn check_bounds<S: BlockSource + ?Sized>(
+    source: &S,
+    offset: u64,
+    size: u64,
+) -> PurdResult<()> {
+    match offset.checked_add(size) {
+        Some(end) if end <= source.len() => Ok(()),
+        _ => Err(PurdError::out_of_bounds(
+            &source.name(),
+            offset,
+            size,
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSource {
+    path: String,
+    file: File,
+    len: u64,
+    sector_size: u64,
+}
+
+impl FileSource {
+    pub fn open(path: &str) -> PurdResult<FileSource> {
+        FileSource::from_file(path, File::open(path)?)
+    }
+    pub fn from_file(
+        name: &str,
+        mut file: File,
+    ) -> PurdResult<FileSource> {
+        // metadata().len() is 0 for block devices, seeking isn't
+        let len = file.seek(SeekFrom::End(0))?;
+        let sector_size = match file.metadata()?.file_type() {
+            t if t.is_block_device() => {
+                block_device_sector_size(&file)?
+            }
+            _ => SMOL_BLOCKS,
+        };
+        Ok(FileSource {
+            path: name.to_string(),
+            file,
+            len,
+            sector_size,
+        })
+    }
+}
+
+// BLKSSZGET, the logical sector size the kernel uses for the device
+#[cfg(target_os = "linux")]
+fn block_device_sector_size(file: &File) -> PurdResult<u64> {
+    use std::os::unix::io::AsRawFd;
+    const BLKSSZGET: libc::c_ulong = 0x1268;
+    let mut sector_size: libc::c_int = 0;
+    let ret = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            BLKSSZGET as _,
+            &mut sector_size,
+        )
+    };
+    if ret < 0 {
+        return Err(PurdError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(sector_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_device_sector_size(_file: &File) -> PurdResult<u64> {
+    Ok(SMOL_BLOCKS)
+}
+
+impl BlockSource for FileSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        match self.file.read_exact_at(buf, offset) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(PurdError::out_of_bounds(
+                    &self.path,
+                    offset,
+                    buf.len() as u64,
+                ))
+            }
+            Err(e) => Err(PurdError::Io(e)),
+        }
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+}
+
+/* the only thing purd writes to, and only when someone hands one
+ * over on purpose. Writes go straight to the file, never through a
+ * cache or an image format. */
+#[derive(Debug)]
+pub struct WritableFile(FileSource);
+
+impl WritableFile {
+    pub fn open(path: &str) -> PurdResult<WritableFile> {
+        let file =
+            OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(WritableFile(FileSource::from_file(path, file)?))
+    }
+    pub fn write_at(
+        &self,
+        offset: u64,
+        buf: &[u8],
+    ) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        self.0.file.write_all_at(buf, offset)?;
+        Ok(())
+    }
+    pub fn sync(&self) -> PurdResult<()> {
+        self.0.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl BlockSource for WritableFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        self.0.read_at(offset, buf)
+    }
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+    fn name(&self) -> String {
+        self.0.name()
+    }
+    fn sector_size(&self) -> u64 {
+        self.0.sector_size()
+    }
+}
+
+pub struct MemorySource {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+impl MemorySource {
+    pub fn new(name: &str, bytes: Vec<u8>) -> MemorySource {
+        MemorySource {
+            name: name.to_string(),
+            bytes,
+        }
+    }
+}
+
+// don't dump a whole disk image into the debug output
+impl fmt::Debug for MemorySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MemorySource {{ name: {} len: 0x{:X} }}",
+            self.name,
+            self.bytes.len()
+        )
+    }
+}
+
+impl BlockSource for MemorySource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let start = offset as usize;
+        buf.copy_from_slice(&self.bytes[start..start + buf.len()]);
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/* a window into another source, offset 0 here is `start` there */
+#[derive(Debug)]
+pub struct SubSource {
+    parent: Arc<dyn BlockSource>,
+    start: u64,
+    len: u64,
+}
+
+impl SubSource {
+    pub fn new(
+        parent: Arc<dyn BlockSource>,
+        start: u64,
+        len: u64,
+    ) -> PurdResult<SubSource> {
+        check_bounds(parent.as_ref(), start, len)?;
+        Ok(SubSource { parent, start, len })
+    }
+}
+
+impl BlockSource for SubSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        self.parent.read_at(self.start + offset, buf)
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        format!(
+            "{}[0x{:X}+0x{:X}]",
+            self.parent.name(),
+            self.start,
+            self.len
+        )
+    }
+    fn sector_size(&self) -> u64 {
+        self.parent.sector_size()
+    }
+    fn cache_stats(&self) -> Option<cache::CacheStats> {
+        self.parent.cache_stats()
+    }
+}
+
+/* sources glued end to end: the segment files of a SplitSource, or
+ * a volume spread over pieces of partitions as a ConcatSource of
+ * SubSources */
+#[derive(Debug)]
+pub struct ConcatSource {
+    name: String,
+    // (start offset in the combined source, piece)
+    pieces: Vec<(u64, Arc<dyn BlockSource>)>,
+    len: u64,
+}
+
+impl ConcatSource {
+    pub fn new(
+        name: &str,
+        pieces: Vec<Arc<dyn BlockSource>>,
+    ) -> ConcatSource {
+        let mut len = 0;
+        let mut starts = vec![];
+        // an empty piece would make the lookup ambiguous
+        for piece in pieces.into_iter().filter(|p| !p.is_empty()) {
+            let piece_len = piece.len();
+            starts.push((len, piece));
+            len += piece_len;
+        }
+        ConcatSource {
+            name: name.to_string(),
+            pieces: starts,
+            len,
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+}
+
+impl BlockSource for ConcatSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let mut done = 0usize;
+        let mut index = self
+            .pieces
+            .partition_point(|(start, _)| *start <= offset)
+            .saturating_sub(1);
+        while done < buf.len() {
+            let (start, piece) = &self.pieces[index];
+            let pos = offset + done as u64 - start;
+            let chunk =
+                ((piece.len() - pos) as usize).min(buf.len() - done);
+            piece.read_at(pos, &mut buf[done..done + chunk])?;
+            done += chunk;
+            index += 1;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        match self.pieces.first() {
+            Some((_, piece)) => piece.sector_size(),
+            None => SMOL_BLOCKS,
+        }
+    }
+    fn cache_stats(&self) -> Option<cache::CacheStats> {
+        self.pieces.first().and_then(|(_, p)| p.cache_stats())
+    }
+}
+
+/* RAID0 style striping, chunk i of the whole thing is chunk i / n of
+ * stripe i % n. Anything past the last full chunk of the shortest
+ * stripe isn't reachable. */
+#[derive(Debug)]
+pub struct StripedSource {
+    name: String,
+    stripes: Vec<Arc<dyn BlockSource>>,
+    chunk: u64,
+    len: u64,
+}
+
+impl StripedSource {
+    pub fn new(
+        name: &str,
+        stripes: Vec<Arc<dyn BlockSource>>,
+        chunk: u64,
+    ) -> PurdResult<StripedSource> {
+        if chunk == 0 || stripes.is_empty() {
+            return Err(PurdError::Unsupported(format!(
+                "striping {} over {} stripes of {} byte chunks",
+                name,
+                stripes.len(),
+                chunk
+            )));
+        }
+        let shortest = stripes.iter().map(|s| s.len()).min().unwrap();
+        let len =
+            (shortest - shortest % chunk) * stripes.len() as u64;
+        Ok(StripedSource {
+            name: name.to_string(),
+            stripes,
+            chunk,
+            len,
+        })
+    }
+}
+
+impl BlockSource for StripedSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let n = self.stripes.len() as u64;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let (chunk, within) = (at / self.chunk, at % self.chunk);
+            let stripe = &self.stripes[(chunk % n) as usize];
+            let size = ((self.chunk - within) as usize)
+                .min(buf.len() - done);
+            stripe.read_at(
+                (chunk / n) * self.chunk + within,
+                &mut buf[done..done + size],
+            )?;
+            done += size;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        self.stripes[0].sector_size()
+    }
+    fn cache_stats(&self) -> Option<cache::CacheStats> {
+        self.stripes[0].cache_stats()
+    }
+}
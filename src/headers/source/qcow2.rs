@@ -0,0 +1,399 @@
+use super::{check_bounds, BlockSource};
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use byteorder::{BigEndian, ByteOrder};
+use serde::Deserialize;
+use std::io::Read;
+use std::sync::Arc;
+
+/* qemu's qcow2, all big endian.
+https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+*/
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Qcow2Header {
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub magic: u32, // "QFI\xfb"
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub version: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub backing_file_offset: u64,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub backing_file_size: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub cluster_bits: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub size: u64, // virtual disk size in bytes
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub crypt_method: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub l1_size: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub l1_table_offset: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub refcount_table_offset: u64,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub refcount_table_clusters: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub nb_snapshots: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub snapshots_offset: u64,
+}
+
+// only present when version >= 3
+#[derive(Deserialize, Debug, Clone)]
+pub struct Qcow2HeaderV3 {
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub incompatible_features: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub compatible_features: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub autoclear_features: u64,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub refcount_order: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub header_length: u32,
+}
+
+pub mod incompat_bitflags {
+    pub const DIRTY: u64 = 1 << 0;
+    pub const CORRUPT: u64 = 1 << 1;
+    pub const EXTERNAL_DATA_FILE: u64 = 1 << 2;
+    pub const COMPRESSION_TYPE: u64 = 1 << 3;
+    pub const EXTENDED_L2: u64 = 1 << 4;
+}
+
+const QCOW2_V2_HEADER_SIZE: u64 = 72;
+const L1E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2E_COMPRESSED: u64 = 1 << 62;
+const L2E_ZERO: u64 = 1;
+const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// backing chains that point back at themselves would never end
+pub const MAX_BACKING_DEPTH: u32 = 16;
+
+impl HasHeaderMagic for Qcow2Header {
+    fn magic_field_offset(&self) -> u64 {
+        0
+    }
+    fn magic_field_size(&self) -> u64 {
+        4
+    }
+    fn magic_field_endianness(&self) -> Endianness {
+        Endianness::Big
+    }
+    // QFI\xfb
+    fn magic_field_upcast(&self) -> u128 {
+        0x514649fb
+    }
+}
+
+pub fn is_qcow2(file: &dyn BlockSource) -> PurdResult<bool> {
+    if file.len() < QCOW2_V2_HEADER_SIZE {
+        return Ok(false);
+    }
+    Ok(file.read_bytes(0, 4)? == b"QFI\xfb")
+}
+
+/* where a guest cluster lives */
+enum Cluster {
+    Unallocated,
+    Zero,
+    Data(u64),
+    Compressed { offset: u64, size: u64 },
+}
+
+#[derive(Debug)]
+pub struct Qcow2Source {
+    name: String,
+    file: Arc<dyn BlockSource>,
+    pub header: Qcow2Header,
+    pub v3: Option<Qcow2HeaderV3>,
+    pub backing_file: Option<String>,
+    backing: Option<Arc<dyn BlockSource>>,
+    l1: Vec<u64>,
+    refcount_table: Vec<u64>,
+}
+
+impl Qcow2Source {
+    pub fn open(
+        path: &str,
+        file: Arc<dyn BlockSource>,
+        depth: u32,
+    ) -> PurdResult<Qcow2Source> {
+        let mut ondisk = from_source(file.clone());
+        let header =
+            ondisk.read_header_from_offset::<Qcow2Header>(0)?;
+        header.require_magic_field(&ondisk, 0)?;
+        if header.version != 2 && header.version != 3 {
+            return Err(PurdError::Unsupported(format!(
+                "qcow2 version {}",
+                header.version
+            )));
+        }
+        // spec allows 9..=21
+        if !(9..=21).contains(&header.cluster_bits) {
+            return Err(PurdError::Unsupported(format!(
+                "qcow2 cluster_bits {}",
+                header.cluster_bits
+            )));
+        }
+        if header.crypt_method != 0 {
+            return Err(PurdError::unsupported(
+                "encrypted qcow2 images",
+            ));
+        }
+        let mut v3 = None;
+        if header.version == 3 {
+            let ext = ondisk
+                .read_header_from_offset::<Qcow2HeaderV3>(
+                    QCOW2_V2_HEADER_SIZE,
+                )?;
+            let unsupported = ext.incompatible_features
+                & !(incompat_bitflags::DIRTY
+                    | incompat_bitflags::CORRUPT);
+            if unsupported != 0 {
+                return Err(PurdError::Unsupported(format!(
+                    "qcow2 incompatible features 0x{:X}",
+                    unsupported
+                )));
+            }
+            if ext.refcount_order > 6 {
+                return Err(PurdError::Unsupported(format!(
+                    "qcow2 refcount_order {}",
+                    ext.refcount_order
+                )));
+            }
+            v3 = Some(ext);
+        }
+
+        let l1 = read_be_u64_table(
+            file.as_ref(),
+            header.l1_table_offset,
+            header.l1_size as u64,
+        )?;
+        let cluster_size = 1u64 << header.cluster_bits;
+        let refcount_table = read_be_u64_table(
+            file.as_ref(),
+            header.refcount_table_offset,
+            header.refcount_table_clusters as u64 * cluster_size / 8,
+        )?;
+
+        let mut backing_file = None;
+        let mut backing = None;
+        if header.backing_file_offset != 0
+            && header.backing_file_size != 0
+        {
+            let raw_name = file.read_bytes(
+                header.backing_file_offset,
+                header.backing_file_size as u64,
+            )?;
+            let backing_name =
+                String::from_utf8_lossy(&raw_name).to_string();
+            let backing_path =
+                super::relative_to(path, &backing_name);
+            backing =
+                Some(super::open_image(&backing_path, depth + 1)?);
+            backing_file = Some(backing_name);
+        }
+
+        Ok(Qcow2Source {
+            name: path.to_string(),
+            file,
+            header,
+            v3,
+            backing_file,
+            backing,
+            l1,
+            refcount_table,
+        })
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.header.cluster_bits
+    }
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+    pub fn refcount_bits(&self) -> u64 {
+        match &self.v3 {
+            Some(v3) => 1 << v3.refcount_order,
+            None => 16,
+        }
+    }
+
+    fn lookup(&self, guest_offset: u64) -> PurdResult<Cluster> {
+        let cluster = guest_offset >> self.header.cluster_bits;
+        let l1_index = cluster / self.l2_entries();
+        let l2_index = cluster % self.l2_entries();
+        let l1_entry = match self.l1.get(l1_index as usize) {
+            Some(entry) => *entry,
+            None => return Ok(Cluster::Unallocated),
+        };
+        let l2_table = l1_entry & L1E_OFFSET_MASK;
+        if l2_table == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        let l2_entry = BigEndian::read_u64(
+            &self.file.read_bytes(l2_table + l2_index * 8, 8)?,
+        );
+        if l2_entry & L2E_COMPRESSED != 0 {
+            // offset and sector count share the low 62 bits, the
+            // split depends on the cluster size
+            let x = 62 - (self.header.cluster_bits as u64 - 8);
+            let offset = l2_entry & ((1 << x) - 1);
+            let sectors = ((l2_entry >> x)
+                & ((1 << (self.header.cluster_bits - 8)) - 1))
+                + 1;
+            let size = sectors * 512 - (offset & 511);
+            return Ok(Cluster::Compressed { offset, size });
+        }
+        if self.v3.is_some() && l2_entry & L2E_ZERO != 0 {
+            return Ok(Cluster::Zero);
+        }
+        match l2_entry & L2E_OFFSET_MASK {
+            0 => Ok(Cluster::Unallocated),
+            host => Ok(Cluster::Data(host)),
+        }
+    }
+
+    fn read_compressed(
+        &self,
+        offset: u64,
+        size: u64,
+    ) -> PurdResult<Vec<u8>> {
+        // the last compressed cluster can claim sectors past eof
+        let size = size.min(self.file.len().saturating_sub(offset));
+        let deflated = self.file.read_bytes(offset, size)?;
+        let mut cluster = vec![0u8; self.cluster_size() as usize];
+        let mut decoder =
+            flate2::read::DeflateDecoder::new(&deflated[..]);
+        decoder.read_exact(&mut cluster)?;
+        Ok(cluster)
+    }
+
+    fn read_backing(
+        &self,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> PurdResult<()> {
+        buf.fill(0);
+        if let Some(backing) = &self.backing {
+            // a backing file smaller than us reads as zeros past its end
+            if offset < backing.len() {
+                let avail = (backing.len() - offset)
+                    .min(buf.len() as u64)
+                    as usize;
+                backing.read_at(offset, &mut buf[..avail])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn refcount(&self, host_offset: u64) -> PurdResult<u64> {
+        let bits = self.refcount_bits();
+        let per_block = self.cluster_size() * 8 / bits;
+        let cluster = host_offset >> self.header.cluster_bits;
+        let block = match self
+            .refcount_table
+            .get((cluster / per_block) as usize)
+        {
+            Some(entry) => entry & REFT_OFFSET_MASK,
+            None => return Ok(0),
+        };
+        if block == 0 {
+            return Ok(0);
+        }
+        let index = cluster % per_block;
+        if bits < 8 {
+            let per_byte = 8 / bits;
+            let byte =
+                self.file.read_bytes(block + index / per_byte, 1)?[0];
+            let shift = (index % per_byte) * bits;
+            return Ok(((byte >> shift) as u64) & ((1 << bits) - 1));
+        }
+        let width = bits / 8;
+        let raw =
+            self.file.read_bytes(block + index * width, width)?;
+        Ok(BigEndian::read_uint(&raw, width as usize))
+    }
+
+    // every cluster the mapping tables point at should be counted,
+    // returns the host offsets of the ones that aren't.
+    pub fn validate_refcounts(&self) -> PurdResult<Vec<u64>> {
+        let mut unreferenced = vec![];
+        for l1_entry in self.l1.iter() {
+            let l2_table = l1_entry & L1E_OFFSET_MASK;
+            if l2_table == 0 {
+                continue;
+            }
+            if self.refcount(l2_table)? == 0 {
+                unreferenced.push(l2_table);
+            }
+            let l2 = read_be_u64_table(
+                self.file.as_ref(),
+                l2_table,
+                self.l2_entries(),
+            )?;
+            for l2_entry in l2 {
+                if l2_entry & L2E_COMPRESSED != 0 {
+                    continue;
+                }
+                let host = l2_entry & L2E_OFFSET_MASK;
+                if host != 0 && self.refcount(host)? == 0 {
+                    unreferenced.push(host);
+                }
+            }
+        }
+        Ok(unreferenced)
+    }
+}
+
+fn read_be_u64_table(
+    file: &dyn BlockSource,
+    offset: u64,
+    entries: u64,
+) -> PurdResult<Vec<u64>> {
+    let bytes = file.read_bytes(offset, entries * 8)?;
+    Ok(bytes.chunks_exact(8).map(BigEndian::read_u64).collect())
+}
+
+impl BlockSource for Qcow2Source {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let cluster_size = self.cluster_size();
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos % cluster_size;
+            let chunk = ((cluster_size - in_cluster) as usize)
+                .min(buf.len() - done);
+            let out = &mut buf[done..done + chunk];
+            match self.lookup(pos)? {
+                Cluster::Unallocated => {
+                    self.read_backing(pos, out)?
+                }
+                Cluster::Zero => out.fill(0),
+                Cluster::Data(host) => {
+                    self.file.read_at(host + in_cluster, out)?
+                }
+                Cluster::Compressed { offset, size } => {
+                    let cluster =
+                        self.read_compressed(offset, size)?;
+                    let start = in_cluster as usize;
+                    out.copy_from_slice(
+                        &cluster[start..start + chunk],
+                    );
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.header.size
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
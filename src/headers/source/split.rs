@@ -0,0 +1,79 @@
+use super::{BlockSource, ConcatSource, FileSource};
+use crate::headers::error::{PurdError, PurdResult};
+use std::path::Path;
+use std::sync::Arc;
+
+/* raw images chopped up by split/FTK/dd: disk.img.001, .002 ...
+ * Segments can be any size, they're glued together in order until
+ * the next number doesn't exist. */
+
+// disk.img.001 -> disk.img.002, keeps the zero padding
+pub fn segment_path(path: &str, number: u32) -> Option<String> {
+    let (stem, ext) = path.rsplit_once('.')?;
+    if ext.is_empty() || !ext.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let next = format!("{:0width$}", number, width = ext.len());
+    if next.len() > ext.len() {
+        return None;
+    }
+    Some(format!("{}.{}", stem, next))
+}
+
+// only the first segment (.000 or .001) starts a split image
+pub fn first_segment(path: &str) -> Option<u32> {
+    let ext = Path::new(path).extension()?.to_str()?;
+    if ext.len() < 3 || !ext.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match ext.parse::<u32>().ok()? {
+        first @ (0 | 1) => Some(first),
+        _ => None,
+    }
+}
+
+// the segment files end to end, the gluing is all ConcatSource
+#[derive(Debug)]
+pub struct SplitSource {
+    inner: ConcatSource,
+}
+
+impl SplitSource {
+    pub fn open(path: &str) -> PurdResult<SplitSource> {
+        let first = first_segment(path).ok_or_else(|| {
+            PurdError::Unsupported(format!(
+                "{} isn't the first segment of a split image",
+                path
+            ))
+        })?;
+        let mut segments: Vec<Arc<dyn BlockSource>> = vec![];
+        let mut number = first;
+        while let Some(segment_path) = segment_path(path, number) {
+            if number != first && !Path::new(&segment_path).exists() {
+                break;
+            }
+            segments.push(Arc::new(FileSource::open(&segment_path)?));
+            number += 1;
+        }
+        Ok(SplitSource {
+            inner: ConcatSource::new(path, segments),
+        })
+    }
+
+    // empty segments don't count, nothing can be read from them
+    pub fn segment_count(&self) -> usize {
+        self.inner.piece_count()
+    }
+}
+
+impl BlockSource for SplitSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        self.inner.read_at(offset, buf)
+    }
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
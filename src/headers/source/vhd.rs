@@ -0,0 +1,302 @@
+use super::{check_bounds, BlockSource};
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use byteorder::{BigEndian, ByteOrder};
+use serde::Deserialize;
+use serde_big_array::BigArray;
+use std::sync::Arc;
+
+/* Virtual PC / Hyper-V gen1 VHD, big endian. Fixed disks are raw
+ * data with a footer stuck on the end, dynamic disks add a header
+ * and a block allocation table (BAT) in front of the blocks.
+https://www.microsoft.com/en-us/download/details.aspx?id=23850
+*/
+
+pub const VHD_FOOTER_SIZE: u64 = 512;
+const VHD_FOOTER_CHECKSUM_OFFSET: usize = 64;
+const VHD_DYNAMIC_CHECKSUM_OFFSET: usize = 36;
+const BAT_UNUSED: u32 = 0xFFFF_FFFF;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdFooter {
+    pub cookie: [u8; 8], // "conectix"
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub features: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub file_format_version: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub data_offset: u64, // dynamic header offset, !0 for fixed
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub timestamp: u32, // seconds since 2000-01-01
+    pub creator_application: [u8; 4],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub creator_version: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub creator_host_os: u32,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub original_size: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub current_size: u64,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub disk_geometry: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub disk_type: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub checksum: u32,
+    pub unique_id: [u8; 16],
+    pub saved_state: u8,
+    #[serde(with = "BigArray")]
+    pub reserved: [u8; 427],
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdDynamicHeader {
+    pub cookie: [u8; 8], // "cxsparse"
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub data_offset: u64,
+    #[serde(deserialize_with = "be_u64_deserialize")]
+    pub table_offset: u64, // BAT offset in the file
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub header_version: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub max_table_entries: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub block_size: u32,
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub checksum: u32,
+    pub parent_unique_id: [u8; 16],
+    #[serde(deserialize_with = "be_u32_deserialize")]
+    pub parent_timestamp: u32,
+    pub reserved: u32,
+    #[serde(with = "BigArray")]
+    pub parent_unicode_name: [u8; 512],
+    #[serde(with = "BigArray")]
+    pub parent_locators: [u8; 24 * 8],
+    #[serde(with = "BigArray")]
+    pub reserved2: [u8; 256],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdDiskType {
+    Fixed,
+    Dynamic,
+    Differencing,
+    Unknown(u32),
+}
+
+impl VhdFooter {
+    pub fn disk_type(&self) -> VhdDiskType {
+        match self.disk_type {
+            2 => VhdDiskType::Fixed,
+            3 => VhdDiskType::Dynamic,
+            4 => VhdDiskType::Differencing,
+            x => VhdDiskType::Unknown(x),
+        }
+    }
+}
+
+// VHD checksums are the one's complement of the byte sum with the
+// checksum field itself left out
+pub fn vhd_checksum(bytes: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u32 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if (checksum_offset..checksum_offset + 4).contains(&i) {
+            continue;
+        }
+        sum = sum.wrapping_add(*byte as u32);
+    }
+    !sum
+}
+
+fn read_footer(
+    file: &dyn BlockSource,
+    offset: u64,
+) -> PurdResult<VhdFooter> {
+    let bytes = file.read_bytes(offset, VHD_FOOTER_SIZE)?;
+    let footer = read_header_from_bytes::<VhdFooter>(&bytes)?;
+    if &footer.cookie != b"conectix" {
+        return Err(PurdError::bad_magic(
+            "VHD:footer",
+            offset,
+            BigEndian::read_u64(b"conectix") as u128,
+            BigEndian::read_u64(&footer.cookie) as u128,
+        ));
+    }
+    let sum = vhd_checksum(&bytes, VHD_FOOTER_CHECKSUM_OFFSET);
+    if sum != footer.checksum {
+        return Err(PurdError::checksum_mismatch(
+            "VHD:footer",
+            footer.checksum as u64,
+            sum as u64,
+        ));
+    }
+    Ok(footer)
+}
+
+// the footer copy at the front counts too, open falls back on it
+pub fn is_vhd(file: &dyn BlockSource) -> PurdResult<bool> {
+    if file.len() < VHD_FOOTER_SIZE {
+        return Ok(false);
+    }
+    for offset in [file.len() - VHD_FOOTER_SIZE, 0] {
+        if file.read_bytes(offset, 8)? == b"conectix" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[derive(Debug)]
+pub struct VhdSource {
+    name: String,
+    file: Arc<dyn BlockSource>,
+    pub footer: VhdFooter,
+    pub dynamic: Option<VhdDynamicHeader>,
+    bat: Vec<u32>,
+}
+
+impl VhdSource {
+    pub fn open(
+        path: &str,
+        file: Arc<dyn BlockSource>,
+    ) -> PurdResult<VhdSource> {
+        if file.len() < VHD_FOOTER_SIZE {
+            return Err(PurdError::out_of_bounds(
+                path,
+                0,
+                VHD_FOOTER_SIZE,
+            ));
+        }
+        // dynamic disks keep a copy of the footer at the front, use
+        // it when the one at the end got mangled
+        let footer = match read_footer(
+            file.as_ref(),
+            file.len() - VHD_FOOTER_SIZE,
+        ) {
+            Ok(footer) => footer,
+            Err(e) => match read_footer(file.as_ref(), 0) {
+                Ok(copy) => {
+                    println!(
+                        "VHD footer at end of {} was bad ({}), using \
+                         the copy at offset 0",
+                        path, e
+                    );
+                    copy
+                }
+                Err(_) => return Err(e),
+            },
+        };
+
+        let mut dynamic = None;
+        let mut bat = vec![];
+        match footer.disk_type() {
+            VhdDiskType::Fixed => {
+                if footer.current_size > file.len() - VHD_FOOTER_SIZE
+                {
+                    return Err(PurdError::out_of_bounds(
+                        path,
+                        0,
+                        footer.current_size,
+                    ));
+                }
+            }
+            VhdDiskType::Dynamic => {
+                let bytes =
+                    file.read_bytes(footer.data_offset, 1024)?;
+                let header = read_header_from_bytes::<
+                    VhdDynamicHeader,
+                >(&bytes)?;
+                if &header.cookie != b"cxsparse" {
+                    return Err(PurdError::bad_magic(
+                        "VHD:dynamic header",
+                        footer.data_offset,
+                        BigEndian::read_u64(b"cxsparse") as u128,
+                        BigEndian::read_u64(&header.cookie) as u128,
+                    ));
+                }
+                let sum =
+                    vhd_checksum(&bytes, VHD_DYNAMIC_CHECKSUM_OFFSET);
+                if sum != header.checksum {
+                    return Err(PurdError::checksum_mismatch(
+                        "VHD:dynamic header",
+                        header.checksum as u64,
+                        sum as u64,
+                    ));
+                }
+                if header.block_size == 0
+                    || header.block_size % 512 != 0
+                {
+                    return Err(PurdError::Unsupported(format!(
+                        "VHD block size 0x{:X}",
+                        header.block_size
+                    )));
+                }
+                let raw = file.read_bytes(
+                    header.table_offset,
+                    header.max_table_entries as u64 * 4,
+                )?;
+                bat = raw
+                    .chunks_exact(4)
+                    .map(BigEndian::read_u32)
+                    .collect();
+                dynamic = Some(header);
+            }
+            other => {
+                return Err(PurdError::Unsupported(format!(
+                    "VHD disk type {:?}",
+                    other
+                )));
+            }
+        }
+        Ok(VhdSource {
+            name: path.to_string(),
+            file,
+            footer,
+            dynamic,
+            bat,
+        })
+    }
+
+    // every block starts with a sector bitmap padded to a sector
+    fn bitmap_size(block_size: u64) -> u64 {
+        let bitmap_bytes = (block_size / 512).div_ceil(8);
+        bitmap_bytes.div_ceil(512) * 512
+    }
+}
+
+impl BlockSource for VhdSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let header = match &self.dynamic {
+            None => return self.file.read_at(offset, buf),
+            Some(header) => header,
+        };
+        let block_size = header.block_size as u64;
+        let bitmap = VhdSource::bitmap_size(block_size);
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_block = pos % block_size;
+            let chunk = ((block_size - in_block) as usize)
+                .min(buf.len() - done);
+            let out = &mut buf[done..done + chunk];
+            match self.bat.get((pos / block_size) as usize) {
+                Some(&sector) if sector != BAT_UNUSED => {
+                    let host =
+                        sector as u64 * 512 + bitmap + in_block;
+                    self.file.read_at(host, out)?;
+                }
+                // unallocated blocks of a dynamic disk read as zero
+                _ => out.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.footer.current_size
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
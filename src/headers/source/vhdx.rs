@@ -0,0 +1,437 @@
+use super::{check_bounds, BlockSource};
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use crate::headers::summer::crc32c;
+use byteorder::{ByteOrder, LittleEndian};
+use colored::Colorize;
+use compiled_uuid::uuid;
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/* Hyper-V VHDX, little endian. File identifier up front, two
+ * headers, two region tables pointing at the BAT and metadata
+ * regions. Payload blocks are interleaved with sector bitmap blocks
+ * in the BAT, one bitmap entry after every chunk_ratio payloads.
+https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-vhdx
+*/
+
+const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const VHDX_HEADER_OFFSETS: [u64; 2] = [0x10000, 0x20000];
+const VHDX_HEADER_SIZE: u64 = 0x1000;
+const VHDX_REGION_OFFSETS: [u64; 2] = [0x30000, 0x40000];
+const VHDX_REGION_SIZE: u64 = 0x10000;
+const VHDX_HEADER_MAGIC: u32 = 0x6461_6568; // "head"
+const VHDX_REGION_MAGIC: u32 = 0x6967_6572; // "regi"
+const VHDX_MAX_REGION_ENTRIES: u32 = 2047;
+const VHDX_CHECKSUM_OFFSET: usize = 4;
+const BAT_OFFSET_MASK: u64 = !0xFFFFF;
+
+const BAT_REGION: Uuid =
+    uuid!("2DC27766-F623-4200-9D64-115E9BFD4A08");
+const METADATA_REGION: Uuid =
+    uuid!("8B7CA206-4790-4B9A-B8FE-575F050F886E");
+const FILE_PARAMETERS: Uuid =
+    uuid!("CAA16737-FA36-4D43-B3B6-33F0AA44E76B");
+const VIRTUAL_DISK_SIZE: Uuid =
+    uuid!("2FA54224-CD1B-4876-B211-5DBED83BF4B8");
+const LOGICAL_SECTOR_SIZE: Uuid =
+    uuid!("8141BF1D-A96F-4709-BA47-F233A8FAAB5F");
+const PHYSICAL_SECTOR_SIZE: Uuid =
+    uuid!("CDA348C7-445D-4471-9CC9-E9885251C556");
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdxHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub sequence_number: u64,
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub file_write_guid: Uuid,
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub data_write_guid: Uuid,
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub log_guid: Uuid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdxRegionTableHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_count: u32,
+    pub reserved: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdxRegionTableEntry {
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub guid: Uuid,
+    pub file_offset: u64,
+    pub length: u32,
+    pub required: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdxMetadataTableHeader {
+    pub signature: [u8; 8], // "metadata"
+    pub reserved: u16,
+    pub entry_count: u16,
+    pub reserved2: [u32; 5],
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VhdxMetadataTableEntry {
+    #[serde(deserialize_with = "guid_deserialize")]
+    pub item_id: Uuid,
+    pub offset: u32, // from the start of the metadata region
+    pub length: u32,
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadState {
+    NotPresent,
+    Undefined,
+    Zero,
+    Unmapped,
+    FullyPresent,
+    PartiallyPresent,
+    Unknown(u8),
+}
+
+impl PayloadState {
+    pub fn from_entry(entry: u64) -> PayloadState {
+        match entry & 0x7 {
+            0 => PayloadState::NotPresent,
+            1 => PayloadState::Undefined,
+            2 => PayloadState::Zero,
+            3 => PayloadState::Unmapped,
+            6 => PayloadState::FullyPresent,
+            7 => PayloadState::PartiallyPresent,
+            x => PayloadState::Unknown(x as u8),
+        }
+    }
+}
+
+// crc32c over the structure with its own checksum field zeroed
+fn vhdx_checksum(bytes: &[u8]) -> u32 {
+    let mut bytes = bytes.to_vec();
+    bytes[VHDX_CHECKSUM_OFFSET..VHDX_CHECKSUM_OFFSET + 4].fill(0);
+    !crc32c(!0, bytes)
+}
+
+fn checked<T>(
+    name: &str,
+    bytes: &[u8],
+    stored: u32,
+    value: T,
+) -> PurdResult<T> {
+    let sum = vhdx_checksum(bytes);
+    if sum != stored {
+        return Err(PurdError::checksum_mismatch(
+            name,
+            stored as u64,
+            sum as u64,
+        ));
+    }
+    Ok(value)
+}
+
+fn read_header(
+    file: &dyn BlockSource,
+    offset: u64,
+) -> PurdResult<VhdxHeader> {
+    let bytes = file.read_bytes(offset, VHDX_HEADER_SIZE)?;
+    let header = read_header_from_bytes::<VhdxHeader>(&bytes)?;
+    if header.signature != VHDX_HEADER_MAGIC {
+        return Err(PurdError::bad_magic(
+            "VHDX:header",
+            offset,
+            VHDX_HEADER_MAGIC as u128,
+            header.signature as u128,
+        ));
+    }
+    let stored = header.checksum;
+    checked("VHDX:header", &bytes, stored, header)
+}
+
+fn read_region_table(
+    file: &dyn BlockSource,
+    offset: u64,
+) -> PurdResult<Vec<VhdxRegionTableEntry>> {
+    let bytes = file.read_bytes(offset, VHDX_REGION_SIZE)?;
+    let header =
+        read_header_from_bytes::<VhdxRegionTableHeader>(&bytes)?;
+    if header.signature != VHDX_REGION_MAGIC {
+        return Err(PurdError::bad_magic(
+            "VHDX:region table",
+            offset,
+            VHDX_REGION_MAGIC as u128,
+            header.signature as u128,
+        ));
+    }
+    if header.entry_count > VHDX_MAX_REGION_ENTRIES {
+        return Err(PurdError::out_of_bounds(
+            "VHDX:region table",
+            offset,
+            header.entry_count as u64 * 32,
+        ));
+    }
+    checked("VHDX:region table", &bytes, header.checksum, ())?;
+    let mut entries = vec![];
+    for i in 0..header.entry_count as usize {
+        let start = 16 + i * 32;
+        entries.push(read_header_from_bytes::<VhdxRegionTableEntry>(
+            &bytes[start..start + 32],
+        )?);
+    }
+    Ok(entries)
+}
+
+// both copies get checked, the good one with the higher sequence
+// number wins for headers, the first good one for region tables
+fn pick_header(file: &dyn BlockSource) -> PurdResult<VhdxHeader> {
+    let mut best: Option<VhdxHeader> = None;
+    let mut last_err = None;
+    for offset in VHDX_HEADER_OFFSETS {
+        match read_header(file, offset) {
+            Ok(header) => {
+                if best.as_ref().is_none_or(|b| {
+                    header.sequence_number > b.sequence_number
+                }) {
+                    best = Some(header);
+                }
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match (best, last_err) {
+        (Some(header), _) => Ok(header),
+        (None, Some(e)) => Err(e),
+        (None, None) => unreachable!(),
+    }
+}
+
+pub fn is_vhdx(file: &dyn BlockSource) -> PurdResult<bool> {
+    if file.len() < VHDX_SIGNATURE.len() as u64 {
+        return Ok(false);
+    }
+    Ok(file.read_bytes(0, 8)? == VHDX_SIGNATURE)
+}
+
+#[derive(Debug)]
+pub struct VhdxSource {
+    name: String,
+    file: Arc<dyn BlockSource>,
+    pub header: VhdxHeader,
+    pub regions: Vec<VhdxRegionTableEntry>,
+    pub metadata: Vec<VhdxMetadataTableEntry>,
+    pub block_size: u32,
+    pub virtual_disk_size: u64,
+    pub logical_sector_size: u32,
+    pub physical_sector_size: u32,
+    chunk_ratio: u64,
+    bat: Vec<u64>,
+}
+
+impl VhdxSource {
+    pub fn open(
+        path: &str,
+        file: Arc<dyn BlockSource>,
+    ) -> PurdResult<VhdxSource> {
+        let header = pick_header(file.as_ref())?;
+        if !header.log_guid.is_nil() {
+            println!(
+                "{}",
+                format!(
+                    "VHDX {} has a log that was never replayed, \
+                     reading without it",
+                    path
+                )
+                .yellow()
+            );
+        }
+        let regions = match read_region_table(
+            file.as_ref(),
+            VHDX_REGION_OFFSETS[0],
+        ) {
+            Ok(regions) => regions,
+            Err(_) => read_region_table(
+                file.as_ref(),
+                VHDX_REGION_OFFSETS[1],
+            )?,
+        };
+        let find_region = |guid: Uuid| {
+            regions
+                .iter()
+                .find(|r| r.guid == guid)
+                .cloned()
+                .ok_or_else(|| {
+                    PurdError::unsupported(
+                        "VHDX without BAT/metadata",
+                    )
+                })
+        };
+        let bat_region = find_region(BAT_REGION)?;
+        let meta_region = find_region(METADATA_REGION)?;
+        if let Some(unknown) = regions.iter().find(|r| {
+            r.required != 0
+                && r.guid != BAT_REGION
+                && r.guid != METADATA_REGION
+        }) {
+            return Err(PurdError::Unsupported(format!(
+                "VHDX required region {}",
+                unknown.guid
+            )));
+        }
+
+        let meta = file.read_bytes(
+            meta_region.file_offset,
+            meta_region.length as u64,
+        )?;
+        let table =
+            read_header_from_bytes::<VhdxMetadataTableHeader>(&meta)?;
+        if &table.signature != b"metadata" {
+            return Err(PurdError::bad_magic(
+                "VHDX:metadata",
+                meta_region.file_offset,
+                LittleEndian::read_u64(b"metadata") as u128,
+                LittleEndian::read_u64(&table.signature) as u128,
+            ));
+        }
+        let mut metadata = vec![];
+        for i in 0..table.entry_count as usize {
+            let start = 32 + i * 32;
+            if start + 32 > meta.len() {
+                return Err(PurdError::out_of_bounds(
+                    "VHDX:metadata",
+                    start as u64,
+                    32,
+                ));
+            }
+            metadata.push(read_header_from_bytes::<
+                VhdxMetadataTableEntry,
+            >(&meta[start..start + 32])?);
+        }
+        let item = |guid: Uuid, size: usize| -> PurdResult<&[u8]> {
+            let entry = metadata
+                .iter()
+                .find(|m| m.item_id == guid)
+                .ok_or_else(|| {
+                PurdError::Unsupported(format!(
+                    "VHDX metadata missing item {}",
+                    guid
+                ))
+            })?;
+            let start = entry.offset as usize;
+            match meta.get(start..start + size) {
+                Some(bytes) if entry.length as usize >= size => {
+                    Ok(bytes)
+                }
+                _ => Err(PurdError::out_of_bounds(
+                    "VHDX:metadata item",
+                    entry.offset as u64,
+                    size as u64,
+                )),
+            }
+        };
+        let params = item(FILE_PARAMETERS, 8)?;
+        let block_size = LittleEndian::read_u32(&params[..4]);
+        let has_parent =
+            LittleEndian::read_u32(&params[4..]) & 0x2 != 0;
+        let virtual_disk_size =
+            LittleEndian::read_u64(item(VIRTUAL_DISK_SIZE, 8)?);
+        let logical_sector_size =
+            LittleEndian::read_u32(item(LOGICAL_SECTOR_SIZE, 4)?);
+        let physical_sector_size =
+            LittleEndian::read_u32(item(PHYSICAL_SECTOR_SIZE, 4)?);
+        if has_parent {
+            return Err(PurdError::unsupported("differencing VHDX"));
+        }
+        // spec limits: 1MB..256MB power of two blocks, 512/4096 sectors
+        if !block_size.is_power_of_two()
+            || !(1 << 20..=1 << 28).contains(&block_size)
+            || !matches!(logical_sector_size, 512 | 4096)
+        {
+            return Err(PurdError::Unsupported(format!(
+                "VHDX block size 0x{:X} sector size {}",
+                block_size, logical_sector_size
+            )));
+        }
+        let chunk_ratio = ((1u64 << 23) * logical_sector_size as u64)
+            / block_size as u64;
+
+        let raw = file.read_bytes(
+            bat_region.file_offset,
+            bat_region.length as u64,
+        )?;
+        let bat =
+            raw.chunks_exact(8).map(LittleEndian::read_u64).collect();
+        Ok(VhdxSource {
+            name: path.to_string(),
+            file,
+            header,
+            regions,
+            metadata,
+            block_size,
+            virtual_disk_size,
+            logical_sector_size,
+            physical_sector_size,
+            chunk_ratio,
+            bat,
+        })
+    }
+
+    // BAT entry for a payload block, skipping the bitmap entries
+    pub fn payload_entry(&self, block: u64) -> Option<u64> {
+        let index = block + block / self.chunk_ratio;
+        self.bat.get(index as usize).copied()
+    }
+}
+
+impl BlockSource for VhdxSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let block_size = self.block_size as u64;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_block = pos % block_size;
+            let chunk = ((block_size - in_block) as usize)
+                .min(buf.len() - done);
+            let out = &mut buf[done..done + chunk];
+            let entry =
+                self.payload_entry(pos / block_size).unwrap_or(0);
+            match PayloadState::from_entry(entry) {
+                PayloadState::FullyPresent => {
+                    let host = (entry & BAT_OFFSET_MASK) + in_block;
+                    self.file.read_at(host, out)?;
+                }
+                PayloadState::PartiallyPresent
+                | PayloadState::Unknown(_) => {
+                    return Err(PurdError::Unsupported(format!(
+                        "VHDX payload block state {:?}",
+                        PayloadState::from_entry(entry)
+                    )));
+                }
+                // nothing written there yet, reads as zero
+                _ => out.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.virtual_disk_size
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn sector_size(&self) -> u64 {
+        self.logical_sector_size as u64
+    }
+}
@@ -0,0 +1,566 @@
+use super::{check_bounds, BlockSource, FileSource};
+use crate::headers::error::{PurdError, PurdResult};
+use crate::headers::reader::*;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Deserialize;
+use serde_big_array::BigArray;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+/* VMware VMDK. A disk is a text descriptor plus a list of extents,
+ * the descriptor is either its own file (split disks) or embedded
+ * in the first sparse extent (monolithicSparse, streamOptimized).
+ * Sparse extents map grains through a grain directory -> grain
+ * table -> grain, all in 512 byte sectors, little endian.
+https://github.com/libyal/libvmdk/blob/main/documentation/VMWare%20Virtual%20Disk%20Format%20(VMDK).asciidoc
+*/
+
+const SPARSE_MAGIC: u32 = 0x564d_444b; // "KDMV"
+const DESCRIPTOR_MAGIC: &[u8] = b"# Disk DescriptorFile";
+const SECTOR: u64 = 512;
+const GD_AT_END: u64 = 0xffff_ffff_ffff_ffff;
+const GTE_ZERO: u32 = 1;
+const NO_PARENT_CID: &str = "ffffffff";
+
+pub mod sparse_bitflags {
+    pub const NEWLINE_TEST: u32 = 1 << 0;
+    pub const REDUNDANT_GT: u32 = 1 << 1;
+    pub const COMPRESSED: u32 = 1 << 16;
+    pub const MARKERS: u32 = 1 << 17;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SparseExtentHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub capacity: u64,   // sectors
+    pub grain_size: u64, // sectors
+    pub descriptor_offset: u64,
+    pub descriptor_size: u64,
+    pub num_gtes_per_gt: u32,
+    pub rgd_offset: u64,
+    pub gd_offset: u64,
+    pub overhead: u64,
+    pub unclean_shutdown: u8,
+    pub single_end_line_char: u8,
+    pub non_end_line_char: u8,
+    pub double_end_line_char1: u8,
+    pub double_end_line_char2: u8,
+    pub compress_algorithm: u16,
+    #[serde(with = "BigArray")]
+    pub pad: [u8; 433],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentKind {
+    Sparse,
+    Flat,
+    Zero,
+    Other(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtentLine {
+    pub access: String,
+    pub sectors: u64,
+    pub kind: ExtentKind,
+    pub file: Option<String>,
+    pub offset: u64, // sectors, only for flat extents
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VmdkDescriptor {
+    pub version: String,
+    pub cid: String,
+    pub parent_cid: String,
+    pub create_type: String,
+    pub parent_hint: Option<String>,
+    pub extents: Vec<ExtentLine>,
+    pub ddb: Vec<(String, String)>,
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('"').to_string()
+}
+
+impl VmdkDescriptor {
+    pub fn parse(text: &str) -> PurdResult<VmdkDescriptor> {
+        let mut desc = VmdkDescriptor::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let first = line.split_whitespace().next().unwrap_or("");
+            if matches!(first, "RW" | "RDONLY" | "NOACCESS") {
+                desc.extents
+                    .push(VmdkDescriptor::parse_extent(line)?);
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), unquote(value)),
+                None => continue,
+            };
+            match key {
+                "version" => desc.version = value,
+                "CID" => desc.cid = value,
+                "parentCID" => desc.parent_cid = value,
+                "createType" => desc.create_type = value,
+                "parentFileNameHint" => {
+                    desc.parent_hint = Some(value)
+                }
+                _ => desc.ddb.push((key.to_string(), value)),
+            }
+        }
+        if desc.extents.is_empty() {
+            return Err(PurdError::unsupported(
+                "VMDK descriptor without extents",
+            ));
+        }
+        Ok(desc)
+    }
+
+    // RW 4192256 SPARSE "disk-s001.vmdk"
+    // RW 4192256 FLAT "disk-flat.vmdk" 0
+    // RW 4192256 ZERO
+    fn parse_extent(line: &str) -> PurdResult<ExtentLine> {
+        let bad = || {
+            PurdError::Unsupported(format!(
+                "VMDK extent line '{}'",
+                line
+            ))
+        };
+        let mut fields = line.splitn(4, char::is_whitespace);
+        let access = fields.next().ok_or_else(bad)?.to_string();
+        let sectors = fields
+            .next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(bad)?;
+        let kind = match fields.next().ok_or_else(bad)? {
+            "SPARSE" => ExtentKind::Sparse,
+            "FLAT" => ExtentKind::Flat,
+            "ZERO" => ExtentKind::Zero,
+            other => ExtentKind::Other(other.to_string()),
+        };
+        let mut file = None;
+        let mut offset = 0;
+        if let Some(rest) = fields.next() {
+            let rest = rest.trim();
+            // file names are quoted and can have spaces in them
+            let (name, tail) = match rest.strip_prefix('"') {
+                Some(quoted) => {
+                    quoted.split_once('"').ok_or_else(bad)?
+                }
+                None => rest.split_once(' ').unwrap_or((rest, "")),
+            };
+            file = Some(name.to_string());
+            if let Some(sector) = tail.split_whitespace().next() {
+                offset = sector.parse::<u64>().map_err(|_| bad())?;
+            }
+        }
+        Ok(ExtentLine {
+            access,
+            sectors,
+            kind,
+            file,
+            offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grain {
+    Unallocated,
+    Zero,
+    Data(u64),       // byte offset in the extent file
+    Compressed(u64), // byte offset of the grain marker
+}
+
+#[derive(Debug)]
+pub struct SparseExtent {
+    file: Arc<dyn BlockSource>,
+    pub header: SparseExtentHeader,
+    gd: Vec<u32>,
+}
+
+fn read_sparse_header(
+    file: &dyn BlockSource,
+    offset: u64,
+) -> PurdResult<SparseExtentHeader> {
+    let header = read_header_from_bytes::<SparseExtentHeader>(
+        &file.read_bytes(offset, SECTOR)?,
+    )?;
+    if header.magic != SPARSE_MAGIC {
+        return Err(PurdError::bad_magic(
+            "VMDK:sparse header",
+            offset,
+            SPARSE_MAGIC as u128,
+            header.magic as u128,
+        ));
+    }
+    Ok(header)
+}
+
+// sector counts come straight out of the header or descriptor, junk
+// ones don't fit in a byte offset
+fn sector_bytes(sectors: u64, what: &str) -> PurdResult<u64> {
+    sectors.checked_mul(SECTOR).ok_or_else(|| {
+        PurdError::out_of_bounds(what, sectors, SECTOR)
+    })
+}
+
+pub fn is_vmdk(file: &dyn BlockSource) -> PurdResult<bool> {
+    if file.len() < DESCRIPTOR_MAGIC.len() as u64 {
+        return Ok(false);
+    }
+    let magic = file.read_bytes(0, DESCRIPTOR_MAGIC.len() as u64)?;
+    Ok(LittleEndian::read_u32(&magic) == SPARSE_MAGIC
+        || magic == DESCRIPTOR_MAGIC)
+}
+
+impl SparseExtent {
+    pub fn open(
+        file: Arc<dyn BlockSource>,
+    ) -> PurdResult<SparseExtent> {
+        let mut header = read_sparse_header(file.as_ref(), 0)?;
+        // streamOptimized writes the real header as a footer once it
+        // knows where the grain directory ended up
+        if header.gd_offset == GD_AT_END {
+            if file.len() < 3 * SECTOR {
+                return Err(PurdError::out_of_bounds(
+                    &file.name(),
+                    0,
+                    3 * SECTOR,
+                ));
+            }
+            header = read_sparse_header(
+                file.as_ref(),
+                file.len() - 2 * SECTOR,
+            )?;
+        }
+        if header.grain_size == 0
+            || !header.grain_size.is_power_of_two()
+            || header.num_gtes_per_gt == 0
+        {
+            return Err(PurdError::Unsupported(format!(
+                "VMDK grain size {} with {} entries per table",
+                header.grain_size, header.num_gtes_per_gt
+            )));
+        }
+        // checked once here, grain_bytes() and len() rely on it
+        sector_bytes(header.grain_size, "VMDK:grain size")?;
+        sector_bytes(header.capacity, "VMDK:capacity")?;
+        let gt_coverage = header
+            .grain_size
+            .checked_mul(header.num_gtes_per_gt as u64)
+            .ok_or_else(|| {
+                PurdError::out_of_bounds(
+                    "VMDK:grain table",
+                    header.grain_size,
+                    header.num_gtes_per_gt as u64,
+                )
+            })?;
+        let gd_entries = header.capacity.div_ceil(gt_coverage);
+        let raw = file.read_bytes(
+            sector_bytes(header.gd_offset, "VMDK:grain directory")?,
+            gd_entries * 4,
+        )?;
+        let gd =
+            raw.chunks_exact(4).map(LittleEndian::read_u32).collect();
+        Ok(SparseExtent { file, header, gd })
+    }
+
+    pub fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR
+    }
+    pub fn len(&self) -> u64 {
+        self.header.capacity * SECTOR
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn lookup(&self, offset: u64) -> PurdResult<Grain> {
+        let grain = offset / self.grain_bytes();
+        let per_gt = self.header.num_gtes_per_gt as u64;
+        let gt = match self.gd.get((grain / per_gt) as usize) {
+            Some(0) | None => return Ok(Grain::Unallocated),
+            Some(sector) => *sector as u64 * SECTOR,
+        };
+        let gte = LittleEndian::read_u32(
+            &self.file.read_bytes(gt + (grain % per_gt) * 4, 4)?,
+        );
+        let compressed =
+            self.header.flags & sparse_bitflags::COMPRESSED != 0;
+        Ok(match gte {
+            0 => Grain::Unallocated,
+            GTE_ZERO if self.header.version >= 2 => Grain::Zero,
+            sector if compressed => {
+                Grain::Compressed(sector as u64 * SECTOR)
+            }
+            sector => Grain::Data(sector as u64 * SECTOR),
+        })
+    }
+
+    // compressed grains start with the lba (u64) and a byte count
+    // (u32), then a zlib stream
+    fn read_compressed(&self, marker: u64) -> PurdResult<Vec<u8>> {
+        let head = self.file.read_bytes(marker, 12)?;
+        let size = LittleEndian::read_u32(&head[8..]) as u64;
+        let deflated = self.file.read_bytes(marker + 12, size)?;
+        let mut grain = vec![0u8; self.grain_bytes() as usize];
+        let mut decoder =
+            flate2::read::ZlibDecoder::new(&deflated[..]);
+        decoder.read_exact(&mut grain)?;
+        Ok(grain)
+    }
+}
+
+#[derive(Debug)]
+pub enum Extent {
+    Sparse(Box<SparseExtent>),
+    Flat {
+        file: Arc<dyn BlockSource>,
+        offset: u64,
+    },
+    Zero,
+}
+
+#[derive(Debug)]
+struct MappedExtent {
+    start: u64, // bytes into the virtual disk
+    len: u64,
+    extent: Extent,
+}
+
+#[derive(Debug)]
+pub struct VmdkSource {
+    name: String,
+    pub descriptor: VmdkDescriptor,
+    extents: Vec<MappedExtent>,
+    parent: Option<Arc<dyn BlockSource>>,
+    len: u64,
+}
+
+impl VmdkSource {
+    pub fn open(
+        path: &str,
+        file: Arc<dyn BlockSource>,
+        depth: u32,
+    ) -> PurdResult<VmdkSource> {
+        let magic = file.read_bytes(0, 4)?;
+        let mut embedded = None;
+        let descriptor = if LittleEndian::read_u32(&magic)
+            == SPARSE_MAGIC
+        {
+            let sparse = SparseExtent::open(file.clone())?;
+            let header = &sparse.header;
+            let descriptor = if header.descriptor_offset != 0 {
+                let text = file.read_bytes(
+                    sector_bytes(
+                        header.descriptor_offset,
+                        "VMDK:descriptor",
+                    )?,
+                    sector_bytes(
+                        header.descriptor_size,
+                        "VMDK:descriptor",
+                    )?,
+                )?;
+                let text = String::from_utf8_lossy(&text);
+                VmdkDescriptor::parse(text.trim_end_matches('\0'))?
+            } else {
+                // a bare extent with no descriptor, it's the whole disk
+                VmdkDescriptor {
+                    extents: vec![ExtentLine {
+                        access: "RW".to_string(),
+                        sectors: header.capacity,
+                        kind: ExtentKind::Sparse,
+                        file: None,
+                        offset: 0,
+                    }],
+                    ..Default::default()
+                }
+            };
+            embedded = Some(sparse);
+            descriptor
+        } else {
+            let text = file.read_bytes(0, file.len())?;
+            VmdkDescriptor::parse(&String::from_utf8_lossy(&text))?
+        };
+
+        let own_name = Path::new(path).file_name();
+        let mut extents = vec![];
+        let mut start = 0;
+        let lone_sparse = descriptor
+            .extents
+            .iter()
+            .filter(|e| e.kind == ExtentKind::Sparse)
+            .count()
+            == 1;
+        for line in &descriptor.extents {
+            // the extent a sparse file's own descriptor points at is
+            // that same file, don't open it twice. Renamed images
+            // still carry the old name so a lone extent counts too.
+            let is_self = embedded.is_some()
+                && line.kind == ExtentKind::Sparse
+                && (lone_sparse
+                    || line.file.as_ref().is_none_or(|f| {
+                        Path::new(f).file_name() == own_name
+                    }));
+            let extent = match &line.kind {
+                ExtentKind::Zero => Extent::Zero,
+                ExtentKind::Sparse if is_self => {
+                    Extent::Sparse(Box::new(embedded.take().unwrap()))
+                }
+                ExtentKind::Sparse => {
+                    Extent::Sparse(Box::new(SparseExtent::open(
+                        VmdkSource::open_extent(path, line)?,
+                    )?))
+                }
+                ExtentKind::Flat => Extent::Flat {
+                    file: VmdkSource::open_extent(path, line)?,
+                    offset: sector_bytes(line.offset, "VMDK:extent")?,
+                },
+                ExtentKind::Other(kind) => {
+                    return Err(PurdError::Unsupported(format!(
+                        "VMDK {} extents",
+                        kind
+                    )));
+                }
+            };
+            let len = sector_bytes(line.sectors, "VMDK:extent")?;
+            if let Extent::Flat { file, offset } = &extent {
+                check_bounds(file.as_ref(), *offset, len)?;
+            }
+            extents.push(MappedExtent { start, len, extent });
+            start = start.checked_add(len).ok_or_else(|| {
+                PurdError::out_of_bounds("VMDK:extent", start, len)
+            })?;
+        }
+
+        let mut parent = None;
+        if !descriptor.parent_cid.is_empty()
+            && descriptor.parent_cid != NO_PARENT_CID
+        {
+            let hint =
+                descriptor.parent_hint.as_ref().ok_or_else(|| {
+                    PurdError::unsupported(
+                        "VMDK with a parent but no hint",
+                    )
+                })?;
+            parent = Some(super::open_image(
+                &super::relative_to(path, hint),
+                depth + 1,
+            )?);
+        }
+
+        Ok(VmdkSource {
+            name: path.to_string(),
+            descriptor,
+            extents,
+            parent,
+            len: start,
+        })
+    }
+
+    fn open_extent(
+        path: &str,
+        line: &ExtentLine,
+    ) -> PurdResult<Arc<dyn BlockSource>> {
+        let name = line.file.as_ref().ok_or_else(|| {
+            PurdError::unsupported("VMDK extent without a file name")
+        })?;
+        Ok(Arc::new(FileSource::open(&super::relative_to(
+            path, name,
+        ))?))
+    }
+
+    fn read_parent(
+        &self,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> PurdResult<()> {
+        buf.fill(0);
+        if let Some(parent) = &self.parent {
+            if offset < parent.len() {
+                let avail = (parent.len() - offset)
+                    .min(buf.len() as u64)
+                    as usize;
+                parent.read_at(offset, &mut buf[..avail])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_sparse(
+        &self,
+        sparse: &SparseExtent,
+        disk_offset: u64,
+        extent_offset: u64,
+        buf: &mut [u8],
+    ) -> PurdResult<()> {
+        let grain_bytes = sparse.grain_bytes();
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = extent_offset + done as u64;
+            let in_grain = pos % grain_bytes;
+            let chunk = ((grain_bytes - in_grain) as usize)
+                .min(buf.len() - done);
+            let out = &mut buf[done..done + chunk];
+            match sparse.lookup(pos)? {
+                Grain::Unallocated => {
+                    self.read_parent(disk_offset + done as u64, out)?
+                }
+                Grain::Zero => out.fill(0),
+                Grain::Data(host) => {
+                    sparse.file.read_at(host + in_grain, out)?
+                }
+                Grain::Compressed(marker) => {
+                    let grain = sparse.read_compressed(marker)?;
+                    let start = in_grain as usize;
+                    out.copy_from_slice(&grain[start..start + chunk]);
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockSource for VmdkSource {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> PurdResult<()> {
+        check_bounds(self, offset, buf.len() as u64)?;
+        let mut done = 0usize;
+        for mapped in &self.extents {
+            if done == buf.len() {
+                break;
+            }
+            let pos = offset + done as u64;
+            if pos >= mapped.start + mapped.len {
+                continue;
+            }
+            let in_extent = pos - mapped.start;
+            let chunk = ((mapped.len - in_extent) as usize)
+                .min(buf.len() - done);
+            let out = &mut buf[done..done + chunk];
+            match &mapped.extent {
+                Extent::Sparse(sparse) => {
+                    self.read_sparse(sparse, pos, in_extent, out)?
+                }
+                Extent::Flat { file, offset } => {
+                    file.read_at(offset + in_extent, out)?
+                }
+                Extent::Zero => out.fill(0),
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+    fn len(&self) -> u64 {
+        self.len
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
@@ -1,4 +1,6 @@
-use super::reader::{print_bool, OnDisk};
+use super::error::PurdResult;
+use super::reader::print_bool;
+use super::source::{BlockSource, MemorySource};
 use crc::{Algorithm, Crc};
 use std::ops::Range;
 
@@ -24,96 +26,104 @@ pub trait Summable64<T: Summable = Self> {
 pub fn struct_validate_checksum32<
     Structure: Summable + Summable32,
 >(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     instance: &Structure,
     label: &str,
     offset: u64,
-) -> bool {
+) -> PurdResult<bool> {
     let chksum = crc32_structure_from_disk::<Structure>(
-        reader, &instance, offset,
-    );
+        reader, instance, offset,
+    )?;
     let result = instance.validate_checksum(chksum);
     print_valid_checksum(label, result);
-    result
+    Ok(result)
 }
 
 pub fn struct_validate_checksum16<
     Structure: Summable + Summable16,
 >(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     instance: &Structure,
     label: &str,
     offset: u64,
-) -> bool {
+) -> PurdResult<bool> {
     let chksum = crc16_structure_from_disk::<Structure>(
-        reader, &instance, offset,
-    );
+        reader, instance, offset,
+    )?;
     let result = instance.validate_checksum(chksum);
     print_valid_checksum(label, result);
-    result
+    Ok(result)
 }
 
 pub fn print_valid_checksum(name: &str, result: bool) {
     println!("Valid checksum {}?: {}", name, print_bool(result));
 }
 fn gather_and_include<T: Summable>(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     summable: &T,
     start_offset: u64,
-) -> Vec<u8> {
+) -> PurdResult<Vec<u8>> {
     let struct_start =
         start_offset + summable.range_to_include().start as u64;
     let struct_size = (summable.range_to_include().end
         - summable.range_to_include().start)
         as u64;
     let mut struct_bytes =
-        reader.read_bytes_from_file(struct_start, struct_size as u64);
+        reader.read_bytes(struct_start, struct_size)?;
     for range in summable.ranges_to_zero() {
-        for i in range.start..range.end {
-            struct_bytes[i] = 0;
+        for byte in struct_bytes[range].iter_mut() {
+            *byte = 0;
         }
     }
-    struct_bytes
+    Ok(struct_bytes)
 }
 
 pub fn crc16_structure_from_disk<T: Summable + Summable16>(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     summable: &T,
     start_offset: u64,
-) -> u16 {
+) -> PurdResult<u16> {
     let struct_bytes =
-        gather_and_include(reader, summable, start_offset);
-    let summer = Crc::<u16>::new(&summable.crc_parameters());
+        gather_and_include(reader, summable, start_offset)?;
+    let summer = Crc::<u16>::new(summable.crc_parameters());
     let mut digest = summer.digest();
     digest.update(&struct_bytes);
-    digest.finalize()
+    Ok(digest.finalize())
 }
 
 pub fn crc32_structure_from_disk<T: Summable + Summable32>(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     summable: &T,
     start_offset: u64,
-) -> u32 {
+) -> PurdResult<u32> {
     let struct_bytes =
-        gather_and_include(reader, summable, start_offset);
-    let summer = Crc::<u32>::new(&summable.crc_parameters());
+        gather_and_include(reader, summable, start_offset)?;
+    let summer = Crc::<u32>::new(summable.crc_parameters());
     let mut digest = summer.digest();
     digest.update(&struct_bytes);
-    digest.finalize()
+    Ok(digest.finalize())
+}
+
+// for structs that only exist in memory so far, like a rebuilt header
+pub fn crc32_structure_from_bytes<T: Summable + Summable32>(
+    summable: &T,
+    bytes: &[u8],
+) -> PurdResult<u32> {
+    let memory = MemorySource::new("summer", bytes.to_vec());
+    crc32_structure_from_disk(&memory, summable, 0)
 }
 
 pub fn crc32_bytes_from_disk(
-    reader: &mut OnDisk,
+    reader: &dyn BlockSource,
     algorithm: &'static Algorithm<u32>,
     start_offset: u64,
     size: u64,
-) -> u32 {
-    let struct_bytes =
-        reader.read_bytes_from_file(start_offset, size);
+) -> PurdResult<u32> {
+    let struct_bytes = reader.read_bytes(start_offset, size)?;
     let summer = Crc::<u32>::new(algorithm);
     let mut digest = summer.digest();
     digest.update(&struct_bytes);
-    digest.finalize()
+    Ok(digest.finalize())
 }
 
 pub fn crc32_bytes(
@@ -251,3 +261,21 @@ pub fn crc16(crc: u16, input: Vec<u8>) -> u16 {
     }
     return _crc;
 }
+
+// zlib's adler32, EWF uses it on section descriptors, tables and
+// uncompressed chunks. Seed is 1.
+pub fn adler32(adler: u32, data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a = adler & 0xffff;
+    let mut b = adler >> 16;
+    // 5552 bytes is the most that can go by before b overflows
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
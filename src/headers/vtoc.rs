@@ -0,0 +1,127 @@
+use super::error::PurdResult;
+use super::reader::*;
+use crate::headers::fs::disk::PartitionType;
+use crate::headers::mbr::{
+    slice_warning, MbrPartitionEntry, SlicePartition,
+};
+use serde::Deserialize;
+use serde_big_array::BigArray;
+
+/* Solaris x86 VTOC, the slice table Solaris keeps in the second
+ * sector of its MBR partition (0xBF, or 0x82 on older installs which
+ * Linux swap also claims, the sanity value tells them apart). The
+ * SPARC flavour sits in sector 0 of a bare disk instead of inside an
+ * MBR, so it doesn't show up here.
+ * https://github.com/illumos/illumos-gate/blob/master/usr/src/uts/common/sys/vtoc.h
+ */
+
+pub const VTOC_SANITY: u32 = 0x600DDEEE;
+const VTOC_SECTOR: u64 = 1; // sectors into the MBR partition
+const VTOC_MAX_SLICES: u16 = 16;
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct VtocSlice {
+    pub tag: u16,
+    pub flag: u16,
+    pub start: u32, // relative to the MBR partition
+    pub size: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Vtoc {
+    pub bootinfo: [u32; 3],
+    pub sanity: u32,
+    pub version: u32,
+    pub volume: [u8; 8],
+    pub sectorsz: u16,
+    pub nparts: u16,
+    pub reserved: [u32; 10],
+    pub slices: [VtocSlice; 16],
+    pub timestamp: [u32; 16],
+    #[serde(with = "BigArray")]
+    pub asciilabel: [u8; 128],
+}
+
+impl HasHeaderMagic for Vtoc {
+    fn magic_field_offset(&self) -> u64 {
+        12
+    }
+    fn magic_field_size(&self) -> u64 {
+        4
+    }
+    fn magic_field_endianness(&self) -> Endianness {
+        Endianness::Little
+    }
+    fn magic_field_upcast(&self) -> u128 {
+        VTOC_SANITY as u128
+    }
+}
+
+// tags from sys/vtoc.h
+pub const V_BACKUP: u16 = 5; // the whole partition, not a real slice
+const V_SWAP: u16 = 3;
+
+impl VtocSlice {
+    pub fn get_disk_partition_type(&self) -> PartitionType {
+        match self.tag {
+            V_SWAP => PartitionType::SolarisSwap,
+            _ => PartitionType::SolarisData,
+        }
+    }
+}
+
+/* Read the VTOC in an MBR partition, None when there isn't one.
+ * Slices start relative to the MBR partition. The backup slice that
+ * covers all of it is left out like the kernel does, and slices that
+ * stick out get skipped with a warning. */
+pub fn read_vtoc_partitions(
+    reader: &mut OnDisk,
+    slice: &MbrPartitionEntry,
+    sector_size: u64,
+) -> PurdResult<Option<Vec<SlicePartition>>> {
+    let slice_start = slice.lba_of_partition_start as u64;
+    let slice_sectors = slice.sectors_in_partition as u64;
+    if VTOC_SECTOR >= slice_sectors {
+        return Ok(None);
+    }
+    let vtoc = reader.read_header_from_offset::<Vtoc>(
+        (slice_start + VTOC_SECTOR) * sector_size,
+    )?;
+    if vtoc.sanity != VTOC_SANITY {
+        return Ok(None);
+    }
+    if vtoc.version != 1 {
+        slice_warning(format!(
+            "Solaris VTOC version {}, reading it as version 1",
+            vtoc.version
+        ));
+    }
+    let mut found = vec![];
+    let nparts = vtoc.nparts.min(VTOC_MAX_SLICES) as usize;
+    for (i, entry) in vtoc.slices[..nparts].iter().enumerate() {
+        if entry.size == 0 || entry.tag == V_BACKUP {
+            continue;
+        }
+        let start = entry.start as u64;
+        let sectors = entry.size as u64;
+        let label = format!("s{}", i);
+        if start + sectors > slice_sectors {
+            slice_warning(format!(
+                "Solaris slice {} ({}..{}) is outside its partition \
+                 ({} sectors), skipping it",
+                label,
+                start,
+                start + sectors,
+                slice_sectors
+            ));
+            continue;
+        }
+        found.push(SlicePartition {
+            label,
+            start_lba: slice_start + start,
+            sectors,
+            p_type: entry.get_disk_partition_type(),
+        });
+    }
+    Ok(Some(found))
+}
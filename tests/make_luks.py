@@ -0,0 +1,169 @@
+# Writes the LUKS fixtures for test_luks.rs, no cryptsetup needed.
+# Deterministic, so running it again gives the same files:
+#   python3 tests/make_luks.py
+# luks2_ext4.img.gz: LUKS2, argon2id keyslot 0 ("purd passphrase"),
+#   pbkdf2 keyslot 1 (keyfile = the bytes of luks_keyfile), 4K sectors,
+#   a 64K ext4 labelled "purdluks" inside with hello.txt on it
+# luks1.img.gz: LUKS1 sha1, keyslot 2 ("purd passphrase"), 8K of text
+import base64, gzip, hashlib, json, os, random, struct, subprocess, tempfile
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2id
+
+rng = random.Random(0x9075)
+HERE = os.path.dirname(os.path.abspath(__file__))
+PASSPHRASE = b"purd passphrase"
+KEYFILE = bytes(range(256)) * 2
+
+
+def xts(key, data, first_iv, sector=512, iv_step=1):
+    out = b""
+    for i in range(0, len(data), sector):
+        tweak = (first_iv + i // sector * iv_step).to_bytes(16, "little")
+        enc = Cipher(algorithms.AES(key), modes.XTS(tweak)).encryptor()
+        out += enc.update(data[i : i + sector]) + enc.finalize()
+    return out
+
+
+def diffuse(block, alg):
+    size = hashlib.new(alg).digest_size
+    out = b""
+    for i in range(0, len(block), size):
+        piece = block[i : i + size]
+        out += hashlib.new(alg, struct.pack(">I", i // size) + piece).digest()[: len(piece)]
+    return out
+
+
+def af_split(key, stripes, alg):
+    buf = bytes(len(key))
+    out = b""
+    for _ in range(stripes - 1):
+        stripe = rng.randbytes(len(key))
+        out += stripe
+        buf = diffuse(bytes(a ^ b for a, b in zip(buf, stripe)), alg)
+    return out + bytes(a ^ b for a, b in zip(buf, key))
+
+
+def keyslot_area(key, derived, stripes, alg):
+    material = af_split(key, stripes, alg)
+    material += bytes(-len(material) % 512)
+    return xts(derived, material, 0)
+
+
+def ext4_image():
+    with tempfile.TemporaryDirectory() as tmp:
+        img = os.path.join(tmp, "ext4.img")
+        hello = os.path.join(tmp, "hello.txt")
+        with open(hello, "w") as f:
+            f.write("hello from inside LUKS\n")
+        with open(img, "wb") as f:
+            f.truncate(64 * 1024)
+        env = dict(os.environ, E2FSPROGS_FAKE_TIME="1700000000")
+        subprocess.run(
+            ["mkfs.ext4", "-q", "-b", "1024", "-N", "16",
+             "-O", "^has_journal,^resize_inode", "-L", "purdluks",
+             "-U", "5b1b7d3c-1e0f-4d51-9a55-6c8f3b8f6d21",
+             "-E", "hash_seed=5b1b7d3c-1e0f-4d51-9a55-6c8f3b8f6d21", img],
+            check=True, env=env)
+        subprocess.run(["debugfs", "-w", "-R", f"write {hello} hello.txt", img],
+                       check=True, env=env, capture_output=True)
+        return open(img, "rb").read()
+
+
+def luks2():
+    hdr_size = 16384
+    key = rng.randbytes(64)
+    data_offset = 48 * 1024
+    slots = {}
+    areas = b""
+    # argon2id, tiny memory so the tests stay quick
+    salt0 = rng.randbytes(32)
+    derived0 = Argon2id(salt=salt0, length=64, iterations=2, lanes=1,
+                        memory_cost=64).derive(PASSPHRASE)
+    area0 = keyslot_area(key, derived0, 100, "sha256")
+    slots["0"] = {
+        "type": "luks2", "key_size": 64,
+        "af": {"type": "luks1", "stripes": 100, "hash": "sha256"},
+        "area": {"type": "raw", "offset": str(2 * hdr_size), "size": "8192",
+                 "encryption": "aes-xts-plain64", "key_size": 64},
+        "kdf": {"type": "argon2id", "time": 2, "memory": 64, "cpus": 1,
+                "salt": base64.b64encode(salt0).decode()},
+    }
+    areas += area0 + bytes(8192 - len(area0))
+    salt1 = rng.randbytes(32)
+    derived1 = hashlib.pbkdf2_hmac("sha512", KEYFILE, salt1, 1000, 64)
+    area1 = keyslot_area(key, derived1, 100, "sha512")
+    slots["1"] = {
+        "type": "luks2", "key_size": 64,
+        "af": {"type": "luks1", "stripes": 100, "hash": "sha512"},
+        "area": {"type": "raw", "offset": str(2 * hdr_size + 8192), "size": "8192",
+                 "encryption": "aes-xts-plain64", "key_size": 64},
+        "kdf": {"type": "pbkdf2", "hash": "sha512", "iterations": 1000,
+                "salt": base64.b64encode(salt1).decode()},
+    }
+    areas += area1 + bytes(8192 - len(area1))
+    dsalt = rng.randbytes(32)
+    digest = hashlib.pbkdf2_hmac("sha256", key, dsalt, 1000, 32)
+    meta = {
+        "keyslots": slots,
+        "tokens": {},
+        "segments": {"0": {"type": "crypt", "offset": str(data_offset),
+                           "size": "dynamic", "iv_tweak": "0",
+                           "encryption": "aes-xts-plain64", "sector_size": 4096}},
+        "digests": {"0": {"type": "pbkdf2", "keyslots": ["0", "1"],
+                          "segments": ["0"], "hash": "sha256", "iterations": 1000,
+                          "salt": base64.b64encode(dsalt).decode(),
+                          "digest": base64.b64encode(digest).decode()}},
+        "config": {"json_size": str(hdr_size - 4096),
+                   "keyslots_size": str(data_offset - 2 * hdr_size)},
+    }
+    js = json.dumps(meta).encode()
+    uuid = b"7d3f1c2a-4b5e-4f60-8a71-92b3c4d5e6f7"
+    salts = [rng.randbytes(64), rng.randbytes(64)]
+
+    def copy(magic, offset, salt):
+        b = magic + struct.pack(">HQQ", 2, hdr_size, 3)
+        b += b"purd".ljust(48, b"\0") + b"sha256".ljust(32, b"\0") + salt
+        b += uuid.ljust(40, b"\0") + bytes(48) + struct.pack(">Q", offset)
+        b = b.ljust(4096, b"\0") + js.ljust(hdr_size - 4096, b"\0")
+        csum = hashlib.sha256(b).digest()
+        return b[:448] + csum.ljust(64, b"\0") + b[512:]
+
+    img = copy(b"LUKS\xba\xbe", 0, salts[0]) + copy(b"SKUL\xba\xbe", hdr_size, salts[1])
+    img += areas
+    assert len(img) == data_offset
+    # 4K sectors with IVs counted in them, dm-crypt's iv_large_sectors
+    return img + xts(key, ext4_image(), 0, 4096, 1)
+
+
+def luks1():
+    key = rng.randbytes(32)  # aes-128 xts
+    stripes = 100
+    dsalt = rng.randbytes(32)
+    digest = hashlib.pbkdf2_hmac("sha1", key, dsalt, 1000, 20)
+    slots = b""
+    area = b""
+    for i in range(8):
+        if i != 2:
+            slots += struct.pack(">II", 0x0000DEAD, 0) + bytes(32) + struct.pack(">II", 0, stripes)
+            continue
+        salt = rng.randbytes(32)
+        derived = hashlib.pbkdf2_hmac("sha1", PASSPHRASE, salt, 1000, 32)
+        area = keyslot_area(key, derived, stripes, "sha1")
+        slots += struct.pack(">II", 0x00AC71F3, 1000) + salt + struct.pack(">II", 8, stripes)
+    uuid = b"4e3b2a19-0f8e-47d6-b5c4-a3928170f6e5"
+    payload = 16
+    hdr = b"LUKS\xba\xbe" + struct.pack(">H", 1) + b"aes".ljust(32, b"\0")
+    hdr += b"xts-plain64".ljust(32, b"\0") + b"sha1".ljust(32, b"\0")
+    hdr += struct.pack(">II", payload, 32) + digest + dsalt + struct.pack(">I", 1000)
+    hdr += uuid.ljust(40, b"\0") + slots
+    img = hdr.ljust(8 * 512, b"\0") + area
+    img = img.ljust(payload * 512, b"\0")
+    text = b"".join(b"purd LUKS1 sector %04d\n" % i for i in range(8192 // 23 + 1))[:8192]
+    return img + xts(key, text, 0)
+
+
+for name, img in [("luks2_ext4.img.gz", luks2()), ("luks1.img.gz", luks1())]:
+    with open(os.path.join(HERE, name), "wb") as f:
+        f.write(gzip.compress(img, mtime=0))
+with open(os.path.join(HERE, "luks_keyfile"), "wb") as f:
+    f.write(KEYFILE)
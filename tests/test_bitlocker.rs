@@ -0,0 +1,274 @@
+use purd::headers::bitlocker::*;
+use purd::headers::fs::disk::{Disk, PartitionType};
+use purd::headers::reader;
+use purd::headers::source::*;
+use std::sync::Arc;
+
+const VOLUME_SIZE: usize = 256 * 1024;
+const COPIES: [usize; 3] = [0x8000, 0x18000, 0x28000];
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn entry(entry_type: u16, value_type: u16, data: &[u8]) -> Vec<u8> {
+    let mut e = vec![0u8; 8];
+    put_u16(&mut e, 0, (8 + data.len()) as u16);
+    put_u16(&mut e, 2, entry_type);
+    put_u16(&mut e, 4, value_type);
+    put_u16(&mut e, 6, 1);
+    e.extend_from_slice(data);
+    e
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain([0])
+        .flat_map(|u| u.to_le_bytes())
+        .collect()
+}
+
+// 2020-01-01 as a FILETIME
+const FILETIME: u64 = (1577836800 + 11644473600) * 10_000_000;
+
+fn vmk(id: u8, protection: u16, nested: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![id; 16];
+    data.extend_from_slice(&FILETIME.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&protection.to_le_bytes());
+    nested.iter().for_each(|n| data.extend_from_slice(n));
+    entry(0x0002, 0x0008, &data)
+}
+
+// block header, metadata header and entries for one copy
+fn fve_block() -> Vec<u8> {
+    let mut entries = vec![];
+    entries.extend(vmk(
+        0x11,
+        0x0800,
+        &[
+            entry(0, 0x0003, &[0xaa; 0x2c]),
+            entry(0, 0x0005, &[0xbb; 0x3c]),
+        ],
+    ));
+    entries.extend(vmk(
+        0x22,
+        0x0100,
+        &[entry(0, 0x0006, &[0xcc; 0x50])],
+    ));
+    entries.extend(entry(0x0003, 0x0005, &[0xdd; 0x4c]));
+    entries.extend(entry(
+        0x0007,
+        0x0002,
+        &utf16("PURD-PC C: 1/1/2020"),
+    ));
+    let mut offset_size = vec![0u8; 16];
+    put_u64(&mut offset_size, 0, 0x38000);
+    put_u64(&mut offset_size, 8, 0x2000);
+    entries.extend(entry(0x000f, 0x000f, &offset_size));
+
+    let mut block = vec![0u8; 64 + 48];
+    block[..8].copy_from_slice(FVE_SIGNATURE);
+    put_u16(&mut block, 10, 2);
+    put_u64(&mut block, 16, VOLUME_SIZE as u64);
+    put_u32(&mut block, 28, 16);
+    for (i, copy) in COPIES.iter().enumerate() {
+        put_u64(&mut block, 32 + i * 8, *copy as u64);
+    }
+    put_u64(&mut block, 56, 0x38000);
+    let size = (48 + entries.len()) as u32;
+    put_u32(&mut block, 64, size);
+    put_u32(&mut block, 68, 1);
+    put_u32(&mut block, 72, 48);
+    put_u32(&mut block, 76, size);
+    block[80..96].copy_from_slice(&[
+        0x78, 0x56, 0x34, 0x12, 0x34, 0x12, 0x78, 0x56, 0x9a, 0xbc,
+        0xde, 0xf0, 0x12, 0x34, 0x56, 0x78,
+    ]);
+    put_u32(&mut block, 100, 0x8004);
+    put_u64(&mut block, 104, FILETIME);
+    block.extend(entries);
+    block
+}
+
+fn fake_bitlocker() -> Vec<u8> {
+    let mut img = vec![0u8; VOLUME_SIZE];
+    img[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+    img[3..11].copy_from_slice(FVE_SIGNATURE);
+    put_u16(&mut img, 11, 512);
+    img[13] = 8;
+    img[0xa0..0xb0].copy_from_slice(&[
+        0x3b, 0xd6, 0x67, 0x49, 0x29, 0x2e, 0xd8, 0x4a, 0x83, 0x99,
+        0xf6, 0xa3, 0x39, 0xe3, 0xd0, 0x01,
+    ]);
+    for (i, copy) in COPIES.iter().enumerate() {
+        put_u64(&mut img, 0xb0 + i * 8, *copy as u64);
+    }
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    let block = fve_block();
+    for copy in COPIES {
+        img[copy..copy + block.len()].copy_from_slice(&block);
+    }
+    img
+}
+
+fn probe(img: Vec<u8>) -> BitLockerVolume {
+    let source = MemorySource::new("bitlocker", img);
+    BitLockerVolume::probe("bitlocker", &source)
+        .unwrap()
+        .unwrap()
+}
+
+#[test]
+fn test_bitlocker_metadata() {
+    let volume = probe(fake_bitlocker());
+    assert!(!volume.vista);
+    assert!(volume.consistent());
+    assert_eq!(volume.block.offset, 0x8000);
+    let m = &volume.block.metadata;
+    assert_eq!(
+        m.volume_id.to_string(),
+        "12345678-1234-5678-9abc-def012345678"
+    );
+    assert_eq!(
+        encryption_method_name(m.encryption_method),
+        "XTS-AES-128"
+    );
+    assert_eq!(
+        volume.block.description().as_deref(),
+        Some("PURD-PC C: 1/1/2020")
+    );
+    assert_eq!(volume.block.volume_header(), Some((0x38000, 0x2000)));
+
+    assert_eq!(volume.protectors.len(), 2);
+    let recovery = &volume.protectors[0];
+    assert_eq!(
+        protection_name(recovery.protection),
+        "recovery password"
+    );
+    assert_eq!(recovery.modified, FILETIME);
+    assert_eq!(
+        recovery
+            .entries
+            .iter()
+            .map(|e| e.value_type)
+            .collect::<Vec<_>>(),
+        [0x0003, 0x0005]
+    );
+    assert_eq!(
+        protection_name(volume.protectors[1].protection),
+        "TPM"
+    );
+    assert_eq!(
+        volume.protectors[1].id.to_string(),
+        "22222222-2222-2222-2222-222222222222"
+    );
+    volume.print();
+
+    // plain NTFS isn't BitLocker
+    let mut ntfs = fake_bitlocker();
+    ntfs[3..11].copy_from_slice(b"NTFS    ");
+    let source = MemorySource::new("ntfs", ntfs);
+    assert!(BitLockerVolume::probe("ntfs", &source)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_bitlocker_copies() {
+    // the last copy disagrees with the other two
+    let mut img = fake_bitlocker();
+    img[COPIES[2] + 64 + 48 + 20] ^= 1;
+    let volume = probe(img.clone());
+    assert!(!volume.consistent());
+    assert_eq!(volume.copies[1].1, FveCopyState::Consistent);
+    assert_eq!(volume.copies[2].1, FveCopyState::Differs);
+
+    // a broken first copy, the second one gets used
+    img[COPIES[0]] = b'X';
+    let volume = probe(img.clone());
+    assert!(matches!(
+        volume.copies[0].1,
+        FveCopyState::Unreadable(_)
+    ));
+    assert_eq!(volume.block.offset, COPIES[1] as u64);
+    assert_eq!(volume.copies[2].1, FveCopyState::Differs);
+
+    // an entry running off the end of the metadata
+    let mut img = fake_bitlocker();
+    put_u16(&mut img, COPIES[1] + 64 + 48, 0x7000);
+    let volume = probe(img);
+    assert!(matches!(
+        volume.copies[1].1,
+        FveCopyState::Unreadable(_)
+    ));
+    assert_eq!(volume.copies[2].1, FveCopyState::Consistent);
+
+    // nothing readable at all
+    let mut img = fake_bitlocker();
+    for copy in COPIES {
+        img[copy] = b'X';
+    }
+    let source = MemorySource::new("bitlocker", img);
+    assert!(BitLockerVolume::probe("bitlocker", &source).is_err());
+}
+
+#[test]
+fn test_bitlocker_bad_protector() {
+    // the recovery password's first nested entry is too short to be
+    // one, the TPM protector still gets listed
+    let mut img = fake_bitlocker();
+    for copy in COPIES {
+        put_u16(&mut img, copy + 64 + 48 + 8 + 0x1c, 3);
+    }
+    let volume = probe(img);
+    assert_eq!(volume.protectors.len(), 1);
+    assert_eq!(
+        protection_name(volume.protectors[0].protection),
+        "TPM"
+    );
+
+    // a Vista metadata cluster that doesn't fit in a u64
+    let mut img = fake_bitlocker();
+    img[0xa0..0xb0].fill(0);
+    put_u64(&mut img, 0x38, u64::MAX);
+    let source = MemorySource::new("vista", img);
+    assert!(matches!(
+        BitLockerVolume::probe("vista", &source),
+        Err(purd::headers::error::PurdError::OutOfBounds { .. })
+    ));
+}
+
+// an 0x07 partition that turns out to be BitLocker
+#[test]
+fn test_bitlocker_partition() {
+    let volume = fake_bitlocker();
+    let mut img = vec![0u8; 512 + volume.len()];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    img[446 + 4] = 0x07;
+    img[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    let sectors = (volume.len() / 512) as u32;
+    img[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+    img[512..].copy_from_slice(&volume);
+
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", img));
+    let mut disk =
+        Disk::new(reader::from_source(source), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::BitLocker);
+    assert_eq!(disk.bitlocker.len(), 1);
+    assert_eq!(disk.bitlocker[0].protectors.len(), 2);
+    disk.print_partitions_pretty().unwrap();
+}
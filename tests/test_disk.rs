@@ -0,0 +1,1746 @@
+use purd::headers::error::PurdError;
+use purd::headers::fs::disk::{self, Disk, PartitionType};
+use purd::headers::gpt::backup::{GptCheck, GptProblem};
+use purd::headers::gpt::discoverable::{DpsMount, DpsSkip, DpsType};
+use purd::headers::gpt::protective::{MbrFinding, MbrKind};
+use purd::headers::gpt::repair::RepairDirection;
+use purd::headers::gpt::uuids::GptArch;
+use purd::headers::gpt::Gpt;
+use purd::headers::md;
+use purd::headers::reader;
+use purd::headers::source::*;
+use std::sync::Arc;
+
+const EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const SECTORS: u64 = 64;
+const ENTRIES: u64 = 4;
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+fn put_guid(bytes: &mut [u8], offset: usize, guid: &str) {
+    // mixed endian, the first three fields are little endian
+    let mut raw = *uuid::Uuid::parse_str(guid).unwrap().as_bytes();
+    raw[..4].reverse();
+    raw[4..6].reverse();
+    raw[6..8].reverse();
+    bytes[offset..offset + 16].copy_from_slice(&raw);
+}
+fn crc32(bytes: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(bytes)
+}
+
+fn gpt_header(
+    sector_size: u64,
+    lba: u64,
+    alt: u64,
+    table: u64,
+    table_crc: u32,
+) -> Vec<u8> {
+    let mut header = vec![0u8; 92];
+    header[..8].copy_from_slice(b"EFI PART");
+    put_u32(&mut header, 8, 0x10000);
+    put_u32(&mut header, 12, 92);
+    put_u64(&mut header, 24, lba);
+    put_u64(&mut header, 32, alt);
+    let table_sectors = (ENTRIES * 128).div_ceil(sector_size);
+    put_u64(&mut header, 40, 2 + table_sectors);
+    put_u64(&mut header, 48, SECTORS - 2 - table_sectors);
+    put_guid(&mut header, 56, "01234567-89AB-CDEF-0123-456789ABCDEF");
+    put_u64(&mut header, 72, table);
+    put_u32(&mut header, 80, ENTRIES as u32);
+    put_u32(&mut header, 84, 128);
+    put_u32(&mut header, 88, table_crc);
+    let sum = crc32(&header);
+    put_u32(&mut header, 16, sum);
+    header
+}
+
+// protective MBR, primary header and table, backup table and header
+// at the end. parts are (type guid, first lba, last lba, name).
+fn fake_gpt_disk(
+    sector_size: u64,
+    parts: &[(&str, u64, u64, &str)],
+) -> Vec<u8> {
+    fake_gpt_disk_attrs(sector_size, parts, &[])
+}
+
+// same, with attribute bits for the first few partitions
+fn fake_gpt_disk_attrs(
+    sector_size: u64,
+    parts: &[(&str, u64, u64, &str)],
+    attrs: &[u64],
+) -> Vec<u8> {
+    let ss = sector_size as usize;
+    let mut img = vec![0u8; SECTORS as usize * ss];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    img[446 + 4] = 0xee;
+    put_u32(&mut img, 446 + 8, 1);
+    put_u32(&mut img, 446 + 12, (SECTORS - 1) as u32);
+
+    let mut table = vec![0u8; (ENTRIES * 128) as usize];
+    for (i, (guid, first, last, name)) in parts.iter().enumerate() {
+        let entry = i * 128;
+        put_guid(&mut table, entry, guid);
+        put_guid(
+            &mut table,
+            entry + 16,
+            "76543210-0000-0000-0000-000000000001",
+        );
+        put_u64(&mut table, entry + 32, *first);
+        put_u64(&mut table, entry + 40, *last);
+        put_u64(
+            &mut table,
+            entry + 48,
+            attrs.get(i).copied().unwrap_or(0),
+        );
+        for (j, c) in name.encode_utf16().enumerate() {
+            table[entry + 56 + j * 2..entry + 58 + j * 2]
+                .copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    let table_crc = crc32(&table);
+    let table_sectors = (ENTRIES * 128).div_ceil(sector_size);
+    let backup_table = SECTORS - 1 - table_sectors;
+
+    let primary =
+        gpt_header(sector_size, 1, SECTORS - 1, 2, table_crc);
+    img[ss..ss + 92].copy_from_slice(&primary);
+    img[2 * ss..2 * ss + table.len()].copy_from_slice(&table);
+    let backup = gpt_header(
+        sector_size,
+        SECTORS - 1,
+        1,
+        backup_table,
+        table_crc,
+    );
+    let at = backup_table as usize * ss;
+    img[at..at + table.len()].copy_from_slice(&table);
+    let at = (SECTORS - 1) as usize * ss;
+    img[at..at + 92].copy_from_slice(&backup);
+    img
+}
+
+fn disk_from(
+    bytes: Vec<u8>,
+    sector_size: Option<u64>,
+) -> Result<Disk, PurdError> {
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", bytes));
+    Disk::new(reader::from_source(source), sector_size)
+}
+
+#[test]
+fn test_4kn_gpt_disk() {
+    let img = fake_gpt_disk(4096, &[(EFI_SYSTEM, 6, 20, "EFI")]);
+    let mut disk = disk_from(img.clone(), None).unwrap();
+    assert_eq!(disk.sector_size, 4096);
+    disk.set_partition_table_type().unwrap();
+    assert!(disk.validate_headers().unwrap());
+    disk.register_partitions().unwrap();
+    let part = disk.get_partition(0).unwrap();
+    assert!(matches!(part.p_type, PartitionType::EfiSystem));
+    assert_eq!(part.p_offset, 6 * 4096);
+    assert_eq!(part.p_sector_size, 4096);
+
+    // forcing 512 byte sectors means there's no GPT at LBA 1
+    let mut forced = disk_from(img.clone(), Some(512)).unwrap();
+    forced.set_partition_table_type().unwrap();
+    assert!(matches!(
+        forced.get_gpt(),
+        Err(PurdError::BadMagic { .. })
+    ));
+    assert!(disk_from(img, Some(1000)).is_err());
+}
+
+#[test]
+fn test_512_gpt_disk() {
+    let img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let mut disk = disk_from(img, None).unwrap();
+    assert_eq!(disk.sector_size, 512);
+    disk.set_partition_table_type().unwrap();
+    assert!(disk.validate_headers().unwrap());
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.get_partition(0).unwrap().p_offset, 3 * 512);
+}
+
+#[test]
+fn test_gpt_bad_partition_name() {
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    // a lone high surrogate after "EF", in both tables
+    for table in [2, SECTORS as usize - 2] {
+        let at = table * 512 + 56 + 4;
+        img[at..at + 2].copy_from_slice(&0xd800u16.to_le_bytes());
+    }
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    let entries = disk
+        .get_gpt()
+        .unwrap()
+        .create_partition_table(&mut disk.reader, 512)
+        .unwrap();
+    assert!(entries[0].name().starts_with("EF\u{fffd}"));
+    disk.register_partitions().unwrap();
+}
+
+#[test]
+fn test_sector_size_from_source() {
+    // no GPT to go on, so the image gets the last word
+    #[derive(Debug)]
+    struct Native4k(MemorySource);
+    impl BlockSource for Native4k {
+        fn read_at(
+            &self,
+            offset: u64,
+            buf: &mut [u8],
+        ) -> Result<(), PurdError> {
+            self.0.read_at(offset, buf)
+        }
+        fn len(&self) -> u64 {
+            self.0.len()
+        }
+        fn name(&self) -> String {
+            self.0.name()
+        }
+        fn sector_size(&self) -> u64 {
+            4096
+        }
+    }
+    let mut bytes = vec![0u8; 8192];
+    bytes[510] = 0x55;
+    bytes[511] = 0xaa;
+    let ondisk = reader::from_source(Arc::new(Native4k(
+        MemorySource::new("4k", bytes),
+    )));
+    assert_eq!(
+        disk::detect_sector_size(&ondisk, None).unwrap(),
+        4096
+    );
+    assert_eq!(
+        disk::detect_sector_size(&ondisk, Some(512)).unwrap(),
+        512
+    );
+}
+
+// one partition table entry in the MBR/EBR at sector lba
+fn put_mbr_entry(
+    img: &mut [u8],
+    lba: usize,
+    slot: usize,
+    (kind, first, sectors): (u8, u32, u32),
+) {
+    let sector = lba * 512;
+    img[sector + 510] = 0x55;
+    img[sector + 511] = 0xaa;
+    let entry = sector + 446 + slot * 16;
+    img[entry + 4] = kind;
+    put_u32(img, entry + 8, first);
+    put_u32(img, entry + 12, sectors);
+}
+
+// plain MBR, parts are (type byte, first lba, sectors)
+fn fake_mbr_disk(parts: &[(u8, u32, u32)]) -> Vec<u8> {
+    let mut img = vec![0u8; SECTORS as usize * 512];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    for (i, part) in parts.iter().enumerate() {
+        put_mbr_entry(&mut img, 0, i, *part);
+    }
+    img
+}
+
+// a linux partition, then an extended one over the rest of the disk
+// with EBRs at 8, 24 and 40. links are relative to the extended
+// partition, the logicals to their own EBR.
+fn fake_ebr_disk() -> Vec<u8> {
+    let mut img = fake_mbr_disk(&[(0x83, 1, 7), (0x05, 8, 56)]);
+    put_mbr_entry(&mut img, 8, 0, (0x0c, 1, 7));
+    put_mbr_entry(&mut img, 8, 1, (0x05, 16, 16));
+    put_mbr_entry(&mut img, 24, 0, (0x82, 2, 10));
+    put_mbr_entry(&mut img, 24, 1, (0x05, 32, 24));
+    put_mbr_entry(&mut img, 40, 0, (0x83, 1, 8));
+    img
+}
+
+fn logical_numbers(disk: &Disk) -> Vec<(u32, u64)> {
+    disk.logical_partitions
+        .iter()
+        .map(|l| (l.number, l.start_lba))
+        .collect()
+}
+
+#[test]
+fn test_mbr_disk() {
+    let mut img = fake_mbr_disk(&[
+        (0x0c, 1, 7),
+        (0x07, 8, 8),
+        (0x83, 16, 24),
+        (0x82, 40, 8),
+    ]);
+    img[8 * 512 + 3..8 * 512 + 11].copy_from_slice(b"EXFAT   ");
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(disk.pt_type, disk::PartitionTableType::Mbr));
+    assert!(disk.validate_headers().unwrap());
+    assert!(disk.get_gpt().is_err());
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions.len(), 4);
+    let types: Vec<PartitionType> =
+        disk.partitions.iter().map(|p| p.p_type).collect();
+    assert!(matches!(
+        types[..],
+        [
+            PartitionType::Fat,
+            PartitionType::Exfat,
+            PartitionType::LinuxFsTBD,
+            PartitionType::LinuxSwap
+        ]
+    ));
+    assert_eq!(disk.get_partition(2).unwrap().p_offset, 16 * 512);
+}
+
+#[test]
+fn test_mbr_partition_past_end() {
+    let img =
+        fake_mbr_disk(&[(0x83, 1, 7), (0x07, 32, SECTORS as u32)]);
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    // the one that fits is still there, the other is left out
+    disk.register_partitions().unwrap();
+    let numbers: Vec<u32> =
+        disk.partitions.iter().map(|p| p.p_number).collect();
+    assert_eq!(numbers, [1, 3, 4]);
+    assert_eq!(disk.partitions[0].p_offset, 512);
+    disk.print_partitions_pretty().unwrap();
+}
+
+#[test]
+fn test_ebr_chain() {
+    let mut disk = disk_from(fake_ebr_disk(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(logical_numbers(&disk), [(5, 9), (6, 26), (7, 41)]);
+    assert_eq!(disk.partitions.len(), 7);
+    assert!(matches!(
+        disk.get_partition(1).unwrap().p_type,
+        PartitionType::Extended
+    ));
+    let swap = disk.get_partition(5).unwrap();
+    assert!(matches!(swap.p_type, PartitionType::LinuxSwap));
+    assert_eq!(swap.p_number, 6);
+    assert_eq!(swap.p_offset, 26 * 512);
+    assert_eq!(
+        disk::partition_name("/dev/sda", swap.p_number),
+        "sda6"
+    );
+    assert_eq!(disk::partition_name("loop0", 5), "loop0p5");
+}
+
+#[test]
+fn test_ebr_chain_loop() {
+    // the second EBR links back to the first
+    let mut img = fake_ebr_disk();
+    put_mbr_entry(&mut img, 24, 1, (0x05, 0, 56));
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(logical_numbers(&disk), [(5, 9), (6, 26)]);
+}
+
+#[test]
+fn test_ebr_chain_overlap_and_escape() {
+    // third EBR moved back to 12, its logical lands on top of sda5
+    // and its link points off the end of the extended partition
+    let mut img = fake_ebr_disk();
+    put_mbr_entry(&mut img, 24, 1, (0x05, 4, 8));
+    put_mbr_entry(&mut img, 12, 0, (0x83, 1, 4));
+    put_mbr_entry(&mut img, 12, 1, (0x05, 100, 8));
+    // a logical poking out of the extended partition
+    put_mbr_entry(&mut img, 12, 2, (0x83, 40, 20));
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(logical_numbers(&disk), [(5, 9), (6, 26)]);
+}
+
+fn gpt_check(img: Vec<u8>) -> GptCheck {
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", img));
+    GptCheck::check(&mut reader::from_source(source), 512).unwrap()
+}
+
+#[test]
+fn test_gpt_backup_clean() {
+    let img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let check = gpt_check(img);
+    assert!(check.is_clean(), "{:?}", check.problems);
+    assert_eq!(check.backup_lba, SECTORS - 1);
+    assert_eq!(check.backup.unwrap().alt_lba, 1);
+}
+
+#[test]
+fn test_gpt_corrupt_primary_uses_backup() {
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    img[512 + 60] ^= 0xff; // disk guid, covered by the header crc
+    let check = gpt_check(img.clone());
+    assert!(!check.primary_valid);
+    assert!(check.backup_valid);
+    assert_eq!(
+        check.problems,
+        [GptProblem::HeaderChecksum { backup: false }]
+    );
+
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(!disk.validate_headers().unwrap());
+    assert_eq!(disk.get_gpt().unwrap().self_lba, SECTORS - 1);
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.get_partition(0).unwrap().p_offset, 3 * 512);
+}
+
+#[test]
+fn test_gpt_backup_table_damage() {
+    // backup entries only, the primary is still fine
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let backup_table = (SECTORS as usize - 2) * 512;
+    img[backup_table + 56] = b'X';
+    let check = gpt_check(img.clone());
+    assert!(check.primary_valid);
+    assert_eq!(
+        check.problems,
+        [GptProblem::TableChecksum { backup: true }]
+    );
+
+    // now with a backup header that vouches for the changed entry
+    let table = img[backup_table..backup_table + 512].to_vec();
+    let header =
+        gpt_header(512, SECTORS - 1, 1, SECTORS - 2, crc32(&table));
+    let at = (SECTORS as usize - 1) * 512;
+    img[at..at + 92].copy_from_slice(&header);
+    let check = gpt_check(img);
+    assert!(check.backup_valid);
+    assert!(matches!(
+        check.problems[..],
+        [
+            GptProblem::FieldMismatch {
+                field: "gpe_table_crc32",
+                ..
+            },
+            GptProblem::EntryMismatch { index: 0 }
+        ]
+    ));
+}
+
+#[test]
+fn test_gpt_alt_lba_mismatch() {
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let table = img[2 * 512..3 * 512].to_vec();
+    let header = gpt_header(512, 1, 40, 2, crc32(&table));
+    img[512..512 + 92].copy_from_slice(&header);
+    let check = gpt_check(img);
+    assert_eq!(
+        check.problems,
+        [GptProblem::PrimaryAltLba {
+            found: 40,
+            backup_lba: SECTORS - 1
+        }]
+    );
+}
+
+#[test]
+fn test_gpt_garbage_lbas() {
+    // no backup at the end and the primary points off the edge
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let at = (SECTORS as usize - 1) * 512;
+    img[at..at + 512].fill(0);
+    let table = img[2 * 512..3 * 512].to_vec();
+    let header = gpt_header(512, 1, u64::MAX, 2, crc32(&table));
+    img[512..512 + 92].copy_from_slice(&header);
+    let mut disk = disk_from(img.clone(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert_eq!(disk.get_gpt().unwrap().alt_lba, u64::MAX);
+
+    // a table start that can't be a byte offset, crc left stale
+    put_u64(&mut img, 512 + 72, u64::MAX / 2);
+    let check = gpt_check(img.clone());
+    assert!(!check.primary_valid);
+    assert_eq!(
+        check.problems[..2],
+        [
+            GptProblem::HeaderChecksum { backup: false },
+            GptProblem::MissingBackup { lba: SECTORS - 1 }
+        ]
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(
+        disk.register_partitions(),
+        Err(PurdError::OutOfBounds { .. })
+    ));
+}
+
+#[test]
+fn test_gpt_zero_entry_size() {
+    // 2^32 entries of nothing, the empty table even checksums right
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    for (at, lba, alt, table) in [
+        (512, 1, SECTORS - 1, 2),
+        ((SECTORS as usize - 1) * 512, SECTORS - 1, 1, SECTORS - 2),
+    ] {
+        let mut header = gpt_header(512, lba, alt, table, 0);
+        put_u32(&mut header, 80, u32::MAX);
+        put_u32(&mut header, 84, 0);
+        put_u32(&mut header, 16, 0);
+        let sum = crc32(&header);
+        put_u32(&mut header, 16, sum);
+        img[at..at + 92].copy_from_slice(&header);
+    }
+    let check = gpt_check(img.clone());
+    assert!(!check.primary_valid && !check.backup_valid);
+    assert_eq!(
+        check.problems[..2],
+        [
+            GptProblem::EntrySize {
+                backup: false,
+                size: 0
+            },
+            GptProblem::EntrySize {
+                backup: true,
+                size: 0
+            }
+        ]
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(
+        disk.register_partitions(),
+        Err(PurdError::OutOfBounds { .. })
+    ));
+}
+
+fn write_temp(name: &str, bytes: &[u8]) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "purd-test-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, bytes).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_gpt_repair_primary_from_backup() {
+    let good = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let mut img = good.clone();
+    img[512 + 60] ^= 0xff; // primary header
+    img[2 * 512 + 57] = b'!'; // and its entries
+    let path = write_temp("gpt-repair-primary", &img);
+
+    // dry run, nothing on disk changes
+    let mut ondisk = reader::from_source(open(&path).unwrap());
+    let repair = Gpt::repair(&mut ondisk, 512, None).unwrap();
+    assert_eq!(repair.direction, RepairDirection::PrimaryFromBackup);
+    assert!(!repair.is_empty());
+    assert_eq!(std::fs::read(&path).unwrap(), img);
+
+    let target = WritableFile::open(&path).unwrap();
+    Gpt::repair(&mut ondisk, 512, Some(&target)).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), good);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_gpt_repair_backup_from_primary() {
+    let good = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let mut img = good.clone();
+    let at = (SECTORS as usize - 1) * 512;
+    img[at..at + 512].fill(0); // backup header gone
+    let path = write_temp("gpt-repair-backup", &img);
+    let mut ondisk = reader::from_source(open(&path).unwrap());
+    let target = WritableFile::open(&path).unwrap();
+    let repair =
+        Gpt::repair(&mut ondisk, 512, Some(&target)).unwrap();
+    assert_eq!(repair.direction, RepairDirection::BackupFromPrimary);
+    assert_eq!(std::fs::read(&path).unwrap(), good);
+
+    // a clean disk has nothing left to do
+    let mut ondisk = reader::from_source(open(&path).unwrap());
+    assert!(Gpt::plan_repair(
+        &mut ondisk,
+        512,
+        RepairDirection::BackupFromPrimary
+    )
+    .unwrap()
+    .is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_gpt_repair_refuses_other_disk() {
+    let mut img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    img[512 + 60] ^= 0xff;
+    let path = write_temp("gpt-repair-other", &img);
+    let other =
+        write_temp("gpt-repair-other-target", &[0u8; 64 * 512]);
+    let mut ondisk = reader::from_source(open(&path).unwrap());
+    let target = WritableFile::open(&other).unwrap();
+    assert!(Gpt::repair(&mut ondisk, 512, Some(&target)).is_err());
+    assert_eq!(std::fs::read(&other).unwrap(), [0u8; 64 * 512]);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&other).unwrap();
+}
+
+const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+#[test]
+fn test_protective_mbr() {
+    let img = fake_gpt_disk(512, &[(EFI_SYSTEM, 3, 20, "EFI")]);
+    let mut disk = disk_from(img.clone(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    let analysis = disk.analyze_mbr().unwrap();
+    assert_eq!(analysis.kind, MbrKind::Protective);
+    assert!(!analysis.has_problems(), "{:?}", analysis.findings);
+
+    // 0xEE that doesn't reach the end of the disk
+    let mut short = img;
+    put_mbr_entry(&mut short, 0, 0, (0xee, 2, 40));
+    let mut disk = disk_from(short, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert_eq!(
+        disk.analyze_mbr().unwrap().findings,
+        [
+            MbrFinding::ProtectiveStart { slot: 0, start: 2 },
+            MbrFinding::ProtectiveSize {
+                slot: 0,
+                sectors: 40,
+                expected: (SECTORS - 1) as u32
+            }
+        ]
+    );
+}
+
+#[test]
+fn test_hybrid_mbr() {
+    // boot camp style: 0xEE up to the first partition, then copies
+    let mut img = fake_gpt_disk(
+        512,
+        &[(EFI_SYSTEM, 3, 20, "EFI"), (BASIC_DATA, 21, 40, "DATA")],
+    );
+    put_mbr_entry(&mut img, 0, 0, (0xee, 1, 2));
+    put_mbr_entry(&mut img, 0, 1, (0xef, 3, 18));
+    put_mbr_entry(&mut img, 0, 2, (0x83, 21, 10));
+    put_mbr_entry(&mut img, 0, 3, (0x0c, 50, 5));
+    let mut disk = disk_from(img.clone(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    let analysis = disk.analyze_mbr().unwrap();
+    assert_eq!(analysis.kind, MbrKind::Hybrid);
+    assert_eq!(
+        analysis.findings,
+        [
+            MbrFinding::Mirrors {
+                slot: 1,
+                gpt_index: 0
+            },
+            MbrFinding::Overlap {
+                slot: 2,
+                gpt_index: 1
+            },
+            MbrFinding::NotInGpt { slot: 3 },
+        ]
+    );
+
+    // same range but the MBR thinks the ESP is NTFS, and the 0xEE
+    // entry moved out of slot 0 on top of it
+    put_mbr_entry(&mut img, 0, 0, (0x07, 3, 18));
+    put_mbr_entry(&mut img, 0, 1, (0xee, 1, 5));
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(disk.pt_type, disk::PartitionTableType::Gpt));
+    let findings = disk.analyze_mbr().unwrap().findings;
+    assert_eq!(
+        findings[..3],
+        [
+            MbrFinding::OverlapsProtective {
+                slot: 0,
+                protective: 1
+            },
+            MbrFinding::Mirrors {
+                slot: 0,
+                gpt_index: 0
+            },
+            MbrFinding::TypeMismatch {
+                slot: 0,
+                gpt_index: 0,
+                mbr: PartitionType::Ntfs,
+                gpt: PartitionType::EfiSystem
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_gpt_partition_types() {
+    const ROOT_X86_64: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+    const MSR: &str = "E3C9E316-0B5C-4DB8-817D-F92DF00215AE";
+    const APFS: &str = "7C3457EF-0000-11AA-AA11-00306543ECAC";
+    let mut img = fake_gpt_disk(
+        512,
+        &[
+            (BASIC_DATA, 3, 10, "DATA"),
+            (ROOT_X86_64, 11, 30, "root"),
+            (MSR, 31, 32, "MSR"),
+            (APFS, 33, 40, "mac"),
+        ],
+    );
+    // a FAT32 boot sector in the basic data partition
+    img[3 * 512 + 0x52..3 * 512 + 0x5a].copy_from_slice(b"FAT32   ");
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    let types: Vec<PartitionType> =
+        disk.partitions.iter().map(|p| p.p_type).collect();
+    assert_eq!(
+        types,
+        [
+            PartitionType::Fat,
+            PartitionType::LinuxRoot(GptArch::X86_64),
+            PartitionType::MicrosoftReserved,
+            PartitionType::AppleApfs,
+        ]
+    );
+    assert_eq!(types[1].to_string(), "Linux root (x86-64)");
+}
+
+#[test]
+fn test_discoverable_layout() {
+    const ROOT_ARM64: &str = "B921B045-1DF0-41C3-AF44-4C6F280D3FAE";
+    const ROOT_X86_64: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+    const VERITY_X86_64: &str =
+        "2C7357ED-EBD2-46D9-AEC1-23D437EC2BF5";
+    const GROW_FS: u64 = 1 << 59;
+    const READ_ONLY: u64 = 1 << 60;
+    let img = fake_gpt_disk_attrs(
+        512,
+        &[
+            (VERITY_X86_64, 3, 4, "verity"),
+            (ROOT_X86_64, 5, 20, "root"),
+            (EFI_SYSTEM, 21, 30, "EFI"),
+            (ROOT_ARM64, 31, 40, "root-arm"),
+        ],
+        &[0, READ_ONLY | GROW_FS],
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    let layout = disk.discoverable_layout(GptArch::X86_64).unwrap();
+    assert_eq!(
+        layout.mounts,
+        [
+            DpsMount {
+                target: "/",
+                number: 2,
+                read_only: true,
+                grow_fs: true,
+                verity: Some(1),
+                verity_sig: None,
+            },
+            DpsMount {
+                target: "/boot",
+                number: 3,
+                read_only: false,
+                grow_fs: false,
+                verity: None,
+                verity_sig: None,
+            },
+        ]
+    );
+    assert_eq!(
+        layout.skipped,
+        [(4, DpsType::Root(GptArch::Aarch64), DpsSkip::OtherArch)]
+    );
+
+    // booted on arm64 it's the other root, and no-auto takes the ESP
+    // out. /var needs the machine id to know it's this machine's.
+    const NO_AUTO: u64 = 1 << 63;
+    const VAR: &str = "4D21B016-B534-45C2-A9FB-5C16E091FD2D";
+    let img = fake_gpt_disk_attrs(
+        512,
+        &[
+            (EFI_SYSTEM, 3, 10, "EFI"),
+            (ROOT_ARM64, 11, 30, "root"),
+            (VAR, 31, 40, "var"),
+        ],
+        &[NO_AUTO],
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    let layout = disk.discoverable_layout(GptArch::Aarch64).unwrap();
+    assert_eq!(layout.mounts.len(), 1);
+    assert_eq!(layout.mounts[0].target, "/");
+    assert_eq!(
+        layout.skipped,
+        [
+            (1, DpsType::Esp, DpsSkip::NoAuto),
+            (3, DpsType::Var, DpsSkip::NeedsMachineId)
+        ]
+    );
+}
+
+// driver descriptor in block 0, a "PM" entry per block after it.
+// parts are (type, start block, blocks), the map lists itself first.
+fn fake_apm_disk(
+    parts: &[(&str, u32, u32)],
+    map_entries: u32,
+) -> Vec<u8> {
+    let mut img = vec![0u8; SECTORS as usize * 512];
+    img[..2].copy_from_slice(b"ER");
+    img[2..4].copy_from_slice(&512u16.to_be_bytes());
+    img[4..8].copy_from_slice(&(SECTORS as u32).to_be_bytes());
+    let map = [("Apple_partition_map", 1, map_entries)];
+    for (i, (kind, start, blocks)) in
+        map.iter().chain(parts.iter()).enumerate()
+    {
+        let at = (i + 1) * 512;
+        img[at..at + 2].copy_from_slice(b"PM");
+        img[at + 4..at + 8]
+            .copy_from_slice(&map_entries.to_be_bytes());
+        img[at + 8..at + 12].copy_from_slice(&start.to_be_bytes());
+        img[at + 12..at + 16].copy_from_slice(&blocks.to_be_bytes());
+        img[at + 16..at + 21].copy_from_slice(b"disk\0");
+        img[at + 48..at + 48 + kind.len()]
+            .copy_from_slice(kind.as_bytes());
+    }
+    img
+}
+
+#[test]
+fn test_apm_disk() {
+    let img = fake_apm_disk(
+        &[
+            ("Apple_Driver43", 4, 4),
+            ("Apple_HFS", 8, 40),
+            ("Apple_Free", 48, 16),
+        ],
+        4,
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(disk.pt_type, disk::PartitionTableType::Apm));
+    assert!(disk.validate_headers().unwrap());
+    assert!(disk.get_gpt().is_err());
+    disk.register_partitions().unwrap();
+    let types: Vec<PartitionType> =
+        disk.partitions.iter().map(|p| p.p_type).collect();
+    assert_eq!(
+        types,
+        [
+            PartitionType::ApplePartitionMap,
+            PartitionType::AppleDriver,
+            PartitionType::AppleHfsPlus,
+            PartitionType::Unused
+        ]
+    );
+    let hfs = disk.get_partition(2).unwrap();
+    assert_eq!(hfs.p_number, 3);
+    assert_eq!(hfs.p_offset, 8 * 512);
+    let apm = disk.get_apm().unwrap();
+    assert_eq!(apm.entries[2].name(), "disk");
+    assert_eq!(apm.entries[2].type_name(), "Apple_HFS");
+}
+
+#[test]
+fn test_apm_short_map_and_hybrid() {
+    // a hybrid ISO has MBR partitions too, the APM still wins
+    let mut img = fake_apm_disk(&[("Apple_HFS", 8, 40)], 3);
+    put_mbr_entry(&mut img, 0, 0, (0x17, 0, SECTORS as u32));
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    assert!(matches!(disk.pt_type, disk::PartitionTableType::Apm));
+    // the map says 3 entries but there are only 2
+    assert!(!disk.validate_headers().unwrap());
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions.len(), 2);
+
+    // an entry past the end of the disk is the only one left out
+    let img = fake_apm_disk(
+        &[("Apple_HFS", 8, SECTORS as u32), ("Apple_Free", 8, 40)],
+        3,
+    );
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    let numbers: Vec<u32> =
+        disk.partitions.iter().map(|p| p.p_number).collect();
+    assert_eq!(numbers, [1, 3]);
+}
+
+// disklabel in the second sector of the slice at lba, parts are
+// (size, offset, fstype) for a, b, c...
+fn put_bsd_label(
+    img: &mut [u8],
+    lba: usize,
+    parts: &[(u32, u32, u8)],
+) {
+    let at = (lba + 1) * 512;
+    let mut label = vec![0u8; 148 + parts.len() * 16];
+    put_u32(&mut label, 0, 0x82564557);
+    put_u32(&mut label, 132, 0x82564557);
+    label[138..140]
+        .copy_from_slice(&(parts.len() as u16).to_le_bytes());
+    for (i, (size, offset, fstype)) in parts.iter().enumerate() {
+        put_u32(&mut label, 148 + i * 16, *size);
+        put_u32(&mut label, 148 + i * 16 + 4, *offset);
+        label[148 + i * 16 + 12] = *fstype;
+    }
+    let sum = label
+        .chunks_exact(2)
+        .fold(0u16, |sum, w| sum ^ u16::from_le_bytes([w[0], w[1]]));
+    label[136..138].copy_from_slice(&sum.to_le_bytes());
+    img[at..at + label.len()].copy_from_slice(&label);
+}
+
+// VTOC in the second sector of the partition at lba, slices are
+// (tag, start, size) relative to the partition
+fn put_vtoc(img: &mut [u8], lba: usize, slices: &[(u16, u32, u32)]) {
+    let at = (lba + 1) * 512;
+    put_u32(img, at + 12, 0x600DDEEE);
+    put_u32(img, at + 16, 1);
+    img[at + 30..at + 32].copy_from_slice(&16u16.to_le_bytes());
+    for (i, (tag, start, size)) in slices.iter().enumerate() {
+        let entry = at + 72 + i * 12;
+        img[entry..entry + 2].copy_from_slice(&tag.to_le_bytes());
+        put_u32(img, entry + 4, *start);
+        put_u32(img, entry + 8, *size);
+    }
+}
+
+fn children(
+    disk: &Disk,
+    index: usize,
+) -> Vec<(u32, String, PartitionType, u64)> {
+    disk.partitions[index]
+        .p_children
+        .iter()
+        .map(|c| {
+            (
+                c.p_number,
+                c.p_label.clone().unwrap(),
+                c.p_type,
+                c.p_offset / 512,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_bsd_and_solaris_slices() {
+    let mut img = fake_mbr_disk(&[(0xa5, 8, 32), (0x82, 40, 24)]);
+    // absolute offsets, d sticks out of the slice
+    put_bsd_label(
+        &mut img,
+        8,
+        &[(8, 16, 7), (8, 24, 1), (32, 8, 0), (10, 36, 7)],
+    );
+    put_vtoc(&mut img, 40, &[(2, 2, 10), (3, 12, 8), (5, 0, 24)]);
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::BsdSlice);
+    assert_eq!(
+        disk.partitions[1].p_type,
+        PartitionType::SolarisSlice
+    );
+    assert_eq!(
+        children(&disk, 0),
+        [
+            (5, "a".to_string(), PartitionType::FreeBsdUfs, 16),
+            (6, "b".to_string(), PartitionType::FreeBsdSwap, 24)
+        ]
+    );
+    assert_eq!(
+        children(&disk, 1),
+        [
+            (7, "s0".to_string(), PartitionType::SolarisData, 42),
+            (8, "s1".to_string(), PartitionType::SolarisSwap, 52)
+        ]
+    );
+
+    // 'c' at 0 means the offsets are relative to the slice, and a
+    // plain swap partition stays swap
+    let mut img = fake_mbr_disk(&[(0xa5, 8, 32), (0x82, 40, 24)]);
+    put_bsd_label(&mut img, 8, &[(8, 8, 7), (0, 0, 0), (32, 0, 0)]);
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(
+        children(&disk, 0),
+        [(5, "a".to_string(), PartitionType::FreeBsdUfs, 16)]
+    );
+    assert_eq!(disk.partitions[1].p_type, PartitionType::LinuxSwap);
+    assert!(disk.partitions[1].p_children.is_empty());
+}
+
+// LDM records are length prefixed numbers and strings
+fn ldm_var(bytes: &[u8]) -> Vec<u8> {
+    [&[bytes.len() as u8], bytes].concat()
+}
+fn ldm_num(n: u64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    ldm_var(&bytes[skip..])
+}
+fn ldm_record(
+    rec_type: u8,
+    id: u64,
+    name: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let data = [ldm_num(id), ldm_var(name.as_bytes()), body.to_vec()]
+        .concat();
+    let mut record = vec![0, 0, 0, rec_type];
+    record.extend((data.len() as u32).to_be_bytes());
+    record.extend(data);
+    record
+}
+fn ldm_volume(id: u64, name: &str, sectors: u64) -> Vec<u8> {
+    let body = [
+        ldm_var(b"gen"),
+        ldm_var(&[0; 8]),
+        vec![0; 21],
+        ldm_num(1),
+        vec![0; 16],
+        ldm_num(sectors),
+        vec![0; 4],
+        vec![0x07],
+        vec![0xab; 16],
+    ]
+    .concat();
+    ldm_record(0x51, id, name, &body)
+}
+fn ldm_component(id: u64, name: &str, volume: u64) -> Vec<u8> {
+    let body = [
+        ldm_var(b"ACTIVE"),
+        vec![2, 0, 0, 0, 0],
+        ldm_num(1),
+        vec![0; 16],
+        ldm_num(volume),
+        vec![0],
+    ]
+    .concat();
+    ldm_record(0x32, id, name, &body)
+}
+fn ldm_partition(
+    id: u64,
+    name: &str,
+    (start, volume_offset, sectors): (u64, u64, u64),
+    component: u64,
+    disk: u64,
+) -> Vec<u8> {
+    let body = [
+        vec![0; 12],
+        start.to_be_bytes().to_vec(),
+        volume_offset.to_be_bytes().to_vec(),
+        ldm_num(sectors),
+        ldm_num(component),
+        ldm_num(disk),
+    ]
+    .concat();
+    ldm_record(0x33, id, name, &body)
+}
+
+const LDM_DISK: &str = "11111111-2222-3333-4444-555555555555";
+const LDM_VBLK: usize = 64;
+
+/* MBR with a 0x42 over the whole disk, PRIVHEAD at 6, data area
+ * from 16 and the database at 192. Volume1 is spanned over two
+ * pieces of this disk, Volume2 lives on a disk we don't have. Every
+ * record is bigger than a 64 byte VBLK so they're all fragmented. */
+fn fake_ldm_disk() -> Vec<u8> {
+    let sectors = 256;
+    let mut img = vec![0u8; sectors * 512];
+    put_mbr_entry(&mut img, 0, 0, (0x42, 1, sectors as u32 - 1));
+    let be64 = |img: &mut [u8], at: usize, v: u64| {
+        img[at..at + 8].copy_from_slice(&v.to_be_bytes())
+    };
+    let ph = 6 * 512;
+    img[ph..ph + 8].copy_from_slice(b"PRIVHEAD");
+    img[ph + 12..ph + 16].copy_from_slice(&[0, 2, 0, 11]);
+    img[ph + 0x30..ph + 0x30 + 36]
+        .copy_from_slice(LDM_DISK.as_bytes());
+    be64(&mut img, ph + 0x11B, 16);
+    be64(&mut img, ph + 0x123, 128);
+    be64(&mut img, ph + 0x12B, 192);
+    be64(&mut img, ph + 0x133, 64);
+
+    let toc = 193 * 512;
+    img[toc..toc + 8].copy_from_slice(b"TOCBLOCK");
+    img[toc + 0x24..toc + 0x2A].copy_from_slice(b"config");
+    be64(&mut img, toc + 0x2E, 17);
+
+    let records = [
+        ldm_record(
+            0x44,
+            1,
+            "Disk1",
+            uuid::Uuid::parse_str(LDM_DISK).unwrap().as_bytes(),
+        ),
+        ldm_record(
+            0x34,
+            2,
+            "Disk2",
+            &[
+                ldm_var(b"99999999-2222-3333-4444-555555555555"),
+                ldm_var(b""),
+            ]
+            .concat(),
+        ),
+        ldm_volume(3, "Volume1", 24),
+        ldm_component(4, "Volume1-01", 3),
+        ldm_partition(5, "Disk1-01", (0, 0, 16), 4, 1),
+        ldm_partition(6, "Disk1-02", (40, 16, 8), 4, 1),
+        ldm_volume(7, "Volume2", 8),
+        ldm_component(8, "Volume2-01", 7),
+        ldm_partition(9, "Disk2-01", (0, 0, 8), 8, 2),
+    ];
+    let vmdb = 209 * 512;
+    let mut vblks = vec![];
+    for (id, record) in records.iter().enumerate() {
+        let chunks: Vec<&[u8]> =
+            record.chunks(LDM_VBLK - 16).collect();
+        for (entry, chunk) in chunks.iter().enumerate() {
+            let mut vblk = vec![0u8; LDM_VBLK];
+            vblk[..4].copy_from_slice(b"VBLK");
+            vblk[8..12]
+                .copy_from_slice(&(id as u32 + 1).to_be_bytes());
+            vblk[12..14]
+                .copy_from_slice(&(entry as u16).to_be_bytes());
+            vblk[14..16].copy_from_slice(
+                &(chunks.len() as u16).to_be_bytes(),
+            );
+            vblk[16..16 + chunk.len()].copy_from_slice(chunk);
+            vblks.push(vblk);
+        }
+    }
+    let first = 512;
+    let last = (first + vblks.len() * LDM_VBLK) / LDM_VBLK;
+    img[vmdb..vmdb + 4].copy_from_slice(b"VMDB");
+    img[vmdb + 4..vmdb + 8]
+        .copy_from_slice(&(last as u32).to_be_bytes());
+    img[vmdb + 8..vmdb + 12]
+        .copy_from_slice(&(LDM_VBLK as u32).to_be_bytes());
+    img[vmdb + 12..vmdb + 16]
+        .copy_from_slice(&(first as u32).to_be_bytes());
+    img[vmdb + 0x12..vmdb + 0x16].copy_from_slice(&[0, 4, 0, 10]);
+    let vblks = vblks.concat();
+    img[vmdb + first..vmdb + first + vblks.len()]
+        .copy_from_slice(&vblks);
+
+    // an NTFS boot sector at the start of Volume1, and something to
+    // find where its second piece starts
+    img[16 * 512 + 3..16 * 512 + 11].copy_from_slice(b"NTFS    ");
+    img[56 * 512..56 * 512 + 6].copy_from_slice(b"piece2");
+    img
+}
+
+#[test]
+fn test_ldm_volumes() {
+    let mut disk = disk_from(fake_ldm_disk(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::LdmData);
+    let db = disk.ldm.as_ref().unwrap();
+    assert_eq!(db.disks.len(), 2);
+    assert_eq!(db.this_disk().unwrap().name, "Disk1");
+
+    assert_eq!(disk.volumes.len(), 2);
+    let spanned = &disk.volumes[0];
+    assert_eq!(spanned.v_name, "Volume1");
+    assert_eq!(spanned.v_layout, "spanned");
+    assert_eq!(spanned.v_type, PartitionType::Ntfs);
+    assert_eq!(spanned.v_size, 24 * 512);
+    let source = spanned.source.as_ref().unwrap();
+    assert_eq!(source.len(), 24 * 512);
+    assert_eq!(source.read_bytes(16 * 512, 6).unwrap(), b"piece2");
+
+    let elsewhere = &disk.volumes[1];
+    assert_eq!(elsewhere.v_layout, "simple");
+    assert!(elsewhere.source.is_none());
+    // never looked inside, so it's whatever the type byte said
+    assert_eq!(elsewhere.v_type, PartitionType::Ntfs);
+}
+
+#[test]
+fn test_ldm_garbage_privhead() {
+    // a database size and a data area start that overflow
+    for (field, value) in [(0x133, u64::MAX), (0x11B, u64::MAX / 2)] {
+        let mut img = fake_ldm_disk();
+        let at = 6 * 512 + field;
+        img[at..at + 8].copy_from_slice(&value.to_be_bytes());
+        let mut disk = disk_from(img, None).unwrap();
+        disk.set_partition_table_type().unwrap();
+        disk.register_partitions().unwrap();
+        assert_eq!(disk.partitions[0].p_type, PartitionType::LdmData);
+        assert!(disk.ldm.is_none());
+        assert!(disk.volumes.is_empty());
+    }
+}
+
+// lib/misc/crc.c done the slow way, to check the table driven one
+fn lvm_crc(bytes: &[u8]) -> u32 {
+    let mut crc = 0xf597a6cfu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 * (crc & 1));
+        }
+    }
+    crc
+}
+
+const LVM_PE_START: u64 = 16; // sectors into the PV
+const LVM_EXTENT: u64 = 8 * 512;
+const LVM_MDA: usize = 4096; // offset and size of the metadata area
+
+// PV at part_lba with its label in sector 1 and the metadata text
+// starting at text_at in the area, wrapping past the end
+fn put_lvm_pv(
+    img: &mut [u8],
+    part_lba: usize,
+    uuid: &str,
+    sectors: u64,
+    text: &str,
+    text_at: usize,
+) {
+    let label = (part_lba + 1) * 512;
+    let l = &mut img[label..label + 512];
+    l[..8].copy_from_slice(b"LABELONE");
+    put_u64(l, 8, 1);
+    put_u32(l, 20, 32);
+    l[24..32].copy_from_slice(b"LVM2 001");
+    l[32..64].copy_from_slice(uuid.as_bytes());
+    put_u64(l, 64, sectors * 512);
+    put_u64(l, 72, LVM_PE_START * 512); // data area, then a 0 entry
+    put_u64(l, 104, LVM_MDA as u64); // metadata area, then a 0 entry
+    put_u64(l, 112, LVM_MDA as u64);
+    let crc = lvm_crc(&l[20..]);
+    put_u32(l, 16, crc);
+
+    let mda = part_lba * 512 + LVM_MDA;
+    let mut text = text.as_bytes().to_vec();
+    text.push(0);
+    for (i, b) in text.iter().enumerate() {
+        let mut at = text_at + i;
+        if at >= LVM_MDA {
+            at = at - LVM_MDA + 512;
+        }
+        img[mda + at] = *b;
+    }
+    let h = &mut img[mda..mda + 512];
+    h[4..20].copy_from_slice(b" LVM2 x[5A%r0N*>");
+    put_u32(h, 20, 1);
+    put_u64(h, 24, LVM_MDA as u64);
+    put_u64(h, 32, LVM_MDA as u64);
+    put_u64(h, 40, text_at as u64);
+    put_u64(h, 48, text.len() as u64);
+    put_u32(h, 56, lvm_crc(&text));
+    let crc = lvm_crc(&h[4..]);
+    put_u32(h, 0, crc);
+}
+
+fn lvm_pv_id(c: char) -> String {
+    [6, 4, 4, 4, 4, 4, 6]
+        .iter()
+        .map(|n| c.to_string().repeat(*n))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn lvm_metadata(seqno: u32, lvs: &str) -> String {
+    format!(
+        "# Generated by LVM2\n\
+         contents = \"Text Format Volume Group\"\n\
+         version = 1\n\
+         description = \"made by \\\"hand\\\"\"\n\n\
+         vg0 {{\n\
+         \tid = \"{vg}\"\n\
+         \tseqno = {seqno}\n\
+         \tstatus = [\"RESIZEABLE\", \"READ\", \"WRITE\"]\n\
+         \textent_size = 8\t# 4 KiB\n\
+         \tphysical_volumes {{\n\
+         \t\tpv0 {{\n\
+         \t\t\tid = \"{a}\"\n\
+         \t\t\tdevice = \"/dev/sda1\"\t# Hint only\n\
+         \t\t\tpe_start = 16\n\
+         \t\t\tpe_count = 13\n\
+         \t\t}}\n\
+         \t\tpv1 {{\n\
+         \t\t\tid = \"{b}\"\n\
+         \t\t\tpe_start = 16\n\
+         \t\t\tpe_count = 14\n\
+         \t\t}}\n\
+         \t\tpv2 {{\n\
+         \t\t\tid = \"{c}\"\n\
+         \t\t\tpe_start = 16\n\
+         \t\t\tpe_count = 100\n\
+         \t\t}}\n\
+         \t}}\n\
+         \tlogical_volumes {{\n{lvs}\t}}\n\
+         }}\n",
+        vg = lvm_pv_id('v'),
+        a = lvm_pv_id('a'),
+        b = lvm_pv_id('b'),
+        c = lvm_pv_id('c'),
+    )
+}
+
+fn lvm_lv(
+    name: &str,
+    status: &str,
+    segments: &[(u64, u64, &str)],
+) -> String {
+    let mut lv = format!(
+        "\t\t{} {{\n\t\t\tid = \"{}\"\n\t\t\tstatus = [{}]\n\
+         \t\t\tsegment_count = {}\n",
+        name,
+        lvm_pv_id('l'),
+        status,
+        segments.len()
+    );
+    for (i, (start, count, body)) in segments.iter().enumerate() {
+        lv += &format!(
+            "\t\t\tsegment{} {{\n\t\t\t\tstart_extent = {}\n\
+             \t\t\t\textent_count = {}\n{}\t\t\t}}\n",
+            i + 1,
+            start,
+            count,
+            body
+        );
+    }
+    lv + "\t\t}\n"
+}
+
+fn lvm_linear(pv: &str, extent: u64) -> String {
+    format!(
+        "\t\t\t\ttype = \"striped\"\n\t\t\t\tstripe_count = 1\n\
+         \t\t\t\tstripes = [\n\t\t\t\t\t\"{}\", {}\n\t\t\t\t]\n",
+        pv, extent
+    )
+}
+
+/* Two PVs on an MBR disk, sda1 (1..128) and sda2 (128..256). vg0
+ * has root linear over both, data striped over both with 2KB
+ * stripes, one LV on a PV that isn't here, a thin pool and a hidden
+ * LV. sda2's copy of the metadata is older and only knows root. */
+fn fake_lvm_disk() -> Vec<u8> {
+    let mut img = vec![0u8; 256 * 512];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    put_mbr_entry(&mut img, 0, 0, (0x8e, 1, 127));
+    put_mbr_entry(&mut img, 0, 1, (0x8e, 128, 128));
+    let visible = "\"READ\", \"WRITE\", \"VISIBLE\"";
+    let root = lvm_lv(
+        "root",
+        visible,
+        &[
+            (0, 2, &lvm_linear("pv0", 0)),
+            (2, 1, &lvm_linear("pv1", 0)),
+        ],
+    );
+    let data = lvm_lv(
+        "data",
+        visible,
+        &[(
+            0,
+            4,
+            "\t\t\t\ttype = \"striped\"\n\t\t\t\tstripe_count = 2\n\
+             \t\t\t\tstripe_size = 4\n\
+             \t\t\t\tstripes = [\"pv0\", 2, \"pv1\", 1]\n",
+        )],
+    );
+    let elsewhere = lvm_lv(
+        "elsewhere",
+        visible,
+        &[(0, 5, &lvm_linear("pv2", 0))],
+    );
+    let pool = lvm_lv(
+        "pool",
+        visible,
+        &[(0, 1, "\t\t\t\ttype = \"thin-pool\"\n\t\t\t\tmetadata = \"pool_tmeta\"\n")],
+    );
+    let hidden = lvm_lv(
+        "pool_tmeta",
+        "\"READ\", \"WRITE\"",
+        &[(0, 1, &lvm_linear("pv1", 3))],
+    );
+    let new = lvm_metadata(
+        3,
+        &(root.clone() + &data + &elsewhere + &pool + &hidden),
+    );
+    let old = lvm_metadata(1, &root);
+    // the newest copy wraps around the end of the ring
+    put_lvm_pv(&mut img, 1, &"a".repeat(32), 127, &new, 3000);
+    put_lvm_pv(&mut img, 128, &"b".repeat(32), 128, &old, 512);
+
+    let pv0 = (1 + LVM_PE_START) as usize * 512;
+    let pv1 = (128 + LVM_PE_START) as usize * 512;
+    let extent = LVM_EXTENT as usize;
+    // root: ext4 magic at the start, then the second segment
+    img[pv0 + 1024 + 0x38..pv0 + 1024 + 0x3a]
+        .copy_from_slice(&[0x53, 0xef]);
+    img[pv1..pv1 + 5].copy_from_slice(b"root2");
+    // data: chunk 0 at pv0 extent 2, chunk 1 at pv1 extent 1
+    img[pv0 + 2 * extent..pv0 + 2 * extent + 4]
+        .copy_from_slice(b"str0");
+    img[pv1 + extent..pv1 + extent + 4].copy_from_slice(b"str1");
+    img
+}
+
+#[test]
+fn test_lvm_volumes() {
+    let mut disk = disk_from(fake_lvm_disk(), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::LinuxLvm);
+    assert_eq!(disk.lvm.len(), 1);
+    let vg = &disk.lvm[0];
+    assert_eq!((vg.name.as_str(), vg.seqno), ("vg0", 3));
+    assert_eq!(vg.lvs.len(), 5);
+
+    let names: Vec<&str> =
+        disk.volumes.iter().map(|v| v.v_name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["vg0/root", "vg0/data", "vg0/elsewhere", "vg0/pool"]
+    );
+
+    let root = &disk.volumes[0];
+    assert_eq!(root.v_layout, "linear");
+    assert_eq!(root.v_type, PartitionType::Ext4);
+    assert_eq!(root.v_size, 3 * LVM_EXTENT);
+    let source = root.source.as_ref().unwrap();
+    assert_eq!(source.len(), 3 * LVM_EXTENT);
+    assert_eq!(
+        source.read_bytes(2 * LVM_EXTENT, 5).unwrap(),
+        b"root2"
+    );
+
+    let data = &disk.volumes[1];
+    assert_eq!(data.v_layout, "striped");
+    assert_eq!(data.v_type, PartitionType::LinuxFsTBD);
+    let source = data.source.as_ref().unwrap();
+    assert_eq!(source.len(), 4 * LVM_EXTENT);
+    assert_eq!(source.read_bytes(0, 4).unwrap(), b"str0");
+    assert_eq!(source.read_bytes(2048, 4).unwrap(), b"str1");
+    assert!(disk.make_ext4_volume_reader(data).is_err());
+
+    assert!(disk.volumes[2].source.is_none());
+    assert_eq!(disk.volumes[3].v_layout, "thin-pool");
+    assert!(disk.volumes[3].source.is_none());
+}
+
+#[test]
+fn test_lvm_bad_label_falls_back() {
+    let mut img = fake_lvm_disk();
+    img[2 * 512 + 40] ^= 0xff; // sda1's PV header, under the label crc
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    // only sda2 is left, with its older metadata
+    assert_eq!(disk.lvm.len(), 1);
+    assert_eq!(disk.lvm[0].seqno, 1);
+    assert_eq!(disk.volumes.len(), 1);
+    assert!(disk.volumes[0].source.is_none());
+}
+
+fn lvm_disk_with(text: &str) -> Disk {
+    let mut img = vec![0u8; 256 * 512];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    put_mbr_entry(&mut img, 0, 0, (0x8e, 1, 127));
+    put_mbr_entry(&mut img, 0, 1, (0x8e, 128, 128));
+    put_lvm_pv(&mut img, 1, &"a".repeat(32), 127, text, 512);
+    put_lvm_pv(&mut img, 128, &"b".repeat(32), 128, text, 512);
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    disk
+}
+
+#[test]
+fn test_lvm_garbage_metadata() {
+    let visible = "\"READ\", \"WRITE\", \"VISIBLE\"";
+    // striped without saying where, the whole group is unusable
+    let stripeless = lvm_lv(
+        "stripeless",
+        visible,
+        &[(0, 2, "\t\t\t\ttype = \"striped\"\n")],
+    );
+    let disk = lvm_disk_with(&lvm_metadata(1, &stripeless));
+    assert!(disk.lvm.is_empty());
+    assert!(disk.volumes.is_empty());
+
+    // extents too big to be bytes, only that LV goes
+    let root =
+        lvm_lv("root", visible, &[(0, 2, &lvm_linear("pv0", 0))]);
+    let text = lvm_metadata(1, &root).replace(
+        "extent_size = 8",
+        "extent_size = 9223372036854775807",
+    );
+    let mut disk = lvm_disk_with(&text);
+    assert_eq!(disk.lvm.len(), 1);
+    assert!(disk.volumes.is_empty());
+    disk.print_partitions_pretty().unwrap();
+}
+
+// md's checksum: the u32 words summed with its own as 0, then folded
+fn md_sum(bytes: &mut [u8], csum_at: usize) {
+    put_u32(bytes, csum_at, 0);
+    let sum: u64 = bytes
+        .chunks(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()) as u64)
+        .sum();
+    put_u32(
+        bytes,
+        csum_at,
+        ((sum & 0xffffffff) + (sum >> 32)) as u32,
+    );
+}
+
+struct MdMemberSpec {
+    level: u32,
+    raid_disks: u32,
+    slot: u16,
+    events: u64,
+}
+
+// v1.2, so 4K in, data from sector 16 for `sectors`
+fn put_md_v1(
+    img: &mut [u8],
+    start: usize,
+    m: &MdMemberSpec,
+    sectors: u64,
+) {
+    let sb = &mut img[start + 4096..start + 4096 + 512];
+    put_u32(sb, 0, 0xa92b4efc);
+    put_u32(sb, 4, 1);
+    sb[16..32].copy_from_slice(&[0x5a; 16]);
+    sb[32..42].copy_from_slice(b"server:md0");
+    put_u32(sb, 72, m.level);
+    if m.level == 1 {
+        put_u64(sb, 80, sectors);
+    }
+    put_u32(sb, 88, 8); // 4K chunks
+    put_u32(sb, 92, m.raid_disks);
+    put_u64(sb, 128, 16);
+    put_u64(sb, 136, sectors);
+    put_u64(sb, 144, 8);
+    put_u32(sb, 160, m.slot as u32);
+    put_u64(sb, 200, m.events);
+    put_u32(sb, 220, m.raid_disks);
+    let role = 256 + m.slot as usize * 2;
+    sb[role..role + 2].copy_from_slice(&m.slot.to_le_bytes());
+    md_sum(&mut sb[..256 + m.raid_disks as usize * 2], 216);
+}
+
+// v0.90 on a 128K member, the superblock at 64K and data before it
+fn fake_md_v090(m: &MdMemberSpec) -> Vec<u8> {
+    let mut img = vec![0u8; 128 * 1024];
+    let sb = &mut img[64 * 1024..68 * 1024];
+    let mut word = |i: usize, v: u32| put_u32(sb, i * 4, v);
+    word(0, 0xa92b4efc);
+    word(2, 90);
+    word(5, 0x11111111);
+    word(7, m.level);
+    word(8, 64); // KB
+    word(10, m.raid_disks);
+    word(13, 0x22222222);
+    word(14, 0x33333333);
+    word(15, 0x44444444);
+    word(39, m.events as u32);
+    word(65, 4096);
+    word(992 + 3, m.slot as u32);
+    word(992 + 4, 6); // active, sync
+    md_sum(sb, 38 * 4);
+    img
+}
+
+fn md_member(name: &str, img: Vec<u8>) -> md::MdMember {
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new(name, img));
+    md::MdMember {
+        name: name.to_string(),
+        superblock: md::MdSuperblock::probe(&*source)
+            .unwrap()
+            .unwrap(),
+        source,
+    }
+}
+
+fn raid1_member(slot: u16, events: u64) -> md::MdMember {
+    let mut img = vec![0u8; 48 * 512];
+    let m = MdMemberSpec {
+        level: 1,
+        raid_disks: 2,
+        slot,
+        events,
+    };
+    put_md_v1(&mut img, 0, &m, 32);
+    img[16 * 512..16 * 512 + 6].copy_from_slice(b"mirror");
+    md_member(&format!("disk{}", slot), img)
+}
+
+#[test]
+fn test_md_raid1() {
+    let a = raid1_member(0, 10);
+    let sb = &a.superblock;
+    assert_eq!(sb.version, md::MdVersion::V12);
+    assert_eq!(sb.name, "server:md0");
+    assert_eq!(sb.role, md::MdRole::Active(0));
+    assert_eq!((sb.level, sb.events), (1, 10));
+    assert_eq!(
+        sb.uuid_string(),
+        "5a5a5a5a:5a5a5a5a:5a5a5a5a:5a5a5a5a"
+    );
+
+    let array =
+        md::MdArray::assemble(vec![a.clone(), raid1_member(1, 10)])
+            .unwrap();
+    assert!(!array.degraded);
+    assert_eq!(array.source.len(), 32 * 512);
+    assert_eq!(array.source.read_bytes(0, 6).unwrap(), b"mirror");
+
+    // the second disk missed a write, so it's left out
+    let array =
+        md::MdArray::assemble(vec![raid1_member(1, 9), a]).unwrap();
+    assert!(array.degraded);
+    assert_eq!(array.stale, ["disk1"]);
+    assert_eq!(array.members.len(), 1);
+    assert_eq!(array.source.read_bytes(0, 6).unwrap(), b"mirror");
+}
+
+#[test]
+fn test_md_raid0_v090() {
+    let spec = |slot| MdMemberSpec {
+        level: 0,
+        raid_disks: 2,
+        slot,
+        events: 3,
+    };
+    let mut first = fake_md_v090(&spec(0));
+    let mut second = fake_md_v090(&spec(1));
+    first[4096..4100].copy_from_slice(b"chk2");
+    second[..4].copy_from_slice(b"chk1");
+    let first = md_member("sda1", first);
+    let second = md_member("sdb1", second);
+    assert_eq!(first.superblock.version, md::MdVersion::V090);
+    assert_eq!(
+        first.superblock.uuid_string(),
+        "11111111:22222222:33333333:44444444"
+    );
+
+    let array =
+        md::MdArray::assemble(vec![second.clone(), first.clone()])
+            .unwrap();
+    assert_eq!(array.source.len(), 128 * 1024);
+    assert_eq!(array.source.read_bytes(4096, 4).unwrap(), b"chk1");
+    assert_eq!(array.source.read_bytes(8192, 4).unwrap(), b"chk2");
+    assert!(md::MdArray::assemble(vec![first]).is_err());
+}
+
+#[test]
+fn test_md_bad_checksum() {
+    let mut img = fake_md_v090(&MdMemberSpec {
+        level: 1,
+        raid_disks: 2,
+        slot: 0,
+        events: 1,
+    });
+    img[64 * 1024 + 7 * 4] ^= 1;
+    let source = MemorySource::new("bad", img);
+    assert!(matches!(
+        md::MdSuperblock::probe(&source),
+        Err(PurdError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_md_garbage_superblock() {
+    let img = fake_md_v090(&MdMemberSpec {
+        level: 0,
+        raid_disks: u32::MAX,
+        slot: 0,
+        events: 1,
+    });
+    let source = MemorySource::new("huge", img);
+    assert!(matches!(
+        md::MdSuperblock::probe(&source),
+        Err(PurdError::Unsupported(_))
+    ));
+
+    // v1 with a data area that wraps around
+    let mut img = vec![0u8; 48 * 512];
+    let m = MdMemberSpec {
+        level: 1,
+        raid_disks: 2,
+        slot: 0,
+        events: 1,
+    };
+    put_md_v1(&mut img, 0, &m, 32);
+    let sb = &mut img[4096..4096 + 512];
+    put_u64(sb, 128, u64::MAX / 2);
+    md_sum(&mut sb[..256 + 4], 216);
+    let source = MemorySource::new("wraps", img);
+    assert!(matches!(
+        md::MdSuperblock::probe(&source),
+        Err(PurdError::OutOfBounds { .. })
+    ));
+}
+
+// an md RAID1 partition with a PV on the array, vg0/root on it
+#[test]
+fn test_md_partition_with_lvm() {
+    let mut img = vec![0u8; 320 * 512];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    put_mbr_entry(&mut img, 0, 0, (0xfd, 1, 300));
+    let spec = MdMemberSpec {
+        level: 1,
+        raid_disks: 2,
+        slot: 1,
+        events: 5,
+    };
+    put_md_v1(&mut img, 512, &spec, 256);
+    let root = lvm_lv(
+        "root",
+        "\"READ\", \"WRITE\", \"VISIBLE\"",
+        &[(0, 2, &lvm_linear("pv0", 0))],
+    );
+    // the array's data starts 16 sectors into the partition
+    put_lvm_pv(
+        &mut img,
+        17,
+        &"a".repeat(32),
+        256,
+        &lvm_metadata(2, &root),
+        512,
+    );
+    let pv0 = (17 + LVM_PE_START) as usize * 512;
+    img[pv0..pv0 + 4].copy_from_slice(b"root");
+
+    let mut disk = disk_from(img, None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::LinuxRaid);
+    assert_eq!(disk.md.len(), 1);
+    assert_eq!(disk.md[0].superblock.role, md::MdRole::Active(1));
+    let names: Vec<&str> =
+        disk.volumes.iter().map(|v| v.v_name.as_str()).collect();
+    assert_eq!(names, ["md server:md0", "vg0/root"]);
+    assert_eq!(disk.volumes[0].v_layout, "raid1");
+    assert_eq!(disk.volumes[0].v_size, 256 * 512);
+    let root = disk.volumes[1].source.as_ref().unwrap();
+    assert_eq!(root.read_bytes(0, 4).unwrap(), b"root");
+}
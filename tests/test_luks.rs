@@ -0,0 +1,369 @@
+use purd::headers::fs::disk::{Disk, PartitionType};
+use purd::headers::luks::*;
+use purd::headers::reader;
+use purd::headers::source::*;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+const HDR_SIZE: usize = 0x4000;
+
+fn put_u32_be(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64_be(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+const LUKS2_JSON: &str = r#"{
+  "keyslots": {
+    "0": {"type": "luks2", "key_size": 64,
+      "af": {"type": "luks1", "stripes": 4000, "hash": "sha256"},
+      "area": {"type": "raw", "offset": "32768", "size": "258048",
+        "encryption": "aes-xts-plain64", "key_size": 64},
+      "kdf": {"type": "argon2id", "time": 4, "memory": 1048576,
+        "cpus": 4, "salt": "c2FsdHNhbHRzYWx0c2FsdA=="}},
+    "10": {"type": "luks2", "key_size": 64, "priority": 2,
+      "af": {"type": "luks1", "stripes": 4000, "hash": "sha256"},
+      "area": {"type": "raw", "offset": "290816", "size": "258048",
+        "encryption": "aes-xts-plain64", "key_size": 64},
+      "kdf": {"type": "pbkdf2", "hash": "sha512",
+        "iterations": 1000, "salt": "c2FsdA=="}}
+  },
+  "tokens": {
+    "0": {"type": "systemd-tpm2", "keyslots": ["10"],
+      "tpm2-pcrs": [7]}
+  },
+  "segments": {
+    "0": {"type": "crypt", "offset": "16777216", "size": "dynamic",
+      "iv_tweak": "0", "encryption": "aes-xts-plain64",
+      "sector_size": 4096}
+  },
+  "digests": {
+    "0": {"type": "pbkdf2", "keyslots": ["0", "10"], "segments": ["0"],
+      "hash": "sha256", "iterations": 117870,
+      "salt": "c2FsdA==", "digest": "ZGlnZXN0"}
+  },
+  "config": {"json_size": "12288", "keyslots_size": "16744448"}
+}"#;
+
+// one copy of the binary header and JSON, checksummed
+fn luks2_copy(
+    magic: &[u8; 6],
+    offset: u64,
+    seqid: u64,
+    json: &str,
+) -> Vec<u8> {
+    let mut copy = vec![0u8; HDR_SIZE];
+    copy[..6].copy_from_slice(magic);
+    copy[6..8].copy_from_slice(&2u16.to_be_bytes());
+    put_u64_be(&mut copy, 8, HDR_SIZE as u64);
+    put_u64_be(&mut copy, 16, seqid);
+    copy[24..28].copy_from_slice(b"data");
+    copy[72..78].copy_from_slice(b"sha256");
+    let uuid = b"0b8c0d4e-6a0b-4b79-9d5c-3b0e0f6c1d2a";
+    copy[168..168 + uuid.len()].copy_from_slice(uuid);
+    put_u64_be(&mut copy, 256, offset);
+    copy[4096..4096 + json.len()].copy_from_slice(json.as_bytes());
+    let csum = Sha256::digest(&copy);
+    copy[448..448 + 32].copy_from_slice(&csum);
+    copy
+}
+
+fn fake_luks2(seqids: (u64, u64)) -> Vec<u8> {
+    fake_luks2_json(seqids, LUKS2_JSON)
+}
+
+fn fake_luks2_json(seqids: (u64, u64), json: &str) -> Vec<u8> {
+    let mut img = vec![0u8; 4 * HDR_SIZE];
+    img[..HDR_SIZE]
+        .copy_from_slice(&luks2_copy(LUKS_MAGIC, 0, seqids.0, json));
+    img[HDR_SIZE..2 * HDR_SIZE].copy_from_slice(&luks2_copy(
+        LUKS2_SECONDARY_MAGIC,
+        HDR_SIZE as u64,
+        seqids.1,
+        json,
+    ));
+    img
+}
+
+fn luks2(img: Vec<u8>) -> Luks2Header {
+    match LuksHeader::probe(&MemorySource::new("luks", img)) {
+        Ok(Some(LuksHeader::Luks2(header))) => *header,
+        other => panic!("not LUKS2: {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_luks2_metadata() {
+    let header = luks2(fake_luks2((7, 7)));
+    assert_eq!(header.binary.seqid, 7);
+    assert_eq!(
+        header.copies,
+        [(0, 7, true), (HDR_SIZE as u64, 7, true)]
+    );
+
+    let m = &header.metadata;
+    let segment = &m.segments["0"];
+    assert_eq!(segment.offset, 16 * 1024 * 1024);
+    assert_eq!(segment.size, None);
+    assert_eq!(segment.encryption, "aes-xts-plain64");
+    assert_eq!(segment.sector_size, 4096);
+
+    assert_eq!(m.keyslots.len(), 2);
+    assert!(matches!(
+        m.keyslots["0"].kdf,
+        Luks2Kdf::Argon2id {
+            time: 4,
+            memory: 1048576,
+            cpus: 4,
+            ..
+        }
+    ));
+    let pbkdf2 = &m.keyslots["10"];
+    assert!(matches!(
+        &pbkdf2.kdf,
+        Luks2Kdf::Pbkdf2 { hash, iterations: 1000, .. } if hash == "sha512"
+    ));
+    assert_eq!(pbkdf2.area.offset, 290816);
+    assert_eq!(pbkdf2.af.as_ref().unwrap().stripes, 4000);
+    assert_eq!(m.digests["0"].keyslots, ["0", "10"]);
+    assert_eq!(m.tokens["0"].token_type, "systemd-tpm2");
+    assert_eq!(m.config.keyslots_size, 16744448);
+}
+
+#[test]
+fn test_luks2_bad_copy() {
+    // a damaged primary, the secondary has the same metadata
+    let mut img = fake_luks2((7, 7));
+    img[4096 + 20] ^= 1;
+    let header = luks2(img.clone());
+    assert_eq!(
+        header.copies,
+        [(0, 7, false), (HDR_SIZE as u64, 7, true)]
+    );
+    assert_eq!(header.binary.hdr_offset, HDR_SIZE as u64);
+
+    // both broken is an error
+    img[HDR_SIZE + 4096 + 20] ^= 1;
+    assert!(
+        LuksHeader::probe(&MemorySource::new("luks", img)).is_err()
+    );
+
+    // the newer copy wins when both are fine
+    let header = luks2(fake_luks2((8, 9)));
+    assert_eq!(header.binary.seqid, 9);
+}
+
+fn fake_luks1() -> Vec<u8> {
+    let mut img = vec![0u8; 4096];
+    img[..6].copy_from_slice(LUKS_MAGIC);
+    img[6..8].copy_from_slice(&1u16.to_be_bytes());
+    img[8..11].copy_from_slice(b"aes");
+    img[40..51].copy_from_slice(b"xts-plain64");
+    img[72..78].copy_from_slice(b"sha256");
+    put_u32_be(&mut img, 104, 4096); // payload
+    put_u32_be(&mut img, 108, 64); // key bytes
+    put_u32_be(&mut img, 164, 50000); // digest iterations
+    img[168..172].copy_from_slice(b"uuid");
+    // keyslot 1 enabled, the rest disabled
+    for slot in 0..8 {
+        let at = 208 + slot * 48;
+        let active = if slot == 1 { 0x00ac71f3 } else { 0x0000dead };
+        put_u32_be(&mut img, at, active);
+        put_u32_be(&mut img, at + 4, 2000);
+        put_u32_be(&mut img, at + 40, 8 + slot as u32 * 512);
+        put_u32_be(&mut img, at + 44, 4000);
+    }
+    img
+}
+
+#[test]
+fn test_luks1_header() {
+    let header =
+        LuksHeader::probe(&MemorySource::new("luks1", fake_luks1()))
+            .unwrap()
+            .unwrap();
+    assert_eq!(header.uuid(), "uuid");
+    let h = match header {
+        LuksHeader::Luks1(h) => h,
+        _ => panic!("not LUKS1"),
+    };
+    assert_eq!(h.payload_offset, 4096);
+    assert_eq!(h.key_bytes, 64);
+    assert!(h.keyslot_enabled(1));
+    assert!(!h.keyslot_enabled(0));
+    assert_eq!(h.keyslots[1].key_material_offset, 520);
+    assert_eq!(h.keyslots[1].stripes, 4000);
+
+    // a key size nobody would use still prints
+    let mut img = fake_luks1();
+    put_u32_be(&mut img, 108, u32::MAX);
+    LuksHeader::probe(&MemorySource::new("luks1", img))
+        .unwrap()
+        .unwrap()
+        .print();
+}
+
+// an 0x83 partition that turns out to be LUKS2
+#[test]
+fn test_luks_partition() {
+    let luks = fake_luks2((1, 1));
+    let mut img = vec![0u8; 512 + luks.len()];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    img[446 + 4] = 0x83;
+    img[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    let sectors = (luks.len() / 512) as u32;
+    img[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+    img[512..].copy_from_slice(&luks);
+
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", img));
+    let mut disk =
+        Disk::new(reader::from_source(source), None).unwrap();
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.partitions[0].p_type, PartitionType::Luks);
+    assert_eq!(disk.luks.len(), 1);
+    assert_eq!(
+        disk.luks[0].header.uuid(),
+        "0b8c0d4e-6a0b-4b79-9d5c-3b0e0f6c1d2a"
+    );
+    disk.print_partitions_pretty().unwrap();
+}
+
+/* made by tests/make_luks.py, see there for what's inside */
+const PASSPHRASE: &[u8] = b"purd passphrase";
+
+fn fixture(name: &str) -> Arc<dyn BlockSource> {
+    let gz = std::fs::read(format!("tests/{}", name)).unwrap();
+    let mut img = vec![];
+    std::io::Read::read_to_end(
+        &mut flate2::read::GzDecoder::new(&gz[..]),
+        &mut img,
+    )
+    .unwrap();
+    Arc::new(MemorySource::new(name, img))
+}
+
+fn device(source: Arc<dyn BlockSource>) -> LuksDevice {
+    LuksDevice {
+        name: source.name(),
+        header: LuksHeader::probe(&*source).unwrap().unwrap(),
+        source,
+        keyslot: None,
+    }
+}
+
+fn keyfile() -> Vec<u8> {
+    std::fs::read("tests/luks_keyfile").unwrap()
+}
+
+#[test]
+fn test_luks2_unlock() {
+    let luks = device(fixture("luks2_ext4.img.gz"));
+    assert_eq!(luks.header.cipher(), "aes-xts-plain64");
+
+    // the argon2id keyslot, after a wrong passphrase
+    let keys = vec![b"not it".to_vec(), PASSPHRASE.to_vec()];
+    let (keyslot, data) = luks.open(&keys).unwrap();
+    assert_eq!(keyslot, "0");
+    assert_eq!(data.len(), 64 * 1024);
+    assert_eq!(data.sector_size(), 4096);
+    let mut ext4 = purd::headers::ext4::reader::Part::from_source(
+        data.clone(),
+        0,
+    )
+    .unwrap();
+    assert!(ext4.s.volume_name().starts_with("purdluks"));
+    ext4.populate_blocks().unwrap();
+
+    // the keyfile only opens the pbkdf2 one
+    let (keyslot, by_keyfile) = luks.open(&[keyfile()]).unwrap();
+    assert_eq!(keyslot, "1");
+    // reads that don't line up with the 4K sectors
+    assert_eq!(
+        by_keyfile.read_bytes(1000, 5000).unwrap(),
+        data.read_bytes(1000, 5000).unwrap()
+    );
+    assert_eq!(
+        &data.read_bytes(1024 + 0x38, 2).unwrap()[..],
+        [0x53, 0xef]
+    );
+}
+
+#[test]
+fn test_luks_wrong_key() {
+    let luks = device(fixture("luks2_ext4.img.gz"));
+    assert!(matches!(
+        luks.open(&[b"purd passphrase\n".to_vec()]),
+        Err(purd::headers::error::PurdError::WrongKey(_))
+    ));
+    assert!(luks.open(&[]).is_err());
+}
+
+#[test]
+fn test_luks2_argon2_limits() {
+    // 4 TiB of argon2 memory, that keyslot gets skipped instead of
+    // taking the process down with it
+    let json = LUKS2_JSON.replace("1048576", "4294967295");
+    let img = fake_luks2_json((1, 1), &json);
+    let luks = device(Arc::new(MemorySource::new("luks", img)));
+    assert!(matches!(
+        luks.open(&[PASSPHRASE.to_vec()]),
+        Err(purd::headers::error::PurdError::WrongKey(_))
+    ));
+}
+
+#[test]
+fn test_luks1_unlock() {
+    let luks = device(fixture("luks1.img.gz"));
+    assert_eq!(luks.header.cipher(), "aes-xts-plain64");
+    assert!(luks.open(&[keyfile()]).is_err());
+    let (keyslot, data) = luks.open(&[PASSPHRASE.to_vec()]).unwrap();
+    assert_eq!(keyslot, "2");
+    assert_eq!(data.len(), 8192);
+    assert_eq!(data.sector_size(), 512);
+    let text = data.read_bytes(0, 8192).unwrap();
+    assert!(text.starts_with(b"purd LUKS1 sector 0000\n"));
+    // sector 22 starts partway into line 21, past the first sector
+    let at = 21 * 23;
+    assert_eq!(
+        &data.read_bytes(at, 23).unwrap()[..],
+        b"purd LUKS1 sector 0021\n"
+    );
+}
+
+// LUKS2 in an 0x83 partition, ext4 inside once it's unlocked
+#[test]
+fn test_luks_partition_unlock() {
+    let luks = fixture("luks2_ext4.img.gz").read_bytes(0, 112 * 1024);
+    let luks = luks.unwrap();
+    let mut img = vec![0u8; 512 + luks.len()];
+    img[510] = 0x55;
+    img[511] = 0xaa;
+    img[446 + 4] = 0x83;
+    img[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+    let sectors = (luks.len() / 512) as u32;
+    img[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+    img[512..].copy_from_slice(&luks);
+
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", img));
+    let mut disk =
+        Disk::new(reader::from_source(source), None).unwrap();
+    disk.luks_keys = vec![keyfile()];
+    disk.set_partition_table_type().unwrap();
+    disk.register_partitions().unwrap();
+    assert_eq!(disk.luks[0].keyslot.as_deref(), Some("1"));
+    let volume = &disk.volumes[0];
+    assert_eq!(volume.v_type, PartitionType::Ext4);
+    assert_eq!(volume.v_layout, "aes-xts-plain64");
+    assert_eq!(volume.v_size, 64 * 1024);
+    disk.make_ext4_volume_reader(volume)
+        .unwrap()
+        .populate_blocks()
+        .unwrap();
+    disk.print_partitions_pretty().unwrap();
+}
@@ -0,0 +1,775 @@
+use purd::headers::error::PurdError;
+use purd::headers::mbr::Mbr;
+use purd::headers::reader::{self, HasHeaderMagic};
+use purd::headers::source::*;
+use std::io::Write;
+use std::sync::Arc;
+
+fn fake_mbr_disk() -> Vec<u8> {
+    let mut bytes = vec![0u8; 4096];
+    bytes[510] = 0x55;
+    bytes[511] = 0xaa;
+    // one partition entry so there's something to look at
+    bytes[446 + 4] = 0x83;
+    bytes[446 + 8..446 + 12].copy_from_slice(&2u32.to_le_bytes());
+    bytes[446 + 12..446 + 16].copy_from_slice(&4u32.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn test_memory_source_reads_mbr() {
+    let source: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", fake_mbr_disk()));
+    let mut ondisk = reader::from_source(source.clone());
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert!(mbr.check_magic_field(source.as_ref(), 0).unwrap());
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    assert_eq!(mbr.partitions[0].sectors_in_partition, 4);
+}
+
+#[test]
+fn test_sub_source_window() {
+    let mut bytes = fake_mbr_disk();
+    bytes[1024..1028].copy_from_slice(b"PURD");
+    let parent: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("fake", bytes));
+    let sub = SubSource::new(parent.clone(), 1024, 2048).unwrap();
+    assert_eq!(sub.len(), 2048);
+    assert_eq!(sub.read_bytes(0, 4).unwrap(), b"PURD".to_vec());
+    assert!(matches!(
+        sub.read_bytes(2046, 4),
+        Err(PurdError::OutOfBounds { .. })
+    ));
+    assert!(SubSource::new(parent, 4000, 200).is_err());
+}
+
+fn write_temp(name: &str, bytes: &[u8]) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "purd-test-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, bytes).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn check_mbr_image(path: &str) {
+    let mut ondisk = reader::new(path).unwrap();
+    assert_eq!(ondisk.source.len(), 4096);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert!(mbr.check_magic_field(&ondisk, 0).unwrap());
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_gzip_image() {
+    let mut encoder = flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    );
+    encoder.write_all(&fake_mbr_disk()).unwrap();
+    let path = write_temp("mbr.img.gz", &encoder.finish().unwrap());
+    check_mbr_image(&path);
+}
+
+#[test]
+fn test_xz_image() {
+    let mut xz = vec![];
+    lzma_rs::xz_compress(&mut &fake_mbr_disk()[..], &mut xz).unwrap();
+    let path = write_temp("mbr.img.xz", &xz);
+    check_mbr_image(&path);
+}
+
+#[test]
+fn test_zstd_image() {
+    // no encoder handy, so hand roll a frame: a raw block with the
+    // mbr and an rle block of zeros for the rest.
+    let disk = fake_mbr_disk();
+    let mut zst = vec![0x28, 0xb5, 0x2f, 0xfd, 0x60];
+    zst.extend_from_slice(&((disk.len() - 256) as u16).to_le_bytes());
+    let raw_header = (512u32 << 3).to_le_bytes();
+    zst.extend_from_slice(&raw_header[..3]);
+    zst.extend_from_slice(&disk[..512]);
+    let rle_header =
+        (((disk.len() as u32 - 512) << 3) | 0b011).to_le_bytes();
+    zst.extend_from_slice(&rle_header[..3]);
+    zst.push(0);
+    let path = write_temp("mbr.img.zst", &zst);
+    check_mbr_image(&path);
+}
+
+fn put_be_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+fn put_be_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+// 512 byte clusters: header, l1, refcount table, l2, refcount
+// block, one data cluster and one compressed cluster.
+fn fake_qcow2(backing: &str) -> Vec<u8> {
+    let mut img = vec![0u8; 512 * 7];
+    img[..4].copy_from_slice(b"QFI\xfb");
+    put_be_u32(&mut img, 4, 3); // version
+    put_be_u64(&mut img, 8, 0x100); // backing file offset
+    put_be_u32(&mut img, 16, backing.len() as u32);
+    put_be_u32(&mut img, 20, 9); // cluster bits
+    put_be_u64(&mut img, 24, 4096); // virtual size
+    put_be_u32(&mut img, 36, 1); // l1 size
+    put_be_u64(&mut img, 40, 512); // l1 offset
+    put_be_u64(&mut img, 48, 1024); // refcount table offset
+    put_be_u32(&mut img, 56, 1); // refcount table clusters
+    put_be_u32(&mut img, 96, 4); // refcount order
+    put_be_u32(&mut img, 100, 104); // header length
+    img[0x100..0x100 + backing.len()]
+        .copy_from_slice(backing.as_bytes());
+    put_be_u64(&mut img, 512, (1 << 63) | 1536); // l1 -> l2
+    put_be_u64(&mut img, 1024, 2048); // refcount table -> block
+    put_be_u64(&mut img, 1536, (1 << 63) | 2560); // guest 0 -> data
+
+    // guest 1 unallocated, goes to the backing file
+    put_be_u64(&mut img, 1536 + 16, 1); // guest 2 zero cluster
+    put_be_u64(&mut img, 1536 + 24, (1 << 62) | 3072); // compressed
+    for cluster in 0..7 {
+        img[2048 + cluster * 2 + 1] = 1;
+    }
+    img[2560..3072].copy_from_slice(&fake_mbr_disk()[..512]);
+    let mut deflate = flate2::write::DeflateEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    );
+    deflate.write_all(&[0xab; 512]).unwrap();
+    let deflated = deflate.finish().unwrap();
+    img[3072..3072 + deflated.len()].copy_from_slice(&deflated);
+    img
+}
+
+#[test]
+fn test_qcow2_image_with_backing_file() {
+    use purd::headers::source::qcow2::Qcow2Source;
+    let mut base = vec![0u8; 4096];
+    base[512..516].copy_from_slice(b"BASE");
+    base[1024..1030].copy_from_slice(b"SHADOW");
+    let base_path = write_temp("base.img", &base);
+    let base_name = std::path::Path::new(&base_path)
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let path = write_temp("overlay.qcow2", &fake_qcow2(&base_name));
+
+    let mut ondisk = reader::new(&path).unwrap();
+    assert_eq!(ondisk.source.len(), 4096);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    assert_eq!(ondisk.read_bytes_from_file(512, 4).unwrap(), b"BASE");
+    assert_eq!(ondisk.read_bytes_from_file(1024, 6).unwrap(), [0; 6]);
+    assert_eq!(
+        ondisk.read_bytes_from_file(1536, 512).unwrap(),
+        vec![0xab; 512]
+    );
+    // a read spanning allocated and unallocated clusters
+    let span = ondisk.read_bytes_from_file(510, 6).unwrap();
+    assert_eq!(span, [0x55, 0xaa, b'B', b'A', b'S', b'E']);
+    assert_eq!(
+        ondisk.read_bytes_from_file(2048, 512).unwrap(),
+        [0; 512]
+    );
+
+    let file = Arc::new(FileSource::open(&path).unwrap());
+    let qcow = Qcow2Source::open(&path, file, 0).unwrap();
+    assert_eq!(qcow.backing_file, Some(base_name));
+    assert_eq!(qcow.refcount(2560).unwrap(), 1);
+    assert!(qcow.validate_refcounts().unwrap().is_empty());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&base_path).unwrap();
+}
+
+fn vhd_footer(
+    disk_type: u32,
+    data_offset: u64,
+    size: u64,
+) -> Vec<u8> {
+    use purd::headers::source::vhd::vhd_checksum;
+    let mut footer = vec![0u8; 512];
+    footer[..8].copy_from_slice(b"conectix");
+    put_be_u32(&mut footer, 12, 0x10000); // format version
+    put_be_u64(&mut footer, 16, data_offset);
+    put_be_u64(&mut footer, 40, size); // original size
+    put_be_u64(&mut footer, 48, size); // current size
+    put_be_u32(&mut footer, 60, disk_type);
+    let sum = vhd_checksum(&footer, 64);
+    put_be_u32(&mut footer, 64, sum);
+    footer
+}
+
+#[test]
+fn test_fixed_vhd_image() {
+    let mut img = fake_mbr_disk();
+    img.extend_from_slice(&vhd_footer(2, !0, 4096));
+    let path = write_temp("fixed.vhd", &img);
+    check_mbr_image(&path);
+
+    // still a VHD once it's been gzipped
+    let mut encoder = flate2::write::GzEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    );
+    encoder.write_all(&img).unwrap();
+    let path = write_temp("fixed.vhd.gz", &encoder.finish().unwrap());
+    check_mbr_image(&path);
+}
+
+// footer copy, dynamic header, BAT, then one 2K block with its
+// sector bitmap. The second block is left unallocated.
+#[test]
+fn test_dynamic_vhd_image() {
+    use purd::headers::source::vhd::vhd_checksum;
+    let footer = vhd_footer(3, 512, 4096);
+    let mut img = footer.clone();
+    let mut dynamic = vec![0u8; 1024];
+    dynamic[..8].copy_from_slice(b"cxsparse");
+    put_be_u64(&mut dynamic, 8, !0);
+    put_be_u64(&mut dynamic, 16, 1536); // BAT
+    put_be_u32(&mut dynamic, 24, 0x10000);
+    put_be_u32(&mut dynamic, 28, 2); // max table entries
+    put_be_u32(&mut dynamic, 32, 2048); // block size
+    let sum = vhd_checksum(&dynamic, 36);
+    put_be_u32(&mut dynamic, 36, sum);
+    img.extend_from_slice(&dynamic);
+    let mut bat = vec![0xffu8; 512];
+    put_be_u32(&mut bat, 0, 4); // block 0 at sector 4
+    img.extend_from_slice(&bat);
+    img.extend_from_slice(&[0xff; 512]); // sector bitmap
+    img.extend_from_slice(&fake_mbr_disk()[..2048]);
+    img.extend_from_slice(&footer);
+    let path = write_temp("dynamic.vhd", &img);
+    check_mbr_image(&path);
+
+    // trailing footer gone, the copy at the front still finds it
+    let end = img.len() - 512;
+    img[end..].fill(0);
+    let path = write_temp("dynamic-no-footer.vhd", &img);
+    check_mbr_image(&path);
+}
+
+fn put_le_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+fn put_le_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+fn put_guid(bytes: &mut [u8], offset: usize, guid: &str) {
+    // mixed endian, the first three fields are little endian
+    let mut raw = *uuid::Uuid::parse_str(guid).unwrap().as_bytes();
+    raw[..4].reverse();
+    raw[4..6].reverse();
+    raw[6..8].reverse();
+    bytes[offset..offset + 16].copy_from_slice(&raw);
+}
+fn vhdx_sum(bytes: &mut [u8], start: usize, len: usize) {
+    use purd::headers::summer::crc32c;
+    let sum = !crc32c(!0, bytes[start..start + len].to_vec());
+    put_le_u32(bytes, start + 4, sum);
+}
+
+// 2MB disk of 1MB blocks with 4K sectors: block 0 lives at 1MB in
+// the file, block 1 was never written.
+fn fake_vhdx() -> Vec<u8> {
+    let mut img = vec![0u8; 0x200000];
+    img[..8].copy_from_slice(b"vhdxfile");
+    for (offset, sequence) in [(0x10000, 1), (0x20000, 2)] {
+        img[offset..offset + 4].copy_from_slice(b"head");
+        put_le_u64(&mut img, offset + 8, sequence);
+        img[offset + 66..offset + 68]
+            .copy_from_slice(&1u16.to_le_bytes());
+        vhdx_sum(&mut img, offset, 0x1000);
+    }
+    // the primary region table, backup left blank on purpose
+    let regi = 0x30000;
+    img[regi..regi + 4].copy_from_slice(b"regi");
+    put_le_u32(&mut img, regi + 8, 2);
+    put_guid(
+        &mut img,
+        regi + 16,
+        "2DC27766-F623-4200-9D64-115E9BFD4A08",
+    );
+    put_le_u64(&mut img, regi + 32, 0x50000);
+    put_le_u32(&mut img, regi + 40, 0x10000);
+    put_le_u32(&mut img, regi + 44, 1);
+    put_guid(
+        &mut img,
+        regi + 48,
+        "8B7CA206-4790-4B9A-B8FE-575F050F886E",
+    );
+    put_le_u64(&mut img, regi + 64, 0x60000);
+    put_le_u32(&mut img, regi + 72, 0x10000);
+    put_le_u32(&mut img, regi + 76, 1);
+    vhdx_sum(&mut img, regi, 0x10000);
+
+    put_le_u64(&mut img, 0x50000, 0x100000 | 6); // fully present
+    let meta = 0x60000;
+    img[meta..meta + 8].copy_from_slice(b"metadata");
+    img[meta + 10..meta + 12].copy_from_slice(&4u16.to_le_bytes());
+    let items = [
+        ("CAA16737-FA36-4D43-B3B6-33F0AA44E76B", 0x100000u64, 8),
+        ("2FA54224-CD1B-4876-B211-5DBED83BF4B8", 0x200000, 8),
+        ("8141BF1D-A96F-4709-BA47-F233A8FAAB5F", 4096, 4),
+        ("CDA348C7-445D-4471-9CC9-E9885251C556", 4096, 4),
+    ];
+    for (i, (guid, value, len)) in items.iter().enumerate() {
+        let entry = meta + 32 + i * 32;
+        let data = 0x10000 / 2 + i * 8;
+        put_guid(&mut img, entry, guid);
+        put_le_u32(&mut img, entry + 16, data as u32);
+        put_le_u32(&mut img, entry + 20, *len);
+        put_le_u64(&mut img, meta + data, *value);
+    }
+    img[0x100000..0x101000].copy_from_slice(&fake_mbr_disk());
+    img
+}
+
+#[test]
+fn test_vhdx_image() {
+    use purd::headers::source::vhdx::VhdxSource;
+    let path = write_temp("disk.vhdx", &fake_vhdx());
+    let mut ondisk = reader::new(&path).unwrap();
+    assert_eq!(ondisk.source.len(), 0x200000);
+    assert_eq!(ondisk.source.sector_size(), 4096);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert!(mbr.check_magic_field(&ondisk, 0).unwrap());
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    // block 1 isn't in the BAT, it reads back as zeros
+    assert_eq!(
+        ondisk.read_bytes_from_file(0x1FFFFC, 4).unwrap(),
+        [0; 4]
+    );
+
+    let file = Arc::new(FileSource::open(&path).unwrap());
+    let vhdx = VhdxSource::open(&path, file).unwrap();
+    assert_eq!(vhdx.header.sequence_number, 2);
+    assert_eq!(vhdx.block_size, 0x100000);
+    std::fs::remove_file(&path).unwrap();
+}
+
+// one grain table covering two 4K grains: header, descriptor, grain
+// directory, grain table, then grain 0 at sector 7.
+fn fake_sparse_extent(
+    descriptor: Option<&str>,
+    grain: &[u8],
+) -> Vec<u8> {
+    let mut img = vec![0u8; 512 * 15];
+    img[..4].copy_from_slice(b"KDMV");
+    put_le_u32(&mut img, 4, 1); // version
+    put_le_u64(&mut img, 12, 16); // capacity
+    put_le_u64(&mut img, 20, 8); // grain size
+    if let Some(text) = descriptor {
+        put_le_u64(&mut img, 28, 1);
+        put_le_u64(&mut img, 36, 1);
+        img[512..512 + text.len()].copy_from_slice(text.as_bytes());
+    }
+    put_le_u32(&mut img, 44, 512); // entries per grain table
+    put_le_u64(&mut img, 56, 2); // grain directory
+    put_le_u32(&mut img, 1024, 3); // gd -> gt
+    put_le_u32(&mut img, 1536, 7); // grain 0, grain 1 unallocated
+    img[3584..3584 + grain.len()].copy_from_slice(grain);
+    img
+}
+
+#[test]
+fn test_vmdk_monolithic_sparse() {
+    let descriptor =
+        "# Disk DescriptorFile\nversion=1\nCID=12345678\n\
+        parentCID=ffffffff\ncreateType=\"monolithicSparse\"\n\
+        RW 16 SPARSE \"mono.vmdk\"\n";
+    let img = fake_sparse_extent(Some(descriptor), &fake_mbr_disk());
+    let path = write_temp("mono.vmdk", &img);
+    let mut ondisk = reader::new(&path).unwrap();
+    assert_eq!(ondisk.source.len(), 8192);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert!(mbr.check_magic_field(&ondisk, 0).unwrap());
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    assert_eq!(ondisk.read_bytes_from_file(4096, 4).unwrap(), [0; 4]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_vmdk_split_extents() {
+    use purd::headers::source::vmdk::{ExtentKind, VmdkSource};
+    let s001 = write_temp(
+        "split-s001.vmdk",
+        &fake_sparse_extent(None, &fake_mbr_disk()),
+    );
+    let s002 = write_temp(
+        "split-s002.vmdk",
+        &fake_sparse_extent(None, b"SECOND"),
+    );
+    let name = |p: &str| {
+        std::path::Path::new(p)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    };
+    let descriptor = format!(
+        "# Disk DescriptorFile\nversion=1\nCID=12345678\n\
+        parentCID=ffffffff\ncreateType=\"twoGbMaxExtentSparse\"\n\n\
+        # Extent description\nRW 16 SPARSE \"{}\"\n\
+        RW 16 SPARSE \"{}\"\nRW 8 ZERO\n\n\
+        ddb.adapterType = \"lsilogic\"\n",
+        name(&s001),
+        name(&s002)
+    );
+    let path = write_temp("split.vmdk", descriptor.as_bytes());
+
+    let mut ondisk = reader::new(&path).unwrap();
+    assert_eq!(ondisk.source.len(), 8192 * 2 + 4096);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    assert_eq!(
+        ondisk.read_bytes_from_file(8192, 6).unwrap(),
+        b"SECOND"
+    );
+    // spans the end of the first extent into the second
+    let span = ondisk.read_bytes_from_file(8190, 4).unwrap();
+    assert_eq!(span, [0, 0, b'S', b'E']);
+
+    let file = Arc::new(FileSource::open(&path).unwrap());
+    let vmdk = VmdkSource::open(&path, file, 0).unwrap();
+    assert_eq!(vmdk.descriptor.create_type, "twoGbMaxExtentSparse");
+    assert_eq!(vmdk.descriptor.extents[2].kind, ExtentKind::Zero);
+    assert_eq!(
+        vmdk.descriptor.ddb,
+        vec![("ddb.adapterType".to_string(), "lsilogic".to_string())]
+    );
+    for p in [&path, &s001, &s002] {
+        std::fs::remove_file(p).unwrap();
+    }
+}
+
+#[test]
+fn test_vmdk_garbage_header() {
+    use purd::headers::error::PurdError;
+    use purd::headers::source::vmdk::VmdkSource;
+    let open = |img: Vec<u8>| {
+        let file: Arc<dyn BlockSource> =
+            Arc::new(MemorySource::new("junk.vmdk", img));
+        VmdkSource::open("junk.vmdk", file, 0)
+    };
+    let mono = |lines: &str| {
+        let descriptor = format!(
+            "# Disk DescriptorFile\nversion=1\nCID=12345678\n\
+            parentCID=ffffffff\ncreateType=\"custom\"\n{}",
+            lines
+        );
+        fake_sparse_extent(Some(&descriptor), &fake_mbr_disk())
+    };
+    let mut cases = vec![];
+    // a power of two grain, but no table of them fits in a u64
+    let mut img = mono("RW 16 SPARSE \"junk.vmdk\"\n");
+    put_le_u64(&mut img, 20, 1 << 62);
+    cases.push(img);
+    let mut img = mono("RW 16 SPARSE \"junk.vmdk\"\n");
+    put_le_u64(&mut img, 12, u64::MAX); // capacity
+    cases.push(img);
+    let mut img = mono("RW 16 SPARSE \"junk.vmdk\"\n");
+    put_le_u64(&mut img, 56, u64::MAX / 2); // grain directory
+    cases.push(img);
+    let mut img = mono("RW 16 SPARSE \"junk.vmdk\"\n");
+    put_le_u64(&mut img, 28, u64::MAX / 2); // descriptor
+    cases.push(img);
+    cases.push(mono(&format!("RW {} ZERO\n", u64::MAX)));
+    // each one fits, the two together don't
+    let half = u64::MAX / 1024 + 1;
+    cases
+        .push(mono(&format!("RW {} ZERO\nRW {} ZERO\n", half, half)));
+    for img in cases {
+        assert!(matches!(
+            open(img),
+            Err(PurdError::OutOfBounds { .. })
+        ));
+    }
+}
+
+// appends a section, next_offset pointing just past it (or at
+// itself for next/done)
+fn ewf_section(seg: &mut Vec<u8>, kind: &str, data: &[u8]) {
+    use purd::headers::summer::adler32;
+    let offset = seg.len();
+    let mut desc = vec![0u8; 76];
+    desc[..kind.len()].copy_from_slice(kind.as_bytes());
+    let size = 76 + data.len() as u64;
+    let next = match kind {
+        "next" | "done" => offset as u64,
+        _ => offset as u64 + size,
+    };
+    put_le_u64(&mut desc, 16, next);
+    put_le_u64(&mut desc, 24, size);
+    let sum = adler32(1, &desc[..72]);
+    put_le_u32(&mut desc, 72, sum);
+    seg.extend_from_slice(&desc);
+    seg.extend_from_slice(data);
+}
+
+fn ewf_segment(number: u16) -> Vec<u8> {
+    let mut seg = b"EVF\x09\x0d\x0a\xff\x00\x01".to_vec();
+    seg.extend_from_slice(&number.to_le_bytes());
+    seg.extend_from_slice(&[0, 0]);
+    seg
+}
+
+// a sectors section holding one chunk and the table pointing at it
+fn ewf_chunk(seg: &mut Vec<u8>, chunk: &[u8], compressed: bool) {
+    use purd::headers::summer::adler32;
+    let stored = if compressed {
+        let mut zlib = flate2::write::ZlibEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        );
+        zlib.write_all(chunk).unwrap();
+        zlib.finish().unwrap()
+    } else {
+        let mut raw = chunk.to_vec();
+        raw.extend_from_slice(&adler32(1, chunk).to_le_bytes());
+        raw
+    };
+    let chunk_offset = seg.len() as u32 + 76;
+    ewf_section(seg, "sectors", &stored);
+    let mut table = vec![0u8; 24];
+    put_le_u32(&mut table, 0, 1);
+    let sum = adler32(1, &table[..20]);
+    put_le_u32(&mut table, 20, sum);
+    let entry = chunk_offset | if compressed { 1 << 31 } else { 0 };
+    table.extend_from_slice(&entry.to_le_bytes());
+    let sum = adler32(1, &entry.to_le_bytes());
+    table.extend_from_slice(&sum.to_le_bytes());
+    ewf_section(seg, "table", &table);
+    ewf_section(seg, "table2", &table);
+}
+
+#[test]
+fn test_ewf_segmented_image() {
+    use md5::{Digest, Md5};
+    use purd::headers::source::ewf::{
+        segment_path, verify_image, EwfSource,
+    };
+    let mut media = fake_mbr_disk();
+    media.extend_from_slice(b"SECOND");
+    media.resize(8192, 0);
+
+    let mut e01 = ewf_segment(1);
+    let mut header = flate2::write::ZlibEncoder::new(
+        vec![],
+        flate2::Compression::default(),
+    );
+    header
+        .write_all(b"1\nmain\nc\tn\ncase 7\tevidence 1\n")
+        .unwrap();
+    ewf_section(&mut e01, "header", &header.finish().unwrap());
+    let mut volume = vec![0u8; 1052];
+    put_le_u32(&mut volume, 4, 2); // chunks
+    put_le_u32(&mut volume, 8, 8); // sectors per chunk
+    put_le_u32(&mut volume, 12, 512);
+    put_le_u64(&mut volume, 16, 16); // sectors
+    ewf_section(&mut e01, "volume", &volume);
+    ewf_chunk(&mut e01, &media[..4096], true);
+    ewf_section(&mut e01, "next", &[]);
+
+    let mut e02 = ewf_segment(2);
+    ewf_chunk(&mut e02, &media[4096..], false);
+    // chunks the tables list past the end of the media
+    ewf_chunk(&mut e02, &[0; 4096], false);
+    ewf_chunk(&mut e02, &[0; 4096], false);
+    let mut digest = vec![0u8; 80];
+    digest[..16].copy_from_slice(&Md5::digest(&media));
+    digest[16..36].copy_from_slice(&sha1::Sha1::digest(&media));
+    ewf_section(&mut e02, "digest", &digest);
+    ewf_section(&mut e02, "done", &[]);
+
+    let path = write_temp("evidence.E01", &e01);
+    let second = segment_path(&path, 2).unwrap();
+    assert!(second.ends_with("evidence.E02"));
+    std::fs::write(&second, &e02).unwrap();
+
+    let mut ondisk = reader::new(&path).unwrap();
+    assert_eq!(ondisk.source.len(), 8192);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert!(mbr.check_magic_field(&ondisk, 0).unwrap());
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    let span = ondisk.read_bytes_from_file(4094, 8).unwrap();
+    assert_eq!(span, [0, 0, b'S', b'E', b'C', b'O', b'N', b'D']);
+
+    let file = Arc::new(FileSource::open(&path).unwrap());
+    let ewf = EwfSource::open(&path, file).unwrap();
+    assert!(ewf.header.as_ref().unwrap().contains("case 7"));
+    // what --verify runs
+    let checks = verify_image(&path).unwrap();
+    assert_eq!(checks.len(), 2);
+    assert!(checks.iter().all(|c| c.matches()));
+    assert!(ewf.read_chunk(1).is_ok());
+    assert!(ewf.read_chunk(3).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&second).unwrap();
+}
+
+#[test]
+fn test_ewf_segment_names() {
+    use purd::headers::source::ewf::segment_path;
+    assert_eq!(segment_path("a.E01", 99).unwrap(), "a.E99");
+    assert_eq!(segment_path("a.E01", 100).unwrap(), "a.EAA");
+    assert_eq!(segment_path("a.e01", 101).unwrap(), "a.eab");
+    assert_eq!(
+        segment_path("a.E01", 100 + 26 * 26).unwrap(),
+        "a.FAA"
+    );
+}
+
+#[test]
+fn test_split_raw_image() {
+    use purd::headers::source::split::{segment_path, SplitSource};
+    let disk: Vec<u8> = fake_mbr_disk()
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if i > 512 { i as u8 } else { *b })
+        .collect();
+    let first = write_temp("split.img.001", &disk[..1000]);
+    let second = segment_path(&first, 2).unwrap();
+    let third = segment_path(&first, 3).unwrap();
+    // .004 is missing so .005 isn't part of the image
+    let stray = segment_path(&first, 5).unwrap();
+    std::fs::write(&second, &disk[1000..2000]).unwrap();
+    std::fs::write(&third, &disk[2000..]).unwrap();
+    std::fs::write(&stray, b"STRAY").unwrap();
+
+    let mut ondisk = reader::new(&first).unwrap();
+    assert_eq!(ondisk.source.len(), 4096);
+    let mbr = ondisk.read_header_from_offset::<Mbr>(0).unwrap();
+    assert_eq!(mbr.partitions[0].lba_of_partition_start, 2);
+    // crosses both segment boundaries
+    let span = ondisk.read_bytes_from_file(990, 1100).unwrap();
+    assert_eq!(span, disk[990..2090].to_vec());
+    assert_eq!(
+        ondisk.read_bytes_from_file(4000, 96).unwrap(),
+        disk[4000..].to_vec()
+    );
+    assert!(ondisk.read_bytes_from_file(4000, 97).is_err());
+    assert_eq!(SplitSource::open(&first).unwrap().segment_count(), 3);
+    // pointing at a later segment just reads that file
+    assert_eq!(reader::new(&second).unwrap().source.len(), 1000);
+
+    for p in [&first, &second, &third, &stray] {
+        std::fs::remove_file(p).unwrap();
+    }
+}
+
+// counts how often the cache actually goes to the image
+#[derive(Debug)]
+struct CountingSource {
+    inner: MemorySource,
+    reads: std::sync::atomic::AtomicU64,
+}
+
+impl BlockSource for CountingSource {
+    fn read_at(
+        &self,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), PurdError> {
+        self.reads
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.inner.read_at(offset, buf)
+    }
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+#[test]
+fn test_block_cache() {
+    use purd::headers::source::cache::{CacheConfig, CachedSource};
+    use std::sync::atomic::Ordering;
+    let mut bytes = vec![0u8; 512 * 64 + 100];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (i / 512) as u8;
+    }
+    let counting = Arc::new(CountingSource {
+        inner: MemorySource::new("counted", bytes.clone()),
+        reads: Default::default(),
+    });
+    let config = CacheConfig {
+        block_size: 512,
+        capacity: 16,
+        read_ahead: 3,
+    };
+    let cache = CachedSource::new(counting.clone(), config);
+
+    // same block twice, one miss then one hit
+    assert_eq!(cache.read_bytes(10, 12).unwrap(), bytes[10..22]);
+    assert_eq!(cache.read_bytes(100, 4).unwrap(), bytes[100..104]);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 1);
+
+    // block 1 follows the last miss so blocks 2-4 come along with it,
+    // then block 5 carries on from there and pulls in 6-8
+    assert_eq!(cache.read_bytes(512, 4).unwrap(), bytes[512..516]);
+    assert_eq!(cache.stats().read_ahead, 3);
+    let span = cache.read_bytes(1000, 1600).unwrap();
+    assert_eq!(span, bytes[1000..2600]);
+    assert_eq!(cache.stats().read_ahead, 6);
+    assert_eq!(counting.reads.load(Ordering::Relaxed), 3);
+
+    // the partial block at the very end
+    let end = bytes.len() as u64;
+    assert_eq!(
+        cache.read_bytes(end - 50, 50).unwrap(),
+        bytes[end as usize - 50..]
+    );
+    assert!(cache.read_bytes(end - 50, 51).is_err());
+
+    // walk far enough to push block 0 out
+    for block in 20..30u64 {
+        cache.read_bytes(block * 512, 1).unwrap();
+    }
+    assert!(cache.stats().evictions > 0);
+    let before = counting.reads.load(Ordering::Relaxed);
+    cache.read_bytes(0, 1).unwrap();
+    assert_eq!(counting.reads.load(Ordering::Relaxed), before + 1);
+
+    // a cacheless reader goes straight through
+    let ondisk = reader::from_source(Arc::new(CachedSource::new(
+        counting.clone(),
+        CacheConfig {
+            capacity: 0,
+            ..config
+        },
+    )));
+    ondisk.source.read_bytes(0, 1).unwrap();
+    ondisk.source.read_bytes(0, 1).unwrap();
+    assert_eq!(ondisk.source.cache_stats().unwrap().hits, 0);
+}
+
+#[test]
+fn test_striped_source() {
+    // two stripes of 4 byte chunks, the second one longer
+    let a: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("a", b"AAAACCCCEEEE".to_vec()));
+    let b: Arc<dyn BlockSource> =
+        Arc::new(MemorySource::new("b", b"BBBBDDDDFFFFxx".to_vec()));
+    let striped = StripedSource::new("ab", vec![a, b], 4).unwrap();
+    assert_eq!(striped.len(), 24);
+    assert_eq!(
+        striped.read_bytes(0, 24).unwrap(),
+        b"AAAABBBBCCCCDDDDEEEEFFFF".to_vec()
+    );
+    assert_eq!(striped.read_bytes(6, 5).unwrap(), b"BBCCC".to_vec());
+    assert!(striped.read_bytes(22, 4).is_err());
+    assert!(StripedSource::new("none", vec![], 4).is_err());
+}
@@ -32,28 +32,31 @@ fn test_md4() {
 
 #[test]
 fn test_validate_known_boot_sector() {
-    let mut reader = reader::new("tests/main_boot_sector.bin");
+    let mut reader = reader::new("tests/main_boot_sector.bin").unwrap();
     let processed_header = reader
-        .read_header_from_offset::<exfat::boot_sector::BootSector>(0);
+        .read_header_from_offset::<exfat::boot_sector::BootSector>(0)
+        .unwrap();
     assert_eq!(processed_header.validate_header(), true);
 }
 
 #[test]
 fn test_read_known_mbr() {
-    let mut reader = reader::new("tests/mbr.bin");
+    let mut reader = reader::new("tests/mbr.bin").unwrap();
     let _processed_header = reader
-        .read_header_from_offset::<exfat::boot_sector::BootSector>(0);
+        .read_header_from_offset::<exfat::boot_sector::BootSector>(0)
+        .unwrap();
 }
 
 #[test]
 fn test_read_mbr_and_boot_sector() {
-    let mut reader = reader::new("tests/mbr_and_mbs.bin");
+    let mut reader = reader::new("tests/mbr_and_mbs.bin").unwrap();
 
-    let mbr = reader.read_header_from_offset::<Mbr>(0);
+    let mbr = reader.read_header_from_offset::<Mbr>(0).unwrap();
     let main_boot_sector = reader
         .read_header_from_offset::<exfat::boot_sector::BootSector>(
             mbr.partitions[0].lba_of_partition_start as u64 * 512,
-        );
+        )
+        .unwrap();
     assert_eq!(main_boot_sector.validate_header(), true);
 }
 
@@ -62,18 +65,39 @@ fn test_read_mbr_and_boot_sector() {
 
 #[test]
 fn test_read_gpt_and_ext4_partition_entries() {
-    let test_file = "tests/multipart.bin";
-    let mut reader = reader::new(test_file);
-    let mbr = reader.read_header_from_offset::<Mbr>(0);
+    let test_file = "tests/multipart.bin.gz";
+    let mut reader = reader::new(test_file).unwrap();
+    let mbr = reader.read_header_from_offset::<Mbr>(0).unwrap();
     println!("{:?}", mbr);
-    let gpt = reader.read_header_from_offset::<Gpt>(512);
+    let gpt = reader.read_header_from_offset::<Gpt>(512).unwrap();
     println!("{:x?}", gpt);
     let ext4 = reader.read_header_from_offset::<PartitionEntry>(
         gpt.gpe_table_start * 512,
-    );
+    )
+    .unwrap();
     let superblock = reader.read_header_from_offset::<Superblock>(
         1024 + ext4.first_lba * 512,
-    );
+    )
+    .unwrap();
     //ext4 pads 1024 bytes ahead of block0
     assert_eq!(superblock.magic, 0xef53) //ext4 magic
 }
+
+#[test]
+fn test_errors_instead_of_panics() {
+    use purd::headers::error::PurdError;
+    use purd::headers::ext4::extent::ExtentTree;
+    assert!(matches!(
+        reader::new("tests/does_not_exist.bin"),
+        Err(PurdError::Io(_))
+    ));
+    let mut reader = reader::new("tests/mbr_and_mbs.bin").unwrap();
+    assert!(matches!(
+        reader.read_bytes_from_file(1 << 40, 512),
+        Err(PurdError::OutOfBounds { .. })
+    ));
+    assert!(matches!(
+        ExtentTree::init(&[0u8; 60]),
+        Err(PurdError::BadMagic { .. })
+    ));
+}